@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use smtp_server::address;
+use smtp_server::command::Command;
+use smtp_server::metrics;
+
+fuzz_target!(|line: &str| {
+    let command = Command::parse(line);
+    assert_eq!(Command::parse(&command.to_string()), command);
+    metrics::command_name(line);
+    match command {
+        Command::Mail(path) => {
+            let _ = address::parse_reverse_path(&path);
+        }
+        Command::Rcpt(path) => {
+            let _ = address::parse_forward_path(&path);
+        }
+        _ => {}
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use smtp_server::server;
+
+fuzz_target!(|raw: &[u8]| {
+    if let Some((content, consumed)) = server::split_data_body(raw) {
+        assert!(consumed <= raw.len());
+        assert!(content.len() <= consumed);
+        assert!(content.is_empty() || content.ends_with(b"\r\n"));
+    }
+});
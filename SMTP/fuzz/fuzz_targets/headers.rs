@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use smtp_server::headers::{self, Headers};
+use smtp_server::mime;
+
+fuzz_target!(|raw: &[u8]| {
+    if let Some(end) = headers::field_end(raw) {
+        assert!(end <= raw.len());
+    }
+    let (head, body) = headers::split_message_bytes(raw);
+    assert!(head.len() + body.len() <= raw.len());
+    let text = String::from_utf8_lossy(raw);
+    let parsed = Headers::parse(&text);
+    for field in parsed.iter() {
+        let _ = field.unfolded();
+    }
+    let _ = mime::parse_message(&text);
+});
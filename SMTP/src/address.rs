@@ -0,0 +1,405 @@
+//! Envelope paths of `MAIL FROM` and `RCPT TO` (RFC 5321 section 4.1.2),
+//! with the UTF-8 local parts and domains RFC 6531 allows, and the address
+//! lists of header fields.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::idna;
+
+const MAX_LOCAL_PART: usize = 64;
+const MAX_DOMAIN: usize = 255;
+const MAX_PATH: usize = 256;
+
+fn syntax(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg.to_string())
+}
+
+/// `atext` of RFC 5322, plus any non-ASCII character for SMTPUTF8.
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+fn is_dot_string(s: &str) -> bool {
+    !s.is_empty() && s.split('.').all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+/// A `local-part@domain` address. The domain is lower cased and in ASCII
+/// form; address literals such as `[192.0.2.1]` are kept with their brackets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mailbox {
+    /// The local part with any quoting removed.
+    pub local_part: String,
+    pub domain: String,
+}
+
+impl Mailbox {
+    /// Parses a bare mailbox, without angle brackets.
+    pub fn parse(s: &str) -> Result<Mailbox> {
+        let mut parser = Parser { s, pos: 0 };
+        let mailbox = parser.mailbox()?;
+        parser.end()?;
+        Ok(mailbox)
+    }
+
+    pub fn is_ascii(&self) -> bool {
+        self.local_part.is_ascii() && self.domain.is_ascii()
+    }
+
+    /// Whether the domain is an address literal rather than a name.
+    pub fn is_literal(&self) -> bool {
+        self.domain.starts_with('[')
+    }
+}
+
+impl fmt::Display for Mailbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_dot_string(&self.local_part) {
+            write!(f, "{}@{}", self.local_part, self.domain)
+        } else {
+            let quoted = self.local_part.replace('\\', "\\\\").replace('"', "\\\"");
+            write!(f, "\"{}\"@{}", quoted, self.domain)
+        }
+    }
+}
+
+/// The addresses in a header field such as `To: Ann <ann@a.test>, bob@b.test`
+/// (RFC 5322 section 3.4), skipping display names, comments and groups.
+/// They are not checked; `Mailbox::parse` does that.
+pub fn parse_address_list(value: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let (mut item, mut quoted, mut angle, mut comment) = (String::new(), false, false, 0);
+    for c in value.chars() {
+        match c {
+            '"' if comment == 0 => quoted = !quoted,
+            '(' if !quoted => comment += 1,
+            ')' if !quoted && comment > 0 => comment -= 1,
+            '<' if !quoted && comment == 0 => angle = true,
+            '>' if !quoted && comment == 0 => angle = false,
+            ',' | ';' if !quoted && !angle && comment == 0 => {
+                items.push(std::mem::take(&mut item));
+                continue;
+            }
+            _ => {}
+        }
+        item.push(c);
+    }
+    items.push(item);
+    items
+        .iter()
+        .filter_map(|item| {
+            let address = match (item.rfind('<'), item.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &item[start + 1..end],
+                // A group name comes before a colon.
+                _ => item.rsplit(':').next().unwrap_or_default().split('(').next().unwrap_or_default(),
+            };
+            let address = address.trim();
+            (!address.is_empty()).then(|| address.to_string())
+        })
+        .collect()
+}
+
+/// The `MAIL FROM` path; `Null` is `<>`, used for bounces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReversePath {
+    Null,
+    Mailbox(Mailbox),
+}
+
+impl ReversePath {
+    pub fn mailbox(&self) -> Option<&Mailbox> {
+        match self {
+            ReversePath::Null => None,
+            ReversePath::Mailbox(mailbox) => Some(mailbox),
+        }
+    }
+
+    pub fn is_ascii(&self) -> bool {
+        self.mailbox().is_none_or(Mailbox::is_ascii)
+    }
+}
+
+/// The address without brackets, empty for the null path.
+impl fmt::Display for ReversePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReversePath::Null => Ok(()),
+            ReversePath::Mailbox(mailbox) => mailbox.fmt(f),
+        }
+    }
+}
+
+/// The `RCPT TO` path; `Postmaster` is the domain-less `<Postmaster>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardPath {
+    Postmaster,
+    Mailbox(Mailbox),
+}
+
+impl ForwardPath {
+    pub fn mailbox(&self) -> Option<&Mailbox> {
+        match self {
+            ForwardPath::Postmaster => None,
+            ForwardPath::Mailbox(mailbox) => Some(mailbox),
+        }
+    }
+
+    pub fn is_ascii(&self) -> bool {
+        self.mailbox().is_none_or(Mailbox::is_ascii)
+    }
+}
+
+impl fmt::Display for ForwardPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardPath::Postmaster => f.write_str("Postmaster"),
+            ForwardPath::Mailbox(mailbox) => mailbox.fmt(f),
+        }
+    }
+}
+
+/// One ESMTP parameter such as `SIZE=1000` or `SMTPUTF8`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Parameter {
+    /// Upper cased keyword.
+    pub keyword: String,
+    pub value: Option<String>,
+}
+
+/// The ESMTP parameters following a path, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Parameters(pub Vec<Parameter>);
+
+impl Parameters {
+    pub fn has(&self, keyword: &str) -> bool {
+        self.0.iter().any(|p| p.keyword.eq_ignore_ascii_case(keyword))
+    }
+
+    /// The value of the first `keyword` parameter that has one.
+    pub fn get(&self, keyword: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|p| p.keyword.eq_ignore_ascii_case(keyword))
+            .and_then(|p| p.value.as_deref())
+    }
+}
+
+/// Whether `s` is what `EHLO`, `HELO` and `LHLO` may give: a domain or an
+/// address literal (RFC 5321 section 4.1.1.1).
+pub fn is_client_domain(s: &str) -> bool {
+    let mut parser = Parser { s, pos: 0 };
+    let parsed = if parser.peek() == Some('[') { parser.address_literal() } else { parser.domain() };
+    parsed.is_ok() && parser.end().is_ok()
+}
+
+/// Parses the text after `MAIL FROM:`, e.g. `<a@example.com> SIZE=100`.
+pub fn parse_reverse_path(value: &str) -> Result<(ReversePath, Parameters)> {
+    let mut parser = Parser { s: value.trim(), pos: 0 };
+    let path = match parser.path(true)? {
+        Some(mailbox) => ReversePath::Mailbox(mailbox),
+        None => ReversePath::Null,
+    };
+    Ok((path, parser.parameters()?))
+}
+
+/// Parses the text after `RCPT TO:`, e.g. `<b@example.com> NOTIFY=NEVER`.
+pub fn parse_forward_path(value: &str) -> Result<(ForwardPath, Parameters)> {
+    let value = value.trim();
+    if value.get(..12).is_some_and(|p| p.eq_ignore_ascii_case("<postmaster>")) {
+        let mut parser = Parser { s: value, pos: 12 };
+        return Ok((ForwardPath::Postmaster, parser.parameters()?));
+    }
+    let mut parser = Parser { s: value, pos: 0 };
+    match parser.path(false)? {
+        Some(mailbox) => Ok((ForwardPath::Mailbox(mailbox), parser.parameters()?)),
+        None => Err(syntax("null path not allowed")),
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char, msg: &str) -> Result<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(syntax(msg))
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &str {
+        let start = self.pos;
+        while let Some(c) = self.peek().filter(|&c| f(c)) {
+            self.pos += c.len_utf8();
+        }
+        &self.s[start..self.pos]
+    }
+
+    fn end(&self) -> Result<()> {
+        if self.pos == self.s.len() {
+            Ok(())
+        } else {
+            Err(syntax("unexpected characters after address"))
+        }
+    }
+
+    /// `"<" [ A-d-l ":" ] Mailbox ">"`, or `<>` when `allow_null`.
+    fn path(&mut self, allow_null: bool) -> Result<Option<Mailbox>> {
+        let start = self.pos;
+        self.expect('<', "address must be enclosed in <>")?;
+        if self.eat('>') {
+            return if allow_null { Ok(None) } else { Err(syntax("null path not allowed")) };
+        }
+        if self.peek() == Some('@') {
+            // A source route; RFC 5321 says to accept and ignore it.
+            loop {
+                self.expect('@', "bad source route")?;
+                self.domain()?;
+                if !self.eat(',') {
+                    break;
+                }
+            }
+            self.expect(':', "bad source route")?;
+        }
+        let mailbox = self.mailbox()?;
+        self.expect('>', "address must be enclosed in <>")?;
+        if self.pos - start > MAX_PATH {
+            return Err(syntax("path too long"));
+        }
+        Ok(Some(mailbox))
+    }
+
+    fn mailbox(&mut self) -> Result<Mailbox> {
+        let local_part = if self.peek() == Some('"') {
+            self.quoted_string()?
+        } else {
+            let dot_string = self.take_while(|c| c == '.' || is_atext(c));
+            if !is_dot_string(dot_string) {
+                return Err(syntax("bad local part"));
+            }
+            dot_string.to_string()
+        };
+        if local_part.len() > MAX_LOCAL_PART {
+            return Err(syntax("local part too long"));
+        }
+        self.expect('@', "missing domain")?;
+        let domain = if self.peek() == Some('[') {
+            self.address_literal()?
+        } else {
+            self.domain()?
+        };
+        Ok(Mailbox { local_part, domain })
+    }
+
+    fn quoted_string(&mut self) -> Result<String> {
+        self.expect('"', "bad quoted string")?;
+        let mut content = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(content);
+                }
+                Some('\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(c) if (' '..='~').contains(&c) => {
+                            content.push(c);
+                            self.pos += 1;
+                        }
+                        _ => return Err(syntax("bad quoted pair")),
+                    }
+                }
+                Some(c) if c == ' ' || c == '!' || (c >= '#' && c != '\x7f') => {
+                    content.push(c);
+                    self.pos += c.len_utf8();
+                }
+                _ => return Err(syntax("unterminated quoted string")),
+            }
+        }
+    }
+
+    /// Dot separated labels of letters, digits and hyphens, returned in ASCII form.
+    fn domain(&mut self) -> Result<String> {
+        let domain = self.take_while(|c| c == '.' || c == '-' || c.is_alphanumeric());
+        let valid_label = |label: &str| {
+            !label.is_empty() && !label.starts_with('-') && !label.ends_with('-')
+        };
+        if !domain.split('.').all(valid_label) {
+            return Err(syntax("bad domain"));
+        }
+        let domain = idna::to_ascii(domain)?;
+        if domain.len() > MAX_DOMAIN {
+            return Err(syntax("domain too long"));
+        }
+        Ok(domain)
+    }
+
+    /// `[192.0.2.1]`, `[IPv6:2001:db8::1]` or a general `[tag:content]` literal.
+    fn address_literal(&mut self) -> Result<String> {
+        self.expect('[', "bad address literal")?;
+        let content = self.take_while(|c| ('!'..='Z').contains(&c) || ('^'..='~').contains(&c));
+        let valid = match content.split_once(':') {
+            Some((tag, ip)) if tag.eq_ignore_ascii_case("IPv6") => ip.parse::<Ipv6Addr>().is_ok(),
+            Some((tag, rest)) => {
+                !tag.is_empty()
+                    && !rest.is_empty()
+                    && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                    && !rest.contains(':')
+            }
+            None => content.parse::<Ipv4Addr>().is_ok(),
+        };
+        if !valid {
+            return Err(syntax("bad address literal"));
+        }
+        let literal = format!("[{}]", content);
+        self.expect(']', "bad address literal")?;
+        Ok(literal)
+    }
+
+    /// `esmtp-param *(SP esmtp-param)` up to the end of the input.
+    fn parameters(&mut self) -> Result<Parameters> {
+        let rest = &self.s[self.pos..];
+        if !rest.is_empty() && !rest.starts_with(' ') {
+            return Err(syntax("unexpected characters after address"));
+        }
+        let mut params = Vec::new();
+        for param in rest.split_whitespace() {
+            let (keyword, value) = match param.split_once('=') {
+                Some((keyword, value)) => (keyword, Some(value)),
+                None => (param, None),
+            };
+            let valid_keyword = keyword.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && keyword.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+            if !valid_keyword {
+                return Err(syntax("bad parameter keyword"));
+            }
+            if value.is_some_and(|v| v.is_empty() || v.contains('=') || v.contains(char::is_control)) {
+                return Err(syntax("bad parameter value"));
+            }
+            params.push(Parameter {
+                keyword: keyword.to_ascii_uppercase(),
+                value: value.map(str::to_string),
+            });
+        }
+        self.pos = self.s.len();
+        Ok(Parameters(params))
+    }
+}
@@ -0,0 +1,114 @@
+//! MailHog style HTTP API over a `MessageStore`.
+//!
+//! - `GET /api/messages` lists summaries, filtered by `to`, `from` and `subject`
+//! - `GET /api/messages/{id}` returns envelope, headers, bodies and MIME parts
+//! - `GET /api/messages/{id}/raw` returns the message as received
+//! - `DELETE /api/messages/{id}` and `DELETE /api/messages` remove messages
+
+use std::io::Result;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::http::{self, Request, Response};
+use crate::json::Json;
+use crate::mime::{self, Part};
+use crate::store::{MessageStore, SearchQuery, StoredMessage};
+
+/// Starts the API on `addr` in a background thread and returns the bound address.
+pub fn spawn(addr: impl ToSocketAddrs, store: Arc<dyn MessageStore>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    println!("HTTP API listening on {}", local);
+    thread::spawn(move || http::serve(listener, move |request| route(store.as_ref(), request)));
+    Ok(local)
+}
+
+/// Answers one API request.
+pub fn route(store: &dyn MessageStore, request: &Request) -> Response {
+    let segments = request.segments();
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["api", "messages"]) | ("GET", ["api", "search"]) => list(store, request),
+        ("DELETE", ["api", "messages"]) => store.delete_all().map(|_| Response::no_content()),
+        ("GET", ["api", "messages", id]) => store.get(id).map(|found| match found {
+            Some(msg) => Response::json(200, &details(&msg)),
+            None => Response::error(404, "no such message"),
+        }),
+        ("GET", ["api", "messages", id, "raw"]) => store.get(id).map(|found| match found {
+            Some(msg) => Response::bytes(200, "message/rfc822", msg.content),
+            None => Response::error(404, "no such message"),
+        }),
+        ("DELETE", ["api", "messages", id]) => store.delete(id).map(|deleted| {
+            if deleted {
+                Response::no_content()
+            } else {
+                Response::error(404, "no such message")
+            }
+        }),
+        (_, ["api", ..]) => Ok(Response::error(405, "method not allowed")),
+        _ => Ok(Response::error(404, "not found")),
+    };
+    result.unwrap_or_else(|e| Response::error(500, &e.to_string()))
+}
+
+fn list(store: &dyn MessageStore, request: &Request) -> Result<Response> {
+    let query = SearchQuery {
+        recipient: request.query("to").map(|s| s.to_string()),
+        sender: request.query("from").map(|s| s.to_string()),
+        subject: request.query("subject").map(|s| s.to_string()),
+    };
+    let messages: Vec<Json> = store.search(&query)?.iter().map(summary).collect();
+    Ok(Response::json(
+        200,
+        &Json::object().field("total", messages.len()).field("messages", messages),
+    ))
+}
+
+fn summary(msg: &StoredMessage) -> Json {
+    Json::object()
+        .field("id", &msg.id)
+        .field("received_at", msg.received_at)
+        .field("mail_from", &msg.mail_from)
+        .field("recipients", msg.recipients.clone())
+        .field("quarantined", msg.quarantined)
+        .field("folder", msg.folder.clone())
+        .field("from", msg.header("From"))
+        .field("to", msg.header("To"))
+        .field("subject", msg.header("Subject"))
+        .field("date", msg.header("Date"))
+        .field("size", msg.content.len())
+}
+
+fn details(msg: &StoredMessage) -> Json {
+    let headers: Vec<Json> = msg
+        .headers()
+        .iter()
+        .map(|h| {
+            Json::object()
+                .field("name", h.name.trim())
+                .field("value", mime::decode_encoded_words(&h.unfolded()))
+        })
+        .collect();
+    let root = msg.mime();
+    summary(msg)
+        .field("headers", headers)
+        .field("text", root.find_text("text/plain"))
+        .field("html", root.find_text("text/html"))
+        .field("body", String::from_utf8_lossy(msg.body()).into_owned())
+        .field("parts", root.leaves().into_iter().map(part).collect::<Vec<_>>())
+}
+
+fn part(part: &Part) -> Json {
+    let json = Json::object()
+        .field("content_type", &part.content_type)
+        .field("filename", part.filename())
+        .field("attachment", part.is_attachment())
+        .field("size", part.body.len());
+    match part.text() {
+        Some(text) if !part.is_attachment() => json.field("text", text),
+        _ => json.field("base64", BASE64.encode(&part.body)),
+    }
+}
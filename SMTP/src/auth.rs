@@ -0,0 +1,83 @@
+//! Accounts that mail clients log in with.
+//!
+//! A password file has one `user:password` pair per line; blank lines and
+//! lines starting with `#` are skipped. Passwords are kept in the clear,
+//! because challenge-response logins such as APOP need them.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use md5::{Digest, Md5};
+
+/// Looks up users' passwords.
+pub trait Credentials: Send + Sync {
+    /// The password of `user`, or `None` if there is no such user.
+    fn password(&self, user: &str) -> Option<String>;
+
+    /// Whether `password` is right for `user`.
+    fn verify(&self, user: &str, password: &str) -> bool {
+        self.password(user).is_some_and(|expected| same(expected.as_bytes(), password.as_bytes()))
+    }
+}
+
+/// Compares without stopping at the first difference, so that the time
+/// taken says nothing about how much of a guess was right.
+pub fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Users held in memory. Names are case-insensitive.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryCredentials {
+    users: HashMap<String, String>,
+}
+
+impl MemoryCredentials {
+    pub fn new() -> MemoryCredentials {
+        MemoryCredentials::default()
+    }
+
+    pub fn add(&mut self, user: &str, password: &str) {
+        self.users.insert(user.to_lowercase(), password.to_string());
+    }
+
+    /// Parses a password file.
+    pub fn parse(text: &str) -> Result<MemoryCredentials> {
+        let mut credentials = MemoryCredentials::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, password)) if !user.is_empty() => credentials.add(user, password),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("password file line {}: expected user:password", i + 1),
+                    ))
+                }
+            }
+        }
+        Ok(credentials)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<MemoryCredentials> {
+        MemoryCredentials::parse(&fs::read_to_string(path)?)
+    }
+}
+
+impl Credentials for MemoryCredentials {
+    fn password(&self, user: &str) -> Option<String> {
+        self.users.get(&user.to_lowercase()).cloned()
+    }
+}
+
+/// The digest an APOP client sends for `timestamp`, the `<...>` string
+/// from the server's greeting (RFC 1939 section 7).
+pub fn apop_digest(timestamp: &str, password: &str) -> String {
+    let digest = Md5::digest(format!("{}{}", timestamp, password).as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
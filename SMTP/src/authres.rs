@@ -0,0 +1,57 @@
+use std::fmt;
+
+/// Builder for an `Authentication-Results` header value (RFC 8601).
+pub struct AuthenticationResults {
+    pub hostname: String,
+    pub results: Vec<String>,
+}
+
+impl AuthenticationResults {
+    pub fn new(hostname: &str) -> AuthenticationResults {
+        AuthenticationResults {
+            hostname: hostname.to_string(),
+            results: Vec::new(),
+        }
+    }
+
+    /// Adds `method=result` followed by `property=value` pairs, for example
+    /// `add("spf", "pass", &[("smtp.mailfrom", "a@example.com")])`.
+    pub fn add(&mut self, method: &str, result: &str, properties: &[(&str, &str)]) {
+        let mut entry = format!("{}={}", method, result);
+        for (property, value) in properties {
+            if !value.is_empty() {
+                entry += &format!(" {}={}", property, quote_value(value));
+            }
+        }
+        self.results.push(entry);
+    }
+
+    /// Like `add`, with a comment explaining the result.
+    pub fn add_with_reason(&mut self, method: &str, result: &str, reason: &str, properties: &[(&str, &str)]) {
+        self.add(method, &format!("{} ({})", result, reason.replace(['(', ')'], "")), properties);
+    }
+}
+
+impl fmt::Display for AuthenticationResults {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.results.is_empty() {
+            return write!(f, "{}; none", self.hostname);
+        }
+        write!(f, "{}", self.hostname)?;
+        for result in &self.results {
+            write!(f, ";\r\n\t{}", result)?;
+        }
+        Ok(())
+    }
+}
+
+fn quote_value(value: &str) -> String {
+    let plain = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "@.-_+".contains(c));
+    if plain {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
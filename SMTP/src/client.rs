@@ -0,0 +1,119 @@
+//! A minimal SMTP client, enough to hand a test message to a server.
+//!
+//! It speaks plain ESMTP, with `AUTH PLAIN` when given credentials, and
+//! stops at the first reply that is not the expected one.
+
+use std::io::{BufRead, BufReader, Error, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::trace;
+
+/// A message and its envelope.
+#[derive(Debug, Clone, Default)]
+pub struct Submission {
+    /// The name to greet with.
+    pub helo: String,
+    /// The envelope sender; empty for the null sender.
+    pub from: String,
+    pub to: Vec<String>,
+    /// The message, header section and body, with CRLF line endings.
+    pub content: Vec<u8>,
+    /// User name and password for `AUTH PLAIN`.
+    pub auth: Option<(String, String)>,
+}
+
+/// A plain text message with the usual headers.
+pub fn compose(from: &str, to: &[String], subject: &str, body: &str) -> Vec<u8> {
+    let mut message = format!(
+        "From: <{}>\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n\r\n",
+        from,
+        to.iter().map(|to| format!("<{}>", to)).collect::<Vec<_>>().join(", "),
+        subject,
+        trace::rfc5322_date(SystemTime::now()),
+        trace::new_queue_id(),
+        from.rsplit_once('@').map(|(_, domain)| domain).unwrap_or("localhost"),
+    );
+    for line in body.lines() {
+        message += line;
+        message += "\r\n";
+    }
+    message.into_bytes()
+}
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    /// Reads one reply, joining the lines of a multi-line one with `\n`.
+    fn reply(&mut self) -> Result<String> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(Error::other(format!("connection closed after {:?}", reply)));
+            }
+            reply += line.trim_end();
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(reply);
+            }
+            reply.push('\n');
+        }
+    }
+
+    /// Reads a reply and checks that it has code `expected`.
+    fn expect(&mut self, expected: &str) -> Result<String> {
+        let reply = self.reply()?;
+        if !reply.starts_with(expected) {
+            return Err(Error::other(format!("server replied: {}", reply)));
+        }
+        Ok(reply)
+    }
+
+    fn command(&mut self, line: &str, expected: &str) -> Result<String> {
+        self.writer.write_all(format!("{}\r\n", line).as_bytes())?;
+        self.expect(expected)
+    }
+}
+
+/// Sends `submission` to the server at `addr` and returns its reply to
+/// the message, such as `250 OK queued as ...`.
+pub fn send(addr: impl ToSocketAddrs, submission: &Submission) -> Result<String> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    let mut client = Client { reader: BufReader::new(stream.try_clone()?), writer: stream };
+    client.expect("220")?;
+    client.command(&format!("EHLO {}", submission.helo), "250")?;
+    if let Some((user, password)) = &submission.auth {
+        let response = BASE64.encode(format!("\0{}\0{}", user, password));
+        client.command(&format!("AUTH PLAIN {}", response), "235")?;
+    }
+    client.command(&format!("MAIL FROM:<{}>", submission.from), "250")?;
+    for to in &submission.to {
+        client.command(&format!("RCPT TO:<{}>", to), "250")?;
+    }
+    client.command("DATA", "354")?;
+
+    let mut data = Vec::with_capacity(submission.content.len() + 5);
+    let mut line_start = true;
+    for &byte in &submission.content {
+        if line_start && byte == b'.' {
+            data.push(b'.');
+        }
+        data.push(byte);
+        line_start = byte == b'\n';
+    }
+    if !line_start {
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(b".\r\n");
+    client.writer.write_all(&data)?;
+    let reply = client.expect("250")?;
+    let _ = client.command("QUIT", "221");
+    Ok(reply)
+}
@@ -0,0 +1,93 @@
+//! Client command lines (RFC 5321 section 4.1), split into a verb and its
+//! arguments. Parsing never fails: anything unrecognised is `Unknown`.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `EHLO domain`
+    Ehlo(String),
+    /// `HELO domain`
+    Helo(String),
+    /// `LHLO domain`, the LMTP greeting (RFC 2033).
+    Lhlo(String),
+    /// `MAIL FROM:<path> params`, holding everything after the colon.
+    Mail(String),
+    /// `RCPT TO:<path> params`, holding everything after the colon.
+    Rcpt(String),
+    Data,
+    /// `BDAT size [LAST]`, holding the arguments (RFC 3030).
+    Bdat(String),
+    Rset,
+    Noop,
+    Quit,
+    Vrfy(String),
+    Expn(String),
+    Help,
+    /// `AUTH mechanism [initial-response]` (RFC 4954), holding the arguments.
+    Auth(String),
+    /// `STARTTLS` (RFC 3207).
+    StartTls,
+    /// Anything else, kept as sent.
+    Unknown(String),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Command {
+        let (verb, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        let verb = verb.to_ascii_uppercase();
+        match verb.as_str() {
+            "EHLO" => Command::Ehlo(args.to_string()),
+            "HELO" => Command::Helo(args.to_string()),
+            "LHLO" => Command::Lhlo(args.to_string()),
+            "DATA" if args.is_empty() => Command::Data,
+            "BDAT" => Command::Bdat(args.to_string()),
+            "RSET" if args.is_empty() => Command::Rset,
+            // NOOP and HELP may carry an argument, which is ignored.
+            "NOOP" => Command::Noop,
+            "HELP" => Command::Help,
+            "QUIT" if args.is_empty() => Command::Quit,
+            "AUTH" => Command::Auth(args.to_string()),
+            "STARTTLS" if args.is_empty() => Command::StartTls,
+            "VRFY" => Command::Vrfy(args.to_string()),
+            "EXPN" => Command::Expn(args.to_string()),
+            "MAIL" | "RCPT" => match line.split_once(':') {
+                Some((head, path)) if head.eq_ignore_ascii_case("MAIL FROM") => Command::Mail(path.trim().to_string()),
+                Some((head, path)) if head.eq_ignore_ascii_case("RCPT TO") => Command::Rcpt(path.trim().to_string()),
+                _ => Command::Unknown(line.to_string()),
+            },
+            _ => Command::Unknown(line.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let with_args = |f: &mut fmt::Formatter, verb: &str, args: &str| {
+            if args.is_empty() {
+                write!(f, "{}", verb)
+            } else {
+                write!(f, "{} {}", verb, args)
+            }
+        };
+        match self {
+            Command::Ehlo(domain) => with_args(f, "EHLO", domain),
+            Command::Helo(domain) => with_args(f, "HELO", domain),
+            Command::Lhlo(domain) => with_args(f, "LHLO", domain),
+            Command::Mail(path) => write!(f, "MAIL FROM:{}", path),
+            Command::Rcpt(path) => write!(f, "RCPT TO:{}", path),
+            Command::Data => write!(f, "DATA"),
+            Command::Bdat(args) => with_args(f, "BDAT", args),
+            Command::Rset => write!(f, "RSET"),
+            Command::Noop => write!(f, "NOOP"),
+            Command::Quit => write!(f, "QUIT"),
+            Command::Vrfy(args) => with_args(f, "VRFY", args),
+            Command::Expn(args) => with_args(f, "EXPN", args),
+            Command::Help => write!(f, "HELP"),
+            Command::Auth(args) => with_args(f, "AUTH", args),
+            Command::StartTls => write!(f, "STARTTLS"),
+            Command::Unknown(line) => write!(f, "{}", line),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Who receives mail here: the users and mailing lists behind the
+//! addresses that `RCPT TO`, `VRFY` and `EXPN` ask about.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::address::Mailbox;
+
+/// A mailbox owner, as `VRFY` and `EXPN` describe them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    /// A display name such as `Quality Assurance`.
+    pub name: Option<String>,
+    pub address: Mailbox,
+}
+
+impl fmt::Display for User {
+    /// `Name <address>`, the form RFC 5321 section 3.5 suggests for replies.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} <{}>", name, self.address),
+            None => write!(f, "<{}>", self.address),
+        }
+    }
+}
+
+/// What an address leads to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    User(User),
+    /// A mailing list and its members.
+    List(Vec<User>),
+}
+
+/// Maps addresses to users and mailing lists.
+pub trait Directory: Send + Sync {
+    /// The entry for `address`, or `None` if nobody receives mail there.
+    fn lookup(&self, address: &Mailbox) -> Option<Entry>;
+}
+
+/// A directory held in memory. Local parts match regardless of case, as
+/// most mail systems do.
+#[derive(Default)]
+pub struct MemoryDirectory {
+    entries: HashMap<String, Entry>,
+}
+
+fn key(address: &Mailbox) -> String {
+    address.to_string().to_lowercase()
+}
+
+impl MemoryDirectory {
+    pub fn new() -> MemoryDirectory {
+        MemoryDirectory::default()
+    }
+
+    /// Adds a user; addresses that do not parse are ignored.
+    pub fn add_user(&mut self, address: &str, name: Option<&str>) {
+        if let Ok(address) = Mailbox::parse(address) {
+            let user = User { name: name.map(str::to_string), address };
+            self.entries.insert(key(&user.address), Entry::User(user));
+        }
+    }
+
+    /// Adds a mailing list. Members that are users of this directory keep
+    /// their display names.
+    pub fn add_list(&mut self, address: &str, members: &[&str]) {
+        let address = match Mailbox::parse(address) {
+            Ok(address) => address,
+            Err(_) => return,
+        };
+        let members = members
+            .iter()
+            .filter_map(|member| Mailbox::parse(member).ok())
+            .map(|member| match self.entries.get(&key(&member)) {
+                Some(Entry::User(user)) => user.clone(),
+                _ => User { name: None, address: member },
+            })
+            .collect();
+        self.entries.insert(key(&address), Entry::List(members));
+    }
+}
+
+impl Directory for MemoryDirectory {
+    fn lookup(&self, address: &Mailbox) -> Option<Entry> {
+        self.entries.get(&key(address)).cloned()
+    }
+}
@@ -0,0 +1,472 @@
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signer as _, Verifier as _};
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey};
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+
+use crate::dns::Resolver;
+use crate::headers::{Header, Headers};
+
+/// Result of verifying one `DKIM-Signature` (RFC 6376 / RFC 8601 names).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DkimResult {
+    None,
+    Pass,
+    Fail,
+    Neutral,
+    TempError,
+    PermError,
+}
+
+impl DkimResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DkimResult::None => "none",
+            DkimResult::Pass => "pass",
+            DkimResult::Fail => "fail",
+            DkimResult::Neutral => "neutral",
+            DkimResult::TempError => "temperror",
+            DkimResult::PermError => "permerror",
+        }
+    }
+}
+
+impl fmt::Display for DkimResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Outcome of one signature, with what is needed for Authentication-Results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DkimOutcome {
+    pub result: DkimResult,
+    /// The signing domain (`d=`).
+    pub domain: String,
+    pub selector: String,
+    /// The first characters of `b=`, to tell signatures apart.
+    pub signature: String,
+    pub reason: Option<String>,
+}
+
+/// Header or body canonicalization algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+impl Canonicalization {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Canonicalization::Simple => "simple",
+            Canonicalization::Relaxed => "relaxed",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Canonicalization> {
+        match s {
+            "simple" => Some(Canonicalization::Simple),
+            "relaxed" => Some(Canonicalization::Relaxed),
+            _ => None,
+        }
+    }
+}
+
+/// Canonicalizes one header field, ready to be hashed.
+pub fn canonicalize_header(header: &Header, canon: Canonicalization) -> String {
+    match canon {
+        Canonicalization::Simple => header.to_string(),
+        Canonicalization::Relaxed => {
+            let unfolded = header.value.replace("\r\n", "");
+            let collapsed = collapse_whitespace(unfolded.as_bytes());
+            format!("{}:{}\r\n", header.name.trim().to_ascii_lowercase(), String::from_utf8_lossy(&collapsed).trim())
+        }
+    }
+}
+
+/// Canonicalizes a CRLF terminated body, ready to be hashed.
+pub fn canonicalize_body(body: &[u8], canon: Canonicalization) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = split_lines(body)
+        .into_iter()
+        .map(|line| match canon {
+            Canonicalization::Simple => line.to_vec(),
+            Canonicalization::Relaxed => {
+                let mut line = collapse_whitespace(line);
+                if line.last() == Some(&b' ') {
+                    line.pop();
+                }
+                line
+            }
+        })
+        .collect();
+    // Splitting "a\r\n" gives a trailing "" which stands for the final CRLF.
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        return match canon {
+            Canonicalization::Simple => b"\r\n".to_vec(),
+            Canonicalization::Relaxed => Vec::new(),
+        };
+    }
+    let mut out = lines.join(&b"\r\n"[..]);
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+fn split_lines(body: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut rest = body;
+    while let Some(i) = rest.windows(2).position(|w| w == b"\r\n") {
+        lines.push(&rest[..i]);
+        rest = &rest[i + 2..];
+    }
+    lines.push(rest);
+    lines
+}
+
+/// Replaces each run of spaces and tabs with a single space.
+fn collapse_whitespace(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut in_space = false;
+    for &b in s {
+        if b == b' ' || b == b'\t' {
+            if !in_space {
+                out.push(b' ');
+            }
+            in_space = true;
+        } else {
+            out.push(b);
+            in_space = false;
+        }
+    }
+    out
+}
+
+/// Parses a `tag=value; tag=value` list as used by signatures and key records.
+pub fn parse_tags(value: &str) -> Vec<(String, String)> {
+    value
+        .split(';')
+        .filter_map(|tag| {
+            let (name, value) = tag.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+fn tag<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    tags.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+}
+
+fn strip_whitespace(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+/// The signature header with the value of its `b=` tag removed.
+fn without_signature(header: &Header) -> Header {
+    let value = header
+        .value
+        .split(';')
+        .map(|part| match part.split_once('=') {
+            Some((name, _)) if name.trim() == "b" => format!("{}=", name),
+            _ => part.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(";");
+    Header {
+        name: header.name.clone(),
+        value,
+    }
+}
+
+/// Hash input for the signed header fields, in `h=` order. Repeated names
+/// consume instances from the bottom up, missing ones contribute nothing.
+fn header_hash_input(headers: &Headers, signed: &[String], signature: &Header, canon: Canonicalization) -> String {
+    let mut used = vec![false; headers.fields.len()];
+    let mut input = String::new();
+    for name in signed {
+        let found = headers
+            .fields
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(i, h)| !used[*i] && h.is(name));
+        if let Some((i, header)) = found {
+            used[i] = true;
+            input += &canonicalize_header(header, canon);
+        }
+    }
+    let signature = canonicalize_header(&without_signature(signature), canon);
+    input + signature.strip_suffix("\r\n").unwrap_or(&signature)
+}
+
+fn body_hash(body: &[u8], canon: Canonicalization, length: Option<usize>) -> Vec<u8> {
+    let canonical = canonicalize_body(body, canon);
+    let bytes = canonical.as_slice();
+    let bytes = match length {
+        Some(l) if l < bytes.len() => &bytes[..l],
+        _ => bytes,
+    };
+    Sha256::digest(bytes).to_vec()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Verifies every `DKIM-Signature` header of a message. No signatures gives
+/// an empty list.
+pub fn verify(resolver: &dyn Resolver, headers: &Headers, body: &[u8]) -> Vec<DkimOutcome> {
+    headers
+        .get_all("DKIM-Signature")
+        .map(|signature| verify_signature(resolver, headers, body, signature))
+        .collect()
+}
+
+fn verify_signature(resolver: &dyn Resolver, headers: &Headers, body: &[u8], signature: &Header) -> DkimOutcome {
+    let tags = parse_tags(&signature.unfolded());
+    let mut outcome = DkimOutcome {
+        result: DkimResult::PermError,
+        domain: tag(&tags, "d").unwrap_or_default().to_ascii_lowercase(),
+        selector: tag(&tags, "s").unwrap_or_default().to_string(),
+        signature: strip_whitespace(tag(&tags, "b").unwrap_or_default()).chars().take(8).collect(),
+        reason: None,
+    };
+    match check_signature(resolver, headers, body, signature, &tags) {
+        Ok(()) => outcome.result = DkimResult::Pass,
+        Err((result, reason)) => {
+            outcome.result = result;
+            outcome.reason = Some(reason.to_string());
+        }
+    }
+    outcome
+}
+
+type Failure = (DkimResult, &'static str);
+
+fn check_signature(
+    resolver: &dyn Resolver,
+    headers: &Headers,
+    body: &[u8],
+    signature: &Header,
+    tags: &[(String, String)],
+) -> std::result::Result<(), Failure> {
+    let perm = |reason| (DkimResult::PermError, reason);
+
+    if tag(tags, "v") != Some("1") {
+        return Err(perm("unsupported version"));
+    }
+    let algorithm = tag(tags, "a").ok_or(perm("missing a= tag"))?;
+    let domain = tag(tags, "d").ok_or(perm("missing d= tag"))?.to_ascii_lowercase();
+    let selector = tag(tags, "s").ok_or(perm("missing s= tag"))?;
+    let signed: Vec<String> = tag(tags, "h")
+        .ok_or(perm("missing h= tag"))?
+        .split(':')
+        .map(|h| h.trim().to_ascii_lowercase())
+        .collect();
+    if !signed.iter().any(|h| h == "from") {
+        return Err(perm("From is not signed"));
+    }
+    let signature_bytes = BASE64
+        .decode(strip_whitespace(tag(tags, "b").ok_or(perm("missing b= tag"))?))
+        .map_err(|_| perm("malformed b= tag"))?;
+    let expected_body_hash = BASE64
+        .decode(strip_whitespace(tag(tags, "bh").ok_or(perm("missing bh= tag"))?))
+        .map_err(|_| perm("malformed bh= tag"))?;
+
+    let (header_canon, body_canon) = match tag(tags, "c").unwrap_or("simple/simple").split_once('/') {
+        Some((h, b)) => (Canonicalization::parse(h), Canonicalization::parse(b)),
+        None => (Canonicalization::parse(tag(tags, "c").unwrap_or("simple")), Some(Canonicalization::Simple)),
+    };
+    let header_canon = header_canon.ok_or(perm("unknown canonicalization"))?;
+    let body_canon = body_canon.ok_or(perm("unknown canonicalization"))?;
+
+    if let Some(identity) = tag(tags, "i") {
+        let identity_domain = identity.rsplit('@').next().unwrap_or_default().to_ascii_lowercase();
+        if identity_domain != domain && !identity_domain.ends_with(&format!(".{}", domain)) {
+            return Err(perm("i= is not within d="));
+        }
+    }
+    if let Some(expires) = tag(tags, "x") {
+        let expires: u64 = expires.parse().map_err(|_| perm("malformed x= tag"))?;
+        if expires < now() {
+            return Err((DkimResult::Fail, "signature expired"));
+        }
+    }
+    let length = match tag(tags, "l") {
+        Some(l) => Some(l.parse::<usize>().map_err(|_| perm("malformed l= tag"))?),
+        None => None,
+    };
+
+    let key_name = format!("{}._domainkey.{}", selector, domain);
+    let records = resolver
+        .txt(&key_name)
+        .map_err(|_| (DkimResult::TempError, "key lookup failed"))?;
+    let record = records.first().ok_or(perm("no key for signature"))?;
+    let key_tags = parse_tags(record);
+    let key_type = tag(&key_tags, "k").unwrap_or("rsa");
+    let key_data = strip_whitespace(tag(&key_tags, "p").ok_or(perm("key record has no p= tag"))?);
+    if key_data.is_empty() {
+        return Err((DkimResult::Fail, "key revoked"));
+    }
+    let key_data = BASE64.decode(key_data).map_err(|_| perm("malformed key"))?;
+
+    if body_hash(body, body_canon, length) != expected_body_hash {
+        return Err((DkimResult::Fail, "body hash did not verify"));
+    }
+    let data = header_hash_input(headers, &signed, signature, header_canon);
+    let hashed = Sha256::digest(data.as_bytes());
+
+    match (algorithm, key_type) {
+        ("rsa-sha256", "rsa") => {
+            let key = RsaPublicKey::from_public_key_der(&key_data)
+                .or_else(|_| RsaPublicKey::from_pkcs1_der(&key_data))
+                .map_err(|_| perm("malformed key"))?;
+            key.verify(Pkcs1v15Sign::new::<Sha256>(), &hashed, &signature_bytes)
+                .map_err(|_| (DkimResult::Fail, "signature did not verify"))
+        }
+        ("ed25519-sha256", "ed25519") => {
+            let key_bytes: [u8; 32] = key_data.as_slice().try_into().map_err(|_| perm("malformed key"))?;
+            let key = ed25519_dalek::VerifyingKey::from_bytes(&key_bytes).map_err(|_| perm("malformed key"))?;
+            let sig = ed25519_dalek::Signature::from_slice(&signature_bytes)
+                .map_err(|_| (DkimResult::Fail, "signature did not verify"))?;
+            key.verify(&hashed, &sig)
+                .map_err(|_| (DkimResult::Fail, "signature did not verify"))
+        }
+        ("rsa-sha256", _) | ("ed25519-sha256", _) => Err(perm("key type does not match algorithm")),
+        _ => Err((DkimResult::Neutral, "unsupported algorithm")),
+    }
+}
+
+/// Private key used to sign outgoing mail.
+pub enum SigningKey {
+    Rsa(RsaPrivateKey),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl SigningKey {
+    /// Reads a PEM encoded RSA (PKCS#1 or PKCS#8) or Ed25519 (PKCS#8) key.
+    pub fn from_pem(pem: &str) -> Result<SigningKey> {
+        if let Ok(key) = RsaPrivateKey::from_pkcs8_pem(pem) {
+            return Ok(SigningKey::Rsa(key));
+        }
+        if let Ok(key) = RsaPrivateKey::from_pkcs1_pem(pem) {
+            return Ok(SigningKey::Rsa(key));
+        }
+        if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+            return Ok(SigningKey::Ed25519(key));
+        }
+        Err(Error::new(ErrorKind::InvalidData, "not a PEM encoded RSA or Ed25519 private key"))
+    }
+
+    pub fn load(path: &str) -> Result<SigningKey> {
+        SigningKey::from_pem(&fs::read_to_string(path)?)
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self {
+            SigningKey::Rsa(_) => "rsa-sha256",
+            SigningKey::Ed25519(_) => "ed25519-sha256",
+        }
+    }
+
+    fn sign(&self, hashed: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            SigningKey::Rsa(key) => key
+                .sign(Pkcs1v15Sign::new::<Sha256>(), hashed)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e)),
+            SigningKey::Ed25519(key) => Ok(key.sign(hashed).to_bytes().to_vec()),
+        }
+    }
+
+    /// The TXT record to publish at `<selector>._domainkey.<domain>`.
+    pub fn dns_record(&self) -> Result<String> {
+        match self {
+            SigningKey::Rsa(key) => {
+                let der = key
+                    .to_public_key()
+                    .to_public_key_der()
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                Ok(format!("v=DKIM1; k=rsa; p={}", BASE64.encode(der.as_bytes())))
+            }
+            SigningKey::Ed25519(key) => Ok(format!(
+                "v=DKIM1; k=ed25519; p={}",
+                BASE64.encode(key.verifying_key().to_bytes())
+            )),
+        }
+    }
+}
+
+/// Header fields signed when present, From always.
+pub const DEFAULT_SIGNED_HEADERS: [&str; 11] = [
+    "from",
+    "to",
+    "cc",
+    "subject",
+    "date",
+    "message-id",
+    "reply-to",
+    "in-reply-to",
+    "references",
+    "mime-version",
+    "content-type",
+];
+
+/// Adds DKIM signatures for one domain.
+pub struct Signer {
+    pub domain: String,
+    pub selector: String,
+    pub key: SigningKey,
+    pub signed_headers: Vec<String>,
+    pub header_canonicalization: Canonicalization,
+    pub body_canonicalization: Canonicalization,
+}
+
+impl Signer {
+    /// A signer using relaxed/relaxed canonicalization and the default header list.
+    pub fn new(domain: &str, selector: &str, key: SigningKey) -> Signer {
+        Signer {
+            domain: domain.to_ascii_lowercase(),
+            selector: selector.to_string(),
+            key,
+            signed_headers: DEFAULT_SIGNED_HEADERS.iter().map(|h| h.to_string()).collect(),
+            header_canonicalization: Canonicalization::Relaxed,
+            body_canonicalization: Canonicalization::Relaxed,
+        }
+    }
+
+    /// Computes the value of a `DKIM-Signature` header for the message.
+    pub fn sign(&self, headers: &Headers, body: &[u8]) -> Result<String> {
+        let signed: Vec<String> = self
+            .signed_headers
+            .iter()
+            .filter(|h| h.as_str() == "from" || headers.contains(h))
+            .cloned()
+            .collect();
+        let body_hash = BASE64.encode(body_hash(body, self.body_canonicalization, None));
+        let unsigned = format!(
+            "v=1; a={}; c={}/{}; d={}; s={}; t={}; h={}; bh={}; b=",
+            self.key.algorithm(),
+            self.header_canonicalization.as_str(),
+            self.body_canonicalization.as_str(),
+            self.domain,
+            self.selector,
+            now(),
+            signed.join(":"),
+            body_hash
+        );
+        let header = Header::new("DKIM-Signature", &unsigned);
+        let data = header_hash_input(headers, &signed, &header, self.header_canonicalization);
+        let signature = self.key.sign(&Sha256::digest(data.as_bytes()))?;
+        Ok(unsigned + &BASE64.encode(signature))
+    }
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dkim::{DkimOutcome, DkimResult};
+use crate::dkim::parse_tags;
+use crate::dns::{self, Resolver};
+use crate::spf::SpfResult;
+
+/// Result of a DMARC (RFC 7489) evaluation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmarcResult {
+    None,
+    Pass,
+    Fail,
+    TempError,
+    PermError,
+}
+
+impl DmarcResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DmarcResult::None => "none",
+            DmarcResult::Pass => "pass",
+            DmarcResult::Fail => "fail",
+            DmarcResult::TempError => "temperror",
+            DmarcResult::PermError => "permerror",
+        }
+    }
+}
+
+impl fmt::Display for DmarcResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A requested or applied policy (`p=`/`sp=`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl Policy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Policy::None => "none",
+            Policy::Quarantine => "quarantine",
+            Policy::Reject => "reject",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Policy> {
+        match value.to_ascii_lowercase().as_str() {
+            "none" => Some(Policy::None),
+            "quarantine" => Some(Policy::Quarantine),
+            "reject" => Some(Policy::Reject),
+            _ => None,
+        }
+    }
+
+    /// The next weaker policy, used for messages outside the `pct=` sample.
+    fn downgrade(self) -> Policy {
+        match self {
+            Policy::Reject => Policy::Quarantine,
+            _ => Policy::None,
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    Relaxed,
+    Strict,
+}
+
+/// A parsed `_dmarc` TXT record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub policy: Policy,
+    pub subdomain_policy: Option<Policy>,
+    pub dkim_alignment: Alignment,
+    pub spf_alignment: Alignment,
+    pub percent: u8,
+}
+
+impl Record {
+    /// Parses a record, or `None` if it is not a valid DMARC record.
+    pub fn parse(record: &str) -> Option<Record> {
+        let tags = parse_tags(record);
+        // v=DMARC1 has to be the first tag.
+        match tags.first() {
+            Some((name, value)) if name == "v" && value == "DMARC1" => {}
+            _ => return None,
+        }
+        let get = |name: &str| tags.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+        let alignment = |value: Option<&str>| match value {
+            Some("s") => Alignment::Strict,
+            _ => Alignment::Relaxed,
+        };
+
+        // An invalid p= with a rua= tag is treated as p=none (RFC 7489 6.6.3).
+        let policy = match get("p").and_then(Policy::parse) {
+            Some(policy) => policy,
+            None if get("rua").is_some() => Policy::None,
+            None => return None,
+        };
+        Some(Record {
+            policy,
+            subdomain_policy: get("sp").and_then(Policy::parse),
+            dkim_alignment: alignment(get("adkim")),
+            spf_alignment: alignment(get("aspf")),
+            percent: get("pct").and_then(|p| p.parse().ok()).unwrap_or(100).min(100),
+        })
+    }
+}
+
+/// The outcome of DMARC for one message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmarcOutcome {
+    pub result: DmarcResult,
+    /// The RFC5322.From domain the policy was looked up for.
+    pub domain: String,
+    /// The policy the domain owner asked for.
+    pub policy: Policy,
+    /// What should happen to the message, after `pct=` sampling.
+    pub disposition: Policy,
+    pub spf_aligned: bool,
+    pub dkim_aligned: bool,
+}
+
+/// Second level labels under which registrations happen one level deeper.
+/// A stand-in for the Public Suffix List covering the common cases.
+const TWO_LABEL_SUFFIXES: [&str; 16] = [
+    "co.uk", "org.uk", "ac.uk", "gov.uk", "com.au", "net.au", "org.au", "co.nz", "co.jp", "ne.jp",
+    "com.br", "com.cn", "co.in", "co.za", "com.mx", "com.tr",
+];
+
+/// The organizational domain of `domain` (RFC 7489 3.2).
+pub fn organizational_domain(domain: &str) -> String {
+    let domain = dns::normalize_name(domain);
+    let labels: Vec<&str> = domain.split('.').collect();
+    if labels.len() <= 2 {
+        return domain;
+    }
+    let last_two = labels[labels.len() - 2..].join(".");
+    let keep = if TWO_LABEL_SUFFIXES.contains(&last_two.as_str()) { 3 } else { 2 };
+    labels[labels.len().saturating_sub(keep)..].join(".")
+}
+
+fn aligned(a: &str, b: &str, mode: Alignment) -> bool {
+    let (a, b) = (dns::normalize_name(a), dns::normalize_name(b));
+    match mode {
+        Alignment::Strict => a == b,
+        Alignment::Relaxed => organizational_domain(&a) == organizational_domain(&b),
+    }
+}
+
+/// Extracts the domain of the (first) address in a From header value, such
+/// as `Joe <joe@example.com>` or `joe@example.com (Joe)`.
+pub fn from_domain(from: &str) -> Option<String> {
+    let address = match (from.rfind('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &from[start + 1..end],
+        _ => from.split(['(', ',']).next().unwrap_or_default(),
+    };
+    let domain = address.trim().rsplit_once('@')?.1.trim();
+    if domain.is_empty() {
+        return None;
+    }
+    Some(dns::normalize_name(domain))
+}
+
+/// Fetches the DMARC record for `domain`, falling back to the organizational
+/// domain. Returns the record and whether it came from the fallback.
+fn lookup(resolver: &dyn Resolver, domain: &str) -> Result<Option<(Record, bool)>, DmarcResult> {
+    let find = |name: &str| -> Result<Option<Record>, DmarcResult> {
+        let records = resolver
+            .txt(&format!("_dmarc.{}", name))
+            .map_err(|_| DmarcResult::TempError)?;
+        let found: Vec<Record> = records.iter().filter_map(|r| Record::parse(r)).collect();
+        // More than one record means none is used (RFC 7489 6.6.3).
+        Ok(if found.len() == 1 { found.into_iter().next() } else { None })
+    };
+
+    if let Some(record) = find(domain)? {
+        return Ok(Some((record, false)));
+    }
+    let org = organizational_domain(domain);
+    if org != domain {
+        if let Some(record) = find(&org)? {
+            return Ok(Some((record, true)));
+        }
+    }
+    Ok(None)
+}
+
+/// Evaluates the DMARC policy of `from_domain` given the SPF result for
+/// `mail_from_domain` and the DKIM results of the message.
+pub fn evaluate(
+    resolver: &dyn Resolver,
+    from_domain: &str,
+    spf: Option<SpfResult>,
+    mail_from_domain: &str,
+    dkim: &[DkimOutcome],
+) -> DmarcOutcome {
+    let mut outcome = DmarcOutcome {
+        result: DmarcResult::None,
+        domain: dns::normalize_name(from_domain),
+        policy: Policy::None,
+        disposition: Policy::None,
+        spf_aligned: false,
+        dkim_aligned: false,
+    };
+
+    let (record, from_org) = match lookup(resolver, &outcome.domain) {
+        Ok(Some(found)) => found,
+        Ok(None) => return outcome,
+        Err(result) => {
+            outcome.result = result;
+            return outcome;
+        }
+    };
+    outcome.policy = match (from_org, record.subdomain_policy) {
+        (true, Some(sp)) => sp,
+        _ => record.policy,
+    };
+
+    outcome.spf_aligned = spf == Some(SpfResult::Pass)
+        && !mail_from_domain.is_empty()
+        && aligned(mail_from_domain, &outcome.domain, record.spf_alignment);
+    outcome.dkim_aligned = dkim
+        .iter()
+        .any(|d| d.result == DkimResult::Pass && aligned(&d.domain, &outcome.domain, record.dkim_alignment));
+
+    if outcome.spf_aligned || outcome.dkim_aligned {
+        outcome.result = DmarcResult::Pass;
+        return outcome;
+    }
+    outcome.result = DmarcResult::Fail;
+    outcome.disposition = if sampled(record.percent) {
+        outcome.policy
+    } else {
+        outcome.policy.downgrade()
+    };
+    outcome
+}
+
+/// True for roughly `percent` out of every hundred calls.
+fn sampled(percent: u8) -> bool {
+    if percent >= 100 {
+        return true;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    (nanos % 100) < percent as u32
+}
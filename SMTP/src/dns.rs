@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// DNS lookups used by the mail checks (SPF, DKIM, DMARC).
+///
+/// A name that does not exist or has no records of the requested type is
+/// `Ok(vec![])`; `Err` means the lookup itself failed (timeout, SERVFAIL, ...)
+/// and callers should treat it as a temporary error.
+pub trait Resolver: Send + Sync {
+    fn txt(&self, name: &str) -> Result<Vec<String>>;
+    fn a(&self, name: &str) -> Result<Vec<Ipv4Addr>>;
+    fn aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>>;
+    fn mx(&self, name: &str) -> Result<Vec<(u16, String)>>;
+    fn ptr(&self, ip: IpAddr) -> Result<Vec<String>>;
+}
+
+/// Lower cases a domain name and strips the trailing root dot.
+pub fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The `in-addr.arpa` / `ip6.arpa` name used for reverse lookups of `ip`.
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(v6) => {
+            let mut name = String::new();
+            for byte in v6.octets().iter().rev() {
+                name += &format!("{:x}.{:x}.", byte & 0x0f, byte >> 4);
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
+
+/// Whether `name` is a host name of letters, digits and hyphens (RFC 1123
+/// section 2.1), as a PTR record should hold.
+fn is_host_name(name: &str) -> bool {
+    name.len() <= 253
+        && name.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// The first name `ip` reverse resolves to that is a valid host name and
+/// resolves back to `ip`. Anyone who controls the reverse zone can put
+/// whatever they like in a PTR record, so only such a name is worth
+/// showing.
+pub fn confirmed_name(resolver: &dyn Resolver, ip: IpAddr) -> Option<String> {
+    let names = resolver.ptr(ip).ok()?;
+    names.into_iter().map(|name| normalize_name(&name)).find(|name| {
+        is_host_name(name)
+            && match ip {
+                IpAddr::V4(v4) => resolver.a(name).is_ok_and(|found| found.contains(&v4)),
+                IpAddr::V6(v6) => resolver.aaaa(name).is_ok_and(|found| found.contains(&v6)),
+            }
+    })
+}
+
+/// Resolver answering from in-memory tables, for tests and fixtures.
+#[derive(Default)]
+pub struct StaticResolver {
+    txt: HashMap<String, Vec<String>>,
+    a: HashMap<String, Vec<Ipv4Addr>>,
+    aaaa: HashMap<String, Vec<Ipv6Addr>>,
+    mx: HashMap<String, Vec<(u16, String)>>,
+    ptr: HashMap<IpAddr, Vec<String>>,
+    failing: Vec<String>,
+}
+
+impl StaticResolver {
+    pub fn new() -> StaticResolver {
+        StaticResolver::default()
+    }
+
+    pub fn add_txt(&mut self, name: &str, value: &str) {
+        self.txt.entry(normalize_name(name)).or_default().push(value.to_string());
+    }
+
+    pub fn add_a(&mut self, name: &str, ip: Ipv4Addr) {
+        self.a.entry(normalize_name(name)).or_default().push(ip);
+    }
+
+    pub fn add_aaaa(&mut self, name: &str, ip: Ipv6Addr) {
+        self.aaaa.entry(normalize_name(name)).or_default().push(ip);
+    }
+
+    pub fn add_mx(&mut self, name: &str, preference: u16, exchange: &str) {
+        self.mx
+            .entry(normalize_name(name))
+            .or_default()
+            .push((preference, normalize_name(exchange)));
+    }
+
+    pub fn add_ptr(&mut self, ip: IpAddr, name: &str) {
+        self.ptr.entry(ip).or_default().push(normalize_name(name));
+    }
+
+    /// Makes every lookup of `name` fail, to simulate a broken name server.
+    pub fn add_failure(&mut self, name: &str) {
+        self.failing.push(normalize_name(name));
+    }
+
+    fn check(&self, name: &str) -> Result<String> {
+        let name = normalize_name(name);
+        if self.failing.contains(&name) {
+            return Err(Error::new(ErrorKind::TimedOut, format!("lookup of {} failed", name)));
+        }
+        Ok(name)
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn txt(&self, name: &str) -> Result<Vec<String>> {
+        let name = self.check(name)?;
+        Ok(self.txt.get(&name).cloned().unwrap_or_default())
+    }
+
+    fn a(&self, name: &str) -> Result<Vec<Ipv4Addr>> {
+        let name = self.check(name)?;
+        Ok(self.a.get(&name).cloned().unwrap_or_default())
+    }
+
+    fn aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>> {
+        let name = self.check(name)?;
+        Ok(self.aaaa.get(&name).cloned().unwrap_or_default())
+    }
+
+    fn mx(&self, name: &str) -> Result<Vec<(u16, String)>> {
+        let name = self.check(name)?;
+        Ok(self.mx.get(&name).cloned().unwrap_or_default())
+    }
+
+    fn ptr(&self, ip: IpAddr) -> Result<Vec<String>> {
+        self.check(&reverse_name(ip))?;
+        Ok(self.ptr.get(&ip).cloned().unwrap_or_default())
+    }
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_MX: u16 = 15;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+
+/// A reply packet and the (start, end) offsets of the RDATA of each matching answer.
+type Answers = (Vec<u8>, Vec<(usize, usize)>);
+
+/// Resolver sending plain UDP queries to the name servers in `/etc/resolv.conf`,
+/// and retrying over TCP when an answer does not fit.
+pub struct SystemResolver {
+    pub servers: Vec<SocketAddr>,
+    pub timeout: Duration,
+}
+
+impl SystemResolver {
+    pub fn new() -> SystemResolver {
+        let mut servers = Vec::new();
+        if let Ok(conf) = fs::read_to_string("/etc/resolv.conf") {
+            for line in conf.lines() {
+                let mut words = line.split_whitespace();
+                if words.next() == Some("nameserver") {
+                    if let Some(Ok(ip)) = words.next().map(|w| w.parse::<IpAddr>()) {
+                        servers.push(SocketAddr::new(ip, 53));
+                    }
+                }
+            }
+        }
+        if servers.is_empty() {
+            servers.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53));
+        }
+        SystemResolver {
+            servers,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Runs one query and returns the RDATA of every answer of type `qtype`,
+    /// together with the whole packet so compressed names can be followed.
+    fn query(&self, name: &str, qtype: u16) -> Result<Answers> {
+        let mut id = [0; 2];
+        getrandom::getrandom(&mut id).map_err(Error::other)?;
+        let packet = build_query(u16::from_be_bytes(id), &normalize_name(name), qtype)?;
+        let mut last_error = Error::new(ErrorKind::NotFound, "no name servers configured");
+
+        for server in &self.servers {
+            let reply = match self.query_udp(&packet, *server) {
+                // The answer did not fit in a datagram (RFC 7766 section 5).
+                Ok(reply) if reply[2] & 0x02 != 0 => self.query_tcp(&packet, *server),
+                result => result,
+            };
+            match reply {
+                Ok(reply) => return parse_answers(reply, qtype),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Sends `packet` to `server` and waits for its reply, ignoring anything
+    /// else that arrives: a forged reply has to come from the server's
+    /// address and match the random ID and the question.
+    fn query_udp(&self, packet: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+        let bind_addr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.send_to(packet, server)?;
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = vec![0; 4096];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(Error::new(ErrorKind::TimedOut, format!("no reply from {}", server)));
+            }
+            socket.set_read_timeout(Some(left))?;
+            let (n, from) = socket.recv_from(&mut buf)?;
+            if from == server && is_reply_to(packet, &buf[..n]) {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+        }
+    }
+
+    /// Sends `packet` to `server` over TCP, with the two byte length prefix
+    /// of RFC 1035 section 4.2.2.
+    fn query_tcp(&self, packet: &[u8], server: SocketAddr) -> Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&server, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut message = (packet.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(packet);
+        stream.write_all(&message)?;
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let mut reply = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut reply)?;
+        if !is_reply_to(packet, &reply) {
+            return Err(Error::new(ErrorKind::InvalidData, "mismatched DNS reply"));
+        }
+        Ok(reply)
+    }
+}
+
+impl Default for SystemResolver {
+    fn default() -> Self {
+        SystemResolver::new()
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn txt(&self, name: &str) -> Result<Vec<String>> {
+        let (packet, answers) = self.query(name, TYPE_TXT)?;
+        let mut records = Vec::new();
+        for (start, end) in answers {
+            // A TXT record is a list of length prefixed strings which are
+            // concatenated for SPF/DKIM/DMARC.
+            let mut value = Vec::new();
+            let mut i = start;
+            while i < end {
+                let len = packet[i] as usize;
+                let stop = (i + 1 + len).min(end);
+                value.extend_from_slice(&packet[i + 1..stop]);
+                i = stop;
+            }
+            records.push(String::from_utf8_lossy(&value).into_owned());
+        }
+        Ok(records)
+    }
+
+    fn a(&self, name: &str) -> Result<Vec<Ipv4Addr>> {
+        let (packet, answers) = self.query(name, TYPE_A)?;
+        Ok(answers
+            .into_iter()
+            .filter(|(start, end)| end - start == 4)
+            .map(|(start, _)| Ipv4Addr::new(packet[start], packet[start + 1], packet[start + 2], packet[start + 3]))
+            .collect())
+    }
+
+    fn aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>> {
+        let (packet, answers) = self.query(name, TYPE_AAAA)?;
+        Ok(answers
+            .into_iter()
+            .filter(|(start, end)| end - start == 16)
+            .map(|(start, _)| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&packet[start..start + 16]);
+                Ipv6Addr::from(octets)
+            })
+            .collect())
+    }
+
+    fn mx(&self, name: &str) -> Result<Vec<(u16, String)>> {
+        let (packet, answers) = self.query(name, TYPE_MX)?;
+        let mut records = Vec::new();
+        for (start, end) in answers {
+            if end - start < 3 {
+                continue;
+            }
+            let preference = u16::from_be_bytes([packet[start], packet[start + 1]]);
+            let (exchange, _) = read_name(&packet, start + 2)?;
+            records.push((preference, exchange));
+        }
+        Ok(records)
+    }
+
+    fn ptr(&self, ip: IpAddr) -> Result<Vec<String>> {
+        let (packet, answers) = self.query(&reverse_name(ip), TYPE_PTR)?;
+        let mut names = Vec::new();
+        for (start, _) in answers {
+            names.push(read_name(&packet, start)?.0);
+        }
+        Ok(names)
+    }
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(512);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question.
+    packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        if label.len() > 63 {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("label too long in {}", name)));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    Ok(packet)
+}
+
+/// Whether `reply` answers `query`: it is a response with the same ID and
+/// the same single question.
+fn is_reply_to(query: &[u8], reply: &[u8]) -> bool {
+    reply.len() >= query.len()
+        && reply[..2] == query[..2]
+        && reply[2] & 0x80 != 0
+        && reply[4..6] == [0, 1]
+        && reply[12..query.len()].eq_ignore_ascii_case(&query[12..])
+}
+
+fn parse_answers(packet: Vec<u8>, qtype: u16) -> Result<Answers> {
+    let rcode = packet[3] & 0x0f;
+    match rcode {
+        0 => {}
+        // NXDOMAIN: the name does not exist, which is an answer, not an error.
+        3 => return Ok((packet, Vec::new())),
+        _ => return Err(Error::other(format!("DNS server returned rcode {}", rcode))),
+    }
+
+    let questions = u16::from_be_bytes([packet[4], packet[5]]);
+    let answers = u16::from_be_bytes([packet[6], packet[7]]);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(&packet, pos)?.1 + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = read_name(&packet, pos)?.1;
+        if pos + 10 > packet.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated DNS answer"));
+        }
+        let rtype = u16::from_be_bytes([packet[pos], packet[pos + 1]]);
+        let rdlength = u16::from_be_bytes([packet[pos + 8], packet[pos + 9]]) as usize;
+        pos += 10;
+        if pos + rdlength > packet.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated DNS answer"));
+        }
+        if rtype == qtype {
+            records.push((pos, pos + rdlength));
+        }
+        pos += rdlength;
+    }
+    Ok((packet, records))
+}
+
+/// Reads a possibly compressed name at `pos`, returning it and the offset
+/// just past it in the original position.
+fn read_name(packet: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "malformed DNS name");
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(pos).ok_or_else(invalid)? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        if len & 0xc0 == 0xc0 {
+            let low = *packet.get(pos + 1).ok_or_else(invalid)? as usize;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            jumps += 1;
+            if jumps > 64 {
+                return Err(invalid());
+            }
+            pos = ((len & 0x3f) << 8) | low;
+            continue;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len).ok_or_else(invalid)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += 1 + len;
+    }
+    Ok((labels.join("."), end.unwrap_or(pos)))
+}
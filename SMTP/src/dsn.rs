@@ -0,0 +1,283 @@
+//! Delivery status notifications (RFC 3461, RFC 3464): the `RET`, `ENVID`,
+//! `NOTIFY` and `ORCPT` envelope parameters and the `multipart/report`
+//! messages built from them.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::time::SystemTime;
+
+use crate::address::Parameters;
+use crate::headers;
+use crate::trace;
+
+/// Longest `ENVID` accepted, from RFC 3461 section 4.4.
+const MAX_ENVID: usize = 100;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg.to_string())
+}
+
+/// Decodes xtext, where `+2B` stands for `+` (RFC 3461 section 4).
+pub fn xtext_decode(s: &str) -> Result<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => {
+                let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+                let valid = hex.filter(|h| h.bytes().all(|b| b.is_ascii_digit() || (b'A'..=b'F').contains(&b)));
+                out.push(valid.and_then(|h| u8::from_str_radix(h, 16).ok()).ok_or_else(|| invalid("bad xtext escape"))?);
+                i += 3;
+            }
+            b @ b'!'..=b'~' if b != b'=' => {
+                out.push(b);
+                i += 1;
+            }
+            _ => return Err(invalid("bad xtext character")),
+        }
+    }
+    String::from_utf8(out).map_err(|_| invalid("xtext is not UTF-8"))
+}
+
+/// Encodes `s` as xtext.
+pub fn xtext_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'!'..=b'~' if b != b'+' && b != b'=' => (b as char).to_string(),
+            _ => format!("+{:02X}", b),
+        })
+        .collect()
+}
+
+/// How much of the original message a DSN returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ret {
+    Full,
+    Hdrs,
+}
+
+/// The DSN parameters of `MAIL FROM`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailDsn {
+    pub ret: Option<Ret>,
+    /// The sender's envelope identifier, decoded.
+    pub envid: Option<String>,
+}
+
+impl MailDsn {
+    pub fn from_params(params: &Parameters) -> Result<MailDsn> {
+        let ret = match params.get("RET") {
+            Some(v) if v.eq_ignore_ascii_case("FULL") => Some(Ret::Full),
+            Some(v) if v.eq_ignore_ascii_case("HDRS") => Some(Ret::Hdrs),
+            Some(_) => return Err(invalid("RET must be FULL or HDRS")),
+            None => None,
+        };
+        let envid = match params.get("ENVID") {
+            Some(v) if v.len() > MAX_ENVID => return Err(invalid("ENVID too long")),
+            Some(v) => Some(xtext_decode(v)?),
+            None => None,
+        };
+        Ok(MailDsn { ret, envid })
+    }
+}
+
+/// The conditions under which a recipient wants a DSN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Notify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+impl Notify {
+    pub const NEVER: Notify = Notify { success: false, failure: false, delay: false };
+
+    /// What a recipient without `NOTIFY` gets: failures and, at the MTA's
+    /// discretion, delays.
+    pub const DEFAULT: Notify = Notify { success: false, failure: true, delay: true };
+
+    /// Parses `NEVER` or a comma separated list of `SUCCESS`, `FAILURE`, `DELAY`.
+    pub fn parse(value: &str) -> Result<Notify> {
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Ok(Notify::NEVER);
+        }
+        let mut notify = Notify::NEVER;
+        for keyword in value.split(',') {
+            match keyword.to_ascii_uppercase().as_str() {
+                "SUCCESS" => notify.success = true,
+                "FAILURE" => notify.failure = true,
+                "DELAY" => notify.delay = true,
+                _ => return Err(invalid("NOTIFY must be NEVER or SUCCESS, FAILURE and DELAY")),
+            }
+        }
+        Ok(notify)
+    }
+
+    pub fn wants(&self, action: Action) -> bool {
+        match action {
+            Action::Delivered | Action::Relayed | Action::Expanded => self.success,
+            Action::Failed => self.failure,
+            Action::Delayed => self.delay,
+        }
+    }
+}
+
+/// The recipient address the sender originally used, from `ORCPT=rfc822;...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OriginalRecipient {
+    /// Address type, normally `rfc822`.
+    pub addr_type: String,
+    /// The decoded address.
+    pub address: String,
+}
+
+/// The DSN parameters of one `RCPT TO`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RcptDsn {
+    pub notify: Option<Notify>,
+    pub orcpt: Option<OriginalRecipient>,
+}
+
+impl RcptDsn {
+    pub fn from_params(params: &Parameters) -> Result<RcptDsn> {
+        let notify = params.get("NOTIFY").map(Notify::parse).transpose()?;
+        let orcpt = match params.get("ORCPT") {
+            Some(value) => {
+                let (addr_type, address) = value.split_once(';').ok_or_else(|| invalid("ORCPT must be type;address"))?;
+                let atom = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+                if !atom(addr_type) {
+                    return Err(invalid("bad ORCPT address type"));
+                }
+                Some(OriginalRecipient { addr_type: addr_type.to_string(), address: xtext_decode(address)? })
+            }
+            None => None,
+        };
+        Ok(RcptDsn { notify, orcpt })
+    }
+
+    /// Whether a DSN should be sent for `action` on this recipient.
+    pub fn wants(&self, action: Action) -> bool {
+        self.notify.unwrap_or(Notify::DEFAULT).wants(action)
+    }
+}
+
+/// The `Action` field of a per-recipient status (RFC 3464 section 2.3.3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Failed,
+    Delayed,
+    Delivered,
+    Relayed,
+    Expanded,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Failed => "failed",
+            Action::Delayed => "delayed",
+            Action::Delivered => "delivered",
+            Action::Relayed => "relayed",
+            Action::Expanded => "expanded",
+        })
+    }
+}
+
+/// What happened to one recipient.
+pub struct RecipientStatus<'a> {
+    pub final_recipient: &'a str,
+    pub original: Option<&'a OriginalRecipient>,
+    pub action: Action,
+    /// Enhanced status code such as `2.0.0`.
+    pub status: &'a str,
+    /// The SMTP reply behind the status, if there was one.
+    pub diagnostic: Option<&'a str>,
+}
+
+/// Everything needed to build a DSN for one received message.
+pub struct Report<'a> {
+    pub reporting_mta: &'a str,
+    /// Where the DSN goes: the original envelope sender.
+    pub to: &'a str,
+    pub envid: Option<&'a str>,
+    pub ret: Ret,
+    pub arrival: SystemTime,
+    pub recipients: Vec<RecipientStatus<'a>>,
+    /// The original message, header section included.
+    pub original_message: &'a [u8],
+}
+
+impl Report<'_> {
+    /// The complete `multipart/report` message, with CRLF line endings.
+    pub fn to_message(&self) -> Vec<u8> {
+        let boundary = format!("{}/{}", trace::new_queue_id(), self.reporting_mta);
+        let mut out = String::new();
+        out += &format!("From: Mail Delivery System <MAILER-DAEMON@{}>\r\n", self.reporting_mta);
+        out += &format!("To: <{}>\r\n", self.to);
+        out += &format!("Subject: {}\r\n", self.subject());
+        out += &format!("Date: {}\r\n", trace::rfc5322_date(SystemTime::now()));
+        out += "Auto-Submitted: auto-replied\r\n";
+        out += "MIME-Version: 1.0\r\n";
+        out += &format!("Content-Type: multipart/report; report-type=delivery-status;\r\n\tboundary=\"{}\"\r\n", boundary);
+        out += "\r\nThis is a MIME-encapsulated message.\r\n\r\n";
+
+        out += &format!("--{}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n", boundary);
+        out += &format!("This is the mail system at host {}.\r\n\r\n", self.reporting_mta);
+        for recipient in &self.recipients {
+            out += &format!("<{}>: {}", recipient.final_recipient, recipient.action);
+            if let Some(diagnostic) = recipient.diagnostic {
+                out += &format!(", {}", diagnostic);
+            }
+            out += "\r\n";
+        }
+
+        out += &format!("\r\n--{}\r\nContent-Type: message/delivery-status\r\n\r\n", boundary);
+        out += &format!("Reporting-MTA: dns; {}\r\n", self.reporting_mta);
+        if let Some(envid) = self.envid {
+            out += &format!("Original-Envelope-Id: {}\r\n", xtext_encode(envid));
+        }
+        out += &format!("Arrival-Date: {}\r\n", trace::rfc5322_date(self.arrival));
+        for recipient in &self.recipients {
+            out += "\r\n";
+            if let Some(original) = recipient.original {
+                out += &format!("Original-Recipient: {};{}\r\n", original.addr_type, original.address);
+            }
+            out += &format!("Final-Recipient: rfc822; {}\r\n", recipient.final_recipient);
+            out += &format!("Action: {}\r\n", recipient.action);
+            out += &format!("Status: {}\r\n", recipient.status);
+            if let Some(diagnostic) = recipient.diagnostic {
+                out += &format!("Diagnostic-Code: smtp; {}\r\n", diagnostic);
+            }
+        }
+
+        let returned = match self.ret {
+            Ret::Full => {
+                out += &format!("\r\n--{}\r\nContent-Type: message/rfc822\r\n\r\n", boundary);
+                self.original_message
+            }
+            Ret::Hdrs => {
+                out += &format!("\r\n--{}\r\nContent-Type: text/rfc822-headers\r\n\r\n", boundary);
+                headers::split_message_bytes(self.original_message).0
+            }
+        };
+        let mut out = out.into_bytes();
+        out.extend_from_slice(returned);
+        if !out.ends_with(b"\r\n") {
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        out
+    }
+
+    fn subject(&self) -> &'static str {
+        let all = |action| self.recipients.iter().all(|r| r.action == action);
+        if all(Action::Delivered) {
+            "Successful Mail Delivery Report"
+        } else if all(Action::Delayed) {
+            "Delayed Mail (still being retried)"
+        } else {
+            "Undelivered Mail Returned to Sender"
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Greylisting: the first delivery attempt for an unfamiliar (client
+//! network, sender, recipient) triplet is deferred with a 451. Real MTAs
+//! retry after a while and get through; much spamware never retries.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::time::{Clock, SystemClock};
+
+/// Default wait before a retry is accepted.
+pub const DEFAULT_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Default time a deferred triplet waits for its retry.
+pub const DEFAULT_RETRY_WINDOW: Duration = Duration::from_secs(24 * 3600);
+/// Default time a client that retried stays whitelisted after its last message.
+pub const DEFAULT_WHITELIST_FOR: Duration = Duration::from_secs(36 * 24 * 3600);
+
+/// The outcome of a greylist check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Pass,
+    /// Try again no sooner than this.
+    Defer(Duration),
+}
+
+/// The /24 (IPv4) or /64 (IPv6) network of `ip`, so that retries from
+/// another host of the same sending farm still count.
+pub fn network(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", segments[0], segments[1], segments[2], segments[3])
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// First attempt of each deferred (network, sender, recipient) triplet.
+    pending: HashMap<(String, String, String), u64>,
+    /// Last message from each whitelisted (network, sender) pair.
+    whitelist: HashMap<(String, String), u64>,
+}
+
+pub struct Greylist {
+    /// How long a client must wait before retrying.
+    pub delay: Duration,
+    /// How long a deferred triplet is remembered; a retry after this starts over.
+    pub retry_window: Duration,
+    /// How long a client that retried is let straight through.
+    pub whitelist_for: Duration,
+    pub clock: Arc<dyn Clock>,
+    /// Where the triplets are kept across restarts, if anywhere.
+    path: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Greylist {
+    /// A greylist kept in memory only.
+    pub fn new() -> Greylist {
+        Greylist {
+            delay: DEFAULT_DELAY,
+            retry_window: DEFAULT_RETRY_WINDOW,
+            whitelist_for: DEFAULT_WHITELIST_FOR,
+            clock: Arc::new(SystemClock),
+            path: None,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// A greylist saved to `path`, loading what is already there.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Greylist> {
+        let path = path.into();
+        let mut state = State::default();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        // Addresses never contain tabs, not even quoted local parts.
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let invalid = || Error::new(ErrorKind::InvalidData, format!("bad greylist line {:?}", line));
+            match fields.as_slice() {
+                ["T", time, network, sender, recipient] => {
+                    let time = time.parse().map_err(|_| invalid())?;
+                    state.pending.insert((network.to_string(), sender.to_string(), recipient.to_string()), time);
+                }
+                ["W", time, network, sender] => {
+                    let time = time.parse().map_err(|_| invalid())?;
+                    state.whitelist.insert((network.to_string(), sender.to_string()), time);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(Greylist { path: Some(path), state: Mutex::new(state), ..Greylist::new() })
+    }
+
+    /// Checks a delivery attempt from `ip`, recording it.
+    pub fn check(&self, ip: IpAddr, sender: &str, recipient: &str) -> Decision {
+        let now = secs(self.clock.now());
+        let network = network(ip);
+        let sender = sender.to_lowercase();
+        let mut state = self.state.lock().unwrap();
+        self.expire(&mut state, now);
+
+        // Only new triplets and newly whitelisted pairs are worth a write;
+        // refreshed times and expiry ride along with the next one.
+        let mut changed = false;
+        let pair = (network, sender);
+        let decision = if let Some(last) = state.whitelist.get_mut(&pair) {
+            *last = now;
+            Decision::Pass
+        } else {
+            let triplet = (pair.0.clone(), pair.1.clone(), recipient.to_lowercase());
+            let first = *state.pending.entry(triplet.clone()).or_insert_with(|| {
+                changed = true;
+                now
+            });
+            let ready = first + self.delay.as_secs();
+            if now >= ready {
+                state.pending.remove(&triplet);
+                state.whitelist.insert(pair, now);
+                changed = true;
+                Decision::Pass
+            } else {
+                Decision::Defer(Duration::from_secs(ready - now))
+            }
+        };
+        if changed {
+            if let Err(e) = self.save(&state) {
+                println!("[ERROR] Could not save greylist: {}", e);
+            }
+        }
+        decision
+    }
+
+    /// Forgets triplets whose retry window has passed and whitelist entries
+    /// that have not been used for a while.
+    fn expire(&self, state: &mut State, now: u64) {
+        let retry_window = self.retry_window.as_secs();
+        let whitelist_for = self.whitelist_for.as_secs();
+        state.pending.retain(|_, first| now.saturating_sub(*first) <= retry_window);
+        state.whitelist.retain(|_, last| now.saturating_sub(*last) <= whitelist_for);
+    }
+
+    fn save(&self, state: &State) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut text = String::new();
+        for ((network, sender, recipient), first) in &state.pending {
+            text += &format!("T\t{}\t{}\t{}\t{}\n", first, network, sender, recipient);
+        }
+        for ((network, sender), last) in &state.whitelist {
+            text += &format!("W\t{}\t{}\t{}\n", last, network, sender);
+        }
+        // Write aside and rename, so a crash never leaves half a file.
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, text)?;
+        fs::rename(temp, path)
+    }
+}
+
+impl Default for Greylist {
+    fn default() -> Self {
+        Greylist::new()
+    }
+}
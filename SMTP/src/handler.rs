@@ -0,0 +1,35 @@
+use crate::dmarc::Policy;
+use crate::server::Message;
+
+/// What to do with a message once its content has been received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Reply 250 and pass the message on.
+    Accept,
+    /// Reply 250 but keep the message aside; the reason is logged.
+    Quarantine(String),
+    /// Reply 550 with the reason.
+    Reject(String),
+}
+
+/// Decides the fate of each received message. Handlers may add headers.
+pub trait MessageHandler: Send + Sync {
+    fn handle(&self, msg: &mut Message) -> Verdict;
+}
+
+/// Accepts mail unless its DMARC disposition says to quarantine or reject it.
+pub struct DmarcHandler;
+
+impl MessageHandler for DmarcHandler {
+    fn handle(&self, msg: &mut Message) -> Verdict {
+        let dmarc = match &msg.dmarc {
+            Some(dmarc) => dmarc,
+            None => return Verdict::Accept,
+        };
+        match dmarc.disposition {
+            Policy::None => Verdict::Accept,
+            Policy::Quarantine => Verdict::Quarantine(format!("DMARC policy of {} is quarantine", dmarc.domain)),
+            Policy::Reject => Verdict::Reject(format!("5.7.1 DMARC policy of {} rejects this message", dmarc.domain)),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+
+/// One header field as it appeared in the message.
+///
+/// `value` is everything after the colon, folding included, so signatures
+/// computed over the original bytes can be checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+impl Header {
+    pub fn new(name: &str, value: &str) -> Header {
+        Header {
+            name: name.to_string(),
+            value: format!(" {}", value),
+        }
+    }
+
+    /// True if this field is called `name`, ignoring case.
+    pub fn is(&self, name: &str) -> bool {
+        self.name.trim_end().eq_ignore_ascii_case(name)
+    }
+
+    /// The value with folding removed and surrounding whitespace trimmed.
+    pub fn unfolded(&self) -> String {
+        self.value.replace("\r\n", "").replace('\n', "").trim().to_string()
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}\r\n", self.name, self.value)
+    }
+}
+
+/// Ordered header section of an RFC 5322 message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    pub fields: Vec<Header>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Parses a header section (everything before the empty line).
+    ///
+    /// Lines without a colon that are not continuations are dropped rather
+    /// than failing the whole message.
+    pub fn parse(raw: &str) -> Headers {
+        let mut fields: Vec<Header> = Vec::new();
+        for line in raw.split_inclusive('\n') {
+            let line = line.strip_suffix('\n').unwrap_or(line);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with([' ', '\t']) {
+                if let Some(last) = fields.last_mut() {
+                    last.value += "\r\n";
+                    last.value += line;
+                }
+                continue;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                fields.push(Header {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                });
+            }
+        }
+        Headers { fields }
+    }
+
+    /// First field called `name` (case insensitive), unfolded.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.fields
+            .iter()
+            .find(|h| h.is(name))
+            .map(|h| h.unfolded())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Header> + 'a {
+        self.fields.iter().filter(move |h| h.is(name))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get_all(name).next().is_some()
+    }
+
+    pub fn count(&self, name: &str) -> usize {
+        self.get_all(name).count()
+    }
+
+    /// Adds a field at the top, where trace and authentication headers go.
+    pub fn prepend(&mut self, name: &str, value: &str) {
+        self.fields.insert(0, Header::new(name, value));
+    }
+
+    pub fn push(&mut self, name: &str, value: &str) {
+        self.fields.push(Header::new(name, value));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|h| !h.is(name));
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Header> {
+        self.fields.iter()
+    }
+}
+
+impl fmt::Display for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for header in &self.fields {
+            write!(f, "{}", header)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits message content into its header section and body at the first
+/// empty line.
+pub fn split_message(content: &str) -> (&str, &str) {
+    let (raw_headers, body) = split_message_bytes(content.as_bytes());
+    (&content[..raw_headers.len()], &content[content.len() - body.len()..])
+}
+
+/// The length of the header field at the start of `buf`, continuation lines
+/// and final CRLF included; 2 for the empty line that ends the header
+/// section. `None` until the field's end, and the first byte after it, have
+/// arrived.
+pub fn field_end(buf: &[u8]) -> Option<usize> {
+    let mut from = 0;
+    loop {
+        let crlf = from + buf[from..].windows(2).position(|w| w == b"\r\n")?;
+        if crlf == 0 {
+            return Some(2);
+        }
+        match buf.get(crlf + 2) {
+            Some(b' ' | b'\t') => from = crlf + 2,
+            Some(_) => return Some(crlf + 2),
+            None => return None,
+        }
+    }
+}
+
+/// `split_message` for content that may not be UTF-8, such as a BINARYMIME body.
+pub fn split_message_bytes(content: &[u8]) -> (&[u8], &[u8]) {
+    if let Some(body) = content.strip_prefix(b"\r\n") {
+        return (b"", body);
+    }
+    match content.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => (&content[..i + 2], &content[i + 4..]),
+        None => (content, b""),
+    }
+}
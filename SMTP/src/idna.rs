@@ -0,0 +1,183 @@
+use std::io::{Error, ErrorKind, Result};
+
+// Punycode parameters from RFC 3492 section 5.
+const BASE: u32 = 36;
+const T_MIN: u32 = 1;
+const T_MAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg.to_string())
+}
+
+fn adapt(mut delta: u32, num_points: u32, first_time: bool) -> u32 {
+    delta /= if first_time { DAMP } else { 2 };
+    delta += delta / num_points;
+    let mut k = 0;
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+    k + (((BASE - T_MIN + 1) * delta) / (delta + SKEW))
+}
+
+fn encode_digit(d: u32) -> char {
+    if d < 26 {
+        (b'a' + d as u8) as char
+    } else {
+        (b'0' + (d - 26) as u8) as char
+    }
+}
+
+fn decode_digit(c: char) -> Option<u32> {
+    match c {
+        'a'..='z' => Some(c as u32 - 'a' as u32),
+        'A'..='Z' => Some(c as u32 - 'A' as u32),
+        '0'..='9' => Some(c as u32 - '0' as u32 + 26),
+        _ => None,
+    }
+}
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        T_MIN
+    } else if k >= bias + T_MAX {
+        T_MAX
+    } else {
+        k - bias
+    }
+}
+
+/// Punycode encoding of one label, without the `xn--` prefix.
+pub fn punycode_encode(input: &str) -> Result<String> {
+    let chars: Vec<u32> = input.chars().map(|c| c as u32).collect();
+    let mut output: String = input.chars().filter(|c| c.is_ascii()).collect();
+    let basic = output.len() as u32;
+    let mut handled = basic;
+    if basic > 0 {
+        output.push('-');
+    }
+
+    let overflow = || invalid("punycode overflow");
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    while (handled as usize) < chars.len() {
+        let m = *chars.iter().filter(|&&c| c >= n).min().ok_or_else(overflow)?;
+        delta = delta
+            .checked_add((m - n).checked_mul(handled + 1).ok_or_else(overflow)?)
+            .ok_or_else(overflow)?;
+        n = m;
+        for &c in &chars {
+            if c < n {
+                delta = delta.checked_add(1).ok_or_else(overflow)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta += 1;
+        n += 1;
+    }
+    Ok(output)
+}
+
+/// Decodes one punycode label, without the `xn--` prefix.
+pub fn punycode_decode(input: &str) -> Result<String> {
+    let (basic, extended) = match input.rfind('-') {
+        Some(i) => (&input[..i], &input[i + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return Err(invalid("non-ASCII basic code points"));
+    }
+    let mut output: Vec<char> = basic.chars().collect();
+
+    let overflow = || invalid("punycode overflow");
+    let mut n = INITIAL_N;
+    let mut i: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut digits = extended.chars().peekable();
+    while digits.peek().is_some() {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        loop {
+            let digit = digits
+                .next()
+                .and_then(decode_digit)
+                .ok_or_else(|| invalid("bad punycode digit"))?;
+            i = i.checked_add(digit.checked_mul(w).ok_or_else(overflow)?).ok_or_else(overflow)?;
+            let t = threshold(k, bias);
+            if digit < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t).ok_or_else(overflow)?;
+            k += BASE;
+        }
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = n.checked_add(i / len).ok_or_else(overflow)?;
+        i %= len;
+        let c = char::from_u32(n).ok_or_else(|| invalid("bad code point"))?;
+        output.insert(i as usize, c);
+        i += 1;
+    }
+    Ok(output.into_iter().collect())
+}
+
+/// Converts a domain to its ASCII (A-label) form, lower cased, so that
+/// `bücher.example` and `xn--bcher-kva.example` compare equal.
+pub fn to_ascii(domain: &str) -> Result<String> {
+    let domain = domain.trim_end_matches('.');
+    let mut labels = Vec::new();
+    for label in domain.split(['.', '\u{3002}', '\u{ff0e}', '\u{ff61}']) {
+        let label = label.to_lowercase();
+        let ascii = if label.is_ascii() {
+            label
+        } else {
+            format!("xn--{}", punycode_encode(&label)?)
+        };
+        if ascii.is_empty() || ascii.len() > 63 {
+            return Err(invalid("bad label length"));
+        }
+        labels.push(ascii);
+    }
+    let domain = labels.join(".");
+    if domain.len() > 253 {
+        return Err(invalid("domain too long"));
+    }
+    Ok(domain)
+}
+
+/// Converts A-labels back to Unicode for display; labels that do not decode are kept.
+pub fn to_unicode(domain: &str) -> String {
+    domain
+        .split('.')
+        .map(|label| {
+            let lower = label.to_ascii_lowercase();
+            match lower.strip_prefix("xn--").map(punycode_decode) {
+                Some(Ok(decoded)) => decoded,
+                _ => label.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
@@ -0,0 +1,1509 @@
+//! An IMAP4rev1 server (RFC 3501) over the mailbox store, so that desktop
+//! mail clients can browse what the SMTP side received, folders included.
+//!
+//! A subset: CAPABILITY, NOOP, LOGOUT, STARTTLS, LOGIN, SELECT, EXAMINE,
+//! CREATE, LIST, LSUB, STATUS, CHECK, CLOSE, EXPUNGE, SEARCH, FETCH, STORE
+//! and UID, plus IDLE (RFC 2177) and LITERAL+ (RFC 7888). Every mailbox
+//! counts as subscribed, and no message is ever `\Recent`.
+
+use std::collections::HashSet;
+use std::fmt::Arguments;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustls::ServerConfig;
+
+use crate::auth::Credentials;
+use crate::headers::{self, Headers};
+use crate::mailbox::{Entry, MailboxStore, DELIMITER, INBOX};
+use crate::mime::{self, Part};
+use crate::stream::Stream;
+use crate::trace;
+
+/// Longest command accepted, literals included.
+const MAX_COMMAND: usize = 64 * 1024;
+
+/// How often an idling client is told about new mail.
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+const SYSTEM_FLAGS: [&str; 5] = ["\\Answered", "\\Flagged", "\\Deleted", "\\Seen", "\\Draft"];
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Settings shared by every connection of an IMAP server.
+pub struct Config {
+    /// Name this server uses for itself in its greeting.
+    pub hostname: String,
+    /// Where the mailboxes are.
+    pub mailboxes: Arc<MailboxStore>,
+    /// Who may log in; user names are the addresses mail was delivered to.
+    pub credentials: Arc<dyn Credentials>,
+    /// When set, clients can switch to TLS with STARTTLS.
+    pub tls: Option<Arc<ServerConfig>>,
+}
+
+/// One element of a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Atom(String),
+    /// A quoted string or a literal.
+    Str(String),
+    List(Vec<Token>),
+}
+
+impl Token {
+    /// The text of an atom or string.
+    fn text(&self) -> Option<&str> {
+        match self {
+            Token::Atom(text) | Token::Str(text) => Some(text),
+            Token::List(_) => None,
+        }
+    }
+}
+
+/// Splits a command, literals included, into tokens.
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn parse(input: &[u8]) -> std::result::Result<Vec<Token>, String> {
+        Parser { input, pos: 0 }.list(false)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn list(&mut self, nested: bool) -> std::result::Result<Vec<Token>, String> {
+        let mut tokens = Vec::new();
+        loop {
+            while self.peek() == Some(b' ') {
+                self.pos += 1;
+            }
+            match self.peek() {
+                None if nested => return Err("Unclosed list".to_string()),
+                None => return Ok(tokens),
+                Some(b')') if nested => {
+                    self.pos += 1;
+                    return Ok(tokens);
+                }
+                Some(b')') => return Err("Unexpected )".to_string()),
+                Some(b'(') => {
+                    self.pos += 1;
+                    tokens.push(Token::List(self.list(true)?));
+                }
+                Some(b'"') => tokens.push(Token::Str(self.quoted()?)),
+                Some(b'{') => tokens.push(Token::Str(self.literal()?)),
+                Some(_) => tokens.push(Token::Atom(self.atom())),
+            }
+        }
+    }
+
+    fn quoted(&mut self) -> std::result::Result<String, String> {
+        let mut text = Vec::new();
+        self.pos += 1;
+        loop {
+            let c = self.peek().ok_or("Unterminated string")?;
+            self.pos += 1;
+            match c {
+                b'"' => return Ok(String::from_utf8_lossy(&text).into_owned()),
+                b'\\' => {
+                    text.push(self.peek().ok_or("Unterminated string")?);
+                    self.pos += 1;
+                }
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn literal(&mut self) -> std::result::Result<String, String> {
+        let rest = &self.input[self.pos..];
+        let close = rest.iter().position(|&c| c == b'}').ok_or("Bad literal")?;
+        let length = String::from_utf8_lossy(&rest[1..close]);
+        let length: usize = length.trim_end_matches('+').parse().map_err(|_| "Bad literal")?;
+        let start = self.pos + close + 3;
+        let end = start.checked_add(length).ok_or("Bad literal")?;
+        let data = self.input.get(start..end).ok_or("Bad literal")?;
+        self.pos = end;
+        Ok(String::from_utf8_lossy(data).into_owned())
+    }
+
+    /// An atom, taking in a `[...]` section as a whole, spaces and all.
+    fn atom(&mut self) -> String {
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                b'[' => depth += 1,
+                b']' if depth > 0 => depth -= 1,
+                b' ' | b'(' | b')' if depth == 0 => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.input[start..self.pos]).into_owned()
+    }
+}
+
+/// The length of the literal a command line ends with, and whether the
+/// client waits to be told to go on (no `+`, RFC 7888).
+fn literal_length(line: &[u8]) -> Option<(usize, bool)> {
+    let inner = line.strip_suffix(b"}")?;
+    let open = inner.iter().rposition(|&c| c == b'{')?;
+    let inner = std::str::from_utf8(&inner[open + 1..]).ok()?;
+    match inner.strip_suffix('+') {
+        Some(length) => Some((length.parse().ok()?, false)),
+        None => Some((inner.parse().ok()?, true)),
+    }
+}
+
+/// Parses a sequence set such as `1:3,5,7:*`, in which `*` is `largest`.
+fn sequence_set(text: &str, largest: u32) -> Option<Vec<(u32, u32)>> {
+    text.split(',')
+        .map(|range| {
+            let (first, last) = range.split_once(':').unwrap_or((range, range));
+            let number = |n: &str| match n {
+                "*" => Some(largest),
+                n => n.parse::<u32>().ok().filter(|n| *n > 0),
+            };
+            let (first, last) = (number(first)?, number(last)?);
+            Some((first.min(last), first.max(last)))
+        })
+        .collect()
+}
+
+fn in_set(set: &[(u32, u32)], n: u32) -> bool {
+    set.iter().any(|(first, last)| (*first..=*last).contains(&n))
+}
+
+/// A quoted string.
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A string or NIL, as a literal when it cannot be quoted.
+fn nstring(text: Option<&[u8]>) -> Vec<u8> {
+    match text {
+        None => b"NIL".to_vec(),
+        Some(text) if text.len() < 1024 && text.iter().all(|&c| (0x20..0x7f).contains(&c)) => {
+            quote(&String::from_utf8_lossy(text)).into_bytes()
+        }
+        Some(text) => literal(text),
+    }
+}
+
+fn literal(data: &[u8]) -> Vec<u8> {
+    let mut out = format!("{{{}}}\r\n", data.len()).into_bytes();
+    out.extend_from_slice(data);
+    out
+}
+
+/// A section's octets as a string, NIL when there is no such section.
+/// Message content always goes as a literal.
+fn nstring_literal(octets: Option<Vec<u8>>) -> Vec<u8> {
+    match octets {
+        Some(octets) => literal(&octets),
+        None => b"NIL".to_vec(),
+    }
+}
+
+fn flag_list(flags: &[String]) -> String {
+    format!("({})", flags.join(" "))
+}
+
+/// A date-time like `17-Jul-1996 02:44:25 +0000`.
+fn internal_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = trace::civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:02}-{}-{} {:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Days since 1970-01-01 of a date like `1-Feb-1994`.
+fn parse_date(text: &str) -> Option<i64> {
+    let mut fields = text.split('-');
+    let day = fields.next()?.parse().ok()?;
+    let month = fields.next()?;
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as u32 + 1;
+    let year = fields.next()?.parse().ok()?;
+    Some(trace::days_from_civil(year, month, day))
+}
+
+/// Days since 1970-01-01 of the date in a `Date` header field, such as
+/// `Mon, 19 Oct 2026 08:11:00 +0000`; the time and zone are ignored, as
+/// SENTBEFORE and friends want.
+fn header_date(value: &str) -> Option<i64> {
+    let value = value.split_once(',').map_or(value, |(_, rest)| rest);
+    let mut words = value.split_whitespace();
+    let (day, month, year) = (words.next()?, words.next()?, words.next()?);
+    parse_date(&format!("{}-{}-{}", day, month, year))
+}
+
+/// The addresses of a header field as `(name, address)` pairs. Group
+/// names are dropped, leaving their members.
+fn address_items(value: &str) -> Vec<(Option<String>, String)> {
+    let mut items = Vec::new();
+    let (mut item, mut quoted, mut angle, mut comment) = (String::new(), false, false, 0);
+    for c in value.chars() {
+        match c {
+            '"' if comment == 0 => quoted = !quoted,
+            '(' if !quoted => comment += 1,
+            ')' if !quoted && comment > 0 => comment -= 1,
+            '<' if !quoted && comment == 0 => angle = true,
+            '>' if !quoted && comment == 0 => angle = false,
+            ',' | ';' if !quoted && !angle && comment == 0 => {
+                items.push(std::mem::take(&mut item));
+                continue;
+            }
+            ':' if !quoted && !angle && comment == 0 => {
+                item.clear();
+                continue;
+            }
+            _ => {}
+        }
+        item.push(c);
+    }
+    items.push(item);
+    items
+        .iter()
+        .filter_map(|item| match (item.find('<'), item.rfind('>')) {
+            (Some(start), Some(end)) if start < end => {
+                let name = item[..start].trim().trim_matches('"').trim();
+                let name = (!name.is_empty()).then(|| name.to_string());
+                Some((name, item[start + 1..end].trim().to_string()))
+            }
+            _ => {
+                let address = item.split('(').next().unwrap_or_default().trim();
+                (!address.is_empty()).then(|| (None, address.to_string()))
+            }
+        })
+        .collect()
+}
+
+fn address_list(value: Option<String>) -> Vec<u8> {
+    let items = value.map(|value| address_items(&value)).unwrap_or_default();
+    if items.is_empty() {
+        return b"NIL".to_vec();
+    }
+    let mut out = b"(".to_vec();
+    for (name, address) in items {
+        let (mailbox, host) = match address.rsplit_once('@') {
+            Some((mailbox, host)) => (mailbox, Some(host)),
+            None => (address.as_str(), None),
+        };
+        out.push(b'(');
+        out.extend(nstring(name.as_deref().map(str::as_bytes)));
+        out.extend_from_slice(b" NIL ");
+        out.extend(nstring(Some(mailbox.as_bytes())));
+        out.push(b' ');
+        out.extend(nstring(host.map(str::as_bytes)));
+        out.push(b')');
+    }
+    out.push(b')');
+    out
+}
+
+/// The ENVELOPE of a message with these header fields.
+fn envelope(headers: &Headers) -> Vec<u8> {
+    let field = |name: &str| headers.get(name).filter(|value| !value.is_empty());
+    let from = field("From");
+    let mut out = b"(".to_vec();
+    out.extend(nstring(field("Date").as_deref().map(str::as_bytes)));
+    out.push(b' ');
+    out.extend(nstring(field("Subject").as_deref().map(str::as_bytes)));
+    for addresses in [
+        from.clone(),
+        field("Sender").or_else(|| from.clone()),
+        field("Reply-To").or_else(|| from.clone()),
+        field("To"),
+        field("Cc"),
+        field("Bcc"),
+    ] {
+        out.push(b' ');
+        out.extend(address_list(addresses));
+    }
+    for name in ["In-Reply-To", "Message-ID"] {
+        out.push(b' ');
+        out.extend(nstring(field(name).as_deref().map(str::as_bytes)));
+    }
+    out.push(b')');
+    out
+}
+
+fn param_list(params: &[(String, String)]) -> Vec<u8> {
+    if params.is_empty() {
+        return b"NIL".to_vec();
+    }
+    let mut out = b"(".to_vec();
+    for (i, (name, value)) in params.iter().enumerate() {
+        if i > 0 {
+            out.push(b' ');
+        }
+        out.extend(nstring(Some(name.to_ascii_uppercase().as_bytes())));
+        out.push(b' ');
+        out.extend(nstring(Some(value.as_bytes())));
+    }
+    out.push(b')');
+    out
+}
+
+fn disposition(part: &Part) -> Vec<u8> {
+    let value = match part.headers.get("Content-Disposition") {
+        Some(value) => value,
+        None => return b"NIL".to_vec(),
+    };
+    let (kind, params) = mime::parse_params(&value);
+    let mut out = b"(".to_vec();
+    out.extend(nstring(Some(kind.to_ascii_uppercase().as_bytes())));
+    out.push(b' ');
+    out.extend(param_list(&params));
+    out.push(b')');
+    out
+}
+
+/// The BODY (`extended` false) or BODYSTRUCTURE of a part.
+fn body_structure(part: &Part, extended: bool) -> Vec<u8> {
+    let mut out = b"(".to_vec();
+    if part.is_multipart() && !part.parts.is_empty() {
+        for child in &part.parts {
+            out.extend(body_structure(child, extended));
+        }
+        let subtype = part.content_type.split_once('/').map_or("mixed", |(_, subtype)| subtype);
+        out.push(b' ');
+        out.extend(nstring(Some(subtype.to_ascii_uppercase().as_bytes())));
+        if extended {
+            out.push(b' ');
+            out.extend(param_list(&part.params));
+            out.push(b' ');
+            out.extend(disposition(part));
+            out.extend_from_slice(b" NIL NIL");
+        }
+        out.push(b')');
+        return out;
+    }
+
+    let (kind, subtype) = part.content_type.split_once('/').unwrap_or((&part.content_type, ""));
+    let mut params = part.params.clone();
+    if kind == "text" && part.param("charset").is_none() {
+        params.push(("charset".to_string(), "us-ascii".to_string()));
+    }
+    let encoding = part.headers.get("Content-Transfer-Encoding").unwrap_or_else(|| "7BIT".to_string());
+    let lines = part.raw_body.matches('\n').count();
+    out.extend(nstring(Some(kind.to_ascii_uppercase().as_bytes())));
+    out.push(b' ');
+    out.extend(nstring(Some(subtype.to_ascii_uppercase().as_bytes())));
+    out.push(b' ');
+    out.extend(param_list(&params));
+    for name in ["Content-ID", "Content-Description"] {
+        out.push(b' ');
+        out.extend(nstring(part.headers.get(name).as_deref().map(str::as_bytes)));
+    }
+    out.push(b' ');
+    out.extend(nstring(Some(encoding.to_ascii_uppercase().as_bytes())));
+    out.extend(format!(" {}", part.raw_body.len()).into_bytes());
+    if part.content_type == "message/rfc822" {
+        let inner = mime::parse_message(&part.raw_body);
+        out.push(b' ');
+        out.extend(envelope(&inner.headers));
+        out.push(b' ');
+        out.extend(body_structure(&inner, extended));
+        out.extend(format!(" {}", lines).into_bytes());
+    } else if kind == "text" {
+        out.extend(format!(" {}", lines).into_bytes());
+    }
+    if extended {
+        out.extend_from_slice(b" NIL ");
+        out.extend(disposition(part));
+        out.extend_from_slice(b" NIL NIL");
+    }
+    out.push(b')');
+    out
+}
+
+/// The header fields named in `names`, or all the others with `not`,
+/// followed by the empty line.
+fn header_fields(headers: &Headers, names: &[String], not: bool) -> Vec<u8> {
+    let mut out = String::new();
+    for field in headers.iter() {
+        if names.iter().any(|name| field.is(name)) != not {
+            out += &field.to_string();
+        }
+    }
+    out += "\r\n";
+    out.into_bytes()
+}
+
+/// What a `BODY[...]` section asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Section {
+    /// Part numbers, outermost first; empty for the whole message.
+    path: Vec<usize>,
+    /// `""`, `HEADER`, `HEADER.FIELDS`, `HEADER.FIELDS.NOT`, `TEXT` or `MIME`.
+    kind: String,
+    fields: Vec<String>,
+}
+
+impl Section {
+    /// Parses the text between the brackets, e.g. `1.2.HEADER.FIELDS (FROM TO)`.
+    fn parse(text: &str) -> Option<Section> {
+        let (spec, fields) = text.split_once(' ').unwrap_or((text, ""));
+        let mut path = Vec::new();
+        let mut kind = String::new();
+        for (i, piece) in spec.split('.').enumerate() {
+            match piece.parse::<usize>() {
+                Ok(n) if n > 0 && kind.is_empty() => path.push(n),
+                _ if spec.is_empty() => {}
+                _ => {
+                    kind = spec.split('.').skip(i).collect::<Vec<_>>().join(".").to_ascii_uppercase();
+                    break;
+                }
+            }
+        }
+        let fields = match Parser::parse(fields.as_bytes()).ok()?.as_slice() {
+            [] => Vec::new(),
+            [Token::List(names)] => names.iter().filter_map(|name| name.text().map(str::to_string)).collect(),
+            _ => return None,
+        };
+        let valid = match kind.as_str() {
+            "" | "HEADER" | "TEXT" => fields.is_empty(),
+            "MIME" => !path.is_empty() && fields.is_empty(),
+            "HEADER.FIELDS" | "HEADER.FIELDS.NOT" => !fields.is_empty(),
+            _ => false,
+        };
+        valid.then_some(Section { path, kind, fields })
+    }
+
+    /// The section's octets within a message, if it has such a part.
+    fn extract(&self, content: &[u8]) -> Option<Vec<u8>> {
+        if self.path.is_empty() {
+            let (header, body) = headers::split_message_bytes(content);
+            return match self.kind.as_str() {
+                "" => Some(content.to_vec()),
+                "TEXT" => Some(body.to_vec()),
+                "HEADER" => Some([header, b"\r\n"].concat()),
+                kind => Some(header_fields(
+                    &Headers::parse(&String::from_utf8_lossy(header)),
+                    &self.fields,
+                    kind.ends_with(".NOT"),
+                )),
+            };
+        }
+        let mut part = mime::parse_message(&String::from_utf8_lossy(content));
+        for &n in &self.path {
+            if part.content_type == "message/rfc822" {
+                part = mime::parse_message(&part.raw_body);
+            }
+            if part.is_multipart() && !part.parts.is_empty() {
+                part = part.parts.get(n - 1)?.clone();
+            } else if n != 1 {
+                return None;
+            }
+        }
+        match self.kind.as_str() {
+            "" => Some(part.raw_body.into_bytes()),
+            "MIME" => Some(format!("{}\r\n", part.headers).into_bytes()),
+            kind if part.content_type == "message/rfc822" => {
+                let inner = mime::parse_message(&part.raw_body);
+                match kind {
+                    "TEXT" => Some(inner.raw_body.into_bytes()),
+                    "HEADER" => Some(format!("{}\r\n", inner.headers).into_bytes()),
+                    kind => Some(header_fields(&inner.headers, &self.fields, kind.ends_with(".NOT"))),
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// One data item of a FETCH.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    Flags,
+    Uid,
+    InternalDate,
+    Size,
+    Envelope,
+    Body,
+    BodyStructure,
+    /// `BODY[...]` and the RFC822 forms, with the name to answer under.
+    Section {
+        name: String,
+        section: Section,
+        peek: bool,
+        partial: Option<(usize, usize)>,
+    },
+}
+
+impl Item {
+    fn parse(text: &str) -> Option<Vec<Item>> {
+        let upper = text.to_ascii_uppercase();
+        let section = |name: &str, spec: &str, peek: bool| {
+            Some(Item::Section { name: name.to_string(), section: Section::parse(spec)?, peek, partial: None })
+        };
+        let item = match upper.as_str() {
+            "ALL" => return Some(vec![Item::Flags, Item::InternalDate, Item::Size, Item::Envelope]),
+            "FAST" => return Some(vec![Item::Flags, Item::InternalDate, Item::Size]),
+            "FULL" => return Some(vec![Item::Flags, Item::InternalDate, Item::Size, Item::Envelope, Item::Body]),
+            "FLAGS" => Item::Flags,
+            "UID" => Item::Uid,
+            "INTERNALDATE" => Item::InternalDate,
+            "RFC822.SIZE" => Item::Size,
+            "ENVELOPE" => Item::Envelope,
+            "BODY" => Item::Body,
+            "BODYSTRUCTURE" => Item::BodyStructure,
+            "RFC822" => section("RFC822", "", false)?,
+            "RFC822.HEADER" => section("RFC822.HEADER", "HEADER", true)?,
+            "RFC822.TEXT" => section("RFC822.TEXT", "TEXT", false)?,
+            _ => {
+                let peek = upper.starts_with("BODY.PEEK[");
+                if !peek && !upper.starts_with("BODY[") {
+                    return None;
+                }
+                let open = text.find('[')?;
+                let close = text.rfind(']')?;
+                let spec = &text[open + 1..close];
+                let partial = match &text[close + 1..] {
+                    "" => None,
+                    rest => {
+                        let (start, length) = rest.strip_prefix('<')?.strip_suffix('>')?.split_once('.')?;
+                        Some((start.parse().ok()?, length.parse().ok()?))
+                    }
+                };
+                Item::Section { name: format!("BODY[{}]", spec), section: Section::parse(spec)?, peek, partial }
+            }
+        };
+        Some(vec![item])
+    }
+}
+
+/// One SEARCH criterion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Key {
+    All,
+    None,
+    /// A flag being set (true) or not.
+    Flag(String, bool),
+    /// A header field containing text; any such field for empty text.
+    Header(String, String),
+    Body(String),
+    Text(String),
+    /// Internal date before, on or since a day, counted from 1970.
+    Before(i64),
+    On(i64),
+    Since(i64),
+    SentBefore(i64),
+    SentOn(i64),
+    SentSince(i64),
+    Larger(u64),
+    Smaller(u64),
+    Uids(Vec<(u32, u32)>),
+    Numbers(Vec<(u32, u32)>),
+    Not(Box<Key>),
+    Or(Box<Key>, Box<Key>),
+    And(Vec<Key>),
+}
+
+/// Parses SEARCH keys; `largest` gives the `*` of message numbers and of UIDs.
+struct KeyParser<'a> {
+    tokens: std::slice::Iter<'a, Token>,
+    largest: (u32, u32),
+}
+
+impl KeyParser<'_> {
+    fn text(&mut self) -> std::result::Result<String, String> {
+        self.tokens.next().and_then(Token::text).map(str::to_string).ok_or_else(|| "Missing argument".to_string())
+    }
+
+    fn date(&mut self) -> std::result::Result<i64, String> {
+        let text = self.text()?;
+        parse_date(&text).ok_or_else(|| format!("Bad date {}", text))
+    }
+
+    fn number(&mut self) -> std::result::Result<u64, String> {
+        let text = self.text()?;
+        text.parse().map_err(|_| format!("Bad number {}", text))
+    }
+
+    fn keys(&mut self) -> std::result::Result<Vec<Key>, String> {
+        let mut keys = Vec::new();
+        while let Some(key) = self.key()? {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    fn key(&mut self) -> std::result::Result<Option<Key>, String> {
+        let token = match self.tokens.next() {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let word = match token {
+            Token::List(tokens) => {
+                let mut inner = KeyParser { tokens: tokens.iter(), largest: self.largest };
+                return Ok(Some(Key::And(inner.keys()?)));
+            }
+            Token::Str(_) => return Err("Unexpected string".to_string()),
+            Token::Atom(word) => word.to_ascii_uppercase(),
+        };
+        let flag = |name: &str, set: bool| Key::Flag(name.to_string(), set);
+        let key = match word.as_str() {
+            "ALL" => Key::All,
+            "ANSWERED" => flag("\\Answered", true),
+            "DELETED" => flag("\\Deleted", true),
+            "DRAFT" => flag("\\Draft", true),
+            "FLAGGED" => flag("\\Flagged", true),
+            "SEEN" | "OLD" => flag("\\Seen", true),
+            "UNANSWERED" => flag("\\Answered", false),
+            "UNDELETED" => flag("\\Deleted", false),
+            "UNDRAFT" => flag("\\Draft", false),
+            "UNFLAGGED" => flag("\\Flagged", false),
+            "UNSEEN" => flag("\\Seen", false),
+            "RECENT" | "NEW" => Key::None,
+            "KEYWORD" => Key::Flag(self.text()?, true),
+            "UNKEYWORD" => Key::Flag(self.text()?, false),
+            "FROM" | "TO" | "CC" | "BCC" | "SUBJECT" => Key::Header(word.clone(), self.text()?),
+            "HEADER" => Key::Header(self.text()?, self.text()?),
+            "BODY" => Key::Body(self.text()?),
+            "TEXT" => Key::Text(self.text()?),
+            "BEFORE" => Key::Before(self.date()?),
+            "ON" => Key::On(self.date()?),
+            "SINCE" => Key::Since(self.date()?),
+            "SENTBEFORE" => Key::SentBefore(self.date()?),
+            "SENTON" => Key::SentOn(self.date()?),
+            "SENTSINCE" => Key::SentSince(self.date()?),
+            "LARGER" => Key::Larger(self.number()?),
+            "SMALLER" => Key::Smaller(self.number()?),
+            "UID" => {
+                let set = self.text()?;
+                Key::Uids(sequence_set(&set, self.largest.1).ok_or_else(|| format!("Bad UID set {}", set))?)
+            }
+            "NOT" => Key::Not(Box::new(self.key()?.ok_or("NOT needs a key")?)),
+            "OR" => {
+                let first = self.key()?.ok_or("OR needs two keys")?;
+                let second = self.key()?.ok_or("OR needs two keys")?;
+                Key::Or(Box::new(first), Box::new(second))
+            }
+            _ => match sequence_set(&word, self.largest.0) {
+                Some(set) => Key::Numbers(set),
+                None => return Err(format!("Unknown search key {}", word)),
+            },
+        };
+        Ok(Some(key))
+    }
+}
+
+/// A message being searched, its content read only if a key needs it.
+struct Candidate<'a> {
+    number: u32,
+    entry: &'a Entry,
+    load: &'a dyn Fn(u32) -> Option<Vec<u8>>,
+    content: Option<Option<(Headers, String)>>,
+}
+
+impl Candidate<'_> {
+    /// The header fields and the text of the body parts.
+    fn content(&mut self) -> Option<&(Headers, String)> {
+        if self.content.is_none() {
+            self.content = Some((self.load)(self.entry.uid).map(|content| {
+                let part = mime::parse_message(&String::from_utf8_lossy(&content));
+                let text = part.leaves().into_iter().filter_map(|part| part.text()).collect::<Vec<_>>().join("\n");
+                (part.headers, text)
+            }));
+        }
+        self.content.as_ref().unwrap().as_ref()
+    }
+
+    fn header_contains(&mut self, name: &str, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.content().is_some_and(|(headers, _)| {
+            headers
+                .get_all(name)
+                .any(|field| mime::decode_encoded_words(&field.unfolded()).to_lowercase().contains(&text))
+        })
+    }
+
+    fn body_contains(&mut self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.content().is_some_and(|(_, body)| body.to_lowercase().contains(&text))
+    }
+
+    fn sent(&mut self) -> Option<i64> {
+        self.content().and_then(|(headers, _)| headers.get("Date")).and_then(|date| header_date(&date))
+    }
+
+    fn matches(&mut self, key: &Key) -> bool {
+        let day = || self.entry.received.duration_since(UNIX_EPOCH).map(|d| (d.as_secs() / 86400) as i64).unwrap_or(0);
+        match key {
+            Key::All => true,
+            Key::None => false,
+            Key::Flag(flag, set) => self.entry.flags.iter().any(|f| f.eq_ignore_ascii_case(flag)) == *set,
+            Key::Header(name, text) => self.header_contains(name, text),
+            Key::Body(text) => self.body_contains(text),
+            Key::Text(text) => {
+                let names: Vec<String> = self
+                    .content()
+                    .map(|(headers, _)| headers.iter().map(|field| field.name.clone()).collect())
+                    .unwrap_or_default();
+                names.iter().any(|name| self.header_contains(name, text)) || self.body_contains(text)
+            }
+            Key::Before(date) => day() < *date,
+            Key::On(date) => day() == *date,
+            Key::Since(date) => day() >= *date,
+            Key::SentBefore(date) => self.sent().is_some_and(|sent| sent < *date),
+            Key::SentOn(date) => self.sent() == Some(*date),
+            Key::SentSince(date) => self.sent().is_some_and(|sent| sent >= *date),
+            Key::Larger(size) => self.entry.size > *size,
+            Key::Smaller(size) => self.entry.size < *size,
+            Key::Uids(set) => in_set(set, self.entry.uid),
+            Key::Numbers(set) => in_set(set, self.number),
+            Key::Not(key) => !self.matches(key),
+            Key::Or(first, second) => self.matches(first) || self.matches(second),
+            Key::And(keys) => keys.iter().all(|key| self.matches(key)),
+        }
+    }
+}
+
+/// Whether `name` matches a LIST pattern, in which `*` matches anything
+/// and `%` anything but the hierarchy delimiter.
+fn list_matches(pattern: &[char], name: &[char]) -> bool {
+    // Works back from the end of the pattern: `next[j]` says whether the
+    // rest of the pattern matches `name[j..]`, so each wildcard costs one
+    // pass over the name instead of a search.
+    let mut next: Vec<bool> = (0..=name.len()).map(|j| j == name.len()).collect();
+    for &c in pattern.iter().rev() {
+        let mut row = vec![false; name.len() + 1];
+        for j in (0..=name.len()).rev() {
+            row[j] = match c {
+                '*' => next[j] || (j < name.len() && row[j + 1]),
+                '%' => next[j] || (j < name.len() && name[j] != DELIMITER && row[j + 1]),
+                c => j < name.len() && name[j] == c && next[j + 1],
+            };
+        }
+        next = row;
+    }
+    next[0]
+}
+
+/// `INBOX` in any case means the inbox (RFC 3501 section 5.1).
+fn mailbox_name(name: &str) -> String {
+    if name.eq_ignore_ascii_case(INBOX) {
+        INBOX.to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// The mailbox a session has selected.
+struct Selected {
+    name: String,
+    read_only: bool,
+    /// The UID of each message, by message number less one.
+    uids: Vec<u32>,
+}
+
+/// What a command left to send after its untagged responses.
+type Outcome = Option<String>;
+
+/// One IMAP session.
+struct Session {
+    stream: Stream,
+    id: usize,
+    buf: Vec<u8>,
+    config: Arc<Config>,
+    tls: bool,
+    /// Set once logged in.
+    user: Option<String>,
+    selected: Option<Selected>,
+}
+
+impl Session {
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        self.stream.write_all(format!("{}\r\n", line).as_bytes())
+    }
+
+    fn read_line(&mut self) -> Result<Vec<u8>> {
+        let mut buffer = [0; 1024];
+        loop {
+            if let Some(i) = self.buf.windows(2).position(|window| window == b"\r\n") {
+                return Ok(self.buf.drain(..i + 2).take(i).collect());
+            }
+            if self.buf.len() > MAX_COMMAND {
+                return Err(Error::new(ErrorKind::InvalidData, "Command too long"));
+            }
+            let n = self.stream.read(&mut buffer)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
+            }
+            self.buf.extend_from_slice(&buffer[..n]);
+        }
+    }
+
+    /// Reads a whole command, taking in the literals it carries.
+    fn read_command(&mut self) -> Result<Vec<u8>> {
+        let mut command = Vec::new();
+        loop {
+            let line = self.read_line()?;
+            command.extend_from_slice(&line);
+            let (length, wait) = match literal_length(&line) {
+                Some(literal) => literal,
+                None => return Ok(command),
+            };
+            if length > MAX_COMMAND.saturating_sub(command.len()) {
+                return Err(Error::new(ErrorKind::InvalidData, "Command too long"));
+            }
+            if wait {
+                self.write_line("+ Ready for literal data")?;
+            }
+            command.extend_from_slice(b"\r\n");
+            let mut buffer = [0; 1024];
+            while self.buf.len() < length {
+                let n = self.stream.read(&mut buffer)?;
+                if n == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
+                }
+                self.buf.extend_from_slice(&buffer[..n]);
+            }
+            command.extend(self.buf.drain(..length));
+        }
+    }
+
+    fn log_info(&self, msg: &str, args: Option<Arguments>) {
+        match args {
+            Some(arguments) => println!("[INFO] [imap {}:{}] {} {}", self.id, self.stream.peer(), msg, arguments),
+            None => println!("[INFO] [imap {}:{}] {}", self.id, self.stream.peer(), msg),
+        }
+    }
+
+    fn log_error(&self, e: Error) {
+        println!("[ERROR] [imap {}:{}] {}", self.id, self.stream.peer(), e);
+    }
+
+    fn handle(&mut self) {
+        if let Err(e) = self.session() {
+            if e.kind() != ErrorKind::UnexpectedEof {
+                self.log_error(e);
+                let _ = self.write_line("* BYE Server error");
+            }
+        }
+    }
+
+    fn capabilities(&self) -> String {
+        let mut capabilities = "IMAP4rev1 LITERAL+ IDLE".to_string();
+        if self.config.tls.is_some() && !self.tls && self.user.is_none() {
+            capabilities += " STARTTLS";
+        }
+        capabilities
+    }
+
+    fn session(&mut self) -> Result<()> {
+        let greeting = format!("* OK [CAPABILITY {}] {} IMAP4rev1 ready", self.capabilities(), self.config.hostname);
+        self.write_line(&greeting)?;
+        loop {
+            let command = self.read_command()?;
+            let tokens = match Parser::parse(&command) {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    self.write_line(&format!("* BAD {}", e))?;
+                    continue;
+                }
+            };
+            let (tag, name, args) = match tokens.as_slice() {
+                [Token::Atom(tag), Token::Atom(name), args @ ..] => (tag.clone(), name.to_ascii_uppercase(), args),
+                [Token::Atom(tag), ..] => {
+                    self.write_line(&format!("{} BAD Missing command", tag))?;
+                    continue;
+                }
+                _ => {
+                    self.write_line("* BAD Missing tag")?;
+                    continue;
+                }
+            };
+            if let Some(outcome) = self.dispatch(&tag, &name, args)? {
+                self.write_line(&format!("{} {}", tag, outcome))?;
+            }
+            if name == "LOGOUT" {
+                return Ok(());
+            }
+        }
+    }
+
+    fn dispatch(&mut self, tag: &str, name: &str, args: &[Token]) -> Result<Outcome> {
+        let outcome = match name {
+            "CAPABILITY" => {
+                let capabilities = format!("* CAPABILITY {}", self.capabilities());
+                self.write_line(&capabilities)?;
+                "OK CAPABILITY completed".to_string()
+            }
+            "NOOP" => {
+                self.refresh()?;
+                "OK NOOP completed".to_string()
+            }
+            "LOGOUT" => {
+                let bye = format!("* BYE {} IMAP4rev1 server logging out", self.config.hostname);
+                self.write_line(&bye)?;
+                "OK LOGOUT completed".to_string()
+            }
+            "STARTTLS" if self.user.is_none() => return self.start_tls(tag).map(|_| None),
+            "LOGIN" if self.user.is_none() => self.login(args),
+            "AUTHENTICATE" if self.user.is_none() => "NO Use LOGIN".to_string(),
+            _ if self.user.is_none() => "BAD Log in first".to_string(),
+            "SELECT" => self.select(args, false)?,
+            "EXAMINE" => self.select(args, true)?,
+            "CREATE" => self.create(args),
+            "SUBSCRIBE" | "UNSUBSCRIBE" => format!("OK {} completed", name),
+            "LIST" | "LSUB" => self.list(name, args)?,
+            "STATUS" => self.status(args)?,
+            _ if self.selected.is_none() => "BAD Select a mailbox first".to_string(),
+            "CHECK" => {
+                self.refresh()?;
+                "OK CHECK completed".to_string()
+            }
+            "CLOSE" => {
+                if !self.selected.as_ref().unwrap().read_only {
+                    self.expunge(false)?;
+                }
+                self.selected = None;
+                "OK CLOSE completed".to_string()
+            }
+            "EXPUNGE" if self.selected.as_ref().unwrap().read_only => "NO Mailbox is read-only".to_string(),
+            "EXPUNGE" => {
+                self.expunge(true)?;
+                "OK EXPUNGE completed".to_string()
+            }
+            "FETCH" | "STORE" | "SEARCH" => self.message_command(name, args, false)?,
+            "UID" => match args.split_first() {
+                Some((Token::Atom(command), args)) => {
+                    let command = command.to_ascii_uppercase();
+                    match command.as_str() {
+                        "FETCH" | "STORE" | "SEARCH" => self.message_command(&command, args, true)?,
+                        _ => "BAD Unknown UID command".to_string(),
+                    }
+                }
+                _ => "BAD Missing UID command".to_string(),
+            },
+            "IDLE" => self.idle()?,
+            _ => "BAD Unknown command".to_string(),
+        };
+        Ok(Some(outcome))
+    }
+
+    fn start_tls(&mut self, tag: &str) -> Result<()> {
+        let config = match &self.config.tls {
+            Some(config) if !self.tls => config.clone(),
+            _ => return self.write_line(&format!("{} NO STARTTLS not available", tag)),
+        };
+        self.write_line(&format!("{} OK Begin TLS negotiation now", tag))?;
+        self.stream.start_tls(config)?;
+        // Anything sent in the clear after STARTTLS could have been injected.
+        self.buf.clear();
+        self.tls = true;
+        Ok(())
+    }
+
+    fn login(&mut self, args: &[Token]) -> String {
+        let (user, password) = match args {
+            [user, password] => match (user.text(), password.text()) {
+                (Some(user), Some(password)) => (user.to_lowercase(), password),
+                _ => return "BAD Expected user name and password".to_string(),
+            },
+            _ => return "BAD Expected user name and password".to_string(),
+        };
+        if !self.config.credentials.verify(&user, password) {
+            self.log_info("Login failed for", Some(format_args!("{}", user)));
+            return "NO [AUTHENTICATIONFAILED] Invalid user name or password".to_string();
+        }
+        // Every user has an inbox, even before any mail came.
+        if let Err(e) = self.config.mailboxes.create(&user, INBOX) {
+            self.log_error(e);
+            return "NO [UNAVAILABLE] Mailboxes unavailable".to_string();
+        }
+        self.log_info("Logged in as", Some(format_args!("{}", user)));
+        self.user = Some(user);
+        "OK LOGIN completed".to_string()
+    }
+
+    fn user(&self) -> &str {
+        self.user.as_deref().unwrap_or_default()
+    }
+
+    /// The UID validity, next UID and messages of one of the user's
+    /// mailboxes, or the reply refusing it.
+    fn open(&self, name: &str) -> std::result::Result<((u32, u32), Vec<Entry>), String> {
+        let mailboxes = &self.config.mailboxes;
+        if !mailboxes.exists(self.user(), name) {
+            return Err("NO [NONEXISTENT] No such mailbox".to_string());
+        }
+        let opened = mailboxes.uids(self.user(), name).and_then(|uids| Ok((uids, mailboxes.list(self.user(), name)?)));
+        opened.map_err(|e| {
+            self.log_error(e);
+            "NO [UNAVAILABLE] Mailbox unavailable".to_string()
+        })
+    }
+
+    fn select(&mut self, args: &[Token], read_only: bool) -> Result<String> {
+        self.selected = None;
+        let name = match args {
+            [name] if name.text().is_some() => mailbox_name(name.text().unwrap()),
+            _ => return Ok("BAD Expected a mailbox name".to_string()),
+        };
+        let ((uid_validity, uid_next), entries) = match self.open(&name) {
+            Ok(opened) => opened,
+            Err(refusal) => return Ok(refusal),
+        };
+        let mut reply = format!("* FLAGS ({})\r\n", SYSTEM_FLAGS.join(" "));
+        reply += &format!("* {} EXISTS\r\n* 0 RECENT\r\n", entries.len());
+        if let Some(unseen) = entries.iter().position(|entry| !entry.flags.iter().any(|f| f == "\\Seen")) {
+            reply += &format!("* OK [UNSEEN {}] First unseen\r\n", unseen + 1);
+        }
+        let permanent = if read_only { String::new() } else { format!("{} \\*", SYSTEM_FLAGS.join(" ")) };
+        reply += &format!("* OK [PERMANENTFLAGS ({})] Flags permitted\r\n", permanent);
+        reply += &format!("* OK [UIDVALIDITY {}] UIDs valid\r\n", uid_validity);
+        reply += &format!("* OK [UIDNEXT {}] Predicted next UID\r\n", uid_next);
+        self.stream.write_all(reply.as_bytes())?;
+        self.selected = Some(Selected { name, read_only, uids: entries.iter().map(|entry| entry.uid).collect() });
+        Ok(match read_only {
+            true => "OK [READ-ONLY] EXAMINE completed".to_string(),
+            false => "OK [READ-WRITE] SELECT completed".to_string(),
+        })
+    }
+
+    fn create(&mut self, args: &[Token]) -> String {
+        let name = match args {
+            [name] if name.text().is_some() => mailbox_name(name.text().unwrap().trim_end_matches(DELIMITER)),
+            _ => return "BAD Expected a mailbox name".to_string(),
+        };
+        if self.config.mailboxes.exists(self.user(), &name) {
+            return "NO [ALREADYEXISTS] Mailbox already exists".to_string();
+        }
+        match self.config.mailboxes.create(self.user(), &name) {
+            Ok(()) => "OK CREATE completed".to_string(),
+            Err(e) if e.kind() == ErrorKind::InvalidInput => "NO [CANNOT] Invalid mailbox name".to_string(),
+            Err(e) => {
+                self.log_error(e);
+                "NO [UNAVAILABLE] Could not create mailbox".to_string()
+            }
+        }
+    }
+
+    fn list(&mut self, command: &str, args: &[Token]) -> Result<String> {
+        let (reference, pattern) = match args {
+            [reference, pattern] => match (reference.text(), pattern.text()) {
+                (Some(reference), Some(pattern)) => (reference, pattern),
+                _ => return Ok("BAD Expected a reference and a pattern".to_string()),
+            },
+            _ => return Ok("BAD Expected a reference and a pattern".to_string()),
+        };
+        let mut reply = String::new();
+        if pattern.is_empty() {
+            // Just the hierarchy delimiter (RFC 3501 section 6.3.8).
+            reply += &format!("* {} (\\Noselect) {} \"\"\r\n", command, quote(&DELIMITER.to_string()));
+        } else {
+            let names = match self.config.mailboxes.mailboxes(self.user()) {
+                Ok(names) => names,
+                Err(e) => {
+                    self.log_error(e);
+                    return Ok("NO [UNAVAILABLE] Mailboxes unavailable".to_string());
+                }
+            };
+            // Levels of the hierarchy that hold no mail themselves are listed
+            // too, as \Noselect.
+            let mut levels: Vec<(String, bool)> = Vec::new();
+            for name in &names {
+                for (i, _) in name.match_indices(DELIMITER) {
+                    let level = &name[..i];
+                    if !names.iter().any(|n| n == level) && !levels.iter().any(|(l, _)| l == level) {
+                        levels.push((level.to_string(), false));
+                    }
+                }
+                levels.push((name.clone(), true));
+            }
+            if !levels.is_empty() {
+                levels[1..].sort();
+            }
+            let pattern: Vec<char> = format!("{}{}", reference, pattern).chars().collect();
+            let inbox_pattern: Vec<char> = pattern.iter().collect::<String>().to_ascii_uppercase().chars().collect();
+            for (name, selectable) in &levels {
+                let chars: Vec<char> = name.chars().collect();
+                let matched = match name.as_str() {
+                    INBOX => list_matches(&inbox_pattern, &chars),
+                    _ => list_matches(&pattern, &chars),
+                };
+                if !matched {
+                    continue;
+                }
+                let prefix = format!("{}{}", name, DELIMITER);
+                let children = names.iter().any(|other| other.starts_with(&prefix));
+                let mut attributes = if children { "\\HasChildren" } else { "\\HasNoChildren" }.to_string();
+                if !selectable {
+                    attributes.insert_str(0, "\\Noselect ");
+                }
+                reply += &format!("* {} ({}) {} {}\r\n", command, attributes, quote(&DELIMITER.to_string()), quote(name));
+            }
+        }
+        self.stream.write_all(reply.as_bytes())?;
+        Ok(format!("OK {} completed", command))
+    }
+
+    fn status(&mut self, args: &[Token]) -> Result<String> {
+        let (name, items) = match args {
+            [name, Token::List(items)] if name.text().is_some() => (mailbox_name(name.text().unwrap()), items),
+            _ => return Ok("BAD Expected a mailbox name and a list of items".to_string()),
+        };
+        let ((uid_validity, uid_next), entries) = match self.open(&name) {
+            Ok(opened) => opened,
+            Err(refusal) => return Ok(refusal),
+        };
+        let mut values = Vec::new();
+        for item in items {
+            let item = item.text().unwrap_or_default().to_ascii_uppercase();
+            let value = match item.as_str() {
+                "MESSAGES" => entries.len(),
+                "RECENT" => 0,
+                "UIDNEXT" => uid_next as usize,
+                "UIDVALIDITY" => uid_validity as usize,
+                "UNSEEN" => entries.iter().filter(|entry| !entry.flags.iter().any(|f| f == "\\Seen")).count(),
+                _ => return Ok(format!("BAD Unknown status item {}", item)),
+            };
+            values.push(format!("{} {}", item, value));
+        }
+        let reply = format!("* STATUS {} ({})", quote(&name), values.join(" "));
+        self.write_line(&reply)?;
+        Ok("OK STATUS completed".to_string())
+    }
+
+    /// Tells the client about messages that arrived in or vanished from the
+    /// selected mailbox since it last looked.
+    fn refresh(&mut self) -> Result<()> {
+        let selected = match &mut self.selected {
+            Some(selected) => selected,
+            None => return Ok(()),
+        };
+        let user = self.user.as_deref().unwrap_or_default();
+        let entries = match self.config.mailboxes.list(user, &selected.name) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("[ERROR] [imap {}:{}] {}", self.id, self.stream.peer(), e);
+                return Ok(());
+            }
+        };
+        let present: HashSet<u32> = entries.iter().map(|entry| entry.uid).collect();
+        let mut reply = String::new();
+        // From the top, so that each number is right when it is sent.
+        for i in (0..selected.uids.len()).rev() {
+            if !present.contains(&selected.uids[i]) {
+                selected.uids.remove(i);
+                reply += &format!("* {} EXPUNGE\r\n", i + 1);
+            }
+        }
+        let last = selected.uids.last().copied().unwrap_or(0);
+        let arrived: Vec<u32> = entries.iter().map(|entry| entry.uid).filter(|uid| *uid > last).collect();
+        if !arrived.is_empty() {
+            selected.uids.extend(arrived);
+            reply += &format!("* {} EXISTS\r\n", selected.uids.len());
+        }
+        self.stream.write_all(reply.as_bytes())
+    }
+
+    /// Removes the messages flagged `\Deleted`, reporting each if `report`.
+    fn expunge(&mut self, report: bool) -> Result<()> {
+        let user = self.user().to_string();
+        let selected = self.selected.as_mut().unwrap();
+        let entries = self.config.mailboxes.list(&user, &selected.name)?;
+        let deleted: HashSet<u32> = entries
+            .iter()
+            .filter(|entry| entry.flags.iter().any(|f| f == "\\Deleted"))
+            .map(|entry| entry.uid)
+            .collect();
+        let mut reply = String::new();
+        for i in (0..selected.uids.len()).rev() {
+            if deleted.contains(&selected.uids[i]) {
+                self.config.mailboxes.remove(&user, &selected.name, selected.uids[i])?;
+                selected.uids.remove(i);
+                reply += &format!("* {} EXPUNGE\r\n", i + 1);
+            }
+        }
+        if report {
+            self.stream.write_all(reply.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// FETCH, STORE and SEARCH, by message number or by UID.
+    fn message_command(&mut self, command: &str, args: &[Token], by_uid: bool) -> Result<String> {
+        let selected = self.selected.as_ref().unwrap();
+        let entries = match self.config.mailboxes.list(self.user(), &selected.name) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.log_error(e);
+                return Ok("NO [UNAVAILABLE] Mailbox unavailable".to_string());
+            }
+        };
+        // The messages as the client knows them; any that another session
+        // removed meanwhile are skipped.
+        let messages: Vec<(u32, &Entry)> = selected
+            .uids
+            .iter()
+            .enumerate()
+            .filter_map(|(i, uid)| Some((i as u32 + 1, entries.iter().find(|entry| entry.uid == *uid)?)))
+            .collect();
+        let largest = (selected.uids.len() as u32, selected.uids.last().copied().unwrap_or(0));
+        if command == "SEARCH" {
+            return self.search(args, &messages, largest, by_uid);
+        }
+        let set = match args.first().and_then(Token::text) {
+            Some(set) => set,
+            None => return Ok(format!("BAD {} needs a message set", command)),
+        };
+        let set = match sequence_set(set, if by_uid { largest.1 } else { largest.0 }) {
+            Some(set) => set,
+            None => return Ok(format!("BAD Bad message set {}", set)),
+        };
+        let targets: Vec<(u32, &Entry)> = messages
+            .into_iter()
+            .filter(|(number, entry)| in_set(&set, if by_uid { entry.uid } else { *number }))
+            .collect();
+        match command {
+            "FETCH" => self.fetch(&args[1..], &targets, by_uid),
+            _ => self.store(&args[1..], &targets, by_uid),
+        }
+    }
+
+    fn search(&mut self, args: &[Token], messages: &[(u32, &Entry)], largest: (u32, u32), by_uid: bool) -> Result<String> {
+        let mut args = args;
+        if let [Token::Atom(charset), name, rest @ ..] = args {
+            if charset.eq_ignore_ascii_case("CHARSET") {
+                let name = name.text().unwrap_or_default().to_ascii_uppercase();
+                if name != "UTF-8" && name != "US-ASCII" {
+                    return Ok("NO [BADCHARSET (UTF-8 US-ASCII)] Unsupported charset".to_string());
+                }
+                args = rest;
+            }
+        }
+        let keys = match (KeyParser { tokens: args.iter(), largest }).keys() {
+            Ok(keys) if !keys.is_empty() => Key::And(keys),
+            Ok(_) => return Ok("BAD SEARCH needs a key".to_string()),
+            Err(e) => return Ok(format!("BAD {}", e)),
+        };
+        let user = self.user().to_string();
+        let name = self.selected.as_ref().unwrap().name.clone();
+        let load = |uid: u32| self.config.mailboxes.read(&user, &name, uid).ok();
+        let mut found = Vec::new();
+        for (number, entry) in messages {
+            let mut candidate = Candidate { number: *number, entry, load: &load, content: None };
+            if candidate.matches(&keys) {
+                found.push(if by_uid { entry.uid } else { *number }.to_string());
+            }
+        }
+        let mut reply = "* SEARCH".to_string();
+        for n in &found {
+            reply += " ";
+            reply += n;
+        }
+        self.write_line(&reply)?;
+        Ok(format!("OK {}SEARCH completed", if by_uid { "UID " } else { "" }))
+    }
+
+    fn fetch(&mut self, args: &[Token], targets: &[(u32, &Entry)], by_uid: bool) -> Result<String> {
+        let mut items = Vec::new();
+        let requested: Vec<&Token> = match args {
+            [Token::List(list)] => list.iter().collect(),
+            [item] => vec![item],
+            _ => return Ok("BAD FETCH needs data items".to_string()),
+        };
+        for item in requested {
+            match item.text().and_then(Item::parse) {
+                Some(parsed) => items.extend(parsed),
+                None => return Ok("BAD Unknown FETCH data item".to_string()),
+            }
+        }
+        if by_uid && !items.contains(&Item::Uid) {
+            items.insert(0, Item::Uid);
+        }
+        let user = self.user().to_string();
+        let selected = self.selected.as_ref().unwrap();
+        let (name, read_only) = (selected.name.clone(), selected.read_only);
+        let mailboxes = self.config.mailboxes.clone();
+        let marks_seen = items.iter().any(|item| matches!(item, Item::Section { peek: false, .. }));
+
+        for (number, entry) in targets {
+            let content = match mailboxes.read(&user, &name, entry.uid) {
+                Ok(content) => content,
+                // Removed by another session since the listing.
+                Err(_) => continue,
+            };
+            let mut flags = entry.flags.clone();
+            // Changed flags are reported even when not asked for.
+            let mut show_flags = false;
+            if marks_seen && !read_only && !flags.iter().any(|f| f == "\\Seen") {
+                flags.push("\\Seen".to_string());
+                if let Err(e) = mailboxes.set_flags(&user, &name, entry.uid, &flags) {
+                    self.log_error(e);
+                }
+                show_flags = !items.contains(&Item::Flags);
+            }
+            let mut part: Option<Part> = None;
+            let mut parsed = || part.get_or_insert_with(|| mime::parse_message(&String::from_utf8_lossy(&content))).clone();
+            let mut data: Vec<Vec<u8>> = Vec::new();
+            for item in &items {
+                data.push(match item {
+                    Item::Flags => format!("FLAGS {}", flag_list(&flags)).into_bytes(),
+                    Item::Uid => format!("UID {}", entry.uid).into_bytes(),
+                    Item::InternalDate => format!("INTERNALDATE {}", quote(&internal_date(entry.received))).into_bytes(),
+                    Item::Size => format!("RFC822.SIZE {}", entry.size).into_bytes(),
+                    Item::Envelope => {
+                        let header = headers::split_message_bytes(&content).0;
+                        [b"ENVELOPE ".as_slice(), &envelope(&Headers::parse(&String::from_utf8_lossy(header)))].concat()
+                    }
+                    Item::Body => [b"BODY ".as_slice(), &body_structure(&parsed(), false)].concat(),
+                    Item::BodyStructure => [b"BODYSTRUCTURE ".as_slice(), &body_structure(&parsed(), true)].concat(),
+                    Item::Section { name, section, partial, .. } => {
+                        let octets = section.extract(&content);
+                        match partial {
+                            Some((start, length)) => {
+                                let octets = octets.map(|octets| {
+                                    let start = (*start).min(octets.len());
+                                    octets[start..start.saturating_add(*length).min(octets.len())].to_vec()
+                                });
+                                [format!("{}<{}> ", name, start).as_bytes(), &nstring_literal(octets)].concat()
+                            }
+                            None => [format!("{} ", name).as_bytes(), &nstring_literal(octets)].concat(),
+                        }
+                    }
+                });
+            }
+            if show_flags {
+                data.push(format!("FLAGS {}", flag_list(&flags)).into_bytes());
+            }
+            let mut reply = format!("* {} FETCH (", number).into_bytes();
+            reply.extend(data.join(&b' '));
+            reply.extend_from_slice(b")\r\n");
+            self.stream.write_all(&reply)?;
+        }
+        Ok(format!("OK {}FETCH completed", if by_uid { "UID " } else { "" }))
+    }
+
+    fn store(&mut self, args: &[Token], targets: &[(u32, &Entry)], by_uid: bool) -> Result<String> {
+        let (action, flags) = match args.split_first() {
+            Some((Token::Atom(action), flags)) => (action.to_ascii_uppercase(), flags),
+            _ => return Ok("BAD STORE needs an action and flags".to_string()),
+        };
+        let flags: Vec<&Token> = match flags {
+            [Token::List(list)] => list.iter().collect(),
+            flags => flags.iter().collect(),
+        };
+        let mut given = Vec::new();
+        for flag in flags {
+            let flag = match flag {
+                Token::Atom(flag) => flag,
+                _ => return Ok("BAD Flags must be atoms".to_string()),
+            };
+            let flag = match SYSTEM_FLAGS.iter().find(|system| system.eq_ignore_ascii_case(flag)) {
+                Some(system) => system.to_string(),
+                None if flag.starts_with('\\') => return Ok(format!("BAD Cannot set {}", flag)),
+                None => flag.clone(),
+            };
+            if !given.contains(&flag) {
+                given.push(flag);
+            }
+        }
+        let silent = action.ends_with(".SILENT");
+        let mode = action.trim_end_matches(".SILENT");
+        if !matches!(mode, "FLAGS" | "+FLAGS" | "-FLAGS") {
+            return Ok(format!("BAD Unknown STORE action {}", action));
+        }
+        let selected = self.selected.as_ref().unwrap();
+        if selected.read_only {
+            return Ok("NO Mailbox is read-only".to_string());
+        }
+        let user = self.user().to_string();
+        let name = selected.name.clone();
+        let mut reply = String::new();
+        for (number, entry) in targets {
+            let flags: Vec<String> = match mode {
+                "FLAGS" => given.clone(),
+                "+FLAGS" => {
+                    let mut flags = entry.flags.clone();
+                    flags.extend(given.iter().filter(|flag| !entry.flags.contains(flag)).cloned());
+                    flags
+                }
+                _ => entry.flags.iter().filter(|flag| !given.contains(flag)).cloned().collect(),
+            };
+            match self.config.mailboxes.set_flags(&user, &name, entry.uid, &flags) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+            if !silent {
+                let uid = if by_uid { format!("UID {} ", entry.uid) } else { String::new() };
+                reply += &format!("* {} FETCH ({}FLAGS {})\r\n", number, uid, flag_list(&flags));
+            }
+        }
+        self.stream.write_all(reply.as_bytes())?;
+        Ok(format!("OK {}STORE completed", if by_uid { "UID " } else { "" }))
+    }
+
+    /// IDLE: reports changes to the selected mailbox until the client says
+    /// DONE.
+    fn idle(&mut self) -> Result<String> {
+        self.write_line("+ idling")?;
+        self.stream.set_read_timeout(Some(IDLE_POLL))?;
+        let outcome = loop {
+            match self.read_line() {
+                Ok(line) if line.eq_ignore_ascii_case(b"DONE") => break Ok("OK IDLE terminated".to_string()),
+                Ok(_) => break Ok("BAD Expected DONE".to_string()),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if let Err(e) = self.refresh() {
+                        break Err(e);
+                    }
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_read_timeout(None)?;
+        outcome
+    }
+}
+
+/// Accepts IMAP connections on `listener` forever, one thread per connection.
+pub fn serve(listener: TcpListener, config: Arc<Config>) -> Result<()> {
+    serve_with(listener, move || config.clone())
+}
+
+/// Like `serve`, asking `config` for the settings of each new connection.
+pub fn serve_with(listener: TcpListener, config: impl Fn() -> Arc<Config>) -> Result<()> {
+    let mut id = 0;
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                id += 1;
+                let mut session = Session {
+                    stream: Stream::Tcp(stream),
+                    id,
+                    buf: Vec::new(),
+                    config: config(),
+                    tls: false,
+                    user: None,
+                    selected: None,
+                };
+                thread::spawn(move || session.handle());
+            }
+            Err(e) => {
+                println!("Failed to accept IMAP connection: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
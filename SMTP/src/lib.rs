@@ -1 +1,38 @@
+pub mod address;
+pub mod auth;
+#[cfg(feature = "http-api")]
+pub mod api;
+pub mod authres;
+pub mod client;
+pub mod command;
+pub mod directory;
+pub mod dkim;
+pub mod dmarc;
+pub mod dns;
+pub mod dsn;
+pub mod greylist;
+pub mod handler;
+pub mod headers;
+#[cfg(any(feature = "http-api", feature = "metrics"))]
+pub mod http;
+pub mod idna;
+pub mod imap;
+pub mod json;
+pub mod mailbox;
+pub mod metrics;
+pub mod milter;
+pub mod mime;
+pub mod pop3;
+pub mod proxy;
+pub mod rewrite;
 pub mod server;
+pub mod settings;
+pub mod sieve;
+pub mod spam;
+pub mod spf;
+pub mod store;
+pub mod stream;
+pub mod time;
+pub mod tls;
+pub mod trace;
+pub mod webhook;
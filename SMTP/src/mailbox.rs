@@ -0,0 +1,225 @@
+//! Per-user mailboxes on disk, which delivery fills and POP3 and IMAP serve.
+//!
+//! Each user (a lowercased address) has a directory of mailboxes, `INBOX`
+//! plus any folders Sieve filed mail into:
+//!
+//! ```text
+//! <root>/qa@sink.test/INBOX/.uids     "<uidvalidity> <next uid>"
+//! <root>/qa@sink.test/INBOX/1.eml     the message
+//! <root>/qa@sink.test/INBOX/1.flags   its IMAP flags, if any
+//! <root>/qa@sink.test/Lists/dev/...   a nested mailbox
+//! ```
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The mailbox new mail goes to.
+pub const INBOX: &str = "INBOX";
+
+/// Separates the levels of a nested mailbox name.
+pub const DELIMITER: char = '/';
+
+const UIDS: &str = ".uids";
+
+/// One message in a mailbox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Unique within the mailbox for as long as its UID validity lasts, and
+    /// increasing in delivery order.
+    pub uid: u32,
+    /// Octets, counting CRLF line endings.
+    pub size: u64,
+    pub flags: Vec<String>,
+    pub received: SystemTime,
+}
+
+pub struct MailboxStore {
+    pub root: PathBuf,
+    /// Serializes UID assignment and flag updates.
+    lock: Mutex<()>,
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+/// Whether `name` is safe as one path component.
+fn plain(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\', '\0'])
+}
+
+impl MailboxStore {
+    /// Opens (creating if needed) a store in `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Result<MailboxStore> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(MailboxStore { root, lock: Mutex::new(()) })
+    }
+
+    fn user_dir(&self, user: &str) -> Result<PathBuf> {
+        let user = user.to_lowercase();
+        if !plain(&user) {
+            return Err(invalid(format!("invalid user {:?}", user)));
+        }
+        Ok(self.root.join(user))
+    }
+
+    /// The directory of a mailbox. `INBOX` is case-insensitive (RFC 3501
+    /// section 5.1); other names are kept as given.
+    fn dir(&self, user: &str, mailbox: &str) -> Result<PathBuf> {
+        let mut dir = self.user_dir(user)?;
+        if mailbox.eq_ignore_ascii_case(INBOX) {
+            return Ok(dir.join(INBOX));
+        }
+        for level in mailbox.split(DELIMITER) {
+            if !plain(level) {
+                return Err(invalid(format!("invalid mailbox name {:?}", mailbox)));
+            }
+            dir.push(level);
+        }
+        Ok(dir)
+    }
+
+    fn read_uids(dir: &Path) -> Result<(u32, u32)> {
+        let text = fs::read_to_string(dir.join(UIDS))?;
+        let mut fields = text.split_whitespace().map(str::parse::<u32>);
+        match (fields.next(), fields.next()) {
+            (Some(Ok(validity)), Some(Ok(next))) => Ok((validity, next)),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("bad {} in {}", UIDS, dir.display()))),
+        }
+    }
+
+    /// Creates a mailbox, doing nothing if it already exists.
+    pub fn create(&self, user: &str, mailbox: &str) -> Result<()> {
+        let dir = self.dir(user, mailbox)?;
+        let _lock = self.lock.lock().unwrap();
+        if dir.join(UIDS).exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(&dir)?;
+        let validity = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(1);
+        fs::write(dir.join(UIDS), format!("{} 1\n", validity.max(1)))
+    }
+
+    pub fn exists(&self, user: &str, mailbox: &str) -> bool {
+        self.dir(user, mailbox).is_ok_and(|dir| dir.join(UIDS).exists())
+    }
+
+    /// Adds a message to a mailbox, creating it if needed, and returns its UID.
+    pub fn deliver(&self, user: &str, mailbox: &str, content: &[u8]) -> Result<u32> {
+        self.create(user, mailbox)?;
+        let dir = self.dir(user, mailbox)?;
+        let _lock = self.lock.lock().unwrap();
+        let (validity, uid) = MailboxStore::read_uids(&dir)?;
+        // Written aside and renamed, so readers never see half a message.
+        let temp = dir.join(format!(".{}.tmp", uid));
+        fs::write(&temp, content)?;
+        fs::rename(&temp, dir.join(format!("{}.eml", uid)))?;
+        fs::write(dir.join(UIDS), format!("{} {}\n", validity, uid + 1))?;
+        Ok(uid)
+    }
+
+    /// The user's mailboxes, `INBOX` first and the rest in name order.
+    pub fn mailboxes(&self, user: &str) -> Result<Vec<String>> {
+        fn walk(dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !entry.file_type()?.is_dir() || !plain(&name) {
+                    continue;
+                }
+                let full = format!("{}{}", prefix, name);
+                if entry.path().join(UIDS).exists() {
+                    names.push(full.clone());
+                }
+                walk(&entry.path(), &format!("{}{}", full, DELIMITER), names)?;
+            }
+            Ok(())
+        }
+        let dir = self.user_dir(user)?;
+        let mut names = Vec::new();
+        match walk(&dir, "", &mut names) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            result => result?,
+        }
+        names.sort_by_key(|name| (name != INBOX, name.clone()));
+        Ok(names)
+    }
+
+    /// The mailbox's UID validity and the UID its next message will get.
+    pub fn uids(&self, user: &str, mailbox: &str) -> Result<(u32, u32)> {
+        MailboxStore::read_uids(&self.dir(user, mailbox)?)
+    }
+
+    /// The messages in a mailbox, in UID order.
+    pub fn list(&self, user: &str, mailbox: &str) -> Result<Vec<Entry>> {
+        let dir = self.dir(user, mailbox)?;
+        MailboxStore::read_uids(&dir)?;
+        let mut entries = Vec::new();
+        for file in fs::read_dir(&dir)? {
+            let path = file?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("eml") {
+                continue;
+            }
+            let uid = match path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                Some(uid) => uid,
+                None => continue,
+            };
+            // It may have been removed by another session meanwhile.
+            let meta = match fs::metadata(&path) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            entries.push(Entry {
+                uid,
+                size: meta.len(),
+                flags: read_flags(&dir, uid),
+                received: meta.modified().unwrap_or(UNIX_EPOCH),
+            });
+        }
+        entries.sort_by_key(|entry| entry.uid);
+        Ok(entries)
+    }
+
+    pub fn read(&self, user: &str, mailbox: &str, uid: u32) -> Result<Vec<u8>> {
+        fs::read(self.dir(user, mailbox)?.join(format!("{}.eml", uid)))
+    }
+
+    /// Removes a message; false if it was already gone.
+    pub fn remove(&self, user: &str, mailbox: &str, uid: u32) -> Result<bool> {
+        let dir = self.dir(user, mailbox)?;
+        let _lock = self.lock.lock().unwrap();
+        let _ = fs::remove_file(dir.join(format!("{}.flags", uid)));
+        match fs::remove_file(dir.join(format!("{}.eml", uid))) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the flags of a message.
+    pub fn set_flags(&self, user: &str, mailbox: &str, uid: u32, flags: &[String]) -> Result<()> {
+        let dir = self.dir(user, mailbox)?;
+        let _lock = self.lock.lock().unwrap();
+        if !dir.join(format!("{}.eml", uid)).exists() {
+            return Err(Error::new(ErrorKind::NotFound, format!("no message with UID {}", uid)));
+        }
+        let path = dir.join(format!("{}.flags", uid));
+        if flags.is_empty() {
+            return match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        fs::write(path, flags.join(" ") + "\n")
+    }
+}
+
+fn read_flags(dir: &Path, uid: u32) -> Vec<String> {
+    fs::read_to_string(dir.join(format!("{}.flags", uid)))
+        .map(|text| text.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
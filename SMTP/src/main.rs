@@ -1,5 +1,114 @@
-use smtp_server::server;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-fn main() -> std::io::Result<()> {
-    server::run_server()
+use clap::{Parser, Subcommand};
+use smtp_server::client::{self, Submission};
+use smtp_server::settings::{Server, Settings};
+
+#[derive(Parser)]
+#[command(name = "smtp_server", about = "An SMTP, LMTP, POP3 and IMAP server")]
+struct Cli {
+    /// TOML configuration file; without one, SMTP is served on port 2525.
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+    /// Validate the configuration and the files it names, then exit.
+    #[arg(long)]
+    check_config: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Send a test message.
+    Send {
+        /// Server to send to.
+        #[arg(short, long, default_value = "localhost:2525")]
+        server: String,
+        /// Envelope sender.
+        #[arg(short, long)]
+        from: String,
+        /// Recipient; may be repeated.
+        #[arg(short, long, required = true)]
+        to: Vec<String>,
+        #[arg(long, default_value = "Test message")]
+        subject: String,
+        #[arg(long, default_value = "This is a test message.")]
+        body: String,
+        /// Send this message file as is instead, `-` for standard input.
+        #[arg(long, conflicts_with_all = ["subject", "body"])]
+        file: Option<PathBuf>,
+        /// Authenticate with AUTH PLAIN as this user.
+        #[arg(short, long, requires = "password")]
+        user: Option<String>,
+        #[arg(short, long, requires = "user")]
+        password: Option<String>,
+        #[arg(long, default_value = "localhost")]
+        helo: String,
+    },
+}
+
+fn load(config: &Option<PathBuf>) -> io::Result<Settings> {
+    match config {
+        Some(path) => Settings::load(path),
+        None => Ok(Settings::default()),
+    }
+}
+
+fn send(command: Command) -> io::Result<String> {
+    let Command::Send { server, from, to, subject, body, file, user, password, helo } = command;
+    let content = match file {
+        Some(path) if path.as_os_str() == "-" => {
+            let mut content = Vec::new();
+            io::stdin().read_to_end(&mut content)?;
+            content
+        }
+        Some(path) => std::fs::read(path)?,
+        None => client::compose(&from, &to, &subject, &body),
+    };
+    let submission = Submission { helo, from, to, content, auth: user.zip(password) };
+    client::send(server.as_str(), &submission)
+}
+
+/// Serves until killed, reloading the configuration on SIGHUP.
+fn run(config: &Option<PathBuf>) -> io::Result<()> {
+    let settings = load(config)?;
+    #[allow(unused_mut)]
+    let mut server = Server::start(&settings)?;
+
+    #[cfg(unix)]
+    {
+        use signal_hook::consts::SIGHUP;
+        use signal_hook::iterator::Signals;
+
+        for _ in Signals::new([SIGHUP])?.forever() {
+            match load(config).and_then(|settings| server.reload(&settings)) {
+                Ok(()) => println!("[INFO] Reloaded configuration"),
+                Err(e) => println!("[ERROR] Keeping previous configuration: {}", e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    loop {
+        std::thread::park();
+    }
+    #[allow(unreachable_code)]
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Some(command) => send(command).map(|reply| println!("{}", reply)),
+        None if cli.check_config => load(&cli.config).and_then(|settings| settings.check()).map(|()| println!("Configuration OK")),
+        None => run(&cli.config),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("smtp_server: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
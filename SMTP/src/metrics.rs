@@ -0,0 +1,195 @@
+//! Counters and histograms about the server, in Prometheus text format.
+//!
+//! With the `metrics` feature, `spawn` serves them on `GET /metrics`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Bucket bounds, in bytes, for message sizes.
+const SIZE_BUCKETS: [f64; 8] = [1e3, 1e4, 1e5, 1e6, 5e6, 1e7, 2.5e7, 5e7];
+/// Bucket bounds, in seconds, for session durations.
+const SESSION_BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+/// Bucket bounds, in seconds, for message handler latency.
+const LATENCY_BUCKETS: [f64; 9] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+/// Commands that get their own label; anything else counts as `UNKNOWN`
+/// so clients cannot grow the label set.
+const COMMANDS: [&str; 16] = [
+    "CONNECT", "EHLO", "HELO", "LHLO", "MAIL", "RCPT", "DATA", "BDAT", "RSET", "NOOP", "QUIT", "VRFY", "EXPN", "HELP",
+    "AUTH", "STARTTLS",
+];
+
+/// The label for the command on `line`: its verb if it is one this server
+/// knows, else `UNKNOWN`.
+pub fn command_name(line: &str) -> &'static str {
+    let verb = line.split([' ', ':']).next().unwrap_or_default();
+    COMMANDS
+        .iter()
+        .find(|command| command.eq_ignore_ascii_case(verb))
+        .copied()
+        .unwrap_or("UNKNOWN")
+}
+
+/// A cumulative histogram over fixed buckets.
+pub struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Default)]
+struct HistogramState {
+    /// Observations per bucket, not cumulative; the last is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        let state = HistogramState { counts: vec![0; bounds.len() + 1], sum: 0.0 };
+        Histogram { bounds, state: Mutex::new(state) }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        let mut state = self.state.lock().unwrap();
+        state.counts[bucket] += 1;
+        state.sum += value;
+    }
+
+    /// How many values have been observed.
+    pub fn count(&self) -> u64 {
+        self.state.lock().unwrap().counts.iter().sum()
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let state = self.state.lock().unwrap();
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        let mut cumulative = 0;
+        for (i, count) in state.counts.iter().enumerate() {
+            cumulative += count;
+            let le = self.bounds.get(i).map(|bound| bound.to_string()).unwrap_or_else(|| "+Inf".to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, state.sum, name, cumulative);
+    }
+}
+
+/// Everything the server counts, shared by all its connections.
+pub struct Metrics {
+    connections: AtomicU64,
+    active_connections: AtomicI64,
+    /// Replies sent, by command and reply code.
+    replies: Mutex<BTreeMap<(&'static str, String), u64>>,
+    /// Messages by outcome: accepted, quarantined, rejected, deferred or discarded.
+    messages: Mutex<BTreeMap<&'static str, u64>>,
+    pub message_size: Histogram,
+    pub session_duration: Histogram,
+    pub handler_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            connections: AtomicU64::new(0),
+            active_connections: AtomicI64::new(0),
+            replies: Mutex::new(BTreeMap::new()),
+            messages: Mutex::new(BTreeMap::new()),
+            message_size: Histogram::new(&SIZE_BUCKETS),
+            session_duration: Histogram::new(&SESSION_BUCKETS),
+            handler_latency: Histogram::new(&LATENCY_BUCKETS),
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self, duration: Duration) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+        self.session_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Counts a reply to `command`; its code is the first three characters.
+    pub fn reply(&self, command: &'static str, reply: &str) {
+        let code = reply.get(..3).unwrap_or(reply).to_string();
+        *self.replies.lock().unwrap().entry((command, code)).or_default() += 1;
+    }
+
+    /// Counts a message with the given outcome.
+    pub fn message(&self, outcome: &'static str) {
+        *self.messages.lock().unwrap().entry(outcome).or_default() += 1;
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// How many `code` replies `command` has had.
+    pub fn replies(&self, command: &str, code: &str) -> u64 {
+        let replies = self.replies.lock().unwrap();
+        replies.iter().filter(|((c, r), _)| *c == command && r == code).map(|(_, n)| n).sum()
+    }
+
+    /// How many messages had the given outcome.
+    pub fn messages(&self, outcome: &str) -> u64 {
+        self.messages.lock().unwrap().get(outcome).copied().unwrap_or(0)
+    }
+
+    /// Everything, in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP smtp_connections_total Connections accepted.\n# TYPE smtp_connections_total counter\nsmtp_connections_total {}",
+            self.connections()
+        );
+        let _ = writeln!(
+            out,
+            "# HELP smtp_connections_active Connections open now.\n# TYPE smtp_connections_active gauge\nsmtp_connections_active {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+        out += "# HELP smtp_replies_total Replies sent, by command and reply code.\n# TYPE smtp_replies_total counter\n";
+        for ((command, code), count) in self.replies.lock().unwrap().iter() {
+            let _ = writeln!(out, "smtp_replies_total{{command=\"{}\",code=\"{}\"}} {}", command, code, count);
+        }
+        out += "# HELP smtp_messages_total Messages received, by outcome.\n# TYPE smtp_messages_total counter\n";
+        for (outcome, count) in self.messages.lock().unwrap().iter() {
+            let _ = writeln!(out, "smtp_messages_total{{outcome=\"{}\"}} {}", outcome, count);
+        }
+        self.message_size.render(&mut out, "smtp_message_size_bytes", "Size of received messages.");
+        self.session_duration.render(&mut out, "smtp_session_duration_seconds", "Length of SMTP sessions.");
+        self.handler_latency.render(&mut out, "smtp_handler_duration_seconds", "Time spent in the message handler.");
+        out
+    }
+}
+
+/// Serves `metrics` on `GET /metrics` at `addr` in a background thread and
+/// returns the bound address.
+#[cfg(feature = "metrics")]
+pub fn spawn(
+    addr: impl std::net::ToSocketAddrs,
+    metrics: std::sync::Arc<Metrics>,
+) -> std::io::Result<std::net::SocketAddr> {
+    use crate::http::{self, Response};
+
+    let listener = std::net::TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    println!("Metrics listening on {}", local);
+    std::thread::spawn(move || {
+        http::serve(listener, move |request| match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/metrics") => Response::text(200, "text/plain; version=0.0.4", &metrics.render()),
+            _ => Response::error(404, "not found"),
+        })
+    });
+    Ok(local)
+}
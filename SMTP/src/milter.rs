@@ -0,0 +1,421 @@
+//! The MTA side of the Sendmail milter protocol (version 6), which lets an
+//! external filter inspect each stage of a session and accept, reject or
+//! modify the message.
+//!
+//! Every packet is a 32-bit big-endian length, a command byte and its data.
+//! Strings are NUL terminated.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::headers::{Header, Headers};
+use crate::stream::Stream;
+
+pub const VERSION: u32 = 6;
+
+// Commands from the MTA.
+pub const SMFIC_ABORT: u8 = b'A';
+pub const SMFIC_BODY: u8 = b'B';
+pub const SMFIC_CONNECT: u8 = b'C';
+pub const SMFIC_BODYEOB: u8 = b'E';
+pub const SMFIC_HELO: u8 = b'H';
+pub const SMFIC_HEADER: u8 = b'L';
+pub const SMFIC_MAIL: u8 = b'M';
+pub const SMFIC_EOH: u8 = b'N';
+pub const SMFIC_OPTNEG: u8 = b'O';
+pub const SMFIC_QUIT: u8 = b'Q';
+pub const SMFIC_RCPT: u8 = b'R';
+
+// Replies from the filter.
+pub const SMFIR_ADDHEADER: u8 = b'h';
+pub const SMFIR_CHGHEADER: u8 = b'm';
+pub const SMFIR_INSHEADER: u8 = b'i';
+pub const SMFIR_REPLBODY: u8 = b'b';
+pub const SMFIR_ACCEPT: u8 = b'a';
+pub const SMFIR_CONTINUE: u8 = b'c';
+pub const SMFIR_DISCARD: u8 = b'd';
+pub const SMFIR_PROGRESS: u8 = b'p';
+pub const SMFIR_QUARANTINE: u8 = b'q';
+pub const SMFIR_REJECT: u8 = b'r';
+pub const SMFIR_TEMPFAIL: u8 = b't';
+pub const SMFIR_REPLYCODE: u8 = b'y';
+
+// Modifications a filter may make (the actions of option negotiation).
+pub const SMFIF_ADDHDRS: u32 = 0x01;
+pub const SMFIF_CHGBODY: u32 = 0x02;
+pub const SMFIF_CHGHDRS: u32 = 0x10;
+pub const SMFIF_QUARANTINE: u32 = 0x20;
+
+// Stages a filter may ask to skip (the protocol of option negotiation).
+pub const SMFIP_NOCONNECT: u32 = 0x01;
+pub const SMFIP_NOHELO: u32 = 0x02;
+pub const SMFIP_NOMAIL: u32 = 0x04;
+pub const SMFIP_NORCPT: u32 = 0x08;
+pub const SMFIP_NOBODY: u32 = 0x10;
+pub const SMFIP_NOHDRS: u32 = 0x20;
+pub const SMFIP_NOEOH: u32 = 0x40;
+
+/// Modifications this server can apply.
+const ACTIONS: u32 = SMFIF_ADDHDRS | SMFIF_CHGBODY | SMFIF_CHGHDRS | SMFIF_QUARANTINE;
+/// Stages a filter may skip.
+const PROTOCOL: u32 = SMFIP_NOCONNECT | SMFIP_NOHELO | SMFIP_NOMAIL | SMFIP_NORCPT | SMFIP_NOBODY | SMFIP_NOHDRS | SMFIP_NOEOH;
+/// Largest body chunk sent at once.
+const MAX_BODY_CHUNK: usize = 65535;
+/// Largest packet accepted from a filter.
+const MAX_PACKET: usize = 1 << 24;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("milter: {}", msg))
+}
+
+/// Writes one packet.
+pub fn write_packet(stream: &mut impl Write, command: u8, data: &[u8]) -> Result<()> {
+    let mut packet = Vec::with_capacity(5 + data.len());
+    packet.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+    packet.push(command);
+    packet.extend_from_slice(data);
+    stream.write_all(&packet)
+}
+
+/// Reads one packet: its command byte and data.
+pub fn read_packet(stream: &mut impl Read) -> Result<(u8, Vec<u8>)> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_PACKET {
+        return Err(invalid("bad packet length"));
+    }
+    let mut packet = vec![0; len];
+    stream.read_exact(&mut packet)?;
+    let data = packet.split_off(1);
+    Ok((packet[0], data))
+}
+
+/// Splits packet data into its NUL terminated strings.
+pub fn strings(data: &[u8]) -> Vec<String> {
+    let data = data.strip_suffix(b"\0").unwrap_or(data);
+    data.split(|&b| b == 0).map(|s| String::from_utf8_lossy(s).into_owned()).collect()
+}
+
+/// Packs `strings` NUL terminated.
+pub fn pack(strings: &[&str]) -> Vec<u8> {
+    strings.iter().flat_map(|s| s.bytes().chain([0])).collect()
+}
+
+/// Where a filter listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MilterAddr {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A filter to consult for every session.
+#[derive(Debug, Clone)]
+pub struct Milter {
+    pub addr: MilterAddr,
+    /// How long to wait for each reply from the filter.
+    pub timeout: Duration,
+    /// When the filter cannot be reached or misbehaves, carry on without it
+    /// instead of deferring the mail with a 451.
+    pub fail_open: bool,
+}
+
+impl Milter {
+    pub fn new(addr: MilterAddr) -> Milter {
+        Milter { addr, timeout: Duration::from_secs(30), fail_open: false }
+    }
+
+    /// Connects to the filter and negotiates options.
+    pub fn connect(&self) -> Result<MilterClient> {
+        let stream = match &self.addr {
+            MilterAddr::Tcp(addr) => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                Stream::Tcp(stream)
+            }
+            #[cfg(unix)]
+            MilterAddr::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                Stream::Unix(stream)
+            }
+        };
+        let mut client = MilterClient { stream, actions: 0, protocol: 0, skip_session: false, skip_message: false, discard: false };
+        let mut data = VERSION.to_be_bytes().to_vec();
+        data.extend_from_slice(&ACTIONS.to_be_bytes());
+        data.extend_from_slice(&PROTOCOL.to_be_bytes());
+        write_packet(&mut client.stream, SMFIC_OPTNEG, &data)?;
+        let (command, data) = read_packet(&mut client.stream)?;
+        if command != SMFIC_OPTNEG || data.len() < 12 {
+            return Err(invalid("bad option negotiation"));
+        }
+        let word = |at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        if word(0) < 2 {
+            return Err(invalid("unsupported protocol version"));
+        }
+        client.actions = word(4) & ACTIONS;
+        client.protocol = word(8) & PROTOCOL;
+        Ok(client)
+    }
+}
+
+/// What the filter said about a stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Continue,
+    /// Accept without consulting the filter again for this message.
+    Accept,
+    /// Refuse, with the filter's own SMTP reply if it gave one.
+    Reject(Option<String>),
+    TempFail(Option<String>),
+    /// Accept the message but throw it away.
+    Discard,
+}
+
+/// A change the filter asked for at the end of the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Modification {
+    AddHeader(String, String),
+    /// Changes the `index`th (from 1) field called `name`; an empty value
+    /// removes it.
+    ChangeHeader(u32, String, String),
+    /// Inserts a field at `index` (from 0) among all the fields.
+    InsertHeader(u32, String, String),
+    /// A chunk of the new body; successive chunks are appended.
+    ReplaceBody(Vec<u8>),
+    Quarantine(String),
+}
+
+/// A connection to a filter for one SMTP session.
+pub struct MilterClient {
+    stream: Stream,
+    /// Modifications the filter may make.
+    pub actions: u32,
+    /// Stages the filter asked to skip.
+    pub protocol: u32,
+    /// The filter accepted the connection, so it hears no more of it.
+    skip_session: bool,
+    /// The filter accepted the current message, so it hears no more of it.
+    skip_message: bool,
+    /// The filter asked for the current message to be discarded.
+    discard: bool,
+}
+
+impl MilterClient {
+    fn skips(&self, stage: u32) -> bool {
+        self.protocol & stage != 0
+    }
+
+    /// Sends a stage and reads the filter's verdict on it.
+    fn stage(&mut self, flag: u32, command: u8, data: &[u8]) -> Result<Response> {
+        if self.skip_session || self.skip_message || self.discard || self.skips(flag) {
+            return Ok(Response::Continue);
+        }
+        write_packet(&mut self.stream, command, data)?;
+        let response = self.response(&mut Vec::new())?;
+        match response {
+            Response::Accept => self.skip_message = true,
+            Response::Discard => self.discard = true,
+            _ => {}
+        }
+        Ok(response)
+    }
+
+    /// Reads replies up to a verdict, collecting modifications on the way.
+    fn response(&mut self, modifications: &mut Vec<Modification>) -> Result<Response> {
+        loop {
+            let (command, data) = read_packet(&mut self.stream)?;
+            let fields = strings(&data);
+            let field = |i: usize| fields.get(i).cloned().unwrap_or_default();
+            let index = || data.get(..4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+            match command {
+                SMFIR_CONTINUE => return Ok(Response::Continue),
+                SMFIR_ACCEPT => return Ok(Response::Accept),
+                SMFIR_REJECT => return Ok(Response::Reject(None)),
+                SMFIR_TEMPFAIL => return Ok(Response::TempFail(None)),
+                SMFIR_DISCARD => return Ok(Response::Discard),
+                SMFIR_REPLYCODE => {
+                    let reply = field(0);
+                    // Three digits and a space or hyphen, like an SMTP reply line.
+                    let well_formed = match reply.as_bytes() {
+                        [a, b, c, d, ..] => {
+                            [a, b, c].iter().all(|x| x.is_ascii_digit()) && (*d == b' ' || *d == b'-')
+                        }
+                        _ => false,
+                    };
+                    return match reply.as_bytes().first() {
+                        Some(b'4') if well_formed => Ok(Response::TempFail(Some(reply))),
+                        Some(b'5') if well_formed => Ok(Response::Reject(Some(reply))),
+                        _ => Err(invalid("reply code must be a 4xx or 5xx reply")),
+                    };
+                }
+                SMFIR_PROGRESS => {}
+                SMFIR_ADDHEADER if self.actions & SMFIF_ADDHDRS != 0 => {
+                    modifications.push(Modification::AddHeader(field(0), field(1)));
+                }
+                SMFIR_INSHEADER | SMFIR_CHGHEADER => {
+                    let allowed = if command == SMFIR_INSHEADER { SMFIF_ADDHDRS } else { SMFIF_CHGHDRS };
+                    let index = index().ok_or_else(|| invalid("header index missing"))?;
+                    if self.actions & allowed != 0 {
+                        let fields = strings(&data[4..]);
+                        let name = fields.first().cloned().unwrap_or_default();
+                        let value = fields.get(1).cloned().unwrap_or_default();
+                        modifications.push(if command == SMFIR_INSHEADER {
+                            Modification::InsertHeader(index, name, value)
+                        } else {
+                            Modification::ChangeHeader(index, name, value)
+                        });
+                    }
+                }
+                SMFIR_REPLBODY if self.actions & SMFIF_CHGBODY != 0 => {
+                    modifications.push(Modification::ReplaceBody(data));
+                }
+                SMFIR_QUARANTINE if self.actions & SMFIF_QUARANTINE != 0 => {
+                    modifications.push(Modification::Quarantine(field(0)));
+                }
+                SMFIR_ADDHEADER | SMFIR_REPLBODY | SMFIR_QUARANTINE => {
+                    return Err(invalid("modification not negotiated"));
+                }
+                other => return Err(invalid(&format!("unexpected reply {:?}", other as char))),
+            }
+        }
+    }
+
+    /// The client connected from `addr`, whose name is `hostname`. Clients
+    /// on a Unix domain socket have no address.
+    pub fn connect(&mut self, hostname: &str, addr: Option<SocketAddr>) -> Result<Response> {
+        let mut data = pack(&[hostname]);
+        match addr {
+            Some(addr) => {
+                data.push(if addr.is_ipv4() { b'4' } else { b'6' });
+                data.extend_from_slice(&addr.port().to_be_bytes());
+                data.extend_from_slice(&pack(&[&addr.ip().to_string()]));
+            }
+            None => data.push(b'U'),
+        }
+        self.session_stage(SMFIP_NOCONNECT, SMFIC_CONNECT, &data)
+    }
+
+    pub fn helo(&mut self, domain: &str) -> Result<Response> {
+        self.session_stage(SMFIP_NOHELO, SMFIC_HELO, &pack(&[domain]))
+    }
+
+    /// A stage about the connection rather than a message, where accepting
+    /// means the rest of the session.
+    fn session_stage(&mut self, flag: u32, command: u8, data: &[u8]) -> Result<Response> {
+        let response = self.stage(flag, command, data)?;
+        self.skip_message = false;
+        self.discard = false;
+        match response {
+            Response::Accept => self.skip_session = true,
+            Response::Discard => return Err(invalid("discard is for messages")),
+            _ => {}
+        }
+        Ok(response)
+    }
+
+    /// `args` are the path in angle brackets followed by the ESMTP parameters.
+    pub fn mail(&mut self, args: &[&str]) -> Result<Response> {
+        self.stage(SMFIP_NOMAIL, SMFIC_MAIL, &pack(args))
+    }
+
+    pub fn rcpt(&mut self, args: &[&str]) -> Result<Response> {
+        self.stage(SMFIP_NORCPT, SMFIC_RCPT, &pack(args))
+    }
+
+    /// Sends the headers, body and end of message, and returns the verdict
+    /// with the modifications to make.
+    pub fn message(&mut self, headers: &Headers, body: &[u8]) -> Result<(Response, Vec<Modification>)> {
+        let outcome = self.end_of_message(headers, body);
+        self.skip_message = false;
+        self.discard = false;
+        outcome
+    }
+
+    fn end_of_message(&mut self, headers: &Headers, body: &[u8]) -> Result<(Response, Vec<Modification>)> {
+        if self.discard {
+            return Ok((Response::Discard, Vec::new()));
+        }
+        if self.skip_session || self.skip_message {
+            return Ok((Response::Accept, Vec::new()));
+        }
+        for header in headers.iter() {
+            let response = self.stage(SMFIP_NOHDRS, SMFIC_HEADER, &pack(&[header.name.trim_end(), header.value.trim_start()]))?;
+            if response != Response::Continue {
+                return Ok((response, Vec::new()));
+            }
+        }
+        let response = self.stage(SMFIP_NOEOH, SMFIC_EOH, &[])?;
+        if response != Response::Continue {
+            return Ok((response, Vec::new()));
+        }
+        for chunk in body.chunks(MAX_BODY_CHUNK) {
+            let response = self.stage(SMFIP_NOBODY, SMFIC_BODY, chunk)?;
+            if response != Response::Continue {
+                return Ok((response, Vec::new()));
+            }
+        }
+        write_packet(&mut self.stream, SMFIC_BODYEOB, &[])?;
+        let mut modifications = Vec::new();
+        let response = self.response(&mut modifications)?;
+        Ok((response, modifications))
+    }
+
+    /// Abandons the current message, e.g. after RSET.
+    pub fn abort(&mut self) -> Result<()> {
+        self.skip_message = false;
+        self.discard = false;
+        write_packet(&mut self.stream, SMFIC_ABORT, &[])
+    }
+
+    /// Ends the session with the filter.
+    pub fn quit(mut self) -> Result<()> {
+        write_packet(&mut self.stream, SMFIC_QUIT, &[])
+    }
+}
+
+/// Applies header and body `modifications` to a message. Returns the
+/// quarantine reason if the filter asked for one.
+pub fn apply(modifications: Vec<Modification>, headers: &mut Headers, body: &mut Vec<u8>) -> Option<String> {
+    // Filters send folded values with bare LF.
+    let value = |value: &str| value.replace("\r\n", "\n").replace('\n', "\r\n");
+    let mut new_body: Option<Vec<u8>> = None;
+    let mut quarantine = None;
+    for modification in modifications {
+        match modification {
+            Modification::AddHeader(name, v) => headers.push(&name, &value(&v)),
+            Modification::InsertHeader(index, name, v) => {
+                let index = (index as usize).min(headers.fields.len());
+                headers.fields.insert(index, Header::new(&name, &value(&v)));
+            }
+            Modification::ChangeHeader(index, name, v) => {
+                let position = headers
+                    .fields
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, header)| header.is(&name))
+                    .nth((index as usize).saturating_sub(1))
+                    .map(|(i, _)| i);
+                match (position, v.is_empty()) {
+                    (Some(i), true) => {
+                        headers.fields.remove(i);
+                    }
+                    (Some(i), false) => headers.fields[i] = Header::new(&name, &value(&v)),
+                    (None, false) => headers.push(&name, &value(&v)),
+                    (None, true) => {}
+                }
+            }
+            Modification::ReplaceBody(chunk) => new_body.get_or_insert_with(Vec::new).extend_from_slice(&chunk),
+            Modification::Quarantine(reason) => quarantine = Some(reason),
+        }
+    }
+    if let Some(new_body) = new_body {
+        *body = new_body;
+    }
+    quarantine
+}
@@ -0,0 +1,285 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::headers::{self, Headers};
+
+/// One MIME entity: the whole message, or a part of a multipart body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub headers: Headers,
+    /// Lower case `type/subtype`, `text/plain` when not given.
+    pub content_type: String,
+    /// Parameters of the Content-Type header, names lower cased.
+    pub params: Vec<(String, String)>,
+    /// The body with its Content-Transfer-Encoding undone. Empty for multiparts.
+    pub body: Vec<u8>,
+    /// The body as it appeared in the message.
+    pub raw_body: String,
+    pub parts: Vec<Part>,
+}
+
+/// Splits a structured header value such as
+/// `multipart/mixed; boundary="abc"` into its value and parameters.
+pub fn parse_params(value: &str) -> (String, Vec<(String, String)>) {
+    let mut pieces = split_unquoted(value, ';').into_iter();
+    let main = pieces.next().unwrap_or_default().trim().to_string();
+    let params = pieces
+        .filter_map(|p| {
+            let (name, value) = p.split_once('=')?;
+            let value = value.trim();
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+                None => value.to_string(),
+            };
+            Some((name.trim().to_ascii_lowercase(), value))
+        })
+        .collect();
+    (main, params)
+}
+
+fn split_unquoted(value: &str, separator: char) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && quoted {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            pieces.push(std::mem::take(&mut current));
+            continue;
+        }
+        current.push(c);
+    }
+    pieces.push(current);
+    pieces
+}
+
+/// Parses message content (headers, empty line, body).
+pub fn parse_message(content: &str) -> Part {
+    let (raw_headers, body) = headers::split_message(content);
+    parse_part(Headers::parse(raw_headers), body, "text/plain", 0)
+}
+
+/// Parses an entity whose headers have already been split off.
+pub fn parse_entity(headers: &Headers, body: &str) -> Part {
+    parse_part(headers.clone(), body, "text/plain", 0)
+}
+
+/// Nesting limit for multiparts, against hostile messages.
+const MAX_DEPTH: usize = 16;
+
+fn parse_part(headers: Headers, body: &str, default_type: &str, depth: usize) -> Part {
+    let (content_type, params) = match headers.get("Content-Type") {
+        Some(value) => {
+            let (main, params) = parse_params(&value);
+            (main.to_ascii_lowercase(), params)
+        }
+        None => (default_type.to_string(), Vec::new()),
+    };
+    let mut part = Part {
+        headers,
+        content_type,
+        params,
+        body: Vec::new(),
+        raw_body: body.to_string(),
+        parts: Vec::new(),
+    };
+
+    let boundary = part.param("boundary").map(|b| b.to_string());
+    if let (true, Some(boundary)) = (part.content_type.starts_with("multipart/"), boundary) {
+        if depth < MAX_DEPTH {
+            let child_type = if part.content_type == "multipart/digest" { "message/rfc822" } else { "text/plain" };
+            for chunk in split_multipart(body, &boundary) {
+                let (raw_headers, child_body) = headers::split_message(chunk);
+                part.parts.push(parse_part(Headers::parse(raw_headers), child_body, child_type, depth + 1));
+            }
+            return part;
+        }
+    }
+
+    let encoding = part
+        .headers
+        .get("Content-Transfer-Encoding")
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    part.body = match encoding.as_str() {
+        "base64" => decode_base64(body),
+        "quoted-printable" => decode_quoted_printable(body),
+        _ => body.as_bytes().to_vec(),
+    };
+    part
+}
+
+/// The bodies between `--boundary` delimiter lines, preamble and epilogue dropped.
+fn split_multipart<'a>(body: &'a str, boundary: &str) -> Vec<&'a str> {
+    let delimiter = format!("--{}", boundary);
+    let mut chunks = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut pos = 0;
+    for line in body.split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        let closing = trimmed.strip_suffix("--") == Some(delimiter.as_str());
+        if trimmed == delimiter || closing {
+            if let Some(s) = start {
+                // The line break before the delimiter belongs to the delimiter.
+                let chunk = &body[s..pos];
+                chunks.push(chunk.strip_suffix("\r\n").or_else(|| chunk.strip_suffix('\n')).unwrap_or(chunk));
+            }
+            if closing {
+                return chunks;
+            }
+            start = Some(pos + line.len());
+        }
+        pos += line.len();
+    }
+    if let Some(s) = start {
+        chunks.push(&body[s.min(body.len())..]);
+    }
+    chunks
+}
+
+pub fn decode_base64(body: &str) -> Vec<u8> {
+    let cleaned: String = body.chars().filter(|c| c.is_ascii_alphanumeric() || "+/=".contains(*c)).collect();
+    let cleaned = cleaned.trim_end_matches('=');
+    let padded = format!("{}{}", cleaned, "=".repeat((4 - cleaned.len() % 4) % 4));
+    BASE64.decode(padded).unwrap_or_default()
+}
+
+pub fn decode_quoted_printable(body: &str) -> Vec<u8> {
+    let bytes = body.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'=' {
+            // Soft line break.
+            if bytes[i + 1..].starts_with(b"\r\n") {
+                i += 3;
+                continue;
+            }
+            if bytes[i + 1..].starts_with(b"\n") {
+                i += 2;
+                continue;
+            }
+            let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+            if let Some(value) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(value);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+/// Converts `bytes` in `charset` to a string; unknown charsets are read as UTF-8.
+pub fn decode_charset(bytes: &[u8], charset: &str) -> String {
+    match charset.to_ascii_lowercase().as_str() {
+        "iso-8859-1" | "latin1" | "latin-1" | "windows-1252" => bytes.iter().map(|&b| b as char).collect(),
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Decodes RFC 2047 encoded-words (`=?utf-8?B?...?=`) in a header value.
+pub fn decode_encoded_words(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    let mut last_was_word = false;
+    while let Some(start) = rest.find("=?") {
+        let decoded = rest[start + 2..].find("?=").and_then(|len| {
+            let word = &rest[start + 2..start + 2 + len + 2];
+            // charset?encoding?text?= ; the text itself may not contain "?=".
+            let mut fields = word[..word.len() - 2].splitn(3, '?');
+            let charset = fields.next()?;
+            let encoding = fields.next()?;
+            let text = fields.next()?;
+            let bytes = match encoding.to_ascii_lowercase().as_str() {
+                "b" => decode_base64(text),
+                "q" => decode_quoted_printable(&text.replace('_', " ")),
+                _ => return None,
+            };
+            let charset = charset.split('*').next().unwrap_or(charset);
+            Some((decode_charset(&bytes, charset), start + 2 + len + 2))
+        });
+        match decoded {
+            Some((text, end)) => {
+                let between = &rest[..start];
+                // Whitespace between adjacent encoded-words is dropped.
+                if !(last_was_word && between.trim().is_empty()) {
+                    out += between;
+                }
+                out += &text;
+                rest = &rest[end..];
+                last_was_word = true;
+            }
+            None => {
+                out += &rest[..start + 2];
+                rest = &rest[start + 2..];
+                last_was_word = false;
+            }
+        }
+    }
+    out + rest
+}
+
+impl Part {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn charset(&self) -> &str {
+        self.param("charset").unwrap_or("us-ascii")
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.content_type.starts_with("multipart/")
+    }
+
+    /// The attachment file name, from Content-Disposition or the `name` parameter.
+    pub fn filename(&self) -> Option<String> {
+        self.headers
+            .get("Content-Disposition")
+            .and_then(|d| parse_params(&d).1.into_iter().find(|(n, _)| n == "filename").map(|(_, v)| v))
+            .or_else(|| self.param("name").map(|n| n.to_string()))
+            .map(|n| decode_encoded_words(&n))
+    }
+
+    /// True for parts meant to be saved rather than displayed.
+    pub fn is_attachment(&self) -> bool {
+        let disposition = self.headers.get("Content-Disposition").unwrap_or_default().to_ascii_lowercase();
+        disposition.starts_with("attachment")
+            || (!self.is_multipart() && !self.content_type.starts_with("text/") && self.filename().is_some())
+    }
+
+    /// The decoded body as text, for `text/*` parts.
+    pub fn text(&self) -> Option<String> {
+        if self.content_type.starts_with("text/") {
+            Some(decode_charset(&self.body, self.charset()))
+        } else {
+            None
+        }
+    }
+
+    /// All non-multipart parts, depth first.
+    pub fn leaves(&self) -> Vec<&Part> {
+        if self.is_multipart() {
+            self.parts.iter().flat_map(|p| p.leaves()).collect()
+        } else {
+            vec![self]
+        }
+    }
+
+    /// The first non-attachment part of the given type, e.g. `text/html`.
+    pub fn find_text(&self, content_type: &str) -> Option<String> {
+        self.leaves()
+            .into_iter()
+            .find(|p| p.content_type == content_type && !p.is_attachment())
+            .and_then(|p| p.text())
+    }
+}
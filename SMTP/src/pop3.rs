@@ -0,0 +1,439 @@
+//! A POP3 server (RFC 1939) over the mailbox store, so that mail clients
+//! can download what the SMTP side received. Each user's maildrop is their
+//! `INBOX`.
+//!
+//! Supported: USER/PASS and APOP logins, STAT, LIST, RETR, DELE, NOOP,
+//! RSET, TOP, UIDL and QUIT, with CAPA (RFC 2449) and STLS (RFC 2595).
+
+use std::collections::HashSet;
+use std::fmt::Arguments;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustls::ServerConfig;
+
+use crate::auth::{self, Credentials};
+use crate::headers;
+use crate::mailbox::{Entry, MailboxStore, INBOX};
+use crate::stream::Stream;
+
+/// Longest command line accepted, well over the 255 octets RFC 2449 allows.
+const MAX_LINE: usize = 1024;
+
+/// Settings shared by every connection of a POP3 server.
+pub struct Config {
+    /// Name this server uses for itself in its greeting.
+    pub hostname: String,
+    /// Where the maildrops are.
+    pub mailboxes: Arc<MailboxStore>,
+    /// Who may log in; user names are the addresses mail was delivered to.
+    pub credentials: Arc<dyn Credentials>,
+    /// When set, clients can switch to TLS with STLS.
+    pub tls: Option<Arc<ServerConfig>>,
+}
+
+/// The maildrop of a logged-in user, as it was at login.
+struct Maildrop {
+    user: String,
+    uid_validity: u32,
+    entries: Vec<Entry>,
+    /// Marked by DELE, and removed at QUIT.
+    deleted: Vec<bool>,
+}
+
+impl Maildrop {
+    /// The messages not marked as deleted, with their numbers.
+    fn live(&self) -> impl Iterator<Item = (usize, &Entry)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !self.deleted[*i])
+            .map(|(i, entry)| (i + 1, entry))
+    }
+
+    /// "N messages (M octets)" for the messages not marked as deleted.
+    fn summary(&self) -> (usize, u64) {
+        self.live().fold((0, 0), |(count, size), (_, entry)| (count + 1, size + entry.size))
+    }
+
+    /// The message numbered `arg`, which must not be marked as deleted.
+    fn get(&self, arg: Option<&str>) -> std::result::Result<(usize, &Entry), String> {
+        let arg = arg.ok_or_else(|| "-ERR Message number required".to_string())?;
+        let number: usize = arg.parse().map_err(|_| format!("-ERR Invalid message number {}", arg))?;
+        match self.entries.get(number.wrapping_sub(1)) {
+            Some(_) if self.deleted[number - 1] => Err(format!("-ERR Message {} already deleted", number)),
+            Some(entry) => Ok((number, entry)),
+            None => Err(format!("-ERR No such message {}", number)),
+        }
+    }
+}
+
+/// One POP3 session.
+struct Session {
+    stream: Stream,
+    id: usize,
+    buf: Vec<u8>,
+    config: Arc<Config>,
+    /// Users whose maildrop some session holds (RFC 1939 section 8).
+    locked: Arc<Mutex<HashSet<String>>>,
+    /// The `<...>` string of the greeting, which APOP digests include.
+    timestamp: String,
+    tls: bool,
+    /// The name given with USER, awaiting PASS.
+    user: Option<String>,
+    /// Set once logged in, in the TRANSACTION state.
+    maildrop: Option<Maildrop>,
+}
+
+impl Session {
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        self.stream.write_all(format!("{}\r\n", line).as_bytes())
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut buffer = [0; 1024];
+        loop {
+            if let Some(i) = self.buf.windows(2).position(|window| window == b"\r\n") {
+                let line: Vec<u8> = self.buf.drain(..i + 2).take(i).collect();
+                return String::from_utf8(line).map_err(|e| Error::new(ErrorKind::InvalidData, e));
+            }
+            if self.buf.len() > MAX_LINE {
+                return Err(Error::new(ErrorKind::InvalidData, "Command line too long"));
+            }
+            let n = self.stream.read(&mut buffer)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
+            }
+            self.buf.extend_from_slice(&buffer[..n]);
+        }
+    }
+
+    fn log_info(&self, msg: &str, args: Option<Arguments>) {
+        match args {
+            Some(arguments) => println!("[INFO] [pop3 {}:{}] {} {}", self.id, self.stream.peer(), msg, arguments),
+            None => println!("[INFO] [pop3 {}:{}] {}", self.id, self.stream.peer(), msg),
+        }
+    }
+
+    fn log_error(&self, e: Error) {
+        println!("[ERROR] [pop3 {}:{}] {}", self.id, self.stream.peer(), e);
+    }
+
+    fn handle(&mut self) {
+        if let Err(e) = self.session() {
+            if e.kind() != ErrorKind::UnexpectedEof {
+                self.log_error(e);
+            }
+        }
+        // A client that went away without QUIT leaves its messages alone.
+        if let Some(maildrop) = self.maildrop.take() {
+            self.locked.lock().unwrap().remove(&maildrop.user);
+        }
+    }
+
+    fn session(&mut self) -> Result<()> {
+        let greeting = format!("+OK POP3 {} ready {}", self.config.hostname, self.timestamp);
+        self.write_line(&greeting)?;
+        loop {
+            let line = self.read_line()?;
+            let (keyword, args) = line.split_once(' ').unwrap_or((&line, ""));
+            let keyword = keyword.to_ascii_uppercase();
+            if keyword == "QUIT" {
+                return self.quit();
+            }
+            let reply = match self.maildrop {
+                None => self.authorization(&keyword, args)?,
+                Some(_) => self.transaction(&keyword, args)?,
+            };
+            if let Some(reply) = reply {
+                self.write_line(&reply)?;
+            }
+        }
+    }
+
+    /// The capabilities for CAPA, which depend on the state.
+    fn capabilities(&self) -> Vec<&'static str> {
+        let mut capabilities = vec!["TOP", "UIDL", "RESP-CODES", "AUTH-RESP-CODE", "PIPELINING"];
+        if self.maildrop.is_none() {
+            capabilities.push("USER");
+            if self.config.tls.is_some() && !self.tls {
+                capabilities.push("STLS");
+            }
+        }
+        capabilities
+    }
+
+    fn write_capabilities(&mut self) -> Result<()> {
+        let mut reply = "+OK Capability list follows\r\n".to_string();
+        for capability in self.capabilities() {
+            reply += &format!("{}\r\n", capability);
+        }
+        reply += ".\r\n";
+        self.stream.write_all(reply.as_bytes())
+    }
+
+    /// Handles a command in the AUTHORIZATION state, returning the reply if
+    /// it has not been sent.
+    fn authorization(&mut self, keyword: &str, args: &str) -> Result<Option<String>> {
+        let user = self.user.take();
+        let reply = match keyword {
+            "CAPA" => {
+                self.user = user;
+                self.write_capabilities()?;
+                return Ok(None);
+            }
+            "USER" if args.is_empty() => "-ERR User name required".to_string(),
+            "USER" => {
+                self.user = Some(args.to_string());
+                format!("+OK {} selected", args)
+            }
+            "PASS" => match user {
+                None => "-ERR USER first".to_string(),
+                Some(user) if self.config.credentials.verify(&user, args) => self.login(&user),
+                Some(user) => self.refuse(&user),
+            },
+            "APOP" => {
+                let (user, digest) = args.split_once(' ').unwrap_or((args, ""));
+                let expected = self.config.credentials.password(user).map(|p| auth::apop_digest(&self.timestamp, &p));
+                match expected {
+                    Some(expected) if auth::same(expected.as_bytes(), digest.to_ascii_lowercase().as_bytes()) => {
+                        self.login(user)
+                    }
+                    _ => self.refuse(user),
+                }
+            }
+            "STLS" => return self.start_tls().map(|_| None),
+            "NOOP" | "STAT" | "LIST" | "RETR" | "DELE" | "RSET" | "TOP" | "UIDL" => {
+                "-ERR Command not valid in this state".to_string()
+            }
+            _ => "-ERR Unknown command".to_string(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn refuse(&self, user: &str) -> String {
+        self.log_info("Login failed for", Some(format_args!("{}", user)));
+        "-ERR [AUTH] Invalid user name or password".to_string()
+    }
+
+    /// Locks and opens the maildrop of a user whose password checked out.
+    fn login(&mut self, user: &str) -> String {
+        let user = user.to_lowercase();
+        if !self.locked.lock().unwrap().insert(user.clone()) {
+            return "-ERR [IN-USE] Maildrop already locked".to_string();
+        }
+        let mailboxes = &self.config.mailboxes;
+        let opened = if mailboxes.exists(&user, INBOX) {
+            mailboxes.uids(&user, INBOX).and_then(|(validity, _)| Ok((validity, mailboxes.list(&user, INBOX)?)))
+        } else {
+            // Nothing has been delivered yet.
+            Ok((0, Vec::new()))
+        };
+        let (uid_validity, entries) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                self.log_error(e);
+                self.locked.lock().unwrap().remove(&user);
+                return "-ERR [SYS/TEMP] Maildrop unavailable".to_string();
+            }
+        };
+        self.log_info("Logged in as", Some(format_args!("{}", user)));
+        let deleted = vec![false; entries.len()];
+        let maildrop = Maildrop { user, uid_validity, entries, deleted };
+        let (count, size) = maildrop.summary();
+        self.maildrop = Some(maildrop);
+        format!("+OK Maildrop has {} messages ({} octets)", count, size)
+    }
+
+    fn start_tls(&mut self) -> Result<()> {
+        let config = match &self.config.tls {
+            Some(config) if !self.tls => config.clone(),
+            _ => return self.write_line("-ERR STLS not available"),
+        };
+        self.write_line("+OK Begin TLS negotiation")?;
+        self.stream.start_tls(config)?;
+        // Anything sent in the clear after STLS could have been injected.
+        self.buf.clear();
+        self.tls = true;
+        Ok(())
+    }
+
+    /// Handles a command in the TRANSACTION state, returning the reply if it
+    /// has not been sent.
+    fn transaction(&mut self, keyword: &str, args: &str) -> Result<Option<String>> {
+        let maildrop = self.maildrop.as_mut().unwrap();
+        let mut args = args.split_whitespace();
+        let reply = match keyword {
+            "CAPA" => {
+                self.write_capabilities()?;
+                return Ok(None);
+            }
+            "STAT" => {
+                let (count, size) = maildrop.summary();
+                format!("+OK {} {}", count, size)
+            }
+            "NOOP" => "+OK".to_string(),
+            "RSET" => {
+                maildrop.deleted.iter_mut().for_each(|deleted| *deleted = false);
+                let (count, size) = maildrop.summary();
+                format!("+OK Maildrop has {} messages ({} octets)", count, size)
+            }
+            "DELE" => match maildrop.get(args.next()) {
+                Ok((number, _)) => {
+                    maildrop.deleted[number - 1] = true;
+                    format!("+OK Message {} deleted", number)
+                }
+                Err(reply) => reply,
+            },
+            "LIST" | "UIDL" => {
+                let validity = maildrop.uid_validity;
+                let column = |entry: &Entry| match keyword {
+                    "LIST" => entry.size.to_string(),
+                    _ => format!("{}.{}", validity, entry.uid),
+                };
+                if let Some(arg) = args.next() {
+                    match maildrop.get(Some(arg)) {
+                        Ok((number, entry)) => format!("+OK {} {}", number, column(entry)),
+                        Err(reply) => reply,
+                    }
+                } else {
+                    let mut reply = "+OK\r\n".to_string();
+                    for (number, entry) in maildrop.live() {
+                        reply += &format!("{} {}\r\n", number, column(entry));
+                    }
+                    reply += ".\r\n";
+                    self.stream.write_all(reply.as_bytes())?;
+                    return Ok(None);
+                }
+            }
+            "RETR" | "TOP" => {
+                let (_, entry) = match maildrop.get(args.next()) {
+                    Ok(found) => found,
+                    Err(reply) => return Ok(Some(reply)),
+                };
+                let lines = match (keyword, args.next().map(str::parse::<usize>)) {
+                    ("RETR", _) => None,
+                    (_, Some(Ok(lines))) => Some(lines),
+                    _ => return Ok(Some("-ERR Number of lines required".to_string())),
+                };
+                let uid = entry.uid;
+                match self.config.mailboxes.read(&maildrop.user, INBOX, uid) {
+                    Ok(content) => {
+                        let content = match lines {
+                            Some(lines) => top(&content, lines),
+                            None => content,
+                        };
+                        let mut reply = format!("+OK {} octets\r\n", content.len()).into_bytes();
+                        reply.extend_from_slice(&dot_stuff(&content));
+                        self.stream.write_all(&reply)?;
+                        return Ok(None);
+                    }
+                    Err(e) => {
+                        self.log_error(e);
+                        "-ERR [SYS/TEMP] Message unavailable".to_string()
+                    }
+                }
+            }
+            "USER" | "PASS" | "APOP" | "STLS" => "-ERR Command not valid in this state".to_string(),
+            _ => "-ERR Unknown command".to_string(),
+        };
+        Ok(Some(reply))
+    }
+
+    /// QUIT: in the TRANSACTION state, first removes the messages marked as
+    /// deleted (the UPDATE state).
+    fn quit(&mut self) -> Result<()> {
+        let maildrop = match self.maildrop.take() {
+            Some(maildrop) => maildrop,
+            None => return self.write_line(&format!("+OK {} POP3 server signing off", self.config.hostname)),
+        };
+        let mut failed = false;
+        for (entry, _) in maildrop.entries.iter().zip(&maildrop.deleted).filter(|(_, deleted)| **deleted) {
+            if let Err(e) = self.config.mailboxes.remove(&maildrop.user, INBOX, entry.uid) {
+                self.log_error(e);
+                failed = true;
+            }
+        }
+        self.locked.lock().unwrap().remove(&maildrop.user);
+        if failed {
+            return self.write_line("-ERR [SYS/TEMP] Some deleted messages not removed");
+        }
+        let left = maildrop.deleted.iter().filter(|deleted| !**deleted).count();
+        self.write_line(&format!("+OK {} POP3 server signing off ({} messages left)", self.config.hostname, left))
+    }
+}
+
+/// The header section of a message and the first `lines` lines of its body.
+fn top(content: &[u8], lines: usize) -> Vec<u8> {
+    let (header, body) = headers::split_message_bytes(content);
+    let mut top = header.to_vec();
+    top.extend_from_slice(b"\r\n");
+    let mut rest = body;
+    for _ in 0..lines {
+        if rest.is_empty() {
+            break;
+        }
+        let end = rest.windows(2).position(|window| window == b"\r\n").map_or(rest.len(), |i| i + 2);
+        top.extend_from_slice(&rest[..end]);
+        rest = &rest[end..];
+    }
+    top
+}
+
+/// A multi-line response body: lines starting with `.` get another one, and
+/// a line holding only `.` ends it.
+fn dot_stuff(content: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(content.len() + 5);
+    let mut line_start = true;
+    for &byte in content {
+        if line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        line_start = byte == b'\n';
+    }
+    if !line_start {
+        stuffed.extend_from_slice(b"\r\n");
+    }
+    stuffed.extend_from_slice(b".\r\n");
+    stuffed
+}
+
+/// Accepts POP3 connections on `listener` forever, one thread per connection.
+pub fn serve(listener: TcpListener, config: Arc<Config>) -> Result<()> {
+    serve_with(listener, move || config.clone())
+}
+
+/// Like `serve`, asking `config` for the settings of each new connection.
+pub fn serve_with(listener: TcpListener, config: impl Fn() -> Arc<Config>) -> Result<()> {
+    let locked = Arc::new(Mutex::new(HashSet::new()));
+    let mut id = 0;
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                id += 1;
+                let config = config();
+                let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+                let mut session = Session {
+                    stream: Stream::Tcp(stream),
+                    id,
+                    buf: Vec::new(),
+                    timestamp: format!("<{}.{}.{}@{}>", std::process::id(), id, nanos, config.hostname),
+                    config,
+                    locked: locked.clone(),
+                    tls: false,
+                    user: None,
+                    maildrop: None,
+                };
+                thread::spawn(move || session.handle());
+            }
+            Err(e) => {
+                println!("Failed to accept POP3 connection: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
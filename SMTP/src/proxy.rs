@@ -0,0 +1,115 @@
+//! HAProxy PROXY protocol headers, versions 1 and 2, which a load balancer
+//! sends ahead of the client's own data to say who the client really is.
+
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Opens every version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// A version 1 header is a single line of at most this many bytes.
+const V1_MAX: usize = 107;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("bad PROXY header: {}", msg))
+}
+
+/// What a PROXY header said about the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The client's address; `None` for health checks from the proxy itself
+    /// and for address families other than TCP over IPv4 or IPv6.
+    pub source: Option<SocketAddr>,
+    /// The address the client connected to.
+    pub destination: Option<SocketAddr>,
+}
+
+/// Parses a header at the start of `buf`. Returns the header and its length,
+/// or `None` when more bytes are needed.
+pub fn parse(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>> {
+    let signature_len = buf.len().min(V2_SIGNATURE.len());
+    if buf[..signature_len] == V2_SIGNATURE[..signature_len] {
+        if buf.len() < 16 {
+            return Ok(None);
+        }
+        return parse_v2(buf);
+    }
+    let prefix_len = buf.len().min(6);
+    if buf[..prefix_len] != b"PROXY "[..prefix_len] {
+        return Err(invalid("missing signature"));
+    }
+    match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX => Ok(Some((parse_v1(&buf[..end])?, end + 2))),
+        Some(_) => Err(invalid("line too long")),
+        None if buf.len() >= V1_MAX => Err(invalid("line too long")),
+        None => Ok(None),
+    }
+}
+
+/// `PROXY TCP4 <src> <dst> <sport> <dport>` or `PROXY UNKNOWN ...`.
+fn parse_v1(line: &[u8]) -> Result<ProxyHeader> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let unknown = ProxyHeader { source: None, destination: None };
+    match fields.get(1).copied() {
+        Some("UNKNOWN") => Ok(unknown),
+        Some(family @ ("TCP4" | "TCP6")) if fields.len() == 6 => {
+            let ip = |s: &str| -> Result<IpAddr> {
+                let ip: IpAddr = s.parse().map_err(|_| invalid("bad address"))?;
+                if ip.is_ipv4() != (family == "TCP4") {
+                    return Err(invalid("address does not match family"));
+                }
+                Ok(ip)
+            };
+            let port = |s: &str| s.parse::<u16>().map_err(|_| invalid("bad port"));
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::new(ip(fields[2])?, port(fields[4])?)),
+                destination: Some(SocketAddr::new(ip(fields[3])?, port(fields[5])?)),
+            })
+        }
+        _ => Err(invalid("unsupported protocol")),
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>> {
+    let version_command = buf[12];
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    let family = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < 16 + len {
+        return Ok(None);
+    }
+    let addresses = &buf[16..16 + len];
+    let unknown = ProxyHeader { source: None, destination: None };
+    let header = match (version_command & 0x0f, family >> 4) {
+        // LOCAL: the proxy talking for itself, e.g. a health check.
+        (0, _) => unknown,
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(addresses[at], addresses[at + 1], addresses[at + 2], addresses[at + 3]))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+            ProxyHeader {
+                source: Some(SocketAddr::new(ip(0), port(8))),
+                destination: Some(SocketAddr::new(ip(4), port(10))),
+            }
+        }
+        (1, 2) if addresses.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&addresses[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+            ProxyHeader {
+                source: Some(SocketAddr::new(ip(0), port(32))),
+                destination: Some(SocketAddr::new(ip(16), port(34))),
+            }
+        }
+        (1, 0) | (1, 3) => unknown,
+        (1, _) => return Err(invalid("address block too short")),
+        _ => return Err(invalid("unsupported command")),
+    };
+    Ok(Some((header, 16 + len)))
+}
@@ -0,0 +1,206 @@
+//! Envelope address rewriting: virtual aliases, catch-all domains,
+//! plus-addressing and canonical senders.
+//!
+//! Tables are text, one directive per line, `#` starting a comment:
+//!
+//! ```text
+//! # sales@ goes to two people
+//! alias      sales@example.com     alice@example.com, bob@example.com
+//! # anyone else at example.org
+//! catchall   example.org           postmaster@example.com
+//! # user+tag@ is user@
+//! separator  +
+//! # senders, by address or by domain
+//! canonical  alice@internal.test   alice.smith@example.com
+//! canonical  @internal.test        @example.com
+//! ```
+//!
+//! Alias targets are rewritten in turn, up to a depth of ten; an alias
+//! naming itself among its targets keeps a copy at that address.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::address::Mailbox;
+use crate::directory::Directory;
+
+/// How many aliases deep targets are rewritten.
+const MAX_DEPTH: usize = 10;
+
+fn key(address: &Mailbox) -> String {
+    address.to_string().to_lowercase()
+}
+
+/// A parsed set of rewriting tables.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tables {
+    /// Alias address, lower cased, to its targets.
+    aliases: HashMap<String, Vec<Mailbox>>,
+    /// Domain to the targets for its addresses that nothing else matches.
+    catch_alls: HashMap<String, Vec<Mailbox>>,
+    /// Sender address, lower cased, to its replacement.
+    canonical: HashMap<String, Mailbox>,
+    /// Sender domain to its replacement domain.
+    canonical_domains: HashMap<String, String>,
+    /// Characters that start a tag in a local part; none by default.
+    separators: Vec<char>,
+}
+
+impl Tables {
+    pub fn new() -> Tables {
+        Tables::default()
+    }
+
+    pub fn parse(text: &str) -> Result<Tables> {
+        let mut tables = Tables::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split_once('#').map(|(before, _)| before).unwrap_or(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |msg: String| Error::new(ErrorKind::InvalidData, format!("rewrite table line {}: {}", i + 1, msg));
+            let (directive, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let args: Vec<&str> = rest.split([',', ' ', '\t']).filter(|arg| !arg.is_empty()).collect();
+            let mailbox = |arg: &str| Mailbox::parse(arg).map_err(|e| invalid(format!("{}: {}", arg, e)));
+            match (directive.to_ascii_lowercase().as_str(), args.as_slice()) {
+                ("alias", [address, targets @ ..]) if !targets.is_empty() => {
+                    let targets = targets.iter().map(|t| mailbox(t)).collect::<Result<_>>()?;
+                    tables.aliases.insert(key(&mailbox(address)?), targets);
+                }
+                ("catchall", [domain, targets @ ..]) if !targets.is_empty() => {
+                    let domain = mailbox(&format!("postmaster@{}", domain))?.domain;
+                    let targets = targets.iter().map(|t| mailbox(t)).collect::<Result<_>>()?;
+                    tables.catch_alls.insert(domain, targets);
+                }
+                ("canonical", [from, to]) => match (from.strip_prefix('@'), to.strip_prefix('@')) {
+                    (Some(from), Some(to)) => {
+                        let from = mailbox(&format!("postmaster@{}", from))?.domain;
+                        let to = mailbox(&format!("postmaster@{}", to))?.domain;
+                        tables.canonical_domains.insert(from, to);
+                    }
+                    (None, None) => {
+                        tables.canonical.insert(key(&mailbox(from)?), mailbox(to)?);
+                    }
+                    _ => return Err(invalid("canonical maps an address to an address, or @domain to @domain".to_string())),
+                },
+                ("separator", [chars]) => tables.separators = chars.chars().collect(),
+                ("alias" | "catchall", _) => return Err(invalid(format!("expected {} <address> <target>...", directive))),
+                ("canonical", _) => return Err(invalid("expected canonical <from> <to>".to_string())),
+                ("separator", _) => return Err(invalid("expected separator <characters>".to_string())),
+                _ => return Err(invalid(format!("unknown directive {:?}", directive))),
+            }
+        }
+        Ok(tables)
+    }
+
+    /// `address` without its `+tag`, for the configured separators.
+    pub fn strip_tag(&self, address: &Mailbox) -> Mailbox {
+        match address.local_part.find(|c| self.separators.contains(&c)) {
+            Some(at) if at > 0 => Mailbox { local_part: address.local_part[..at].to_string(), domain: address.domain.clone() },
+            _ => address.clone(),
+        }
+    }
+
+    /// Where mail for `address` goes. Catch-alls only take addresses that
+    /// `directory` does not know, or any address without an alias when
+    /// there is no directory.
+    pub fn recipients(&self, address: &Mailbox, directory: Option<&dyn Directory>) -> Vec<Mailbox> {
+        let mut out = Vec::new();
+        let mut seen = Vec::new();
+        self.expand(address, directory, 0, &mut seen, &mut out);
+        out
+    }
+
+    fn expand(&self, address: &Mailbox, directory: Option<&dyn Directory>, depth: usize, seen: &mut Vec<String>, out: &mut Vec<Mailbox>) {
+        let untagged = self.strip_tag(address);
+        let targets = if seen.contains(&key(address)) || depth >= MAX_DEPTH {
+            None
+        } else {
+            seen.push(key(address));
+            self.aliases
+                .get(&key(address))
+                .or_else(|| self.aliases.get(&key(&untagged)))
+                .or_else(|| match directory {
+                    Some(directory) if directory.lookup(&untagged).is_some() => None,
+                    _ => self.catch_alls.get(&untagged.domain),
+                })
+        };
+        match targets {
+            Some(targets) => {
+                for target in targets {
+                    self.expand(target, directory, depth + 1, seen, out);
+                }
+            }
+            None if out.iter().any(|m| key(m) == key(&untagged)) => {}
+            None => out.push(untagged),
+        }
+    }
+
+    /// The canonical form of the sender `address`.
+    pub fn sender(&self, address: &Mailbox) -> Mailbox {
+        if let Some(canonical) = self.canonical.get(&key(address)) {
+            return canonical.clone();
+        }
+        match self.canonical_domains.get(&address.domain) {
+            Some(domain) => Mailbox { local_part: address.local_part.clone(), domain: domain.clone() },
+            None => address.clone(),
+        }
+    }
+}
+
+/// When a file was last modified and its size, to tell when it changed.
+type Stamp = (SystemTime, u64);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Rewriting tables, kept up to date with the file they came from.
+pub struct Rewriter {
+    path: Option<PathBuf>,
+    /// The tables, and the modification time and size of the file they
+    /// were read from.
+    current: Mutex<(Arc<Tables>, Option<Stamp>)>,
+}
+
+impl Rewriter {
+    /// Fixed tables.
+    pub fn new(tables: Tables) -> Rewriter {
+        Rewriter { path: None, current: Mutex::new((Arc::new(tables), None)) }
+    }
+
+    /// Tables read from `path`, which is read again whenever it changes.
+    pub fn open(path: impl AsRef<Path>) -> Result<Rewriter> {
+        let path = path.as_ref().to_path_buf();
+        let modified = stamp(&path);
+        let tables = Tables::parse(&fs::read_to_string(&path)?)?;
+        Ok(Rewriter { path: Some(path), current: Mutex::new((Arc::new(tables), modified)) })
+    }
+
+    /// The current tables. If the file changed and no longer parses, the
+    /// tables from before stay in use.
+    pub fn tables(&self) -> Arc<Tables> {
+        let mut current = self.current.lock().unwrap();
+        let path = match &self.path {
+            Some(path) => path,
+            None => return current.0.clone(),
+        };
+        let modified = stamp(path);
+        if modified.is_some() && modified != current.1 {
+            current.1 = modified;
+            match fs::read_to_string(path).and_then(|text| Tables::parse(&text)) {
+                Ok(tables) => {
+                    println!("[INFO] Reloaded rewrite tables from {}", path.display());
+                    current.0 = Arc::new(tables);
+                }
+                Err(e) => println!("[ERROR] Keeping previous rewrite tables, {}: {}", path.display(), e),
+            }
+        }
+        current.0.clone()
+    }
+}
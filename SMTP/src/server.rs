@@ -1,30 +1,338 @@
 use std::collections::HashMap;
 use std::io::{Read, Write, Error, ErrorKind, Result};
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::fmt::Arguments;
 
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rustls::ServerConfig;
+
+use crate::address::{self, ForwardPath, Mailbox, ReversePath};
+use crate::auth::Credentials;
+use crate::authres::AuthenticationResults;
+use crate::command::Command;
+use crate::directory::{Directory, Entry};
+use crate::dkim::{self, DkimOutcome};
+use crate::dmarc::{self, DmarcOutcome};
+use crate::dsn::{self, Action, MailDsn, OriginalRecipient, RcptDsn, RecipientStatus, Report};
+use crate::dns::{self, Resolver, SystemResolver};
+use crate::greylist::{Decision, Greylist};
+use crate::handler::{DmarcHandler, MessageHandler, Verdict};
+use crate::headers::{self, Headers};
+use crate::mailbox::{self, MailboxStore};
+use crate::metrics::{self, Metrics};
+use crate::milter::{self, Milter, MilterClient, Response};
+use crate::proxy;
+use crate::rewrite::Rewriter;
+use crate::sieve::{self, Sieve};
+use crate::spf::{self, SpfResult};
+use crate::store::{MessageStore, StoredMessage};
+use crate::stream::Stream;
+use crate::trace::{self, Hop};
+use crate::webhook::Webhook;
+
+/// Longest command line accepted. RFC 5321 section 4.5.3.1.4 only asks for
+/// 512 octets, but extensions add parameters.
+const MAX_LINE: usize = 4096;
+
+/// Settings shared by every connection of a server.
+pub struct Config {
+    /// Name this server uses for itself in replies and trace headers.
+    pub hostname: String,
+    /// Used for the SPF, DKIM and DMARC lookups.
+    pub resolver: Arc<dyn Resolver>,
+    /// When set, accepted messages get a `DKIM-Signature` before being passed on.
+    pub dkim_signer: Option<dkim::Signer>,
+    /// Decides whether each received message is accepted, quarantined or rejected.
+    pub handler: Arc<dyn MessageHandler>,
+    /// Messages with more `Received` headers than this are rejected as looping.
+    pub max_hops: usize,
+    /// Where accepted and quarantined messages are kept, if anywhere.
+    pub store: Option<Arc<dyn MessageStore>>,
+    /// Speak LMTP (RFC 2033) instead of SMTP: clients greet with LHLO and
+    /// get one reply per recipient after the message.
+    pub lmtp: bool,
+    /// Expect a HAProxy PROXY protocol header (v1 or v2) at the start of
+    /// every connection, and take the client's address from it.
+    pub proxy_protocol: bool,
+    /// Counts connections, replies and messages; see `metrics::spawn` to serve them.
+    pub metrics: Arc<Metrics>,
+    /// Who receives mail here. When set, `RCPT TO` an unknown address is
+    /// refused with 550.
+    pub directory: Option<Arc<dyn Directory>>,
+    /// Answer `VRFY` from the directory. Off by default, when it answers 252
+    /// so that addresses cannot be harvested.
+    pub vrfy: bool,
+    /// Answer `EXPN` from the directory; 252 otherwise, like `vrfy`.
+    pub expn: bool,
+    /// Defers `RCPT TO` from unfamiliar senders with a 451, unless the
+    /// client authenticated.
+    pub greylist: Option<Arc<Greylist>>,
+    /// A content filter spoken to over the Sendmail milter protocol, asked
+    /// about every stage of each session.
+    pub milter: Option<Milter>,
+    /// Runs the recipients' Sieve scripts on accepted mail, which decide
+    /// its folder, or redirect, reject or answer it.
+    pub sieve: Option<Arc<Sieve>>,
+    /// When set, accepted mail is also delivered into each recipient's
+    /// mailbox, for POP3 and IMAP clients; Sieve folders become mailboxes.
+    pub mailboxes: Option<Arc<MailboxStore>>,
+    /// When set, accepted mail is also POSTed to this endpoint, and is
+    /// deferred with a 451 unless the endpoint takes it.
+    pub webhook: Option<Arc<Webhook>>,
+    /// Rewrites envelope senders and recipients before any other check:
+    /// aliases, catch-alls, plus-addressing and canonical senders.
+    pub rewriter: Option<Arc<Rewriter>>,
+    /// When set, clients can switch to TLS with `STARTTLS` (RFC 3207).
+    pub tls: Option<Arc<ServerConfig>>,
+    /// Checks `AUTH PLAIN` and `AUTH LOGIN` (RFC 4954). With `tls` set they
+    /// are only offered once the session is encrypted.
+    pub credentials: Option<Arc<dyn Credentials>>,
+    /// Act as a message submission agent (RFC 6409): `MAIL FROM` needs
+    /// authentication, and each message gets any missing `Date`,
+    /// `Message-ID` and `From`, must be from the authenticated user, and
+    /// loses its `Bcc`.
+    pub submission: bool,
+    /// The largest message accepted, in octets, advertised with `SIZE`
+    /// (RFC 1870). Unlimited when unset.
+    pub max_message_size: Option<usize>,
+    /// How long to wait for each command or piece of data before hanging up.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            hostname: "localhost".to_string(),
+            resolver: Arc::new(SystemResolver::new()),
+            dkim_signer: None,
+            handler: Arc::new(DmarcHandler),
+            max_hops: trace::DEFAULT_MAX_HOPS,
+            store: None,
+            lmtp: false,
+            proxy_protocol: false,
+            metrics: Arc::new(Metrics::new()),
+            directory: None,
+            vrfy: false,
+            expn: false,
+            greylist: None,
+            milter: None,
+            sieve: None,
+            mailboxes: None,
+            webhook: None,
+            rewriter: None,
+            tls: None,
+            credentials: None,
+            submission: false,
+            max_message_size: None,
+            timeout: None,
+        }
+    }
+}
+
+/// The `BODY` parameter of `MAIL FROM` (RFC 6152, RFC 3030).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BodyType {
+    #[default]
+    SevenBit,
+    EightBitMime,
+    /// Arbitrary octets, which can only be sent with BDAT.
+    BinaryMime,
+}
+
+impl BodyType {
+    pub fn parse(value: &str) -> Option<BodyType> {
+        match value.to_ascii_uppercase().as_str() {
+            "7BIT" => Some(BodyType::SevenBit),
+            "8BITMIME" => Some(BodyType::EightBitMime),
+            "BINARYMIME" => Some(BodyType::BinaryMime),
+            _ => None,
+        }
+    }
+}
+
 pub struct Message {
+    /// Identifies the message in logs and in its `Received` header.
+    pub queue_id: String,
     pub client_domain: String,
+    /// The address the message came from; set once its content has arrived.
+    pub client_ip: Option<IpAddr>,
     pub smtp_commands: HashMap<String, String>,
+    /// The `MAIL FROM` path, once one has been accepted.
+    pub reverse_path: Option<ReversePath>,
+    /// Every accepted `RCPT TO` path, in order, after rewriting.
+    pub forward_paths: Vec<ForwardPath>,
+    /// DSN parameters of `MAIL FROM`.
+    pub dsn: MailDsn,
+    /// DSN parameters, one per `forward_paths` entry.
+    pub rcpt_dsn: Vec<RcptDsn>,
+    /// How many `RCPT TO` commands were accepted; an alias can add several
+    /// recipients with one.
+    pub rcpt_count: usize,
+    /// For each `forward_paths` entry, which accepted `RCPT TO` it came
+    /// from, counting from 0.
+    pub rcpt_numbers: Vec<usize>,
     pub atm_headers: HashMap<String, String>,
-    pub body: String,
+    /// The body exactly as received; with 8BITMIME or BINARYMIME it need not be UTF-8.
+    pub body: Vec<u8>,
+    pub body_type: BodyType,
     pub from: String,
     pub date: String,
     pub subject: String,
     pub to: String,
+    /// The message header section, in order, including fields added by this server.
+    pub headers: Headers,
+    /// SPF result for the envelope sender, once `MAIL FROM` has been seen.
+    pub spf: Option<SpfResult>,
+    /// One entry per `DKIM-Signature` on the received message.
+    pub dkim: Vec<DkimOutcome>,
+    /// DMARC outcome for the From header domain, if it has one.
+    pub dmarc: Option<DmarcOutcome>,
+    /// Whether `MAIL FROM` carried the SMTPUTF8 parameter (RFC 6531), allowing
+    /// UTF-8 in envelope addresses and headers.
+    pub smtputf8: bool,
+}
+
+impl Message {
+    /// An empty transaction from the client that greeted as `client_domain`.
+    pub fn new(client_domain: &str) -> Message {
+        Message {
+            queue_id: trace::new_queue_id(),
+            client_domain: client_domain.to_string(),
+            client_ip: None,
+            smtp_commands: HashMap::new(),
+            reverse_path: None,
+            forward_paths: Vec::new(),
+            dsn: MailDsn::default(),
+            rcpt_dsn: Vec::new(),
+            rcpt_count: 0,
+            rcpt_numbers: Vec::new(),
+            atm_headers: HashMap::new(),
+            body: Vec::new(),
+            body_type: BodyType::SevenBit,
+            from: String::new(),
+            date: String::new(),
+            subject: String::new(),
+            to: String::new(),
+            headers: Headers::new(),
+            spf: None,
+            dkim: Vec::new(),
+            dmarc: None,
+            smtputf8: false,
+        }
+    }
+
+    /// Fills in the headers and body from the content received after `DATA`.
+    pub fn set_content(&mut self, content: &[u8]) {
+        let (raw_headers, body) = headers::split_message_bytes(content);
+        for header in Headers::parse(&String::from_utf8_lossy(raw_headers)).fields {
+            let name = header.name.trim().to_uppercase();
+            let value = header.unfolded();
+            if name == "SUBJECT" {
+                self.subject = value.clone();
+            } else if name == "TO"{
+                self.to = value.clone();
+            } else if name == "FROM"{
+                self.from = value.clone();
+            }else if name == "DATE"{
+                self.date = value.clone();
+            }
+            self.atm_headers.insert(name, value);
+            self.headers.fields.push(header);
+        }
+        self.body = body.to_vec();
+    }
+
+    /// Replaces the header section and body, as a content filter may, and
+    /// everything taken from them.
+    pub fn replace_content(&mut self, content: &[u8]) {
+        self.headers = Headers::new();
+        self.atm_headers.clear();
+        for field in [&mut self.from, &mut self.date, &mut self.subject, &mut self.to] {
+            field.clear();
+        }
+        self.set_content(content);
+    }
+
+    /// The header section, an empty line and the body.
+    pub fn content(&self) -> Vec<u8> {
+        let mut content = format!("{}\r\n", self.headers).into_bytes();
+        content.extend_from_slice(&self.body);
+        content
+    }
+
+    /// Adds a header field above the ones received from the client.
+    pub fn prepend_header(&mut self, name: &str, value: &str) {
+        self.headers.prepend(name, value);
+        self.atm_headers.insert(name.to_uppercase(), value.to_string());
+    }
+
+    /// The envelope sender without angle brackets, empty for `MAIL FROM:<>`.
+    pub fn mail_from(&self) -> String {
+        self.reverse_path.as_ref().map(|path| path.to_string()).unwrap_or_default()
+    }
+
+    /// The `forward_paths` as strings.
+    pub fn recipients(&self) -> Vec<String> {
+        self.forward_paths.iter().map(ForwardPath::to_string).collect()
+    }
+}
+
+/// What filing an accepted message involves, worked out before any of it
+/// is done.
+struct Filing {
+    /// Recipient and folder of each copy; no folder means the inbox.
+    copies: Vec<(String, Option<String>)>,
+    /// Whether the store gets a copy per recipient, as Sieve files them,
+    /// rather than one for them all.
+    per_recipient: bool,
+    /// Messages to send: envelope sender, recipient and content.
+    outgoing: Vec<(String, String, Vec<u8>)>,
+    /// The `RCPT TO`s that LMTP refuses, with their replies.
+    refused: Vec<(usize, String)>,
+}
+
+impl Filing {
+    /// A copy in each recipient's inbox.
+    fn inbox(msg: &Message) -> Filing {
+        let copies = msg.recipients().into_iter().map(|recipient| (recipient, None)).collect();
+        Filing { copies, per_recipient: false, outgoing: Vec::new(), refused: Vec::new() }
+    }
 }
 
 pub struct Connection {
-    pub stream: TcpStream,
+    pub stream: Stream,
     pub id: u32,
     pub buf: Vec<u8>,
+    pub config: Arc<Config>,
+    /// Whether the session is running over TLS.
+    pub tls: bool,
+    /// The identity the client authenticated as, if it did.
+    pub auth_user: Option<String>,
+    /// The client's address as given by a PROXY protocol header, which
+    /// stands in for the socket's peer (the proxy) everywhere.
+    pub client_addr: Option<SocketAddr>,
+    /// The command being replied to, for the reply metrics.
+    pub command: &'static str,
+    /// Whether the client greeted with EHLO or LHLO rather than HELO.
+    pub esmtp: bool,
+    /// This session's connection to the configured milter, if it is up.
+    pub milter: Option<MilterClient>,
+    /// The milter's refusal of the greeting, given to each `MAIL FROM`
+    /// until the client greets again.
+    pub helo_refusal: Option<String>,
 }
 
 impl Connection {
     pub fn write_line(&mut self, msg: &str) -> Result<()> {
         let mut msg_csrf = msg.to_owned();
         msg_csrf += "\r\n";
+        self.config.metrics.reply(self.command, msg);
         self.stream.write_all(msg_csrf.as_bytes())?;
         Ok(())
     }
@@ -32,21 +340,267 @@ impl Connection {
     pub fn read_line(&mut self) -> Result<String> {
         let mut buffer = [0; 1024];
         loop {
+            // Clients may pipeline commands, so a whole line can already be buffered.
+            if let Some(i) = self.buf.windows(2).position(|window| window == b"\r\n") {
+                let line: Vec<u8> = self.buf.drain(..i + 2).take(i).collect();
+                return String::from_utf8(line).map_err(|e| Error::new(ErrorKind::InvalidData, e));
+            }
+            // Without a limit, a client that never ends its line would be
+            // buffered until memory runs out.
+            if self.buf.len() > MAX_LINE {
+                let _ = self.write_line("500 5.5.2 Line too long");
+                return Err(Error::other("Command line too long"));
+            }
             let n = self.stream.read(&mut buffer)?;
             if n == 0 {
                 return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
             }
             self.buf.extend_from_slice(&buffer[..n]);
-            if let Some(i) = self.buf.windows(2).position(|window| window == b"\r\n") {
-                let line = String::from_utf8(self.buf[..i].to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
-                self.buf.drain(..=i + 1);
-                return Ok(line);
+        }
+    }
+
+    /// Writes a reply of several lines, `250-first` up to `250 last`.
+    pub fn write_multiline(&mut self, code: &str, lines: &[String]) -> Result<()> {
+        let mut reply = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            let separator = if i + 1 == lines.len() { ' ' } else { '-' };
+            reply += &format!("{}{}{}\r\n", code, separator, line);
+        }
+        self.config.metrics.reply(self.command, code);
+        self.stream.write_all(reply.as_bytes())
+    }
+
+    /// The EHLO reply: this server's name followed by the extensions it supports.
+    fn ehlo_reply(&self) -> Vec<String> {
+        let mut lines = vec![self.config.hostname.clone()];
+        lines.extend(["8BITMIME", "BINARYMIME", "CHUNKING", "DSN", "PIPELINING", "SMTPUTF8"].map(String::from));
+        if let Some(max) = self.config.max_message_size {
+            lines.push(format!("SIZE {}", max));
+        }
+        if self.config.tls.is_some() && !self.tls {
+            lines.push("STARTTLS".to_string());
+        }
+        if self.auth_offered() {
+            lines.push("AUTH PLAIN LOGIN".to_string());
+        }
+        lines
+    }
+
+    /// Whether `AUTH` is available: there are credentials to check, and
+    /// the session is encrypted if it can be.
+    fn auth_offered(&self) -> bool {
+        self.config.credentials.is_some() && (self.tls || self.config.tls.is_none())
+    }
+
+    /// Handles `STARTTLS`, replying to the client. Errors only when the
+    /// session is over.
+    fn start_tls(&mut self) -> Result<()> {
+        let config = match &self.config.tls {
+            Some(config) if !self.tls => config.clone(),
+            Some(_) => return self.write_line("503 5.5.1 TLS already active"),
+            None => return self.write_line("502 5.5.1 STARTTLS not available"),
+        };
+        self.write_line("220 2.0.0 Ready to start TLS")?;
+        self.stream.start_tls(config)?;
+        // Anything sent in the clear after STARTTLS could have been injected.
+        self.buf.clear();
+        self.tls = true;
+        self.auth_user = None;
+        self.log_info("Started TLS", None);
+        Ok(())
+    }
+
+    /// Reads the client's answer to a `334` challenge, base64 decoded.
+    /// `None` if the client cancelled with `*` or sent something that does
+    /// not decode, which has then been replied to.
+    fn auth_response(&mut self, challenge: &str) -> Result<Option<Vec<u8>>> {
+        self.write_line(&format!("334 {}", challenge))?;
+        let line = self.read_line()?;
+        self.decode_auth(line.trim())
+    }
+
+    fn decode_auth(&mut self, response: &str) -> Result<Option<Vec<u8>>> {
+        if response == "*" {
+            self.write_line("501 5.0.0 Authentication cancelled")?;
+            return Ok(None);
+        }
+        // `=` stands for an empty initial response.
+        let response = if response == "=" { "" } else { response };
+        match BASE64.decode(response) {
+            Ok(decoded) => Ok(Some(decoded)),
+            Err(_) => {
+                self.write_line("501 5.5.2 Cannot decode response")?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Handles `AUTH` with the PLAIN (RFC 4616) and LOGIN mechanisms,
+    /// replying to the client. Errors only when the session is over.
+    fn authenticate(&mut self, msg: &Message, args: &str) -> Result<()> {
+        let credentials = match &self.config.credentials {
+            Some(credentials) => credentials.clone(),
+            None => return self.write_line("502 5.5.1 AUTH not available"),
+        };
+        if !self.auth_offered() {
+            return self.write_line("538 5.7.11 Encryption required for requested authentication mechanism");
+        }
+        if self.auth_user.is_some() {
+            return self.write_line("503 5.5.1 Already authenticated");
+        }
+        if msg.reverse_path.is_some() {
+            return self.write_line("503 5.5.1 AUTH not permitted during a mail transaction");
+        }
+        let mut words = args.split_whitespace();
+        let mechanism = words.next().unwrap_or_default().to_ascii_uppercase();
+        let initial = words.next();
+        let (user, password) = match mechanism.as_str() {
+            "PLAIN" => {
+                let response = match initial {
+                    Some(initial) => self.decode_auth(initial)?,
+                    None => self.auth_response("")?,
+                };
+                let response = match response {
+                    Some(response) => String::from_utf8_lossy(&response).into_owned(),
+                    None => return Ok(()),
+                };
+                let mut fields = response.splitn(3, '\0');
+                match (fields.next(), fields.next(), fields.next()) {
+                    // Acting for someone else is not supported.
+                    (Some(authzid), Some(user), Some(password)) if authzid.is_empty() || authzid == user => {
+                        (user.to_string(), password.to_string())
+                    }
+                    _ => return self.write_line("501 5.5.2 Malformed PLAIN response"),
+                }
+            }
+            "LOGIN" => {
+                let user = match initial {
+                    Some(initial) => self.decode_auth(initial)?,
+                    None => self.auth_response(&BASE64.encode("Username:"))?,
+                };
+                let user = match user {
+                    Some(user) => String::from_utf8_lossy(&user).into_owned(),
+                    None => return Ok(()),
+                };
+                match self.auth_response(&BASE64.encode("Password:"))? {
+                    Some(password) => (user, String::from_utf8_lossy(&password).into_owned()),
+                    None => return Ok(()),
+                }
             }
+            "" => return self.write_line("501 5.5.4 Syntax: AUTH mechanism [initial-response]"),
+            _ => return self.write_line("504 5.5.4 Unrecognized authentication type"),
+        };
+        if credentials.verify(&user, &password) {
+            self.log_info("Authenticated", Some(format_args!("as {}", user)));
+            self.auth_user = Some(user);
+            self.write_line("235 2.7.0 Authentication successful")
+        } else {
+            self.log_info("Authentication failed", Some(format_args!("for {}", user)));
+            self.write_line("535 5.7.8 Authentication credentials invalid")
+        }
+    }
+
+    /// The address the authenticated user sends as: their user name, at
+    /// this server's hostname if it has no domain.
+    fn auth_identity(&self) -> Option<Mailbox> {
+        let user = self.auth_user.as_ref()?;
+        let address = if user.contains('@') { user.clone() } else { format!("{}@{}", user, self.config.hostname) };
+        Mailbox::parse(&address).ok()
+    }
+
+    /// Completes a submitted message (RFC 6409 section 8): adds a missing
+    /// `Date`, `Message-ID` or `From`, and removes `Bcc`. A `From` other
+    /// than the authenticated user is refused, and false returned.
+    fn fix_up_submission(&mut self, msg: &mut Message) -> bool {
+        let identity = match self.auth_identity() {
+            Some(identity) => identity,
+            None => return true,
+        };
+        let key = |address: &Mailbox| address.to_string().to_lowercase();
+        let from = msg.headers.get("From");
+        if let Some(from) = &from {
+            let addresses = address::parse_address_list(from);
+            let matches = |address: &String| Mailbox::parse(address).is_ok_and(|address| key(&address) == key(&identity));
+            if addresses.is_empty() || !addresses.iter().all(matches) {
+                self.write_final(msg, &format!("550 5.7.1 From header must be <{}>", identity));
+                self.log_info("Refused submission", Some(format_args!("{} From: {}", msg.queue_id, from)));
+                self.config.metrics.message("rejected");
+                return false;
+            }
+        }
+        if from.is_none() {
+            msg.headers.push("From", &identity.to_string());
+        }
+        if !msg.headers.contains("Date") {
+            msg.headers.push("Date", &trace::rfc5322_date(SystemTime::now()));
+        }
+        if !msg.headers.contains("Message-ID") {
+            msg.headers.push("Message-ID", &format!("<{}@{}>", msg.queue_id, self.config.hostname));
+        }
+        msg.headers.remove("Bcc");
+        let content = msg.content();
+        msg.replace_content(&content);
+        true
+    }
+
+    /// Sends the reply to the end of a message: once for SMTP, and once per
+    /// recipient for LMTP (RFC 2033 section 4.2).
+    fn write_final(&mut self, msg: &Message, reply: &str) {
+        let count = if self.config.lmtp { msg.rcpt_count } else { 1 };
+        let replies = format!("{}\r\n", reply).repeat(count);
+        for _ in 0..count {
+            self.config.metrics.reply(self.command, reply);
+        }
+        if let Err(e) = self.stream.write_all(replies.as_bytes()) {
+            self.log_error(e);
+        }
+    }
+
+    /// Like `write_final`, except that under LMTP each `RCPT TO` numbered in
+    /// `refused` gets its own reply instead.
+    fn write_final_except(&mut self, msg: &Message, refused: &[(usize, String)], reply: &str) {
+        if !self.config.lmtp || refused.is_empty() {
+            return self.write_final(msg, reply);
+        }
+        let mut replies = String::new();
+        for rcpt in 0..msg.rcpt_count {
+            let reply = refused.iter().find(|(refused, _)| *refused == rcpt).map_or(reply, |(_, reply)| reply.as_str());
+            self.config.metrics.reply(self.command, reply);
+            replies += &format!("{}\r\n", reply);
+        }
+        if let Err(e) = self.stream.write_all(replies.as_bytes()) {
+            self.log_error(e);
+        }
+    }
+
+    /// Reads the PROXY protocol header that opens the connection, keeping
+    /// anything after it for the SMTP session.
+    fn read_proxy_header(&mut self) -> Result<()> {
+        let mut buffer = [0; 1024];
+        loop {
+            if let Some((header, len)) = proxy::parse(&self.buf)? {
+                self.buf.drain(..len);
+                self.client_addr = header.source;
+                return Ok(());
+            }
+            let n = self.stream.read(&mut buffer)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
+            }
+            self.buf.extend_from_slice(&buffer[..n]);
+        }
+    }
+
+    /// The client's address for logs.
+    fn peer(&self) -> String {
+        match self.client_addr {
+            Some(addr) => addr.to_string(),
+            None => self.stream.peer(),
         }
     }
 
     fn log_info(&self, msg: &str, args: Option<Arguments>) {
-        let peer_address = self.stream.peer_addr().expect("Could not get the remote address");
+        let peer_address = self.peer();
         if let Some(arguments) = args {
             println!("[INFO] [{}:{}] {}", self.id, peer_address, format_args!("{} {}", msg, arguments));
         } else {
@@ -55,188 +609,1149 @@ impl Connection {
     }
 
     fn log_error(&self, e:Error) {
-        let peer_address = self.stream.peer_addr().expect("Could not get the remote address");
+        let peer_address = self.peer();
         println!("[ERROR] [{}:{}] {}", self.id, peer_address, e);
     }
 
+    /// Reads one header field, continuation lines included, without its
+    /// final CRLF. The empty line that ends the header section comes back as
+    /// an empty string.
     pub fn read_multiline(&mut self) -> Result<String> {
-        loop{
-            let mut no_more_reads = false;
-            for i in 0..self.buf.len(){
-                if i > 1 &&
-                self.buf[i] != b' ' &&
-                self.buf[i] != b'\t' &&
-                self.buf[i-2] != b'\r' &&
-                self.buf[i-1] != b'\n'{
-                    let line = String::from_utf8_lossy(&self.buf[..i-2]).into_owned();
-                    self.buf = self.buf[i..].to_vec();
-                    return Ok(line);
-                }
-                no_more_reads = self.is_body_close(i);
+        let mut buffer = [0; 1024];
+        loop {
+            if let Some(end) = headers::field_end(&self.buf) {
+                let field: Vec<u8> = self.buf.drain(..end).take(end - 2).collect();
+                return Ok(String::from_utf8_lossy(&field).into_owned());
+            }
+            let n = self.stream.read(&mut buffer)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
             }
+            self.buf.extend_from_slice(&buffer[..n]);
+        }
+    }
 
-            if !no_more_reads{
-                let mut b = vec![0;1024];
-                let n = self.stream.read(&mut b)?;
-                self.buf.extend_from_slice(&b[..n]);
+    fn peer_ip(&self) -> Option<IpAddr> {
+        if let Some(addr) = self.client_addr {
+            return Some(addr.ip());
+        }
+        match self.stream.peer_ip() {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                self.log_error(e);
+                None
             }
-            
         }
     }
 
-    fn is_body_close(&self, i:usize) -> bool{
-        return i > 4 && 
-        self.buf[i-4] == b'\r' &&
-        self.buf[i-3] == b'\n' &&
-        self.buf[i-2] == b'.' &&
-        self.buf[i-1] == b'\r' &&
-        self.buf[i-0] == b'\n'
+    /// Passes a stage of the session to the milter. Returns the reply that
+    /// refuses the command, `rejected` when the filter gave none, or `None`
+    /// to carry on.
+    fn milter_check(&mut self, stage: impl FnOnce(&mut MilterClient) -> Result<Response>, rejected: &str) -> Option<String> {
+        let client = match &mut self.milter {
+            Some(client) => client,
+            None => return self.milter_unavailable(None),
+        };
+        match stage(client) {
+            Ok(Response::Reject(reply)) => Some(reply.unwrap_or_else(|| rejected.to_string())),
+            Ok(Response::TempFail(reply)) => Some(reply.unwrap_or_else(|| "451 4.7.1 Service unavailable, try again later".to_string())),
+            Ok(_) => None,
+            Err(e) => self.milter_unavailable(Some(e)),
+        }
     }
 
-    fn read_to_end_of_body(&mut self) -> Result<String>{
-        loop{
-            for i in 0..self.buf.len(){
-                if self.is_body_close(i){
-                    let line: String = String::from_utf8_lossy(&self.buf[..i-4]).into_owned();
-                    return Ok(line);
+    /// Gives up on a milter that failed with `error`. Returns the reply that
+    /// defers the command, unless there is no milter or it fails open.
+    fn milter_unavailable(&mut self, error: Option<Error>) -> Option<String> {
+        if let Some(e) = error {
+            self.log_error(e);
+            self.milter = None;
+        }
+        match &self.config.milter {
+            Some(milter) if !milter.fail_open => Some("451 4.3.0 Mail filter unavailable".to_string()),
+            _ => None,
+        }
+    }
+
+    /// Tells the milter that the current transaction was abandoned.
+    fn milter_abort(&mut self, msg: &Message) {
+        if msg.reverse_path.is_none() {
+            return;
+        }
+        if let Some(Err(e)) = self.milter.as_mut().map(MilterClient::abort) {
+            self.milter_unavailable(Some(e));
+        }
+    }
+
+    /// Handles `MAIL FROM`, replying to the client.
+    fn mail_from(&mut self, msg: &mut Message, value: &str) {
+        if msg.reverse_path.is_some() {
+            let _ = self.write_line("503 5.5.1 Sender already specified");
+            return;
+        }
+        let (path, params) = match address::parse_reverse_path(value) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let _ = self.write_line(&format!("501 5.1.7 Syntax error in sender address: {}", e));
+                return;
+            }
+        };
+        let dsn = match MailDsn::from_params(&params) {
+            Ok(dsn) => dsn,
+            Err(e) => {
+                let _ = self.write_line(&format!("501 5.5.4 Invalid DSN parameter: {}", e));
+                return;
+            }
+        };
+        let body_type = match params.get("BODY").map(BodyType::parse) {
+            Some(Some(body_type)) => body_type,
+            Some(None) => {
+                let _ = self.write_line("501 5.5.4 BODY must be 7BIT, 8BITMIME or BINARYMIME");
+                return;
+            }
+            None => BodyType::SevenBit,
+        };
+        let declared = params.get("SIZE").map(|size| size.parse::<usize>());
+        if let Some(Err(_)) = declared {
+            let _ = self.write_line("501 5.5.4 SIZE must be a number");
+            return;
+        }
+        if let (Some(Ok(declared)), Some(max)) = (declared, self.config.max_message_size) {
+            if declared > max {
+                let _ = self.write_line("552 5.3.4 Message size exceeds fixed maximum message size");
+                return;
+            }
+        }
+        let smtputf8 = params.has("SMTPUTF8");
+        if !path.is_ascii() && !smtputf8 {
+            let _ = self.write_line("553 5.6.7 Non-ASCII addresses require SMTPUTF8");
+            return;
+        }
+        if let Some(reply) = self.helo_refusal.clone() {
+            let _ = self.write_line(&reply);
+            return;
+        }
+        if self.config.submission && self.auth_user.is_none() {
+            let _ = self.write_line("530 5.7.0 Authentication required");
+            return;
+        }
+        if self.config.milter.is_some() {
+            let sender = format!("<{}>", path);
+            let mut args = vec![sender.as_str()];
+            args.extend(value.trim().split_once('>').map(|(_, params)| params).unwrap_or("").split_whitespace());
+            if let Some(reply) = self.milter_check(|milter| milter.mail(&args), "550 5.7.1 Sender rejected") {
+                let _ = self.write_line(&reply);
+                return;
+            }
+        }
+        msg.smtputf8 = smtputf8;
+        msg.dsn = dsn;
+        msg.body_type = body_type;
+        msg.reverse_path = Some(path);
+        self.check_spf(msg);
+        let _ = self.write_line("250 2.1.0 Sender OK");
+    }
+
+    /// Handles `RCPT TO`, replying to the client.
+    fn rcpt_to(&mut self, msg: &mut Message, value: &str) {
+        if msg.reverse_path.is_none() {
+            let _ = self.write_line("503 5.5.1 MAIL FROM first");
+            return;
+        }
+        let (path, params) = match address::parse_forward_path(value) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let _ = self.write_line(&format!("501 5.1.3 Syntax error in recipient address: {}", e));
+                return;
+            }
+        };
+        let dsn = match RcptDsn::from_params(&params) {
+            Ok(dsn) => dsn,
+            Err(e) => {
+                let _ = self.write_line(&format!("501 5.5.4 Invalid DSN parameter: {}", e));
+                return;
+            }
+        };
+        if !path.is_ascii() && !msg.smtputf8 {
+            let _ = self.write_line("553 5.6.7 Non-ASCII addresses require SMTPUTF8");
+            return;
+        }
+        let paths = match (&self.config.rewriter, path.mailbox()) {
+            (Some(rewriter), Some(mailbox)) => {
+                let rewritten = rewriter.tables().recipients(mailbox, self.config.directory.as_deref());
+                if rewritten.len() != 1 || &rewritten[0] != mailbox {
+                    let targets: Vec<String> = rewritten.iter().map(Mailbox::to_string).collect();
+                    self.log_info("Rewrote recipient", Some(format_args!("{} to {}", mailbox, targets.join(", "))));
                 }
+                rewritten.into_iter().map(ForwardPath::Mailbox).collect()
             }
+            _ => vec![path.clone()],
+        };
+        if let Some(directory) = &self.config.directory {
+            if let Some(unknown) = paths.iter().filter_map(ForwardPath::mailbox).find(|mailbox| directory.lookup(mailbox).is_none()) {
+                let _ = self.write_line(&format!("550 5.1.1 <{}>: User unknown", unknown));
+                return;
+            }
+        }
+        if let (Some(greylist), None) = (self.config.greylist.clone(), &self.auth_user) {
+            if let Some(ip) = self.peer_ip() {
+                if let Decision::Defer(wait) = greylist.check(ip, &msg.mail_from(), &path.to_string()) {
+                    let reply = format!("451 4.7.1 Greylisted, please try again in {} seconds", wait.as_secs());
+                    let _ = self.write_line(&reply);
+                    return;
+                }
+            }
+        }
+        if self.config.milter.is_some() {
+            for target in &paths {
+                let recipient = format!("<{}>", target);
+                let mut args = vec![recipient.as_str()];
+                args.extend(value.trim().split_once('>').map(|(_, params)| params).unwrap_or("").split_whitespace());
+                if let Some(reply) = self.milter_check(|milter| milter.rcpt(&args), "550 5.7.1 Recipient rejected") {
+                    let _ = self.write_line(&reply);
+                    return;
+                }
+            }
+        }
+        for target in paths {
+            // Aliases may lead to someone who is already a recipient.
+            if self.config.rewriter.is_some() && msg.forward_paths.contains(&target) {
+                continue;
+            }
+            let mut dsn = dsn.clone();
+            // Reports name the address the client asked for.
+            if dsn.orcpt.is_none() && target != path {
+                dsn.orcpt = Some(OriginalRecipient { addr_type: "rfc822".to_string(), address: path.to_string() });
+            }
+            msg.forward_paths.push(target);
+            msg.rcpt_dsn.push(dsn);
+            msg.rcpt_numbers.push(msg.rcpt_count);
+        }
+        msg.rcpt_count += 1;
+        let _ = self.write_line("250 2.1.5 Recipient OK");
+    }
 
-            let mut b = [0;1024];
-            let n = self.stream.read(&mut b)?;
-            self.buf.extend_from_slice(&b[..n]);
-        }   
+    /// Handles `VRFY` (`expand` false) and `EXPN` (`expand` true), replying
+    /// to the client. A bare user name is looked up at this server's hostname.
+    fn verify(&mut self, arg: &str, expand: bool) {
+        let enabled = if expand { self.config.expn } else { self.config.vrfy };
+        let directory = match &self.config.directory {
+            Some(directory) if enabled => directory.clone(),
+            _ => {
+                let _ = self.write_line("252 2.5.2 Cannot verify the user, but will accept the message");
+                return;
+            }
+        };
+        let arg = arg.trim();
+        let arg = arg.strip_prefix('<').and_then(|a| a.strip_suffix('>')).unwrap_or(arg);
+        let address = if arg.contains('@') { arg.to_string() } else { format!("{}@{}", arg, self.config.hostname) };
+        let mailbox = match Mailbox::parse(&address) {
+            Ok(mailbox) if !arg.is_empty() => mailbox,
+            _ => {
+                let verb = if expand { "EXPN" } else { "VRFY" };
+                let _ = self.write_line(&format!("501 5.5.4 Syntax: {} <address>", verb));
+                return;
+            }
+        };
+        match (directory.lookup(&mailbox), expand) {
+            (Some(Entry::User(user)), false) => {
+                let _ = self.write_line(&format!("250 2.1.5 {}", user));
+            }
+            (Some(Entry::List(members)), true) if members.is_empty() => {
+                let _ = self.write_line(&format!("250 2.1.5 <{}> has no members", mailbox));
+            }
+            (Some(Entry::List(members)), true) => {
+                let lines: Vec<String> = members.iter().map(|member| format!("2.1.5 {}", member)).collect();
+                let _ = self.write_multiline("250", &lines);
+            }
+            (Some(Entry::User(_)), true) => {
+                let _ = self.write_line(&format!("550 5.1.1 <{}> is not a mailing list", mailbox));
+            }
+            (Some(Entry::List(_)), false) => {
+                let _ = self.write_line(&format!("550 5.1.1 <{}> is a mailing list, use EXPN", mailbox));
+            }
+            (None, _) => {
+                let _ = self.write_line(&format!("550 5.1.1 <{}>: User unknown", mailbox));
+            }
+        }
     }
 
-    pub fn handle(&mut self) {
-        println!("Handling connection {}", self.id);
+    /// Evaluates SPF for the envelope sender and stamps a `Received-SPF` header.
+    fn check_spf(&self, msg: &mut Message) {
+        let ip = match self.peer_ip() {
+            Some(ip) => ip,
+            None => return,
+        };
+        let sender = msg.mail_from();
+        let result = spf::check_sender(self.config.resolver.as_ref(), ip, &sender, &msg.client_domain);
+        self.log_info("SPF", Some(format_args!("{} for {} from {}", result, sender, ip)));
 
-        if let Err(e) = self.write_line("220") {
-            self.log_error(e);
+        let header = spf::received_spf(result, &self.config.hostname, ip, &sender, &msg.client_domain);
+        msg.prepend_header("Received-SPF", &header);
+        msg.spf = Some(result);
+    }
+
+    /// Verifies DKIM signatures, evaluates DMARC for the From domain and
+    /// records the results in `Authentication-Results`.
+    fn check_authentication(&self, msg: &mut Message) {
+        msg.dkim = dkim::verify(self.config.resolver.as_ref(), &msg.headers, &msg.body);
+        for outcome in &msg.dkim {
+            self.log_info("DKIM", Some(format_args!("{} for d={} s={}", outcome.result, outcome.domain, outcome.selector)));
+        }
+
+        let mail_from = msg.mail_from();
+        let mail_from_domain = mail_from.rsplit_once('@').map(|(_, d)| d).unwrap_or(&msg.client_domain);
+        msg.dmarc = dmarc::from_domain(&msg.from)
+            .map(|domain| dmarc::evaluate(self.config.resolver.as_ref(), &domain, msg.spf, mail_from_domain, &msg.dkim));
+        if let Some(outcome) = &msg.dmarc {
+            self.log_info("DMARC", Some(format_args!("{} for {} (p={} dis={})", outcome.result, outcome.domain, outcome.policy, outcome.disposition)));
+        }
+
+        let mut results = AuthenticationResults::new(&self.config.hostname);
+        if let Some(spf) = msg.spf {
+            results.add("spf", spf.as_str(), &[("smtp.mailfrom", &msg.mail_from())]);
+        }
+        if msg.dkim.is_empty() {
+            results.add("dkim", "none", &[]);
+        }
+        for outcome in &msg.dkim {
+            let properties = [
+                ("header.d", outcome.domain.as_str()),
+                ("header.s", outcome.selector.as_str()),
+                ("header.b", outcome.signature.as_str()),
+            ];
+            match &outcome.reason {
+                Some(reason) => results.add_with_reason("dkim", outcome.result.as_str(), reason, &properties),
+                None => results.add("dkim", outcome.result.as_str(), &properties),
+            }
         }
+        if let Some(outcome) = &msg.dmarc {
+            let result = match outcome.result {
+                dmarc::DmarcResult::Fail => format!("fail (p={} dis={})", outcome.policy, outcome.disposition),
+                result => result.to_string(),
+            };
+            results.add("dmarc", &result, &[("header.from", &outcome.domain)]);
+        }
+        msg.prepend_header("Authentication-Results", &results.to_string());
+    }
+
+    /// Prepends this hop's `Received` header.
+    fn stamp_received(&self, msg: &mut Message) {
+        let ip = match self.peer_ip() {
+            Some(ip) => ip,
+            None => return,
+        };
+        let reverse_name = dns::confirmed_name(self.config.resolver.as_ref(), ip);
+        // Naming the recipient would disclose the others when there are several.
+        let recipients = msg.recipients();
+        let recipient = match recipients.as_slice() {
+            [recipient] => Some(recipient.as_str()),
+            _ => None,
+        };
+        let mut protocol = trace::protocol(self.esmtp, self.tls, self.auth_user.is_some()).to_string();
+        if self.config.lmtp {
+            protocol = trace::lmtp_protocol(&protocol);
+        }
+        if msg.smtputf8 {
+            protocol = trace::utf8_protocol(&protocol);
+        }
+        let hop = Hop {
+            helo: &msg.client_domain,
+            reverse_name: reverse_name.as_deref(),
+            ip,
+            by: &self.config.hostname,
+            protocol: &protocol,
+            queue_id: &msg.queue_id,
+            recipient,
+            time: SystemTime::now(),
+        };
+        let received = trace::received(&hop);
+        msg.prepend_header("Received", &received);
+    }
 
-        self.log_info("Awaiting EHLO", None);
-        let mut line = String::new();
+    /// Saves the message in the configured store. On failure the client is
+    /// told to retry later and false is returned.
+    fn store_message(&mut self, msg: &Message, quarantined: bool) -> bool {
+        let store = match &self.config.store {
+            Some(store) => store.clone(),
+            None => return true,
+        };
+        match store.add(StoredMessage::from_message(msg, quarantined)) {
+            Ok(()) => true,
+            Err(e) => {
+                self.log_error(e);
+                self.write_final(msg, "451 4.3.0 Could not store message, try again later");
+                false
+            }
+        }
+    }
 
-        match self.read_line(){
-            Ok(l)=>{
-                if !l.starts_with("EHLO"){
-                    self.log_error(Error::new(ErrorKind::Other, format!("Expected EHLO Got: {}",l)));
-                    return 
+    /// Delivers a copy of the message to each recipient's mailbox: the
+    /// folder given, or the inbox. On failure the client is told to retry
+    /// later and false is returned.
+    fn deliver_to_mailboxes(&mut self, msg: &Message, copies: &[(String, Option<String>)]) -> bool {
+        let mailboxes = match &self.config.mailboxes {
+            Some(mailboxes) => mailboxes.clone(),
+            None => return true,
+        };
+        let content = msg.content();
+        for (recipient, folder) in copies {
+            let folder = folder.as_deref().unwrap_or(mailbox::INBOX);
+            let delivered = match mailboxes.deliver(recipient, folder, &content) {
+                // A folder name that cannot be a mailbox files into the inbox.
+                Err(e) if e.kind() == ErrorKind::InvalidInput && folder != mailbox::INBOX => {
+                    self.log_error(e);
+                    mailboxes.deliver(recipient, mailbox::INBOX, &content)
                 }
-                line = l
-            },
-            Err(e)=>{
+                result => result,
+            };
+            if let Err(e) = delivered {
                 self.log_error(e);
+                self.write_final(msg, "451 4.3.0 Could not deliver message, try again later");
+                return false;
             }
         }
+        true
+    }
 
-        let mut msg = Message{
-            client_domain: line[5..].to_string(),
-            smtp_commands:HashMap::new(),
-            atm_headers:HashMap::new(),
-            body: String::new(),
-            from:String::new(),
-            date:String::new(),
-            subject:String::new(),
-            to:String::new(),
+    /// POSTs the message to the configured webhook. If the endpoint does not
+    /// take it, the client is told to retry later and false is returned.
+    fn post_to_webhook(&mut self, msg: &Message) -> bool {
+        let webhook = match &self.config.webhook {
+            Some(webhook) => webhook.clone(),
+            None => return true,
         };
+        match webhook.deliver(msg) {
+            Ok(()) => true,
+            Err(e) => {
+                self.log_error(e);
+                self.write_final(msg, "451 4.3.0 Could not deliver message, try again later");
+                false
+            }
+        }
+    }
 
-        if let Err(e) = self.write_line("250"){
-            self.log_error(e);
+    /// Sends the envelope sender a success DSN for the recipients that asked
+    /// for one with `NOTIFY=SUCCESS`. The report goes to the configured store.
+    fn notify_delivered(&self, msg: &Message) {
+        // Bounces themselves never get a DSN.
+        let sender = match msg.reverse_path.as_ref().and_then(ReversePath::mailbox) {
+            Some(mailbox) => mailbox.to_string(),
+            None => return,
+        };
+        let addresses = msg.recipients();
+        let recipients: Vec<RecipientStatus> = addresses
+            .iter()
+            .zip(&msg.rcpt_dsn)
+            .filter(|(_, dsn)| dsn.wants(Action::Delivered))
+            .map(|(recipient, dsn)| RecipientStatus {
+                final_recipient: recipient,
+                original: dsn.orcpt.as_ref(),
+                action: Action::Delivered,
+                status: "2.0.0",
+                diagnostic: None,
+            })
+            .collect();
+        if recipients.is_empty() {
+            return;
         }
+        let original_message = msg.content();
+        let report = Report {
+            reporting_mta: &self.config.hostname,
+            to: &sender,
+            envid: msg.dsn.envid.as_deref(),
+            // RET only applies to failures, success reports carry headers alone.
+            ret: dsn::Ret::Hdrs,
+            arrival: SystemTime::now(),
+            recipients,
+            original_message: &original_message,
+        };
+        let id = self.send_generated("", &sender, report.to_message());
+        self.log_info("DSN", Some(format_args!("{} success report for {} sent to {}", id, msg.queue_id, sender)));
+    }
 
-        self.log_info("Done EHLO", None);
+    /// Sends a message this server wrote itself, such as a DSN, which here
+    /// means putting it in the configured store. Returns its queue id.
+    fn send_generated(&self, mail_from: &str, to: &str, content: Vec<u8>) -> String {
+        let id = trace::new_queue_id();
+        if let Some(store) = &self.config.store {
+            let generated = StoredMessage {
+                id: id.clone(),
+                received_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+                mail_from: mail_from.to_string(),
+                recipients: vec![to.to_string()],
+                quarantined: false,
+                folder: None,
+                content,
+            };
+            if let Err(e) = store.add(generated) {
+                self.log_error(e);
+            }
+        }
+        id
+    }
 
-        loop{
-            let mut line = String::new();
-            match self.read_line(){
-                Ok(l) =>{                    
-                    if l.trim().is_empty(){
-                        self.log_error(Error::new(ErrorKind::Other, format!("Line is empty {}",l))); 
-                        break;
+    /// Runs each recipient's Sieve script and works out what filing the
+    /// message means: copies kept or filed, one per recipient, and
+    /// redirects, rejection notices and vacation replies to send. When
+    /// every recipient rejects the message it is refused with a 550
+    /// instead, and None is returned.
+    fn sieve_filing(&mut self, msg: &Message, sieve: &Sieve) -> Option<Filing> {
+        let mut copies = Vec::new();
+        let mut rejections = Vec::new();
+        let mut outgoing = Vec::new();
+        for recipient in &msg.recipients() {
+            let actions = sieve.run(msg, recipient).unwrap_or_else(|e| {
+                // A broken script must not lose mail (RFC 5228 section 2.10.6).
+                self.log_error(e);
+                vec![sieve::Action::Keep]
+            });
+            for action in actions {
+                match action {
+                    sieve::Action::Keep => copies.push((recipient.clone(), None)),
+                    sieve::Action::FileInto(folder) => copies.push((recipient.clone(), Some(folder))),
+                    sieve::Action::Redirect(address) => outgoing.push((msg.mail_from(), address, msg.content())),
+                    sieve::Action::Reject(reason) => rejections.push((recipient.clone(), reason)),
+                    sieve::Action::Discard => {}
+                    sieve::Action::Vacation(vacation) => {
+                        if let Some(reply) = sieve.vacation_reply(msg, recipient, &vacation, &self.config.hostname) {
+                            outgoing.push((String::new(), msg.mail_from(), reply));
+                        }
                     }
-                    //parts = line.splitn(2,":").collect();                    
-                    line = l;
-                },
-                Err(e)=>{
+                }
+            }
+        }
+
+        let refusal = |reason: &str| format!("550 5.7.1 {}", reason.trim_end().replace("\r\n", " "));
+        if rejections.len() == msg.forward_paths.len() && copies.is_empty() && outgoing.is_empty() {
+            let reply = refusal(&rejections[0].1);
+            self.write_final(msg, &reply);
+            self.log_info("Rejected by Sieve", Some(format_args!("{} {}", msg.queue_id, reply)));
+            self.config.metrics.message("rejected");
+            return None;
+        }
+        // LMTP can refuse the RCPT TOs whose every recipient rejected the
+        // message; the client then reports those itself.
+        let mut refused = Vec::new();
+        let mut refused_recipients = Vec::new();
+        if self.config.lmtp {
+            let recipients = msg.recipients();
+            for rcpt in 0..msg.rcpt_count {
+                let targets: Vec<&String> = recipients.iter().zip(&msg.rcpt_numbers).filter(|(_, n)| **n == rcpt).map(|(r, _)| r).collect();
+                let rejected = |target: &&String| rejections.iter().any(|(r, _)| r == *target) && !copies.iter().any(|(r, _)| r == *target);
+                if !targets.is_empty() && targets.iter().all(rejected) {
+                    let reason = rejections.iter().find(|(r, _)| r == targets[0]).map(|(_, reason)| reason.as_str()).unwrap_or("");
+                    refused.push((rcpt, refusal(reason)));
+                    self.log_info("Rejected by Sieve", Some(format_args!("{} for {}", msg.queue_id, targets[0])));
+                    refused_recipients.extend(targets.into_iter().cloned());
+                }
+            }
+        }
+        if !msg.mail_from().is_empty() {
+            for (recipient, reason) in rejections.iter().filter(|(recipient, _)| !refused_recipients.contains(recipient)) {
+                outgoing.push((String::new(), msg.mail_from(), sieve::rejection_notice(msg, recipient, reason, &self.config.hostname)));
+            }
+        }
+
+        Some(Filing { copies, per_recipient: true, outgoing, refused })
+    }
+
+    /// Files the message as `filing` says. On failure the client is told
+    /// to retry later and false is returned.
+    fn file(&mut self, msg: &Message, filing: Filing) -> bool {
+        if !filing.per_recipient {
+            if !self.store_message(msg, false) {
+                return false;
+            }
+        } else if let Some(store) = self.config.store.clone() {
+            for (recipient, folder) in &filing.copies {
+                let copy = StoredMessage {
+                    id: trace::new_queue_id(),
+                    recipients: vec![recipient.clone()],
+                    folder: folder.clone(),
+                    ..StoredMessage::from_message(msg, false)
+                };
+                if let Err(e) = store.add(copy) {
                     self.log_error(e);
+                    self.write_final(msg, "451 4.3.0 Could not store message, try again later");
+                    return false;
                 }
+            }
+        }
+        if !self.deliver_to_mailboxes(msg, &filing.copies) {
+            return false;
+        }
+        for (mail_from, to, content) in filing.outgoing {
+            let id = self.send_generated(&mail_from, &to, content);
+            self.log_info("Sieve", Some(format_args!("{} sent to {} for {}", id, to, msg.queue_id)));
+        }
+        true
+    }
 
+    fn sign_dkim(&self, msg: &mut Message) {
+        if let Some(signer) = &self.config.dkim_signer {
+            match signer.sign(&msg.headers, &msg.body) {
+                Ok(signature) => msg.prepend_header("DKIM-Signature", &signature),
+                Err(e) => self.log_error(e),
             }
-            let parts:Vec<&str> = line.splitn(2, ":").collect();
+        }
+    }
 
-            if parts.len() != 2{
-                continue;
+    /// Reads the message content up to the terminating `.` line and undoes
+    /// dot-stuffing. The returned content ends with the CRLF of its last line.
+    /// Content over `max_message_size` is read to its end but not kept, and
+    /// `None` returned.
+    fn read_to_end_of_body(&mut self) -> Result<Option<Vec<u8>>>{
+        let max = self.config.max_message_size;
+        let mut too_big = false;
+        loop{
+            if too_big {
+                if let Some(i) = self.buf.windows(5).position(|w| w == b"\r\n.\r\n") {
+                    self.buf.drain(..i + 5);
+                    return Ok(None);
+                }
+                // Only the start of a terminator split across reads is kept.
+                self.buf.drain(..self.buf.len().saturating_sub(4));
+            } else if let Some((content, consumed)) = split_data_body(&self.buf) {
+                self.buf.drain(..consumed);
+                return Ok(Some(content).filter(|content| max.is_none_or(|max| content.len() <= max)));
+            } else if let Some(max) = max.filter(|&max| self.buf.len() > max) {
+                let buf = &self.buf;
+                let stuffed = buf.iter().enumerate().filter(|&(i, &b)| b == b'.' && (i == 0 || buf[i - 1] == b'\n')).count();
+                if buf.len() - stuffed > max {
+                    too_big = true;
+                    continue;
+                }
+            }
+
+            let mut b = [0;1024];
+            let n = self.stream.read(&mut b)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed during DATA"));
+            }
+            self.buf.extend_from_slice(&b[..n]);
+        }
+    }
+
+    /// Reads exactly `size` octets of BDAT data into `chunk`, or discards
+    /// them when there is nowhere to put them.
+    fn read_chunk(&mut self, size: usize, mut chunk: Option<&mut Vec<u8>>) -> Result<()> {
+        let buffered = size.min(self.buf.len());
+        let drained = self.buf.drain(..buffered);
+        if let Some(chunk) = chunk.as_mut() {
+            chunk.extend(drained);
+        } else {
+            drop(drained);
+        }
+        let mut remaining = size - buffered;
+        let mut b = [0; 8192];
+        while remaining > 0 {
+            let n = self.stream.read(&mut b[..remaining.min(8192)])?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed during BDAT"));
+            }
+            if let Some(chunk) = chunk.as_mut() {
+                chunk.extend_from_slice(&b[..n]);
+            }
+            remaining -= n;
+        }
+        Ok(())
+    }
+
+    /// Refuses a message over `max_message_size`, whose content has been
+    /// read and dropped, and ends the transaction. Under LMTP only the reply
+    /// ending the message is repeated for each recipient.
+    fn refuse_too_big(&mut self, msg: &mut Message, last: bool) {
+        let reply = "552 5.3.4 Message size exceeds fixed maximum message size";
+        if last {
+            self.write_final(msg, reply);
+        } else {
+            let _ = self.write_line(reply);
+        }
+        let max = self.config.max_message_size.unwrap_or_default();
+        self.log_info("Rejected", Some(format_args!("{} is over {} octets", msg.queue_id, max)));
+        self.config.metrics.message("rejected");
+        self.milter_abort(msg);
+        *msg = Message::new(&msg.client_domain);
+    }
+
+    /// Handles `BDAT <size> [LAST]` (RFC 3030), adding the chunk to
+    /// `content`. Returns true once the last chunk has been read; its reply
+    /// waits until the message has been dealt with.
+    fn bdat(&mut self, msg: &mut Message, args: &str, content: &mut Vec<u8>) -> Result<bool> {
+        let mut args = args.split_whitespace();
+        let size = args.next().and_then(|s| s.parse::<usize>().ok());
+        let last = match args.next() {
+            Some(arg) if arg.eq_ignore_ascii_case("LAST") => true,
+            Some(_) => {
+                let _ = self.write_line("501 5.5.4 Syntax: BDAT <size> [LAST]");
+                return Ok(false);
             }
+            None => false,
+        };
+        let size = match size {
+            Some(size) if args.next().is_none() => size,
+            _ => {
+                let _ = self.write_line("501 5.5.4 Syntax: BDAT <size> [LAST]");
+                return Ok(false);
+            }
+        };
+        if msg.forward_paths.is_empty() {
+            self.read_chunk(size, None)?;
+            self.write_line("503 5.5.1 RCPT TO first")?;
+            return Ok(false);
+        }
+        if self.config.max_message_size.is_some_and(|max| content.len().saturating_add(size) > max) {
+            self.read_chunk(size, None)?;
+            self.refuse_too_big(msg, last);
+            content.clear();
+            return Ok(false);
+        }
+        self.read_chunk(size, Some(content))?;
+        if !last {
+            self.write_line(&format!("250 2.0.0 {} octets received", size))?;
+        }
+        Ok(last)
+    }
 
-            let command = parts[0].to_uppercase();
-            let value = parts[1].trim().to_string();
+    pub fn handle(&mut self) {
+        let start = Instant::now();
+        self.config.metrics.connection_opened();
+        self.session();
+        self.config.metrics.connection_closed(start.elapsed());
+    }
+
+    fn session(&mut self) {
+        println!("Handling connection {}", self.id);
 
-            if command == "DATA"{
-                if let Err(e) = self.write_line("354"){
+        if let Err(e) = self.stream.set_read_timeout(self.config.timeout) {
+            self.log_error(e);
+            return
+        }
+        if self.config.proxy_protocol {
+            if let Err(e) = self.read_proxy_header() {
+                self.log_error(e);
+                return
+            }
+        }
+
+        self.command = "CONNECT";
+        if let Some(reply) = self.milter_connect() {
+            if let Err(e) = self.write_line(&reply) {
+                self.log_error(e);
+            }
+            self.milter_quit();
+            return;
+        }
+        if let Err(e) = self.write_line("220") {
+            self.log_error(e);
+        }
+
+        let greeting = if self.config.lmtp { "LHLO" } else { "EHLO" };
+        self.log_info("Awaiting", Some(format_args!("{}", greeting)));
+        let client_domain = loop {
+            let l = match self.read_line(){
+                Ok(l) => l,
+                Err(e)=>{
                     self.log_error(e);
+                    return
+                }
+            };
+            self.command = metrics::command_name(&l);
+            let command = Command::parse(&l);
+            if !self.check_greeting(&command) {
+                continue;
+            }
+            match self.greet(command) {
+                Some(domain) => break domain,
+                None => {
+                    self.log_error(Error::other(format!("Expected {} Got: {}",greeting,l)));
+                    return
                 }
-                 
-                break;
             }
-            msg.smtp_commands.insert(command,value);
+        };
+
+        self.log_info("Done EHLO", None);
+
+        let mut msg = Message::new(&client_domain);
+        while let Some(content) = self.transaction(&mut msg) {
+            let next = Message::new(&msg.client_domain);
+            self.deliver(std::mem::replace(&mut msg, next), content);
         }
+        self.milter_quit();
+    }
 
-        self.log_info("Done SMTP headers, reading ARPA text message headers",None);
+    /// Connects to the milter and tells it about the client. Returns the
+    /// reply that turns the client away, if the filter refuses it.
+    fn milter_connect(&mut self) -> Option<String> {
+        let milter = self.config.milter.as_ref()?;
+        match milter.connect() {
+            Ok(client) => self.milter = Some(client),
+            Err(e) => return self.milter_unavailable(Some(e)).map(|_| "421 4.3.0 Mail filter unavailable".to_string()),
+        }
+        let addr = self.client_addr.or_else(|| self.stream.peer_addr());
+        let hostname = addr
+            .and_then(|addr| dns::confirmed_name(self.config.resolver.as_ref(), addr.ip()))
+            .unwrap_or_else(|| match addr {
+                Some(addr) => format!("[{}]", addr.ip()),
+                None => "localhost".to_string(),
+            });
+        let reply = self.milter_check(|milter| milter.connect(&hostname, addr), "554 5.7.1 Connection rejected")?;
+        // The connection is closed either way, which a 4xx must say with 421.
+        Some(match reply.strip_prefix('4') {
+            Some(_) => format!("421{}", reply.get(3..).unwrap_or("")),
+            None => reply,
+        })
+    }
 
-        loop {
-           
-            let mut line = String::new();
-            match self.read_multiline(){
-                Ok(l) => {
+    /// Ends the session with the milter, if there is one.
+    fn milter_quit(&mut self) {
+        if let Some(Err(e)) = self.milter.take().map(MilterClient::quit) {
+            self.log_error(e);
+        }
+    }
+
+    /// Replies 501 to a greeting that gives neither a domain nor an address
+    /// literal, which would otherwise end up in the `Received` header.
+    /// Anything else passes.
+    fn check_greeting(&mut self, command: &Command) -> bool {
+        match command {
+            Command::Ehlo(domain) | Command::Helo(domain) | Command::Lhlo(domain) if !address::is_client_domain(domain) => {
+                let _ = self.write_line("501 5.5.4 Invalid domain name");
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Answers a greeting allowed in this mode and returns the client's
+    /// domain, or returns `None` without replying.
+    fn greet(&mut self, command: Command) -> Option<String> {
+        let domain = match (command, self.config.lmtp) {
+            (Command::Ehlo(domain), false) | (Command::Lhlo(domain), true) => {
+                self.esmtp = true;
+                let ehlo = self.ehlo_reply();
+                if let Err(e) = self.write_multiline("250", &ehlo){
+                    self.log_error(e);
+                }
+                domain
+            }
+            (Command::Helo(domain), false) => {
+                self.esmtp = false;
+                let hostname = self.config.hostname.clone();
+                if let Err(e) = self.write_line(&format!("250 {}", hostname)) {
+                    self.log_error(e);
+                }
+                domain
+            }
+            _ => return None,
+        };
+        // A refusal only shows at MAIL FROM, as Postfix does it, so that the
+        // client can still QUIT politely.
+        if self.config.milter.is_some() {
+            self.helo_refusal = self.milter_check(|milter| milter.helo(&domain), "550 5.7.1 Greeting rejected");
+        }
+        Some(domain)
+    }
+
+    /// Runs commands until a whole message has arrived, returning its
+    /// content, or `None` once the session is over. RSET and a new greeting
+    /// start `msg` afresh.
+    fn transaction(&mut self, msg: &mut Message) -> Option<Vec<u8>> {
+        let mut chunks = Vec::new();
+        loop{
+            let line = match self.read_line(){
+                Ok(l) =>{
+                    self.command = metrics::command_name(&l);
                     if l.trim().is_empty(){
-                        self.log_error(Error::new(ErrorKind::Other, format!("lLine is empty at reading ARPA {}",l)));
-                        break;
+                        self.log_error(Error::other(format!("Line is empty {}",l)));
+                        let _ = self.write_line("500 5.5.2 Empty command");
+                        continue;
                     }
-                    line = l;
+                    l
                 },
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    self.command = "UNKNOWN";
+                    let _ = self.write_line("500 5.6.7 Command is not valid UTF-8");
+                    continue;
+                }
                 Err(e)=>{
                     self.log_error(e);
+                    return None;
+                }
+
+            };
+
+            match Command::parse(&line) {
+                Command::Data => {
+                    if msg.body_type == BodyType::BinaryMime || !chunks.is_empty() {
+                        let _ = self.write_line("503 5.5.1 Use BDAT for this message");
+                        continue;
+                    }
+                    if msg.forward_paths.is_empty() {
+                        let _ = self.write_line("503 5.5.1 RCPT TO first");
+                        continue;
+                    }
+                    if let Err(e) = self.write_line("354"){
+                        self.log_error(e);
+                    }
+
+                    match self.read_to_end_of_body(){
+                        Ok(Some(content)) => return Some(content),
+                        Ok(None) => self.refuse_too_big(msg, true),
+                        Err(e) => {
+                            self.log_error(e);
+                            return None;
+                        }
+                    }
+                }
+                Command::Bdat(args) => match self.bdat(msg, &args, &mut chunks) {
+                    Ok(true) => return Some(chunks),
+                    Ok(false) => continue,
+                    Err(e) => {
+                        self.log_error(e);
+                        return None;
+                    }
                 },
-            }        
-            let pieces:Vec<&str> = line.splitn(2, ":").collect();
+                Command::Mail(value) => {
+                    self.mail_from(msg, &value);
+                    msg.smtp_commands.insert("MAIL FROM".to_string(), value);
+                }
+                Command::Rcpt(value) => {
+                    self.rcpt_to(msg, &value);
+                    msg.smtp_commands.insert("RCPT TO".to_string(), value);
+                }
+                command @ (Command::Ehlo(_) | Command::Helo(_) | Command::Lhlo(_)) => {
+                    if !self.check_greeting(&command) {
+                        continue;
+                    }
+                    if matches!((&command, self.config.lmtp), (Command::Ehlo(_) | Command::Helo(_), false) | (Command::Lhlo(_), true)) {
+                        self.milter_abort(msg);
+                    }
+                    match self.greet(command) {
+                        Some(domain) => {
+                            *msg = Message::new(&domain);
+                            chunks.clear();
+                        }
+                        None => {
+                            let _ = self.write_line("500 5.5.1 Command not recognized");
+                        }
+                    }
+                }
+                Command::Rset => {
+                    self.milter_abort(msg);
+                    *msg = Message::new(&msg.client_domain);
+                    chunks.clear();
+                    let _ = self.write_line("250 2.0.0 OK");
+                }
+                Command::Noop => {
+                    let _ = self.write_line("250 2.0.0 OK");
+                }
+                Command::Vrfy(arg) => self.verify(&arg, false),
+                Command::Expn(arg) => self.verify(&arg, true),
+                Command::Auth(args) => {
+                    if let Err(e) = self.authenticate(msg, &args) {
+                        self.log_error(e);
+                        return None;
+                    }
+                }
+                Command::StartTls => {
+                    if let Err(e) = self.start_tls() {
+                        self.log_error(e);
+                        return None;
+                    }
+                    // The client starts over with a new greeting.
+                    *msg = Message::new(&msg.client_domain);
+                    chunks.clear();
+                }
+                Command::Help => {
+                    let _ = self.write_line("214 2.0.0 Commands: EHLO HELO MAIL RCPT DATA BDAT RSET NOOP QUIT VRFY EXPN HELP AUTH STARTTLS");
+                }
+                Command::Quit => {
+                    let reply = format!("221 2.0.0 {} closing connection", self.config.hostname);
+                    let _ = self.write_line(&reply);
+                    return None;
+                }
+                Command::Unknown(_) => {
+                    let _ = self.write_line("500 5.5.2 Command not recognized");
+                }
+            }
+        }
+    }
 
-            let atm_headers = pieces[0].to_uppercase();
-            let atm_value = pieces[1].trim().to_string(); // so it owns and not just the reference
-            msg.atm_headers.insert(atm_headers.clone(),atm_value.clone());
+    /// Checks, traces and hands a received message to the handler, then
+    /// sends the final reply.
+    fn deliver(&mut self, mut msg: Message, content: Vec<u8>) {
+        self.log_info("Received message content",Some(format_args!("{} octets", content.len())));
+        self.config.metrics.message_size.observe(content.len() as f64);
+        msg.set_content(&content);
+        msg.client_ip = self.peer_ip();
+        if self.config.submission && !self.fix_up_submission(&mut msg) {
+            return;
+        }
+        self.check_authentication(&mut msg);
+        // Authentication judged the sender the client gave; from here on
+        // it is the canonical one.
+        if let (Some(rewriter), Some(ReversePath::Mailbox(sender))) = (&self.config.rewriter, &msg.reverse_path) {
+            let canonical = rewriter.tables().sender(sender);
+            if &canonical != sender {
+                self.log_info("Rewrote sender", Some(format_args!("{} to {}", sender, canonical)));
+                msg.reverse_path = Some(ReversePath::Mailbox(canonical));
+            }
+        }
 
-            if atm_headers == "SUBJECT" {
-                msg.subject = atm_value;
-            } else if atm_headers == "TO"{
-                msg.to = atm_value;
-            } else if atm_headers == "FROM"{
-                msg.from = atm_value;
-            }else if atm_headers == "DATE"{
-                msg.date = atm_value;
+        let mut quarantine = None;
+        if self.config.milter.is_some() {
+            let (mut response, mut modifications) = (Response::Continue, Vec::new());
+            let refusal = self.milter_check(|milter| {
+                (response, modifications) = milter.message(&msg.headers, &msg.body)?;
+                Ok(response.clone())
+            }, "550 5.7.1 Message rejected by content filter");
+            if let Some(reply) = refusal {
+                self.write_final(&msg, &reply);
+                self.log_info("Refused by milter", Some(format_args!("{} {}", msg.queue_id, reply)));
+                self.config.metrics.message(if reply.starts_with('4') { "deferred" } else { "rejected" });
+                return;
+            }
+            if response == Response::Discard {
+                // The client must not learn that the message went nowhere.
+                self.write_final(&msg, &format!("250 OK queued as {}", msg.queue_id));
+                self.log_info("Discarded by milter", Some(format_args!("{}", msg.queue_id)));
+                self.config.metrics.message("discarded");
+                return;
+            }
+            if !modifications.is_empty() {
+                quarantine = milter::apply(modifications, &mut msg.headers, &mut msg.body);
+                let content = msg.content();
+                msg.replace_content(&content);
             }
         }
-        self.log_info("Done ARPA text message headers, reading body", None);
 
-        match self.read_to_end_of_body(){
-            Ok(body)=> {
-                let _ = self.write_line("250 OK");
-                self.log_info(&format!("Message: {} \n", body),None);
+        self.sign_dkim(&mut msg);
+        self.stamp_received(&mut msg);
+
+        let hops = msg.headers.count("Received");
+        if hops > self.config.max_hops {
+            self.write_final(&msg, "554 5.4.6 Too many hops, mail loop detected");
+            self.log_info("Rejected", Some(format_args!("{} looping after {} hops", msg.queue_id, hops)));
+            self.config.metrics.message("rejected");
+            return;
+        }
+
+        // This server is the final hop, so record the envelope sender.
+        msg.headers.remove("Return-Path");
+        msg.prepend_header("Return-Path", &trace::return_path(&msg.mail_from()));
+
+        let handler_start = Instant::now();
+        let verdict = self.config.handler.handle(&mut msg);
+        self.config.metrics.handler_latency.observe(handler_start.elapsed().as_secs_f64());
+        let verdict = match (verdict, quarantine) {
+            (Verdict::Accept, Some(reason)) => Verdict::Quarantine(reason),
+            (verdict, _) => verdict,
+        };
+        match verdict {
+            Verdict::Accept => {
+                let filing = match self.config.sieve.clone() {
+                    Some(sieve) => match self.sieve_filing(&msg, &sieve) {
+                        Some(filing) => filing,
+                        None => return,
+                    },
+                    None => Filing::inbox(&msg),
+                };
+                // The webhook goes first: once anything is filed, a retry
+                // would file it again.
+                let refused = filing.refused.clone();
+                if self.post_to_webhook(&msg) && self.file(&msg, filing) {
+                    self.config.metrics.message("accepted");
+                    self.notify_delivered(&msg);
+                    self.write_final_except(&msg, &refused, &format!("250 OK queued as {}", msg.queue_id));
+                    self.log_info(&format!("Message: {}\r\n{} \n", msg.headers, String::from_utf8_lossy(&msg.body)),None);
+                }
+            },
+            Verdict::Quarantine(reason) => {
+                self.config.metrics.message("quarantined");
+                if self.store_message(&msg, true) {
+                    self.notify_delivered(&msg);
+                    self.write_final(&msg, &format!("250 OK queued as {}", msg.queue_id));
+                    self.log_info("Quarantined", Some(format_args!("{}", reason)));
+                    self.log_info(&format!("Message: {}\r\n{} \n", msg.headers, String::from_utf8_lossy(&msg.body)),None);
+                }
+            },
+            Verdict::Reject(reason) => {
+                self.config.metrics.message("rejected");
+                self.write_final(&msg, &format!("550 {}", reason));
+                self.log_info("Rejected", Some(format_args!("{}", reason)));
             },
-            Err(e)=>{
-                self.log_error(e);
-            }
         }
+    }
+}
 
+/// Finds the end of a DATA body in `buf`, the line holding only `.`, and
+/// returns the content before it with dot-stuffing undone, along with how
+/// many bytes of `buf` that took. `None` until the end has arrived.
+pub fn split_data_body(buf: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let (content_end, consumed) = if buf.starts_with(b".\r\n") {
+        (0, 3)
+    } else {
+        buf.windows(5).position(|w| w == b"\r\n.\r\n").map(|i| (i + 2, i + 5))?
+    };
+    let mut content = Vec::with_capacity(content_end);
+    let mut at_line_start = true;
+    for &b in &buf[..content_end] {
+        if !(at_line_start && b == b'.') {
+            content.push(b);
+        }
+        at_line_start = b == b'\n';
     }
+    Some((content, consumed))
+}
+
+/// Lower cases the domain of `address` and converts it to its ASCII form, so
+/// that IDN domains compare equal however the client spelled them. The local
+/// part keeps its case. The null sender stays empty.
+pub fn normalize_address(address: &str) -> Result<String> {
+    if address.is_empty() {
+        return Ok(String::new());
+    }
+    Mailbox::parse(address).map(|mailbox| mailbox.to_string())
 }
 
 pub fn run_server() -> Result<()> {
     let listener = TcpListener::bind("0.0.0.0:2525")?;
     println!("Listening on port 2525");
+    let config = Arc::new(Config::default());
+    #[cfg(feature = "metrics")]
+    metrics::spawn("0.0.0.0:9025", config.metrics.clone())?;
+    serve(listener, config)
+}
+
+/// Accepts connections on `listener` forever, one thread per connection.
+pub fn serve(listener: TcpListener, config: Arc<Config>) -> Result<()> {
+    serve_with(listener, move || config.clone())
+}
+
+/// Like `serve`, asking `config` for the settings of each new connection,
+/// so that they can be replaced while serving.
+pub fn serve_with(listener: TcpListener, config: impl Fn() -> Arc<Config>) -> Result<()> {
+    accept(listener.incoming().map(|stream| stream.map(Stream::Tcp)), config)
+}
+
+/// Like `serve`, for clients on a Unix domain socket, which is how an MTA
+/// usually hands mail to an LMTP server.
+#[cfg(unix)]
+pub fn serve_unix(listener: UnixListener, config: Arc<Config>) -> Result<()> {
+    serve_unix_with(listener, move || config.clone())
+}
+
+/// Like `serve_with`, for clients on a Unix domain socket.
+#[cfg(unix)]
+pub fn serve_unix_with(listener: UnixListener, config: impl Fn() -> Arc<Config>) -> Result<()> {
+    accept(listener.incoming().map(|stream| stream.map(Stream::Unix)), config)
+}
 
+fn accept(incoming: impl Iterator<Item = Result<Stream>>, config: impl Fn() -> Arc<Config>) -> Result<()> {
     let mut id = 0;
-    for stream in listener.incoming() {
+    for stream in incoming {
         match stream {
             Ok(stream) => {
                 id += 1;
@@ -244,6 +1759,14 @@ pub fn run_server() -> Result<()> {
                     stream,
                     id,
                     buf: Vec::new(),
+                    config: config(),
+                    tls: false,
+                    auth_user: None,
+                    client_addr: None,
+                    command: "CONNECT",
+                    esmtp: true,
+                    milter: None,
+                    helo_refusal: None,
                 };
                 thread::spawn(move || {
                     connection.handle();
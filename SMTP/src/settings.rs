@@ -0,0 +1,708 @@
+//! The server's TOML configuration file, and running the listeners it
+//! describes.
+//!
+//! ```toml
+//! hostname = "mx.example.com"
+//!
+//! [[listener]]
+//! address = "0.0.0.0:25"
+//!
+//! [[listener]]
+//! address = "0.0.0.0:587"
+//! protocol = "submission"     # or smtp, lmtp, pop3, imap
+//!
+//! [tls]
+//! cert = "/etc/smtp/cert.pem"
+//! key = "/etc/smtp/key.pem"
+//!
+//! [auth]
+//! password_file = "/etc/smtp/passwords"   # user:password lines
+//!
+//! [storage]
+//! backend = "directory"       # none, memory or directory
+//! path = "/var/spool/smtp"
+//! mailboxes = "/var/mail"     # for POP3 and IMAP
+//!
+//! [policy]
+//! spam_rules = "/etc/smtp/spam.rules"
+//! rewrite_table = "/etc/smtp/aliases"
+//!
+//! [limits]
+//! max_message_size = 26214400
+//! timeout = 300
+//! ```
+//!
+//! Unknown keys are errors, so that typos do not go unnoticed. A reload
+//! applies everything but the listeners, storage and HTTP endpoints, which
+//! need a restart. A greylist whose settings change starts over unless it
+//! keeps its state in a `greylist_file`, and a new `sieve_dir` forgets whom
+//! `vacation` has answered.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use rustls::ServerConfig;
+use serde::Deserialize;
+
+use crate::auth::{Credentials, MemoryCredentials};
+use crate::directory::MemoryDirectory;
+use crate::dkim::{self, SigningKey};
+use crate::greylist::Greylist;
+use crate::handler::{DmarcHandler, MessageHandler};
+use crate::mailbox::MailboxStore;
+use crate::metrics::Metrics;
+use crate::milter::{Milter, MilterAddr};
+use crate::rewrite::Rewriter;
+use crate::server;
+use crate::sieve::{ScriptDir, Sieve};
+use crate::spam::{SpamHandler, SpamRules};
+use crate::store::{DirStore, MemoryStore, MessageStore};
+use crate::webhook::Webhook;
+use crate::{imap, pop3, tls, trace};
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Prefixes an error with what was being done.
+fn context<T>(result: Result<T>, what: impl Display) -> Result<T> {
+    result.map_err(|e| Error::new(e.kind(), format!("{}: {}", what, e)))
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(default = "default_hostname")]
+    pub hostname: String,
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerSettings>,
+    pub tls: Option<TlsSettings>,
+    #[serde(default)]
+    pub auth: AuthSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
+    #[serde(default)]
+    pub directory: DirectorySettings,
+    #[serde(default)]
+    pub policy: PolicySettings,
+    #[serde(default)]
+    pub limits: LimitSettings,
+    pub webhook: Option<WebhookSettings>,
+    #[serde(default)]
+    pub http: HttpSettings,
+}
+
+fn default_hostname() -> String {
+    "localhost".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Smtp,
+    Lmtp,
+    /// SMTP for a mail submission agent: clients must authenticate.
+    Submission,
+    Pop3,
+    Imap,
+}
+
+/// Where to accept connections: a TCP `address`, or for SMTP and LMTP a
+/// Unix socket `path`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSettings {
+    pub address: Option<String>,
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Expect a HAProxy PROXY protocol header; SMTP and LMTP only.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Who may authenticate: users from a password file and listed here.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthSettings {
+    pub password_file: Option<PathBuf>,
+    #[serde(default)]
+    pub users: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    None,
+    Memory,
+    Directory,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageSettings {
+    #[serde(default)]
+    pub backend: Backend,
+    /// The directory of the `directory` backend.
+    pub path: Option<PathBuf>,
+    /// Root of the per-user mailboxes that POP3 and IMAP serve.
+    pub mailboxes: Option<PathBuf>,
+}
+
+/// Who receives mail here. Without users or lists, anyone does.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectorySettings {
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub lists: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub vrfy: bool,
+    #[serde(default)]
+    pub expn: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicySettings {
+    #[serde(default)]
+    pub greylist: bool,
+    /// Where greylisting state is kept across restarts.
+    pub greylist_file: Option<PathBuf>,
+    /// Seconds a greylisted client must wait before retrying.
+    pub greylist_delay: Option<u64>,
+    pub spam_rules: Option<PathBuf>,
+    /// Directory of `<address>.sieve` scripts.
+    pub sieve_dir: Option<PathBuf>,
+    pub rewrite_table: Option<PathBuf>,
+    /// `host:port`, or the path of a Unix socket.
+    pub milter: Option<String>,
+    #[serde(default)]
+    pub milter_fail_open: bool,
+    pub dkim: Option<DkimSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DkimSettings {
+    pub domain: String,
+    pub selector: String,
+    /// PEM private key, RSA or Ed25519.
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitSettings {
+    #[serde(default = "default_max_hops")]
+    pub max_hops: usize,
+    /// In octets.
+    pub max_message_size: Option<usize>,
+    /// Seconds to wait for each command before hanging up.
+    pub timeout: Option<u64>,
+}
+
+fn default_max_hops() -> usize {
+    trace::DEFAULT_MAX_HOPS
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        LimitSettings { max_hops: default_max_hops(), max_message_size: None, timeout: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookSettings {
+    pub url: String,
+    /// Key for the `X-Webhook-Signature` HMAC.
+    pub secret: Option<String>,
+    pub attempts: Option<u32>,
+    /// Milliseconds before the first retry.
+    pub backoff: Option<u64>,
+    /// Seconds to wait for the endpoint.
+    pub timeout: Option<u64>,
+}
+
+/// Addresses for the HTTP API and the Prometheus metrics, which need the
+/// `http-api` and `metrics` features.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpSettings {
+    pub api: Option<String>,
+    pub metrics: Option<String>,
+}
+
+/// Without a file: SMTP on port 2525, and with the `http-api` and
+/// `metrics` features their endpoints on 8025 and 9025 and mail kept in
+/// memory.
+impl Default for Settings {
+    fn default() -> Self {
+        let http_api = cfg!(feature = "http-api");
+        Settings {
+            hostname: default_hostname(),
+            listeners: vec![ListenerSettings { address: Some("0.0.0.0:2525".to_string()), ..ListenerSettings::default() }],
+            tls: None,
+            auth: AuthSettings::default(),
+            storage: StorageSettings {
+                backend: if http_api { Backend::Memory } else { Backend::None },
+                ..StorageSettings::default()
+            },
+            directory: DirectorySettings::default(),
+            policy: PolicySettings::default(),
+            limits: LimitSettings::default(),
+            webhook: None,
+            http: HttpSettings {
+                api: http_api.then(|| "0.0.0.0:8025".to_string()),
+                metrics: cfg!(feature = "metrics").then(|| "0.0.0.0:9025".to_string()),
+            },
+        }
+    }
+}
+
+/// What lives as long as the process: storage, greylisting state, the
+/// Sieve runner with its record of vacation replies, and counters, kept
+/// across reloads.
+#[derive(Clone)]
+pub struct Shared {
+    pub store: Option<Arc<dyn MessageStore>>,
+    pub mailboxes: Option<Arc<MailboxStore>>,
+    pub greylist: Option<Arc<Greylist>>,
+    pub sieve: Option<Arc<Sieve>>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl Settings {
+    pub fn parse(text: &str) -> Result<Settings> {
+        let settings: Settings = toml::from_str(text).map_err(|e| invalid(e.to_string().trim_end().to_string()))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Settings> {
+        let path = path.as_ref();
+        context(fs::read_to_string(path).and_then(|text| Settings::parse(&text)), path.display())
+    }
+
+    /// Checks what can be checked without touching the file system.
+    fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            return Err(invalid("no [[listener]]".to_string()));
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let what = |msg: &str| invalid(format!("listener {}: {}", i + 1, msg));
+            let tcp_only = matches!(listener.protocol, Protocol::Pop3 | Protocol::Imap | Protocol::Submission);
+            match (&listener.address, &listener.path) {
+                (Some(_), None) => {}
+                (None, Some(_)) if !tcp_only => {}
+                (None, Some(_)) => return Err(what("only smtp and lmtp can listen on a Unix socket")),
+                _ => return Err(what("needs an address or a path, not both")),
+            }
+            if listener.proxy_protocol && matches!(listener.protocol, Protocol::Pop3 | Protocol::Imap) {
+                return Err(what("proxy_protocol is only for smtp, lmtp and submission"));
+            }
+            match listener.protocol {
+                Protocol::Pop3 | Protocol::Imap if self.storage.mailboxes.is_none() => {
+                    return Err(what("pop3 and imap need storage.mailboxes"));
+                }
+                Protocol::Pop3 | Protocol::Imap | Protocol::Submission if !self.has_credentials() => {
+                    return Err(what("pop3, imap and submission need [auth] users"));
+                }
+                _ => {}
+            }
+        }
+        if self.storage.backend == Backend::Directory && self.storage.path.is_none() {
+            return Err(invalid("storage: the directory backend needs a path".to_string()));
+        }
+        if self.http.api.is_some() {
+            if !cfg!(feature = "http-api") {
+                return Err(invalid("http.api: built without the http-api feature".to_string()));
+            }
+            if self.storage.backend == Backend::None {
+                return Err(invalid("http.api: needs a storage backend".to_string()));
+            }
+        }
+        if self.http.metrics.is_some() && !cfg!(feature = "metrics") {
+            return Err(invalid("http.metrics: built without the metrics feature".to_string()));
+        }
+        Ok(())
+    }
+
+    fn has_credentials(&self) -> bool {
+        self.auth.password_file.is_some() || !self.auth.users.is_empty()
+    }
+
+    /// Validates everything a start would use, reading the files named but
+    /// creating nothing.
+    pub fn check(&self) -> Result<()> {
+        self.validate()?;
+        for listener in &self.listeners {
+            if let Some(address) = &listener.address {
+                context(address.to_socket_addrs().map(|_| ()), format!("listener {}", address))?;
+            }
+        }
+        for (what, path) in [("storage.path", &self.storage.path), ("storage.mailboxes", &self.storage.mailboxes)] {
+            let path = match path {
+                Some(path) => path,
+                None => continue,
+            };
+            let usable = match fs::metadata(path) {
+                Ok(meta) => meta.is_dir(),
+                // It will be created, if its parent is there.
+                Err(_) => path.parent().is_none_or(|parent| parent.as_os_str().is_empty() || parent.is_dir()),
+            };
+            if !usable {
+                return Err(invalid(format!("{}: {} is not a directory that can be used", what, path.display())));
+            }
+        }
+        for listener in &self.listeners {
+            if matches!(listener.protocol, Protocol::Smtp | Protocol::Lmtp | Protocol::Submission) {
+                let shared = Shared { store: None, mailboxes: None, greylist: None, sieve: None, metrics: Arc::new(Metrics::new()) };
+                self.smtp_config(listener, &shared)?;
+            }
+        }
+        self.tls_config()?;
+        self.credentials()?;
+        if let Some(path) = &self.policy.greylist_file {
+            context(Greylist::open(path).map(|_| ()), format!("policy.greylist_file {}", path.display()))?;
+        }
+        self.sieve()?;
+        Ok(())
+    }
+
+    /// Opens the storage, the greylist and the Sieve runner.
+    pub fn open(&self) -> Result<Shared> {
+        let store: Option<Arc<dyn MessageStore>> = match self.storage.backend {
+            Backend::None => None,
+            Backend::Memory => Some(Arc::new(MemoryStore::new())),
+            Backend::Directory => {
+                let path = self.storage.path.clone().unwrap_or_default();
+                Some(Arc::new(context(DirStore::new(&path), format!("storage.path {}", path.display()))?))
+            }
+        };
+        let mailboxes = match &self.storage.mailboxes {
+            Some(path) => Some(Arc::new(context(MailboxStore::new(path), format!("storage.mailboxes {}", path.display()))?)),
+            None => None,
+        };
+        Ok(Shared { store, mailboxes, greylist: self.greylist()?, sieve: self.sieve()?, metrics: Arc::new(Metrics::new()) })
+    }
+
+    fn greylist(&self) -> Result<Option<Arc<Greylist>>> {
+        let greylist = match (&self.policy.greylist_file, self.policy.greylist) {
+            (Some(path), _) => Some(context(Greylist::open(path), format!("policy.greylist_file {}", path.display()))?),
+            (None, true) => Some(Greylist::new()),
+            (None, false) => None,
+        };
+        Ok(greylist.map(|mut greylist| {
+            if let Some(delay) = self.policy.greylist_delay {
+                greylist.delay = Duration::from_secs(delay);
+            }
+            Arc::new(greylist)
+        }))
+    }
+
+    /// Whether the greylist of `other` differs from this one's.
+    fn greylist_changed(&self, other: &Settings) -> bool {
+        let policy = |settings: &Settings| (settings.policy.greylist, settings.policy.greylist_file.clone(), settings.policy.greylist_delay);
+        policy(self) != policy(other)
+    }
+
+    fn sieve(&self) -> Result<Option<Arc<Sieve>>> {
+        match &self.policy.sieve_dir {
+            Some(dir) if !dir.is_dir() => Err(invalid(format!("policy.sieve_dir: {} is not a directory", dir.display()))),
+            Some(dir) => Ok(Some(Arc::new(Sieve::new(Arc::new(ScriptDir { dir: dir.clone() }))))),
+            None => Ok(None),
+        }
+    }
+
+    fn tls_config(&self) -> Result<Option<Arc<ServerConfig>>> {
+        match &self.tls {
+            Some(settings) => Ok(Some(context(tls::load(&settings.cert, &settings.key), "tls")?)),
+            None => Ok(None),
+        }
+    }
+
+    fn credentials(&self) -> Result<Option<Arc<dyn Credentials>>> {
+        if !self.has_credentials() {
+            return Ok(None);
+        }
+        let mut credentials = match &self.auth.password_file {
+            Some(path) => context(MemoryCredentials::load(path), format!("auth.password_file {}", path.display()))?,
+            None => MemoryCredentials::new(),
+        };
+        for (user, password) in &self.auth.users {
+            credentials.add(user, password);
+        }
+        Ok(Some(Arc::new(credentials)))
+    }
+
+    /// The settings of an SMTP, LMTP or submission listener.
+    pub fn smtp_config(&self, listener: &ListenerSettings, shared: &Shared) -> Result<server::Config> {
+        let policy = &self.policy;
+        let directory = &self.directory;
+        let mut handler: Arc<dyn MessageHandler> = Arc::new(DmarcHandler);
+        if let Some(path) = &policy.spam_rules {
+            let rules = context(SpamRules::load(path), format!("policy.spam_rules {}", path.display()))?;
+            handler = Arc::new(SpamHandler::new(rules, handler));
+        }
+        let dkim_signer = match &policy.dkim {
+            Some(dkim) => {
+                let key = context(SigningKey::load(&dkim.key.to_string_lossy()), format!("policy.dkim.key {}", dkim.key.display()))?;
+                Some(dkim::Signer::new(&dkim.domain, &dkim.selector, key))
+            }
+            None => None,
+        };
+        let milter = policy.milter.as_ref().map(|addr| {
+            #[cfg(unix)]
+            let addr = if addr.starts_with('/') { MilterAddr::Unix(PathBuf::from(addr)) } else { MilterAddr::Tcp(addr.clone()) };
+            #[cfg(not(unix))]
+            let addr = MilterAddr::Tcp(addr.clone());
+            Milter { fail_open: policy.milter_fail_open, ..Milter::new(addr) }
+        });
+        let rewriter = match &policy.rewrite_table {
+            Some(path) => Some(Arc::new(context(Rewriter::open(path), format!("policy.rewrite_table {}", path.display()))?)),
+            None => None,
+        };
+        let webhook = match &self.webhook {
+            Some(settings) => {
+                let mut webhook = context(Webhook::new(&settings.url), "webhook.url")?;
+                webhook.secret = settings.secret.as_ref().map(|secret| secret.as_bytes().to_vec());
+                webhook.attempts = settings.attempts.unwrap_or(webhook.attempts).max(1);
+                webhook.backoff = settings.backoff.map(Duration::from_millis).unwrap_or(webhook.backoff);
+                webhook.timeout = settings.timeout.map(Duration::from_secs).unwrap_or(webhook.timeout);
+                Some(Arc::new(webhook))
+            }
+            None => None,
+        };
+        let known = if directory.users.is_empty() && directory.lists.is_empty() {
+            None
+        } else {
+            let mut known = MemoryDirectory::new();
+            for user in &directory.users {
+                known.add_user(user, None);
+            }
+            for (list, members) in &directory.lists {
+                known.add_list(list, &members.iter().map(String::as_str).collect::<Vec<_>>());
+            }
+            Some(Arc::new(known) as Arc<_>)
+        };
+        Ok(server::Config {
+            hostname: self.hostname.clone(),
+            dkim_signer,
+            handler,
+            max_hops: self.limits.max_hops,
+            store: shared.store.clone(),
+            lmtp: listener.protocol == Protocol::Lmtp,
+            proxy_protocol: listener.proxy_protocol,
+            metrics: shared.metrics.clone(),
+            directory: known,
+            vrfy: directory.vrfy,
+            expn: directory.expn,
+            greylist: shared.greylist.clone(),
+            milter,
+            sieve: shared.sieve.clone(),
+            mailboxes: shared.mailboxes.clone(),
+            webhook,
+            rewriter,
+            tls: self.tls_config()?,
+            credentials: self.credentials()?,
+            submission: listener.protocol == Protocol::Submission,
+            max_message_size: self.limits.max_message_size,
+            timeout: self.limits.timeout.map(Duration::from_secs),
+            ..server::Config::default()
+        })
+    }
+
+    fn mailbox_parts(&self, shared: &Shared) -> Result<(Arc<MailboxStore>, Arc<dyn Credentials>)> {
+        let mailboxes = shared.mailboxes.clone().ok_or_else(|| invalid("pop3 and imap need storage.mailboxes".to_string()))?;
+        let credentials = self.credentials()?.ok_or_else(|| invalid("pop3 and imap need [auth] users".to_string()))?;
+        Ok((mailboxes, credentials))
+    }
+
+    pub fn pop3_config(&self, shared: &Shared) -> Result<pop3::Config> {
+        let (mailboxes, credentials) = self.mailbox_parts(shared)?;
+        Ok(pop3::Config { hostname: self.hostname.clone(), mailboxes, credentials, tls: self.tls_config()? })
+    }
+
+    pub fn imap_config(&self, shared: &Shared) -> Result<imap::Config> {
+        let (mailboxes, credentials) = self.mailbox_parts(shared)?;
+        Ok(imap::Config { hostname: self.hostname.clone(), mailboxes, credentials, tls: self.tls_config()? })
+    }
+}
+
+/// The settings new connections of one listener take, swapped on reload.
+enum Live {
+    Smtp(Arc<RwLock<Arc<server::Config>>>),
+    Pop3(Arc<RwLock<Arc<pop3::Config>>>),
+    Imap(Arc<RwLock<Arc<imap::Config>>>),
+}
+
+/// A listener's settings, built and ready to be swapped in.
+enum Built {
+    Smtp(Arc<server::Config>),
+    Pop3(Arc<pop3::Config>),
+    Imap(Arc<imap::Config>),
+}
+
+fn build(settings: &Settings, listener: &ListenerSettings, shared: &Shared) -> Result<Built> {
+    Ok(match listener.protocol {
+        Protocol::Smtp | Protocol::Lmtp | Protocol::Submission => Built::Smtp(Arc::new(settings.smtp_config(listener, shared)?)),
+        Protocol::Pop3 => Built::Pop3(Arc::new(settings.pop3_config(shared)?)),
+        Protocol::Imap => Built::Imap(Arc::new(settings.imap_config(shared)?)),
+    })
+}
+
+/// The running listeners.
+pub struct Server {
+    settings: Settings,
+    shared: Shared,
+    live: Vec<Live>,
+    /// Where each listener is bound, in the order of the settings.
+    pub addrs: Vec<String>,
+}
+
+impl Server {
+    /// Opens the storage, binds every listener and serves each from a
+    /// thread of its own.
+    pub fn start(settings: &Settings) -> Result<Server> {
+        settings.validate()?;
+        let shared = settings.open()?;
+        let built = settings.listeners.iter().map(|listener| build(settings, listener, &shared)).collect::<Result<Vec<_>>>()?;
+
+        #[cfg(feature = "http-api")]
+        if let (Some(addr), Some(store)) = (&settings.http.api, &shared.store) {
+            context(crate::api::spawn(addr.as_str(), store.clone()), format!("http.api {}", addr))?;
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = &settings.http.metrics {
+            context(crate::metrics::spawn(addr.as_str(), shared.metrics.clone()), format!("http.metrics {}", addr))?;
+        }
+
+        let mut live = Vec::new();
+        let mut addrs = Vec::new();
+        for (listener, built) in settings.listeners.iter().zip(built) {
+            let (name, local) = match (&listener.address, &listener.path) {
+                (Some(address), _) => {
+                    let tcp = context(TcpListener::bind(address), format!("listener {}", address))?;
+                    let local = tcp.local_addr()?.to_string();
+                    (local.clone(), Bound::Tcp(tcp))
+                }
+                #[cfg(unix)]
+                (None, Some(path)) => {
+                    // A socket left over from a previous run would be in the way.
+                    let _ = fs::remove_file(path);
+                    let unix = context(UnixListener::bind(path), format!("listener {}", path.display()))?;
+                    (path.display().to_string(), Bound::Unix(unix))
+                }
+                _ => return Err(invalid("listener needs an address or a path".to_string())),
+            };
+            println!("Listening for {:?} on {}", listener.protocol, name);
+            live.push(spawn(built, local));
+            addrs.push(name);
+        }
+        Ok(Server { settings: settings.clone(), shared, live, addrs })
+    }
+
+    /// Applies `settings` to new connections. Nothing changes unless all of
+    /// them can be built.
+    pub fn reload(&mut self, settings: &Settings) -> Result<()> {
+        settings.validate()?;
+        for (what, changed) in [
+            ("listeners", settings.listeners != self.settings.listeners),
+            ("storage", settings.storage != self.settings.storage),
+            ("http", settings.http != self.settings.http),
+        ] {
+            if changed {
+                println!("[WARN] Changes to {} take effect after a restart", what);
+            }
+        }
+        let mut shared = self.shared.clone();
+        if settings.greylist_changed(&self.settings) {
+            shared.greylist = settings.greylist()?;
+        }
+        if settings.policy.sieve_dir != self.settings.policy.sieve_dir {
+            shared.sieve = settings.sieve()?;
+        }
+        // The listeners stay as they were bound.
+        let built = self
+            .settings
+            .listeners
+            .iter()
+            .map(|listener| build(settings, listener, &shared))
+            .collect::<Result<Vec<_>>>()?;
+        for (live, built) in self.live.iter().zip(built) {
+            match (live, built) {
+                (Live::Smtp(current), Built::Smtp(config)) => *current.write().unwrap() = config,
+                (Live::Pop3(current), Built::Pop3(config)) => *current.write().unwrap() = config,
+                (Live::Imap(current), Built::Imap(config)) => *current.write().unwrap() = config,
+                _ => unreachable!("listeners keep their protocol"),
+            }
+        }
+        self.shared = shared;
+        let listeners = std::mem::take(&mut self.settings.listeners);
+        self.settings = Settings { listeners, ..settings.clone() };
+        Ok(())
+    }
+
+    /// The storage and counters the listeners share.
+    pub fn shared(&self) -> &Shared {
+        &self.shared
+    }
+}
+
+enum Bound {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+fn spawn(built: Built, bound: Bound) -> Live {
+    match (built, bound) {
+        (Built::Smtp(config), bound) => {
+            let current = Arc::new(RwLock::new(config));
+            let config = current.clone();
+            let latest = move || config.read().unwrap().clone();
+            thread::spawn(move || match bound {
+                Bound::Tcp(listener) => server::serve_with(listener, latest),
+                #[cfg(unix)]
+                Bound::Unix(listener) => server::serve_unix_with(listener, latest),
+            });
+            Live::Smtp(current)
+        }
+        (Built::Pop3(config), Bound::Tcp(listener)) => {
+            let current = Arc::new(RwLock::new(config));
+            let config = current.clone();
+            thread::spawn(move || pop3::serve_with(listener, move || config.read().unwrap().clone()));
+            Live::Pop3(current)
+        }
+        (Built::Imap(config), Bound::Tcp(listener)) => {
+            let current = Arc::new(RwLock::new(config));
+            let config = current.clone();
+            thread::spawn(move || imap::serve_with(listener, move || config.read().unwrap().clone()));
+            Live::Imap(current)
+        }
+        #[cfg(unix)]
+        (_, Bound::Unix(_)) => unreachable!("validated: POP3 and IMAP listen on TCP"),
+    }
+}
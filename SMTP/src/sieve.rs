@@ -0,0 +1,1038 @@
+//! Sieve (RFC 5228) mail filtering, run for each local recipient at
+//! delivery. Besides the base language it supports the `fileinto`,
+//! `envelope`, `reject` (RFC 5429) and `vacation` (RFC 5230) extensions.
+//!
+//! ```text
+//! require ["fileinto", "vacation"];
+//! if address :domain "from" "lists.example.org" {
+//!     fileinto "Lists";
+//! } elsif header :contains "subject" "urgent" {
+//!     redirect "pager@example.com";
+//! }
+//! vacation :days 3 "Back on Monday.";
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::address;
+use crate::mime;
+use crate::server::Message;
+use crate::time::{Clock, SystemClock};
+use crate::trace;
+
+/// Extensions a script may `require`.
+pub const CAPABILITIES: [&str; 6] = ["comparator-i;ascii-casemap", "comparator-i;octet", "envelope", "fileinto", "reject", "vacation"];
+
+fn invalid(line: usize, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("sieve line {}: {}", line, msg))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Identifier(String),
+    Tag(String),
+    Number(u64),
+    String(String),
+    /// One of `[ ] ( ) { } , ;`.
+    Punct(char),
+}
+
+/// Splits a script into tokens, each with its line number.
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    let identifier_end = |mut j: usize| {
+        while j < chars.len() && (chars[j].is_ascii_alphanumeric() || chars[j] == '_') {
+            j += 1;
+        }
+        j
+    };
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\n' => {
+                line += 1;
+                i += 1;
+            }
+            c if c.is_whitespace() => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                let start = line;
+                i += 2;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(invalid(start, "unterminated comment")),
+                        Some('*') if chars.get(i + 1) == Some(&'/') => break,
+                        Some('\n') => line += 1,
+                        _ => {}
+                    }
+                    i += 1;
+                }
+                i += 2;
+            }
+            '[' | ']' | '(' | ')' | '{' | '}' | ',' | ';' => {
+                tokens.push((Token::Punct(c), line));
+                i += 1;
+            }
+            '"' => {
+                let start = line;
+                let mut s = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(invalid(start, "unterminated string")),
+                        Some('"') => break,
+                        Some('\\') if i + 1 < chars.len() => {
+                            i += 1;
+                            s.push(chars[i]);
+                        }
+                        Some(&c) => s.push(c),
+                    }
+                    if chars[i] == '\n' {
+                        line += 1;
+                    }
+                    i += 1;
+                }
+                tokens.push((Token::String(s.replace("\r\n", "\n").replace('\n', "\r\n")), start));
+                i += 1;
+            }
+            ':' => {
+                let end = identifier_end(i + 1);
+                if end == i + 1 {
+                    return Err(invalid(line, "expected a tag after ':'"));
+                }
+                tokens.push((Token::Tag(chars[i + 1..end].iter().collect::<String>().to_ascii_lowercase()), line));
+                i = end;
+            }
+            c if c.is_ascii_digit() => {
+                let mut end = i;
+                while end < chars.len() && chars[end].is_ascii_digit() {
+                    end += 1;
+                }
+                let digits: String = chars[i..end].iter().collect();
+                let mut n: u64 = digits.parse().map_err(|_| invalid(line, "number too large"))?;
+                let shift = match chars.get(end).map(char::to_ascii_uppercase) {
+                    Some('K') => 10,
+                    Some('M') => 20,
+                    Some('G') => 30,
+                    _ => 0,
+                };
+                if shift > 0 {
+                    end += 1;
+                    n = n.checked_mul(1 << shift).ok_or_else(|| invalid(line, "number too large"))?;
+                }
+                tokens.push((Token::Number(n), line));
+                i = end;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let end = identifier_end(i);
+                let word: String = chars[i..end].iter().collect::<String>().to_ascii_lowercase();
+                if word == "text" && chars.get(end) == Some(&':') {
+                    let (s, next, lines) = multiline(&chars, end + 1).ok_or_else(|| invalid(line, "unterminated text: block"))?;
+                    tokens.push((Token::String(s), line));
+                    line += lines;
+                    i = next;
+                } else {
+                    tokens.push((Token::Identifier(word), line));
+                    i = end;
+                }
+            }
+            c => return Err(invalid(line, &format!("unexpected character {:?}", c))),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Reads a `text:` string starting just after the colon, up to the line
+/// holding only a dot. Returns the string, where reading stopped and how
+/// many line breaks were consumed.
+fn multiline(chars: &[char], start: usize) -> Option<(String, usize, usize)> {
+    let rest: String = chars[start..].iter().collect();
+    let first_break = rest.find('\n')?;
+    let mut s = String::new();
+    let mut consumed = start + rest[..=first_break].chars().count();
+    let mut lines = 1;
+    for raw in rest[first_break + 1..].split_inclusive('\n') {
+        consumed += raw.chars().count();
+        lines += 1;
+        let text = raw.trim_end_matches('\n').trim_end_matches('\r');
+        if text == "." {
+            return Some((s, consumed, lines));
+        }
+        s += text.strip_prefix('.').filter(|t| t.starts_with('.')).unwrap_or(text);
+        s += "\r\n";
+    }
+    None
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Arg {
+    Tag(String),
+    Number(u64),
+    Strings(Vec<String>),
+}
+
+/// A command or test as written: a name, arguments, tests and a block.
+#[derive(Debug)]
+struct Node {
+    name: String,
+    line: usize,
+    args: Vec<Arg>,
+    tests: Vec<Node>,
+    block: Option<Vec<Node>>,
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens.get(self.at).or(self.tokens.last()).map(|(_, line)| *line).unwrap_or(1)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).map(|(token, _)| token.clone());
+        self.at += 1;
+        token
+    }
+
+    fn expect(&mut self, punct: char) -> Result<()> {
+        let line = self.line();
+        match self.next() {
+            Some(Token::Punct(c)) if c == punct => Ok(()),
+            _ => Err(invalid(line, &format!("expected '{}'", punct))),
+        }
+    }
+
+    /// Commands up to the end of the script or of the enclosing block.
+    fn commands(&mut self) -> Result<Vec<Node>> {
+        let mut commands = Vec::new();
+        while !matches!(self.peek(), None | Some(Token::Punct('}'))) {
+            let mut command = self.node()?;
+            if self.peek() == Some(&Token::Punct('{')) {
+                self.next();
+                command.block = Some(self.commands()?);
+                self.expect('}')?;
+            } else {
+                self.expect(';')?;
+            }
+            commands.push(command);
+        }
+        Ok(commands)
+    }
+
+    /// An identifier and its arguments, which may end in tests.
+    fn node(&mut self) -> Result<Node> {
+        let line = self.line();
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(invalid(line, "expected a command")),
+        };
+        let mut node = Node { name, line, args: Vec::new(), tests: Vec::new(), block: None };
+        loop {
+            match self.peek() {
+                Some(Token::Tag(tag)) => {
+                    node.args.push(Arg::Tag(tag.clone()));
+                    self.next();
+                }
+                Some(&Token::Number(n)) => {
+                    node.args.push(Arg::Number(n));
+                    self.next();
+                }
+                Some(Token::String(s)) => {
+                    node.args.push(Arg::Strings(vec![s.clone()]));
+                    self.next();
+                }
+                Some(Token::Punct('[')) => {
+                    self.next();
+                    let mut strings = Vec::new();
+                    loop {
+                        let line = self.line();
+                        match self.next() {
+                            Some(Token::String(s)) => strings.push(s),
+                            _ => return Err(invalid(line, "expected a string")),
+                        }
+                        match self.next() {
+                            Some(Token::Punct(',')) => continue,
+                            Some(Token::Punct(']')) => break,
+                            _ => return Err(invalid(line, "expected ',' or ']'")),
+                        }
+                    }
+                    node.args.push(Arg::Strings(strings));
+                }
+                _ => break,
+            }
+        }
+        match self.peek() {
+            Some(Token::Identifier(_)) => node.tests.push(self.node()?),
+            Some(Token::Punct('(')) => {
+                self.next();
+                loop {
+                    node.tests.push(self.node()?);
+                    let line = self.line();
+                    match self.next() {
+                        Some(Token::Punct(',')) => continue,
+                        Some(Token::Punct(')')) => break,
+                        _ => return Err(invalid(line, "expected ',' or ')'")),
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(node)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparator {
+    Octet,
+    AsciiCasemap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchType {
+    Is,
+    Contains,
+    Matches,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+}
+
+/// How a test compares values with its keys.
+#[derive(Debug, Clone, Copy)]
+struct Spec {
+    comparator: Comparator,
+    match_type: MatchType,
+    part: AddressPart,
+}
+
+#[derive(Debug, Clone)]
+enum Test {
+    Address(Spec, Vec<String>, Vec<String>),
+    Envelope(Spec, Vec<String>, Vec<String>),
+    Header(Spec, Vec<String>, Vec<String>),
+    Exists(Vec<String>),
+    /// `size :over` (true) or `:under` (false) a number of octets.
+    Size(bool, u64),
+    AllOf(Vec<Test>),
+    AnyOf(Vec<Test>),
+    Not(Box<Test>),
+    True,
+    False,
+}
+
+/// An auto-reply to send, as `vacation` asks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vacation {
+    /// Answer each sender at most once in this many days.
+    pub days: u64,
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    /// Other addresses of the recipient, besides the one mail arrived for.
+    pub addresses: Vec<String>,
+    /// `reason` is a MIME entity, headers included, rather than plain text.
+    pub mime: bool,
+    /// Tells apart the replies of several `vacation` commands.
+    pub handle: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone)]
+enum Command {
+    /// `if`, its `elsif`s and an `else`.
+    If(Vec<(Test, Vec<Command>)>, Option<Vec<Command>>),
+    Stop,
+    Keep,
+    Discard,
+    FileInto(String),
+    Redirect(String),
+    Reject(String),
+    Vacation(Vacation),
+}
+
+/// What a script decided should happen to a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Deliver to the inbox.
+    Keep,
+    FileInto(String),
+    /// Pass the message on to another address.
+    Redirect(String),
+    /// Refuse the message, telling the sender why.
+    Reject(String),
+    Discard,
+    Vacation(Vacation),
+}
+
+/// Tags followed by a value.
+/// The most `:days` a script may ask for; RFC 5230 section 4.1 lets a site
+/// cap it.
+pub const MAX_VACATION_DAYS: u64 = 365;
+
+const VALUE_TAGS: [&str; 6] = ["comparator", "days", "subject", "from", "addresses", "handle"];
+
+/// The arguments of a node, tags apart from positional ones.
+struct Args {
+    line: usize,
+    tags: Vec<(String, Option<Arg>)>,
+    positional: Vec<Arg>,
+}
+
+impl Args {
+    fn new(node: &Node) -> Result<Args> {
+        let mut args = Args { line: node.line, tags: Vec::new(), positional: Vec::new() };
+        let mut iter = node.args.iter().cloned();
+        while let Some(arg) = iter.next() {
+            match arg {
+                Arg::Tag(tag) if VALUE_TAGS.contains(&tag.as_str()) => {
+                    let value = iter.next().ok_or_else(|| invalid(node.line, &format!(":{} needs a value", tag)))?;
+                    args.tags.push((tag, Some(value)));
+                }
+                Arg::Tag(tag) if !args.positional.is_empty() => {
+                    return Err(invalid(node.line, &format!(":{} must come before the other arguments", tag)));
+                }
+                Arg::Tag(tag) => args.tags.push((tag, None)),
+                arg => args.positional.push(arg),
+            }
+        }
+        Ok(args)
+    }
+
+    fn error(&self, msg: &str) -> Error {
+        invalid(self.line, msg)
+    }
+
+    /// Checks the number of positional arguments.
+    fn count(&self, name: &str, count: usize) -> Result<()> {
+        if self.positional.len() != count {
+            return Err(self.error(&format!("{} takes {} argument{}", name, count, if count == 1 { "" } else { "s" })));
+        }
+        Ok(())
+    }
+
+    fn strings(&self, i: usize) -> Result<Vec<String>> {
+        match &self.positional[i] {
+            Arg::Strings(strings) => Ok(strings.clone()),
+            _ => Err(self.error("expected a string list")),
+        }
+    }
+
+    fn string(&self, i: usize) -> Result<String> {
+        match self.strings(i)?.as_slice() {
+            [s] => Ok(s.clone()),
+            _ => Err(self.error("expected a single string")),
+        }
+    }
+
+    /// The comparator, match type and address part given by the tags.
+    fn spec(&self, address: bool) -> Result<Spec> {
+        let mut spec = Spec { comparator: Comparator::AsciiCasemap, match_type: MatchType::Is, part: AddressPart::All };
+        let (mut match_type, mut part, mut comparator) = (false, false, false);
+        for (tag, value) in &self.tags {
+            let (seen, what) = match tag.as_str() {
+                "is" | "contains" | "matches" => {
+                    spec.match_type = match tag.as_str() {
+                        "is" => MatchType::Is,
+                        "contains" => MatchType::Contains,
+                        _ => MatchType::Matches,
+                    };
+                    (&mut match_type, "match type")
+                }
+                "all" | "localpart" | "domain" if address => {
+                    spec.part = match tag.as_str() {
+                        "all" => AddressPart::All,
+                        "localpart" => AddressPart::LocalPart,
+                        _ => AddressPart::Domain,
+                    };
+                    (&mut part, "address part")
+                }
+                "comparator" => {
+                    spec.comparator = match value {
+                        Some(Arg::Strings(name)) if name == &["i;octet"] => Comparator::Octet,
+                        Some(Arg::Strings(name)) if name == &["i;ascii-casemap"] => Comparator::AsciiCasemap,
+                        _ => return Err(self.error("unknown comparator")),
+                    };
+                    (&mut comparator, "comparator")
+                }
+                tag => return Err(self.error(&format!("unknown tag :{}", tag))),
+            };
+            if *seen {
+                return Err(self.error(&format!("more than one {}", what)));
+            }
+            *seen = true;
+        }
+        Ok(spec)
+    }
+
+    fn no_tags(&self, name: &str) -> Result<()> {
+        match self.tags.first() {
+            Some((tag, _)) => Err(self.error(&format!("{} takes no :{}", name, tag))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A compiled Sieve script.
+#[derive(Debug, Clone)]
+pub struct Script {
+    commands: Vec<Command>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script> {
+        let mut parser = Parser { tokens: tokenize(text)?, at: 0 };
+        let nodes = parser.commands()?;
+        if parser.peek().is_some() {
+            return Err(invalid(parser.line(), "unexpected '}'"));
+        }
+        let mut required = Vec::new();
+        let mut rest = nodes.as_slice();
+        while let Some((node, tail)) = rest.split_first() {
+            if node.name != "require" {
+                break;
+            }
+            let args = Args::new(node)?;
+            args.count("require", 1)?;
+            args.no_tags("require")?;
+            for capability in args.strings(0)? {
+                if !CAPABILITIES.contains(&capability.as_str()) {
+                    return Err(invalid(node.line, &format!("unsupported extension {:?}", capability)));
+                }
+                required.push(capability);
+            }
+            rest = tail;
+        }
+        Ok(Script { commands: compile(rest, &required)? })
+    }
+
+    /// Runs the script for mail to `recipient` and returns what to do.
+    /// Without an explicit decision the message is kept.
+    pub fn evaluate(&self, msg: &Message, recipient: &str) -> Vec<Action> {
+        let mut run = Run { msg, recipient, actions: Vec::new(), implicit_keep: true, size: msg.content().len() as u64 };
+        run.execute(&self.commands);
+        let mut actions = run.actions;
+        if run.implicit_keep && !actions.contains(&Action::Keep) {
+            actions.push(Action::Keep);
+        }
+        // RFC 5429 section 2.1: reject goes with no delivery nor auto-reply.
+        // Breaking that is a runtime error, which means the implicit keep.
+        let rejects = actions.iter().any(|a| matches!(a, Action::Reject(_)));
+        if rejects && actions.iter().any(|a| !matches!(a, Action::Reject(_) | Action::Discard)) {
+            return vec![Action::Keep];
+        }
+        actions
+    }
+}
+
+fn compile(nodes: &[Node], required: &[String]) -> Result<Vec<Command>> {
+    let require = |node: &Node, capability: &str| {
+        if required.iter().any(|r| r == capability) {
+            Ok(())
+        } else {
+            Err(invalid(node.line, &format!("{} needs require \"{}\"", node.name, capability)))
+        }
+    };
+    let mut commands: Vec<Command> = Vec::new();
+    for node in nodes {
+        let args = Args::new(node)?;
+        let block = |node: &Node| match &node.block {
+            Some(block) => compile(block, required),
+            None => Err(invalid(node.line, &format!("{} needs a block", node.name))),
+        };
+        if node.block.is_some() && !matches!(node.name.as_str(), "if" | "elsif" | "else") {
+            return Err(invalid(node.line, &format!("{} takes no block", node.name)));
+        }
+        let needs_test = matches!(node.name.as_str(), "if" | "elsif");
+        if node.tests.len() != needs_test as usize {
+            return Err(invalid(node.line, &format!("{} takes {} test", node.name, if needs_test { "a" } else { "no" })));
+        }
+        let simple = |name: &str| -> Result<()> {
+            args.count(name, 0)?;
+            args.no_tags(name)
+        };
+        let command = match node.name.as_str() {
+            "if" => {
+                simple("if")?;
+                Command::If(vec![(compile_test(&node.tests[0], required)?, block(node)?)], None)
+            }
+            "elsif" | "else" => {
+                simple(&node.name)?;
+                let branches = match commands.last_mut() {
+                    Some(Command::If(branches, None)) => branches,
+                    _ => return Err(invalid(node.line, &format!("{} without if", node.name))),
+                };
+                if node.name == "elsif" {
+                    branches.push((compile_test(&node.tests[0], required)?, block(node)?));
+                } else if let Some(Command::If(_, otherwise)) = commands.last_mut() {
+                    *otherwise = Some(block(node)?);
+                }
+                continue;
+            }
+            "require" => return Err(invalid(node.line, "require must come first")),
+            "stop" => {
+                simple("stop")?;
+                Command::Stop
+            }
+            "keep" => {
+                simple("keep")?;
+                Command::Keep
+            }
+            "discard" => {
+                simple("discard")?;
+                Command::Discard
+            }
+            "fileinto" => {
+                require(node, "fileinto")?;
+                args.no_tags("fileinto")?;
+                args.count("fileinto", 1)?;
+                Command::FileInto(args.string(0)?)
+            }
+            "redirect" => {
+                args.no_tags("redirect")?;
+                args.count("redirect", 1)?;
+                let address = args.string(0)?;
+                if !address.contains('@') {
+                    return Err(invalid(node.line, &format!("bad redirect address {:?}", address)));
+                }
+                Command::Redirect(address)
+            }
+            "reject" => {
+                require(node, "reject")?;
+                args.no_tags("reject")?;
+                args.count("reject", 1)?;
+                Command::Reject(args.string(0)?)
+            }
+            "vacation" => {
+                require(node, "vacation")?;
+                args.count("vacation", 1)?;
+                let mut vacation = Vacation {
+                    days: 7,
+                    subject: None,
+                    from: None,
+                    addresses: Vec::new(),
+                    mime: false,
+                    handle: None,
+                    reason: args.string(0)?,
+                };
+                for (tag, value) in &args.tags {
+                    let string = || match value {
+                        Some(Arg::Strings(s)) if s.len() == 1 => Ok(s[0].clone()),
+                        _ => Err(args.error(&format!(":{} needs a string", tag))),
+                    };
+                    match (tag.as_str(), value) {
+                        // RFC 5230 section 4.1: at least a day.
+                        ("days", Some(Arg::Number(days))) => vacation.days = (*days).clamp(1, MAX_VACATION_DAYS),
+                        ("subject", _) => vacation.subject = Some(string()?),
+                        ("from", _) => vacation.from = Some(string()?),
+                        ("handle", _) => vacation.handle = Some(string()?),
+                        ("addresses", Some(Arg::Strings(addresses))) => vacation.addresses = addresses.clone(),
+                        ("mime", None) => vacation.mime = true,
+                        (tag, _) => return Err(args.error(&format!("bad vacation argument :{}", tag))),
+                    }
+                }
+                Command::Vacation(vacation)
+            }
+            name => return Err(invalid(node.line, &format!("unknown command {:?}", name))),
+        };
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+fn compile_test(node: &Node, required: &[String]) -> Result<Test> {
+    let args = Args::new(node)?;
+    let tests = || node.tests.iter().map(|test| compile_test(test, required)).collect::<Result<Vec<Test>>>();
+    if !node.tests.is_empty() && !matches!(node.name.as_str(), "allof" | "anyof" | "not") {
+        return Err(invalid(node.line, &format!("{} takes no tests", node.name)));
+    }
+    let test = match node.name.as_str() {
+        "address" | "envelope" | "header" => {
+            if node.name == "envelope" && !required.iter().any(|r| r == "envelope") {
+                return Err(invalid(node.line, "envelope needs require \"envelope\""));
+            }
+            args.count(&node.name, 2)?;
+            let spec = args.spec(node.name != "header")?;
+            let (names, keys) = (args.strings(0)?, args.strings(1)?);
+            match node.name.as_str() {
+                "address" => Test::Address(spec, names, keys),
+                "header" => Test::Header(spec, names, keys),
+                _ => {
+                    if let Some(part) = names.iter().find(|part| !matches!(part.to_ascii_lowercase().as_str(), "from" | "to")) {
+                        return Err(invalid(node.line, &format!("unknown envelope part {:?}", part)));
+                    }
+                    Test::Envelope(spec, names, keys)
+                }
+            }
+        }
+        "exists" => {
+            args.no_tags("exists")?;
+            args.count("exists", 1)?;
+            Test::Exists(args.strings(0)?)
+        }
+        "size" => {
+            args.count("size", 1)?;
+            let over = match args.tags.as_slice() {
+                [(tag, None)] if tag == "over" => true,
+                [(tag, None)] if tag == "under" => false,
+                _ => return Err(args.error("size takes :over or :under")),
+            };
+            match args.positional[0] {
+                Arg::Number(limit) => Test::Size(over, limit),
+                _ => return Err(args.error("size needs a number")),
+            }
+        }
+        "allof" | "anyof" | "not" => {
+            args.count(&node.name, 0)?;
+            args.no_tags(&node.name)?;
+            let mut tests = tests()?;
+            match node.name.as_str() {
+                "allof" => Test::AllOf(tests),
+                "anyof" => Test::AnyOf(tests),
+                _ if tests.len() == 1 => Test::Not(Box::new(tests.remove(0))),
+                _ => return Err(invalid(node.line, "not takes one test")),
+            }
+        }
+        "true" | "false" => {
+            args.count(&node.name, 0)?;
+            args.no_tags(&node.name)?;
+            if node.name == "true" { Test::True } else { Test::False }
+        }
+        name => return Err(invalid(node.line, &format!("unknown test {:?}", name))),
+    };
+    Ok(test)
+}
+
+/// Whether `text` matches the `pattern` of `:matches`, where `*` stands for
+/// any run of characters, `?` for one, and `\` escapes either.
+fn wildcard(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(&c) => {
+                let (c, width) = if c == '\\' && p + 1 < pattern.len() { (pattern[p + 1], 2) } else { (c, 1) };
+                if c == text[t] {
+                    p += width;
+                    t += 1;
+                    continue;
+                }
+            }
+            None => {}
+        }
+        match backtrack {
+            Some((bp, bt)) => {
+                p = bp;
+                t = bt + 1;
+                backtrack = Some((bp, bt + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl Spec {
+    fn matches(&self, value: &str, key: &str) -> bool {
+        let (value, key) = match self.comparator {
+            Comparator::Octet => (value.to_string(), key.to_string()),
+            Comparator::AsciiCasemap => (value.to_ascii_lowercase(), key.to_ascii_lowercase()),
+        };
+        match self.match_type {
+            MatchType::Is => value == key,
+            MatchType::Contains => value.contains(&key),
+            MatchType::Matches => {
+                let (pattern, text): (Vec<char>, Vec<char>) = (key.chars().collect(), value.chars().collect());
+                wildcard(&pattern, &text)
+            }
+        }
+    }
+
+    fn any(&self, values: &[String], keys: &[String]) -> bool {
+        values.iter().any(|value| keys.iter().any(|key| self.matches(value, key)))
+    }
+
+    /// The part of `address` this spec looks at.
+    fn part(&self, address: &str) -> String {
+        let (local, domain) = address.rsplit_once('@').unwrap_or((address, ""));
+        match self.part {
+            AddressPart::All => address.to_string(),
+            AddressPart::LocalPart => local.to_string(),
+            AddressPart::Domain => domain.to_string(),
+        }
+    }
+}
+
+/// The state of one run of a script.
+struct Run<'a> {
+    msg: &'a Message,
+    recipient: &'a str,
+    actions: Vec<Action>,
+    implicit_keep: bool,
+    size: u64,
+}
+
+impl Run<'_> {
+    /// The unfolded, decoded values of the header fields called `names`.
+    fn header_values(&self, names: &[String]) -> Vec<String> {
+        names
+            .iter()
+            .flat_map(|name| self.msg.headers.get_all(name))
+            .map(|header| mime::decode_encoded_words(&header.unfolded()))
+            .collect()
+    }
+
+    fn test(&self, test: &Test) -> bool {
+        match test {
+            Test::Header(spec, names, keys) => spec.any(&self.header_values(names), keys),
+            Test::Address(spec, names, keys) => {
+                let values: Vec<String> = self.header_values(names).iter().flat_map(|v| address::parse_address_list(v)).map(|a| spec.part(&a)).collect();
+                spec.any(&values, keys)
+            }
+            Test::Envelope(spec, parts, keys) => {
+                let values: Vec<String> = parts
+                    .iter()
+                    .map(|part| match part.to_ascii_lowercase().as_str() {
+                        "from" => spec.part(&self.msg.mail_from()),
+                        _ => spec.part(self.recipient),
+                    })
+                    .collect();
+                spec.any(&values, keys)
+            }
+            Test::Exists(names) => names.iter().all(|name| self.msg.headers.contains(name)),
+            Test::Size(true, limit) => self.size > *limit,
+            Test::Size(false, limit) => self.size < *limit,
+            Test::AllOf(tests) => tests.iter().all(|t| self.test(t)),
+            Test::AnyOf(tests) => tests.iter().any(|t| self.test(t)),
+            Test::Not(test) => !self.test(test),
+            Test::True => true,
+            Test::False => false,
+        }
+    }
+
+    fn act(&mut self, action: Action) {
+        if !matches!(action, Action::Vacation(_)) {
+            self.implicit_keep = false;
+        }
+        // Doing the same thing twice does it once (RFC 5228 section 2.10.3).
+        if !self.actions.contains(&action) {
+            self.actions.push(action);
+        }
+    }
+
+    /// Runs `commands`, returning true once `stop` is reached.
+    fn execute(&mut self, commands: &[Command]) -> bool {
+        for command in commands {
+            match command {
+                Command::If(branches, otherwise) => {
+                    let block = branches.iter().find(|(test, _)| self.test(test)).map(|(_, block)| block).or(otherwise.as_ref());
+                    if let Some(block) = block {
+                        if self.execute(block) {
+                            return true;
+                        }
+                    }
+                }
+                Command::Stop => return true,
+                Command::Keep => self.act(Action::Keep),
+                Command::Discard => {
+                    self.implicit_keep = false;
+                    self.act(Action::Discard);
+                }
+                Command::FileInto(folder) => self.act(Action::FileInto(folder.clone())),
+                Command::Redirect(address) => self.act(Action::Redirect(address.clone())),
+                Command::Reject(reason) => self.act(Action::Reject(reason.clone())),
+                Command::Vacation(vacation) => {
+                    // Only the first vacation counts.
+                    if !self.actions.iter().any(|a| matches!(a, Action::Vacation(_))) {
+                        self.act(Action::Vacation(vacation.clone()));
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Where the recipients' scripts come from.
+pub trait ScriptSource: Send + Sync {
+    /// The script of `recipient`, or `None` if they have none.
+    fn script(&self, recipient: &str) -> Result<Option<Arc<Script>>>;
+}
+
+/// Scripts held in memory, by recipient address regardless of case.
+#[derive(Default)]
+pub struct MemoryScripts {
+    scripts: HashMap<String, Arc<Script>>,
+}
+
+impl MemoryScripts {
+    pub fn new() -> MemoryScripts {
+        MemoryScripts::default()
+    }
+
+    pub fn add(&mut self, recipient: &str, script: Script) {
+        self.scripts.insert(recipient.to_lowercase(), Arc::new(script));
+    }
+}
+
+impl ScriptSource for MemoryScripts {
+    fn script(&self, recipient: &str) -> Result<Option<Arc<Script>>> {
+        Ok(self.scripts.get(&recipient.to_lowercase()).cloned())
+    }
+}
+
+/// Scripts kept as `<address>.sieve` files in a directory, read at each
+/// delivery so that users' edits apply straight away.
+pub struct ScriptDir {
+    pub dir: PathBuf,
+}
+
+impl ScriptSource for ScriptDir {
+    fn script(&self, recipient: &str) -> Result<Option<Arc<Script>>> {
+        let name = recipient.to_lowercase();
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Ok(None);
+        }
+        match fs::read_to_string(self.dir.join(format!("{}.sieve", name))) {
+            Ok(text) => Script::parse(&text).map(|script| Some(Arc::new(script))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Runs the recipients' scripts and remembers whom `vacation` answered.
+pub struct Sieve {
+    pub scripts: Arc<dyn ScriptSource>,
+    pub clock: Arc<dyn Clock>,
+    /// Until when each (recipient, sender, handle) goes unanswered again.
+    replied: Mutex<HashMap<(String, String, String), SystemTime>>,
+}
+
+/// Senders that must never get an auto-reply (RFC 5230 section 4.6).
+fn is_robot(sender: &str) -> bool {
+    let local = sender.rsplit_once('@').map(|(local, _)| local).unwrap_or(sender).to_ascii_lowercase();
+    local == "mailer-daemon" || local == "listserv" || local == "majordomo" || local.starts_with("owner-") || local.ends_with("-request")
+}
+
+impl Sieve {
+    pub fn new(scripts: Arc<dyn ScriptSource>) -> Sieve {
+        Sieve { scripts, clock: Arc::new(SystemClock), replied: Mutex::new(HashMap::new()) }
+    }
+
+    /// Runs the script of `recipient` on `msg`; mail for recipients without
+    /// one is kept.
+    pub fn run(&self, msg: &Message, recipient: &str) -> Result<Vec<Action>> {
+        Ok(match self.scripts.script(recipient)? {
+            Some(script) => script.evaluate(msg, recipient),
+            None => vec![Action::Keep],
+        })
+    }
+
+    /// The reply `vacation` sends to the sender of `msg`, or `None` when
+    /// RFC 5230 says not to answer: mail from robots and lists, mail not
+    /// addressed to the recipient, and senders answered recently.
+    pub fn vacation_reply(&self, msg: &Message, recipient: &str, vacation: &Vacation, hostname: &str) -> Option<Vec<u8>> {
+        let sender = msg.mail_from();
+        let header = |name: &str| msg.headers.get(name).unwrap_or_default().trim().to_ascii_lowercase();
+        if sender.is_empty() || is_robot(&sender) || sender.eq_ignore_ascii_case(recipient) {
+            return None;
+        }
+        if !matches!(header("Auto-Submitted").as_str(), "" | "no") || ["bulk", "list", "junk"].contains(&header("Precedence").as_str()) {
+            return None;
+        }
+        if msg.headers.iter().any(|h| h.name.trim().to_ascii_lowercase().starts_with("list-")) {
+            return None;
+        }
+        let mine: Vec<String> = std::iter::once(recipient.to_string()).chain(vacation.addresses.iter().cloned()).map(|a| a.to_lowercase()).collect();
+        let addressed = ["To", "Cc", "Bcc", "Resent-To", "Resent-Cc"]
+            .iter()
+            .flat_map(|name| msg.headers.get_all(name))
+            .flat_map(|h| address::parse_address_list(&h.unfolded()))
+            .any(|address| mine.contains(&address.to_lowercase()));
+        if !addressed {
+            return None;
+        }
+
+        let handle = vacation.handle.clone().unwrap_or_else(|| {
+            format!("{}\0{}\0{}", vacation.reason, vacation.subject.as_deref().unwrap_or(""), vacation.from.as_deref().unwrap_or(""))
+        });
+        let key = (recipient.to_lowercase(), sender.to_lowercase(), handle);
+        let now = self.clock.now();
+        let mut replied = self.replied.lock().unwrap();
+        replied.retain(|_, until| now < *until);
+        if replied.contains_key(&key) {
+            return None;
+        }
+        replied.insert(key, now + Duration::from_secs(vacation.days.saturating_mul(24 * 3600)));
+
+        let subject = vacation.subject.clone().unwrap_or_else(|| format!("Auto: {}", msg.headers.get("Subject").unwrap_or_default().trim()));
+        let mut out = format!("From: {}\r\n", vacation.from.as_deref().map(str::to_string).unwrap_or_else(|| format!("<{}>", recipient)));
+        out += &format!("To: <{}>\r\n", sender);
+        out += &format!("Subject: {}\r\n", subject);
+        out += &format!("Date: {}\r\n", trace::rfc5322_date(SystemTime::now()));
+        out += &format!("Message-ID: <{}@{}>\r\n", trace::new_queue_id(), hostname);
+        if let Some(id) = msg.headers.get("Message-ID") {
+            let id = id.trim();
+            out += &format!("In-Reply-To: {}\r\n", id);
+            let references = msg.headers.get("References").map(|r| format!("{} ", r.trim())).unwrap_or_default();
+            out += &format!("References: {}{}\r\n", references, id);
+        }
+        out += "Auto-Submitted: auto-replied (vacation)\r\n";
+        out += "MIME-Version: 1.0\r\n";
+        if vacation.mime {
+            out += &vacation.reason;
+        } else {
+            out += "Content-Type: text/plain; charset=utf-8\r\n\r\n";
+            out += &vacation.reason;
+        }
+        if !out.ends_with("\r\n") {
+            out += "\r\n";
+        }
+        Some(out.into_bytes())
+    }
+}
+
+/// The notice `reject` sends to the sender of `msg` when only some of its
+/// recipients refused it.
+pub fn rejection_notice(msg: &Message, recipient: &str, reason: &str, hostname: &str) -> Vec<u8> {
+    let mut out = format!("From: Mail Delivery System <MAILER-DAEMON@{}>\r\n", hostname);
+    out += &format!("To: <{}>\r\n", msg.mail_from());
+    out += &format!("Subject: Rejected: {}\r\n", msg.headers.get("Subject").unwrap_or_default().trim());
+    out += &format!("Date: {}\r\n", trace::rfc5322_date(SystemTime::now()));
+    out += "Auto-Submitted: auto-replied\r\n";
+    out += "Content-Type: text/plain; charset=utf-8\r\n\r\n";
+    out += &format!("Your message to <{}> was rejected by the recipient:\r\n\r\n{}\r\n", recipient, reason.trim_end());
+    out.into_bytes()
+}
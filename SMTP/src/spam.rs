@@ -0,0 +1,236 @@
+//! Rule-based spam scoring. Each rule that matches a message adds its score,
+//! and messages whose total reaches a threshold are rejected or quarantined.
+//!
+//! Rules come from a file with one rule or setting per line:
+//!
+//! ```text
+//! # name           score  test    arguments
+//! FREE_MONEY       2.5    header  Subject (?i)free money
+//! LOTTERY          3.0    body    (?i)you have won
+//! DYNAMIC_HELO     1.5    helo    ^(dsl|dyn|ppp)-
+//! SPF_FAIL         3.5    spf     fail
+//! TRUSTED_NET     -10     ip      192.0.2.0/24
+//!
+//! threshold 5
+//! action quarantine
+//! ```
+//!
+//! Patterns are regular expressions, matched anywhere in the unfolded header
+//! fields of that name, the text of the body parts, or the HELO name.
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use crate::handler::{MessageHandler, Verdict};
+use crate::mime;
+use crate::server::Message;
+use crate::spf::{self, SpfResult};
+
+/// Default score at which a message counts as spam.
+pub const DEFAULT_THRESHOLD: f64 = 5.0;
+
+/// What a rule looks at.
+#[derive(Debug, Clone)]
+pub enum Test {
+    /// Header fields with this name.
+    Header(String, Regex),
+    /// The text of the body, transfer encodings undone.
+    Body(Regex),
+    /// The domain the client greeted with.
+    Helo(Regex),
+    Spf(SpfResult),
+    /// The client's address within a network, given by prefix length.
+    Ip(IpAddr, u8),
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    /// Added to the total when the rule matches; negative to vouch for mail.
+    pub score: f64,
+    pub test: Test,
+}
+
+/// What to do with spam.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Reject,
+    Quarantine,
+}
+
+/// The outcome of scoring a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Score {
+    pub total: f64,
+    /// Names of the rules that matched, in file order.
+    pub tests: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpamRules {
+    pub rules: Vec<Rule>,
+    pub threshold: f64,
+    pub action: Action,
+}
+
+fn invalid(line: usize, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("spam rules line {}: {}", line, msg))
+}
+
+/// Splits off the first word of `text`, returning it and the rest.
+fn word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], text[end..].trim())
+}
+
+impl SpamRules {
+    /// Parses a rules file; see the module documentation for the format.
+    pub fn parse(text: &str) -> Result<SpamRules> {
+        let mut rules = SpamRules { rules: Vec::new(), threshold: DEFAULT_THRESHOLD, action: Action::Reject };
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (first, rest) = word(line);
+            match first {
+                "threshold" => {
+                    rules.threshold = rest.parse().map_err(|_| invalid(line_no, "threshold must be a number"))?;
+                }
+                "action" => {
+                    rules.action = match rest {
+                        "reject" => Action::Reject,
+                        "quarantine" => Action::Quarantine,
+                        _ => return Err(invalid(line_no, "action must be reject or quarantine")),
+                    };
+                }
+                name => rules.rules.push(Rule::parse(name, rest).map_err(|e| invalid(line_no, &e))?),
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<SpamRules> {
+        SpamRules::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Adds up the scores of the rules `msg` matches.
+    pub fn score(&self, msg: &Message) -> Score {
+        let body = body_text(msg);
+        let mut score = Score { total: 0.0, tests: Vec::new() };
+        for rule in &self.rules {
+            if rule.matches(msg, &body) {
+                score.total += rule.score;
+                score.tests.push(rule.name.clone());
+            }
+        }
+        score
+    }
+}
+
+impl Rule {
+    /// Parses the part of a rule line after its name.
+    fn parse(name: &str, rest: &str) -> std::result::Result<Rule, String> {
+        let (score, rest) = word(rest);
+        let score = score.parse().map_err(|_| format!("bad score {:?}", score))?;
+        let (kind, args) = word(rest);
+        let regex = |pattern: &str| Regex::new(pattern).map_err(|e| e.to_string());
+        let test = match kind {
+            "header" => {
+                let (field, pattern) = word(args);
+                if pattern.is_empty() {
+                    return Err("header needs a name and a pattern".to_string());
+                }
+                Test::Header(field.to_string(), regex(pattern)?)
+            }
+            "body" => Test::Body(regex(args)?),
+            "helo" => Test::Helo(regex(args)?),
+            "spf" => Test::Spf(SpfResult::parse(args).ok_or_else(|| format!("unknown SPF result {:?}", args))?),
+            "ip" => {
+                let (network, prefix) = args.split_once('/').unwrap_or((args, ""));
+                let network: IpAddr = network.parse().map_err(|_| format!("bad address {:?}", network))?;
+                let bits = if network.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    "" => bits,
+                    prefix => prefix.parse().ok().filter(|&prefix| prefix <= bits).ok_or_else(|| format!("bad prefix {:?}", prefix))?,
+                };
+                Test::Ip(network, prefix)
+            }
+            _ => return Err(format!("unknown test {:?}", kind)),
+        };
+        Ok(Rule { name: name.to_string(), score, test })
+    }
+
+    fn matches(&self, msg: &Message, body: &str) -> bool {
+        match &self.test {
+            Test::Header(name, regex) => msg
+                .headers
+                .get_all(name)
+                .any(|header| regex.is_match(&mime::decode_encoded_words(&header.unfolded()))),
+            Test::Body(regex) => regex.is_match(body),
+            Test::Helo(regex) => regex.is_match(&msg.client_domain),
+            Test::Spf(result) => msg.spf.as_ref() == Some(result),
+            Test::Ip(network, prefix) => msg.client_ip.is_some_and(|ip| spf::in_network(ip, *network, *prefix)),
+        }
+    }
+}
+
+/// The text of every body part that is not an attachment.
+fn body_text(msg: &Message) -> String {
+    let part = mime::parse_entity(&msg.headers, &String::from_utf8_lossy(&msg.body));
+    part.leaves()
+        .into_iter()
+        .filter(|part| !part.is_attachment())
+        .filter_map(|part| part.text())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Scores each message and marks it with `X-Spam-Score` and
+/// `X-Spam-Status`. Spam is rejected or quarantined; everything else goes
+/// on to the next handler.
+pub struct SpamHandler {
+    pub rules: SpamRules,
+    pub next: Arc<dyn MessageHandler>,
+}
+
+impl SpamHandler {
+    pub fn new(rules: SpamRules, next: Arc<dyn MessageHandler>) -> SpamHandler {
+        SpamHandler { rules, next }
+    }
+}
+
+impl MessageHandler for SpamHandler {
+    fn handle(&self, msg: &mut Message) -> Verdict {
+        let score = self.rules.score(msg);
+        let spam = score.total >= self.rules.threshold;
+        // The same layout as SpamAssassin's, which many filters understand.
+        let status = format!(
+            "{}, score={:.1} required={:.1} tests={}",
+            if spam { "Yes" } else { "No" },
+            score.total,
+            self.rules.threshold,
+            if score.tests.is_empty() { "none".to_string() } else { score.tests.join(",") },
+        );
+        // Never trust a verdict that came with the message.
+        msg.headers.remove("X-Spam-Score");
+        msg.headers.remove("X-Spam-Status");
+        msg.prepend_header("X-Spam-Status", &status);
+        msg.prepend_header("X-Spam-Score", &format!("{:.1}", score.total));
+        if !spam {
+            return self.next.handle(msg);
+        }
+        let reason = format!("spam score {:.1} reaches {:.1}", score.total, self.rules.threshold);
+        match self.rules.action {
+            Action::Reject => Verdict::Reject(format!("5.7.1 Message rejected as spam ({})", reason)),
+            Action::Quarantine => Verdict::Quarantine(reason),
+        }
+    }
+}
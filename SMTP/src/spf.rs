@@ -0,0 +1,630 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::dns::{self, Resolver};
+
+/// Result of an SPF (RFC 7208) `check_host()` evaluation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpfResult {
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    TempError,
+    PermError,
+}
+
+impl SpfResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpfResult::None => "none",
+            SpfResult::Neutral => "neutral",
+            SpfResult::Pass => "pass",
+            SpfResult::Fail => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::TempError => "temperror",
+            SpfResult::PermError => "permerror",
+        }
+    }
+
+    /// The result named `name`, as `as_str` spells it.
+    pub fn parse(name: &str) -> Option<SpfResult> {
+        [
+            SpfResult::None,
+            SpfResult::Neutral,
+            SpfResult::Pass,
+            SpfResult::Fail,
+            SpfResult::SoftFail,
+            SpfResult::TempError,
+            SpfResult::PermError,
+        ]
+        .into_iter()
+        .find(|result| result.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Limit on mechanisms and modifiers that cause DNS queries (RFC 7208 4.6.4).
+const MAX_DNS_LOOKUPS: u32 = 10;
+/// Limit on lookups returning no records before giving up.
+const MAX_VOID_LOOKUPS: u32 = 2;
+/// Limit on names looked up for a single `mx` or `ptr` mechanism.
+const MAX_NAME_LOOKUPS: usize = 10;
+
+/// Checks the envelope sender (the address from `MAIL FROM`, without angle
+/// brackets) against the connecting `ip`.
+///
+/// For the null reverse-path the HELO identity is checked instead, as
+/// `postmaster@<helo>`.
+pub fn check_sender(resolver: &dyn Resolver, ip: IpAddr, sender: &str, helo: &str) -> SpfResult {
+    let sender = if sender.is_empty() {
+        format!("postmaster@{}", helo)
+    } else if !sender.contains('@') {
+        format!("postmaster@{}", sender)
+    } else {
+        sender.to_string()
+    };
+    let domain = sender.rsplit_once('@').map(|(_, d)| d).unwrap_or_default().to_string();
+    check_host(resolver, ip, &domain, &sender, helo)
+}
+
+/// The `check_host()` function from RFC 7208 section 4.
+pub fn check_host(resolver: &dyn Resolver, ip: IpAddr, domain: &str, sender: &str, helo: &str) -> SpfResult {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    };
+    let mut evaluator = Evaluator {
+        resolver,
+        ip,
+        sender: sender.to_string(),
+        helo: helo.to_string(),
+        lookups: 0,
+        void_lookups: 0,
+    };
+    evaluator.check_host(&dns::normalize_name(domain))
+}
+
+/// Builds the value of a `Received-SPF` header (RFC 7208 section 9.1).
+pub fn received_spf(result: SpfResult, receiver: &str, ip: IpAddr, sender: &str, helo: &str) -> String {
+    let identity = if sender.is_empty() {
+        format!("postmaster@{}", helo)
+    } else {
+        sender.to_string()
+    };
+    let comment = match result {
+        SpfResult::Pass => format!("domain of {} designates {} as permitted sender", identity, ip),
+        SpfResult::Fail => format!("domain of {} does not designate {} as permitted sender", identity, ip),
+        SpfResult::SoftFail => format!(
+            "domain of transitioning {} does not designate {} as permitted sender",
+            identity, ip
+        ),
+        SpfResult::Neutral => format!("{} is neither permitted nor denied by domain of {}", ip, identity),
+        SpfResult::None => format!("domain of {} does not publish SPF records", identity),
+        SpfResult::TempError => format!("error in processing during lookup of {}", identity),
+        SpfResult::PermError => format!("domain of {} has an invalid SPF record", identity),
+    };
+    format!(
+        "{} ({}: {}) receiver={}; client-ip={}; envelope-from=\"{}\"; helo={};",
+        result, receiver, comment, receiver, ip, sender, helo
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+impl Qualifier {
+    fn result(self) -> SpfResult {
+        match self {
+            Qualifier::Pass => SpfResult::Pass,
+            Qualifier::Fail => SpfResult::Fail,
+            Qualifier::SoftFail => SpfResult::SoftFail,
+            Qualifier::Neutral => SpfResult::Neutral,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Mechanism {
+    All,
+    Include(String),
+    A(Option<String>, Option<u8>, Option<u8>),
+    Mx(Option<String>, Option<u8>, Option<u8>),
+    Ptr(Option<String>),
+    Ip4(IpAddr, u8),
+    Ip6(IpAddr, u8),
+    Exists(String),
+}
+
+struct Record {
+    directives: Vec<(Qualifier, Mechanism)>,
+    redirect: Option<String>,
+}
+
+/// Marker for a record or macro that violates the SPF grammar.
+struct PermError;
+
+fn parse_record(record: &str) -> Result<Record, PermError> {
+    let mut directives = Vec::new();
+    let mut redirect = None;
+    let mut seen_exp = false;
+
+    for term in record.split_ascii_whitespace().skip(1) {
+        // A modifier is `name=value` where the name comes before any ':' or '/'.
+        if let Some(eq) = term.find('=') {
+            let name = &term[..eq];
+            if !name.is_empty() && !name.contains([':', '/']) {
+                let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+                if !valid_name {
+                    return Err(PermError);
+                }
+                let value = &term[eq + 1..];
+                match name.to_ascii_lowercase().as_str() {
+                    "redirect" => {
+                        if redirect.is_some() || value.is_empty() {
+                            return Err(PermError);
+                        }
+                        redirect = Some(value.to_string());
+                    }
+                    "exp" => {
+                        if seen_exp || value.is_empty() {
+                            return Err(PermError);
+                        }
+                        seen_exp = true;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+        }
+
+        let (qualifier, rest) = match term.chars().next() {
+            Some('+') => (Qualifier::Pass, &term[1..]),
+            Some('-') => (Qualifier::Fail, &term[1..]),
+            Some('~') => (Qualifier::SoftFail, &term[1..]),
+            Some('?') => (Qualifier::Neutral, &term[1..]),
+            _ => (Qualifier::Pass, term),
+        };
+        directives.push((qualifier, parse_mechanism(rest)?));
+    }
+    Ok(Record { directives, redirect })
+}
+
+fn parse_mechanism(term: &str) -> Result<Mechanism, PermError> {
+    let name_end = term.find([':', '/']).unwrap_or(term.len());
+    let name = term[..name_end].to_ascii_lowercase();
+    let rest = &term[name_end..];
+    let arg = rest.strip_prefix(':');
+
+    match name.as_str() {
+        "all" if rest.is_empty() => Ok(Mechanism::All),
+        "include" => Ok(Mechanism::Include(required(arg)?)),
+        "exists" => Ok(Mechanism::Exists(required(arg)?)),
+        "ptr" => match arg {
+            Some(domain) if !domain.is_empty() => Ok(Mechanism::Ptr(Some(domain.to_string()))),
+            None if rest.is_empty() => Ok(Mechanism::Ptr(None)),
+            _ => Err(PermError),
+        },
+        "a" | "mx" => {
+            let (domain, v4, v6) = parse_dual_cidr(arg.unwrap_or(rest), arg.is_some())?;
+            if name == "a" {
+                Ok(Mechanism::A(domain, v4, v6))
+            } else {
+                Ok(Mechanism::Mx(domain, v4, v6))
+            }
+        }
+        "ip4" => {
+            let value = required(arg)?;
+            let (addr, len) = value.split_once('/').map(|(a, l)| (a, Some(l))).unwrap_or((&value, None));
+            let addr: std::net::Ipv4Addr = addr.parse().map_err(|_| PermError)?;
+            Ok(Mechanism::Ip4(IpAddr::V4(addr), parse_cidr(len, 32)?))
+        }
+        "ip6" => {
+            let value = required(arg)?;
+            let (addr, len) = value.split_once('/').map(|(a, l)| (a, Some(l))).unwrap_or((&value, None));
+            let addr: std::net::Ipv6Addr = addr.parse().map_err(|_| PermError)?;
+            Ok(Mechanism::Ip6(IpAddr::V6(addr), parse_cidr(len, 128)?))
+        }
+        _ => Err(PermError),
+    }
+}
+
+fn required(arg: Option<&str>) -> Result<String, PermError> {
+    match arg {
+        Some(value) if !value.is_empty() => Ok(value.to_string()),
+        _ => Err(PermError),
+    }
+}
+
+fn parse_cidr(len: Option<&str>, max: u8) -> Result<u8, PermError> {
+    match len {
+        None => Ok(max),
+        Some(len) => {
+            if len.is_empty() || (len.len() > 1 && len.starts_with('0')) {
+                return Err(PermError);
+            }
+            let len: u8 = len.parse().map_err(|_| PermError)?;
+            if len > max {
+                return Err(PermError);
+            }
+            Ok(len)
+        }
+    }
+}
+
+/// Domain-spec and IPv4/IPv6 prefix lengths of an `a` or `mx` mechanism.
+type DualCidr = (Option<String>, Option<u8>, Option<u8>);
+
+/// Splits `domain/24//64` style arguments of `a` and `mx`.
+fn parse_dual_cidr(value: &str, has_domain: bool) -> Result<DualCidr, PermError> {
+    let mut rest = value;
+    let mut v6 = None;
+    if let Some(i) = rest.rfind("//") {
+        if rest[i + 2..].chars().all(|c| c.is_ascii_digit()) {
+            v6 = Some(parse_cidr(Some(&rest[i + 2..]), 128)?);
+            rest = &rest[..i];
+        }
+    }
+    let mut v4 = None;
+    if let Some(i) = rest.rfind('/') {
+        if rest[i + 1..].chars().all(|c| c.is_ascii_digit()) {
+            v4 = Some(parse_cidr(Some(&rest[i + 1..]), 32)?);
+            rest = &rest[..i];
+        }
+    }
+    if has_domain {
+        if rest.is_empty() {
+            return Err(PermError);
+        }
+        Ok((Some(rest.to_string()), v4, v6))
+    } else if rest.is_empty() {
+        Ok((None, v4, v6))
+    } else {
+        Err(PermError)
+    }
+}
+
+/// Whether `ip` is within `network`/`len`, which must be at most the
+/// address length.
+pub(crate) fn in_network(ip: IpAddr, network: IpAddr, len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = if len == 0 { 0 } else { u32::MAX << (32 - len as u32) };
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = if len == 0 { 0 } else { u128::MAX << (128 - len as u32) };
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+struct Evaluator<'a> {
+    resolver: &'a dyn Resolver,
+    ip: IpAddr,
+    sender: String,
+    helo: String,
+    lookups: u32,
+    void_lookups: u32,
+}
+
+/// Outcome of matching one mechanism.
+enum Match {
+    Yes,
+    No,
+    Error(SpfResult),
+}
+
+impl Evaluator<'_> {
+    fn check_host(&mut self, domain: &str) -> SpfResult {
+        if !valid_domain(domain) {
+            return SpfResult::None;
+        }
+
+        let records = match self.resolver.txt(domain) {
+            Ok(records) => records,
+            Err(_) => return SpfResult::TempError,
+        };
+        let spf_records: Vec<&String> = records
+            .iter()
+            .filter(|r| {
+                let lower = r.to_ascii_lowercase();
+                lower == "v=spf1" || lower.starts_with("v=spf1 ")
+            })
+            .collect();
+        let record = match spf_records.len() {
+            0 => return SpfResult::None,
+            1 => spf_records[0],
+            _ => return SpfResult::PermError,
+        };
+        let record = match parse_record(record) {
+            Ok(record) => record,
+            Err(PermError) => return SpfResult::PermError,
+        };
+
+        for (qualifier, mechanism) in &record.directives {
+            match self.matches(mechanism, domain) {
+                Match::Yes => return qualifier.result(),
+                Match::No => {}
+                Match::Error(result) => return result,
+            }
+        }
+
+        if let Some(redirect) = &record.redirect {
+            if let Err(result) = self.count_lookup() {
+                return result;
+            }
+            let target = match self.expand(redirect, domain) {
+                Ok(target) => target,
+                Err(PermError) => return SpfResult::PermError,
+            };
+            return match self.check_host(&target) {
+                SpfResult::None => SpfResult::PermError,
+                result => result,
+            };
+        }
+        SpfResult::Neutral
+    }
+
+    fn count_lookup(&mut self) -> Result<(), SpfResult> {
+        self.lookups += 1;
+        if self.lookups > MAX_DNS_LOOKUPS {
+            return Err(SpfResult::PermError);
+        }
+        Ok(())
+    }
+
+    fn count_void(&mut self, empty: bool) -> Result<(), SpfResult> {
+        if empty {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err(SpfResult::PermError);
+            }
+        }
+        Ok(())
+    }
+
+    fn target(&self, spec: &Option<String>, domain: &str) -> Result<String, SpfResult> {
+        match spec {
+            Some(spec) => self.expand(spec, domain).map_err(|_| SpfResult::PermError),
+            None => Ok(domain.to_string()),
+        }
+    }
+
+    /// A or AAAA addresses of `name`, matching the family of the client.
+    fn addresses(&mut self, name: &str) -> Result<Vec<IpAddr>, SpfResult> {
+        let found: Vec<IpAddr> = match self.ip {
+            IpAddr::V4(_) => self
+                .resolver
+                .a(name)
+                .map_err(|_| SpfResult::TempError)?
+                .into_iter()
+                .map(IpAddr::V4)
+                .collect(),
+            IpAddr::V6(_) => self
+                .resolver
+                .aaaa(name)
+                .map_err(|_| SpfResult::TempError)?
+                .into_iter()
+                .map(IpAddr::V6)
+                .collect(),
+        };
+        Ok(found)
+    }
+
+    fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Match {
+        match self.try_match(mechanism, domain) {
+            Ok(true) => Match::Yes,
+            Ok(false) => Match::No,
+            Err(result) => Match::Error(result),
+        }
+    }
+
+    fn try_match(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, SpfResult> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip4(net, len) | Mechanism::Ip6(net, len) => Ok(in_network(self.ip, *net, *len)),
+            Mechanism::Include(spec) => {
+                self.count_lookup()?;
+                let target = self.target(&Some(spec.clone()), domain)?;
+                match self.check_host(&target) {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    SpfResult::TempError => Err(SpfResult::TempError),
+                    SpfResult::PermError | SpfResult::None => Err(SpfResult::PermError),
+                }
+            }
+            Mechanism::A(spec, v4, v6) => {
+                self.count_lookup()?;
+                let target = self.target(spec, domain)?;
+                let found = self.addresses(&target)?;
+                self.count_void(found.is_empty())?;
+                Ok(self.any_in_network(&found, *v4, *v6))
+            }
+            Mechanism::Mx(spec, v4, v6) => {
+                self.count_lookup()?;
+                let target = self.target(spec, domain)?;
+                let exchanges = self.resolver.mx(&target).map_err(|_| SpfResult::TempError)?;
+                self.count_void(exchanges.is_empty())?;
+                if exchanges.len() > MAX_NAME_LOOKUPS {
+                    return Err(SpfResult::PermError);
+                }
+                for (_, exchange) in exchanges {
+                    let found = self.addresses(&exchange)?;
+                    if self.any_in_network(&found, *v4, *v6) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(spec) => {
+                self.count_lookup()?;
+                let target = self.target(spec, domain)?;
+                let names = match self.resolver.ptr(self.ip) {
+                    Ok(names) => names,
+                    // A failed reverse lookup is simply no match for ptr.
+                    Err(_) => return Ok(false),
+                };
+                self.count_void(names.is_empty())?;
+                for name in names.iter().take(MAX_NAME_LOOKUPS) {
+                    let name = dns::normalize_name(name);
+                    if name != target && !name.ends_with(&format!(".{}", target)) {
+                        continue;
+                    }
+                    if let Ok(found) = self.addresses(&name) {
+                        if found.contains(&self.ip) {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Exists(spec) => {
+                self.count_lookup()?;
+                let target = self.target(&Some(spec.clone()), domain)?;
+                let found = self.resolver.a(&target).map_err(|_| SpfResult::TempError)?;
+                self.count_void(found.is_empty())?;
+                Ok(!found.is_empty())
+            }
+        }
+    }
+
+    fn any_in_network(&self, found: &[IpAddr], v4: Option<u8>, v6: Option<u8>) -> bool {
+        found.iter().any(|addr| match addr {
+            IpAddr::V4(_) => in_network(self.ip, *addr, v4.unwrap_or(32)),
+            IpAddr::V6(_) => in_network(self.ip, *addr, v6.unwrap_or(128)),
+        })
+    }
+
+    /// Expands the macros of a domain-spec (RFC 7208 section 7).
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, PermError> {
+        let mut out = String::new();
+        let mut chars = spec.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('_') => out.push(' '),
+                Some('-') => out.push_str("%20"),
+                Some('{') => {
+                    let mut body = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => body.push(c),
+                            None => return Err(PermError),
+                        }
+                    }
+                    out += &self.expand_macro(&body, domain)?;
+                }
+                _ => return Err(PermError),
+            }
+        }
+
+        // Over long results lose labels from the left until they fit.
+        let mut expanded = out.as_str();
+        while expanded.len() > 253 {
+            match expanded.find('.') {
+                Some(i) => expanded = &expanded[i + 1..],
+                None => break,
+            }
+        }
+        Ok(dns::normalize_name(expanded))
+    }
+
+    fn expand_macro(&self, body: &str, domain: &str) -> Result<String, PermError> {
+        let mut chars = body.chars();
+        let letter = chars.next().ok_or(PermError)?;
+        let rest: String = chars.collect();
+
+        let (local, sender_domain) = self.sender.rsplit_once('@').unwrap_or(("postmaster", &self.sender));
+        let value = match letter.to_ascii_lowercase() {
+            's' => self.sender.clone(),
+            'l' => local.to_string(),
+            'o' => sender_domain.to_string(),
+            'd' => domain.to_string(),
+            'h' => self.helo.clone(),
+            'i' => match self.ip {
+                IpAddr::V4(v4) => v4.to_string(),
+                IpAddr::V6(v6) => v6
+                    .octets()
+                    .iter()
+                    .map(|b| format!("{:x}.{:x}", b >> 4, b & 0x0f))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            'v' => match self.ip {
+                IpAddr::V4(_) => "in-addr".to_string(),
+                IpAddr::V6(_) => "ip6".to_string(),
+            },
+            // Validating the PTR name costs extra lookups, the RFC allows "unknown".
+            'p' => "unknown".to_string(),
+            _ => return Err(PermError),
+        };
+
+        let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let mut rest = &rest[digits.len()..];
+        let reverse = rest.starts_with(['r', 'R']);
+        if reverse {
+            rest = &rest[1..];
+        }
+        if !rest.chars().all(|c| ".-+,/_=".contains(c)) {
+            return Err(PermError);
+        }
+        let delimiters = if rest.is_empty() { "." } else { rest };
+
+        let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+        if reverse {
+            parts.reverse();
+        }
+        if !digits.is_empty() {
+            let keep: usize = digits.parse().map_err(|_| PermError)?;
+            if keep == 0 {
+                return Err(PermError);
+            }
+            if parts.len() > keep {
+                parts = parts.split_off(parts.len() - keep);
+            }
+        }
+        let joined = parts.join(".");
+
+        if letter.is_ascii_uppercase() {
+            Ok(url_escape(&joined))
+        } else {
+            Ok(joined)
+        }
+    }
+}
+
+fn url_escape(value: &str) -> String {
+    let mut out = String::new();
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out += &format!("%{:02X}", b);
+        }
+    }
+    out
+}
+
+fn valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+    labels.len() > 1 && domain.len() <= 253 && labels.iter().all(|l| !l.is_empty() && l.len() <= 63)
+}
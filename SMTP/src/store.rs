@@ -0,0 +1,261 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::headers::{self, Headers};
+use crate::mime::{self, Part};
+use crate::server::Message;
+
+/// A captured message together with its envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredMessage {
+    /// The queue id the message was accepted under.
+    pub id: String,
+    /// Seconds since the Unix epoch.
+    pub received_at: u64,
+    pub mail_from: String,
+    pub recipients: Vec<String>,
+    pub quarantined: bool,
+    /// The folder a Sieve script filed the message into; `None` for the inbox.
+    pub folder: Option<String>,
+    /// Header section, empty line and body, with CRLF line endings. The body
+    /// is kept byte for byte, so it need not be UTF-8.
+    pub content: Vec<u8>,
+}
+
+impl StoredMessage {
+    pub fn from_message(msg: &Message, quarantined: bool) -> StoredMessage {
+        StoredMessage {
+            id: msg.queue_id.clone(),
+            received_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            mail_from: msg.mail_from(),
+            recipients: msg.recipients(),
+            quarantined,
+            folder: None,
+            content: msg.content(),
+        }
+    }
+
+    /// The content as text, with any invalid UTF-8 replaced.
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.content)
+    }
+
+    pub fn headers(&self) -> Headers {
+        Headers::parse(&String::from_utf8_lossy(headers::split_message_bytes(&self.content).0))
+    }
+
+    pub fn body(&self) -> &[u8] {
+        headers::split_message_bytes(&self.content).1
+    }
+
+    pub fn mime(&self) -> Part {
+        mime::parse_message(&self.text())
+    }
+
+    /// A decoded header value, empty when missing.
+    pub fn header(&self, name: &str) -> String {
+        self.headers().get(name).map(|v| mime::decode_encoded_words(&v)).unwrap_or_default()
+    }
+
+    pub fn matches(&self, query: &SearchQuery) -> bool {
+        let contains = |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+        if let Some(recipient) = &query.recipient {
+            let in_envelope = self.recipients.iter().any(|r| contains(r, recipient));
+            let in_headers = ["To", "Cc"].iter().any(|h| contains(&self.header(h), recipient));
+            if !in_envelope && !in_headers {
+                return false;
+            }
+        }
+        if let Some(sender) = &query.sender {
+            if !contains(&self.mail_from, sender) && !contains(&self.header("From"), sender) {
+                return false;
+            }
+        }
+        if let Some(subject) = &query.subject {
+            if !contains(&self.header("Subject"), subject) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Filters for `MessageStore::search`; unset fields match everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub recipient: Option<String>,
+    pub sender: Option<String>,
+    pub subject: Option<String>,
+}
+
+/// Somewhere accepted messages are kept for later inspection.
+pub trait MessageStore: Send + Sync {
+    fn add(&self, msg: StoredMessage) -> Result<()>;
+    /// All messages, oldest first.
+    fn list(&self) -> Result<Vec<StoredMessage>>;
+    fn get(&self, id: &str) -> Result<Option<StoredMessage>>;
+    /// Returns whether a message was deleted.
+    fn delete(&self, id: &str) -> Result<bool>;
+    fn delete_all(&self) -> Result<()>;
+
+    fn search(&self, query: &SearchQuery) -> Result<Vec<StoredMessage>> {
+        Ok(self.list()?.into_iter().filter(|m| m.matches(query)).collect())
+    }
+}
+
+/// Keeps messages in memory; they are lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    messages: Mutex<Vec<StoredMessage>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn add(&self, msg: StoredMessage) -> Result<()> {
+        self.messages.lock().unwrap().push(msg);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<StoredMessage>> {
+        Ok(self.messages.lock().unwrap().clone())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<StoredMessage>> {
+        Ok(self.messages.lock().unwrap().iter().find(|m| m.id == id).cloned())
+    }
+
+    fn delete(&self, id: &str) -> Result<bool> {
+        let mut messages = self.messages.lock().unwrap();
+        let before = messages.len();
+        messages.retain(|m| m.id != id);
+        Ok(messages.len() != before)
+    }
+
+    fn delete_all(&self) -> Result<()> {
+        self.messages.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// Keeps each message as `<id>.eml` next to an `<id>.envelope` file in a directory.
+pub struct DirStore {
+    pub dir: PathBuf,
+}
+
+impl DirStore {
+    /// Opens (creating if needed) a store in `dir`.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<DirStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(DirStore { dir })
+    }
+
+    fn paths(&self, id: &str) -> Result<(PathBuf, PathBuf)> {
+        // Ids come from clients of the HTTP API, so keep them to plain names.
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid message id {:?}", id)));
+        }
+        Ok((self.dir.join(format!("{}.eml", id)), self.dir.join(format!("{}.envelope", id))))
+    }
+
+    fn read(&self, id: &str) -> Result<Option<StoredMessage>> {
+        let (eml, envelope) = self.paths(id)?;
+        let content = match fs::read(&eml) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut msg = StoredMessage {
+            id: id.to_string(),
+            received_at: 0,
+            mail_from: String::new(),
+            recipients: Vec::new(),
+            quarantined: false,
+            folder: None,
+            content,
+        };
+        for line in fs::read_to_string(envelope).unwrap_or_default().lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "received" => msg.received_at = value.parse().unwrap_or(0),
+                "from" => msg.mail_from = value.to_string(),
+                "to" => msg.recipients.push(value.to_string()),
+                "quarantined" => msg.quarantined = true,
+                "folder" => msg.folder = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok(Some(msg))
+    }
+}
+
+impl MessageStore for DirStore {
+    fn add(&self, msg: StoredMessage) -> Result<()> {
+        let (eml, envelope) = self.paths(&msg.id)?;
+        let mut env = format!("received {}\nfrom {}\n", msg.received_at, msg.mail_from);
+        for recipient in &msg.recipients {
+            env += &format!("to {}\n", recipient);
+        }
+        if msg.quarantined {
+            env += "quarantined\n";
+        }
+        if let Some(folder) = &msg.folder {
+            env += &format!("folder {}\n", folder);
+        }
+        fs::write(envelope, env)?;
+        fs::write(eml, msg.content)
+    }
+
+    fn list(&self) -> Result<Vec<StoredMessage>> {
+        let mut messages = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("eml") {
+                continue;
+            }
+            if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+                if let Ok(Some(msg)) = self.read(id) {
+                    messages.push(msg);
+                }
+            }
+        }
+        messages.sort_by(|a, b| (a.received_at, &a.id).cmp(&(b.received_at, &b.id)));
+        Ok(messages)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<StoredMessage>> {
+        match self.read(id) {
+            Err(e) if e.kind() == ErrorKind::InvalidInput => Ok(None),
+            result => result,
+        }
+    }
+
+    fn delete(&self, id: &str) -> Result<bool> {
+        let (eml, envelope) = match self.paths(id) {
+            Ok(paths) => paths,
+            Err(_) => return Ok(false),
+        };
+        let _ = fs::remove_file(envelope);
+        match fs::remove_file(eml) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn delete_all(&self) -> Result<()> {
+        for msg in self.list()? {
+            self.delete(&msg.id)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+/// A client connection, over TCP or a Unix domain socket, or TCP once the
+/// client has started TLS.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Switches a TCP connection to TLS, with this side as the server. The
+    /// handshake happens on the next read or write.
+    pub fn start_tls(&mut self, config: Arc<ServerConfig>) -> Result<()> {
+        let tcp = match self {
+            // A second handle on the same socket; dropping the old one
+            // leaves the connection open.
+            Stream::Tcp(stream) => stream.try_clone()?,
+            _ => return Err(Error::new(ErrorKind::Unsupported, "TLS is only available over TCP")),
+        };
+        let connection = ServerConnection::new(config).map_err(|e| Error::other(e.to_string()))?;
+        *self = Stream::Tls(Box::new(StreamOwned::new(connection, tcp)));
+        Ok(())
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Stream::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+            Stream::Tls(stream) => Some(stream.get_ref()),
+        }
+    }
+
+    /// Makes reads give up with `WouldBlock` or `TimedOut` after `timeout`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            _ => self.tcp().map_or(Ok(()), |stream| stream.set_read_timeout(timeout)),
+        }
+    }
+
+    /// The client's address for logs.
+    pub fn peer(&self) -> String {
+        match self.tcp() {
+            Some(stream) => stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "unknown".to_string()),
+            None => "local".to_string(),
+        }
+    }
+
+    /// The client's IP address. Unix socket clients are on this host, so
+    /// they count as loopback.
+    pub fn peer_ip(&self) -> Result<IpAddr> {
+        match self.tcp() {
+            Some(stream) => stream.peer_addr().map(|addr| addr.ip()),
+            None => Ok(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        }
+    }
+
+    /// The client's socket address; `None` over a Unix domain socket.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.tcp().and_then(|stream| stream.peer_addr().ok())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,17 @@
+//! The time of day, behind a trait so that tests can move it along.
+
+use std::time::SystemTime;
+
+/// Tells the time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The real time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
@@ -0,0 +1,36 @@
+//! Server TLS settings, from a PEM certificate chain and private key.
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::ServerConfig;
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Builds a server configuration from PEM text: the certificate chain, leaf
+/// first, and its private key in PKCS#8, PKCS#1 or SEC1 form.
+pub fn server_config(cert_pem: &[u8], key_pem: &[u8]) -> Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut &cert_pem[..]).collect::<Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid("no certificate in PEM".to_string()));
+    }
+    let key = rustls_pemfile::private_key(&mut &key_pem[..])?
+        .ok_or_else(|| invalid("no private key in PEM".to_string()))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+/// Like `server_config`, reading the PEM files at `cert` and `key`.
+pub fn load(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Arc<ServerConfig>> {
+    server_config(&fs::read(cert)?, &fs::read(key)?)
+}
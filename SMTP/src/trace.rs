@@ -0,0 +1,128 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Received headers allowed before a message is considered to be looping.
+pub const DEFAULT_MAX_HOPS: usize = 100;
+
+static QUEUE_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// A new identifier for a message, unique within this process and unlikely
+/// to repeat across restarts.
+pub fn new_queue_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let count = QUEUE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:09X}{:05X}{:04X}", now.as_secs(), now.subsec_micros(), count & 0xffff)
+}
+
+/// Formats `time` as an RFC 5322 date-time in UTC, e.g.
+/// `Mon, 19 Oct 2026 08:11:00 +0000`.
+pub fn rfc5322_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Converts days since 1970-01-01 into (year, month, day), after Howard
+/// Hinnant's `civil_from_days`.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Converts a date into days since 1970-01-01; the inverse of
+/// `civil_from_days`.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// The `with` protocol keyword of a Received header (RFC 3848).
+pub fn protocol(esmtp: bool, tls: bool, authenticated: bool) -> &'static str {
+    match (esmtp, tls, authenticated) {
+        (false, _, _) => "SMTP",
+        (true, false, false) => "ESMTP",
+        (true, true, false) => "ESMTPS",
+        (true, false, true) => "ESMTPA",
+        (true, true, true) => "ESMTPSA",
+    }
+}
+
+/// The LMTP form of a keyword, e.g. `LMTPA` for `ESMTPA` (RFC 3848).
+pub fn lmtp_protocol(protocol: &str) -> String {
+    format!("L{}", protocol.strip_prefix("ES").unwrap_or(protocol))
+}
+
+/// The keyword for a session that used SMTPUTF8, e.g. `UTF8SMTPS` for
+/// `ESMTPS` (RFC 6531 section 3.7.3).
+pub fn utf8_protocol(protocol: &str) -> String {
+    format!("UTF8{}", protocol.strip_prefix('E').unwrap_or(protocol))
+}
+
+/// What a receiving server knows about one hop.
+pub struct Hop<'a> {
+    /// The name the client gave in EHLO/HELO.
+    pub helo: &'a str,
+    /// The client's reverse DNS name, if it has one.
+    pub reverse_name: Option<&'a str>,
+    pub ip: IpAddr,
+    /// This server's name.
+    pub by: &'a str,
+    pub protocol: &'a str,
+    pub queue_id: &'a str,
+    /// The recipient, given only for single recipient messages.
+    pub recipient: Option<&'a str>,
+    pub time: SystemTime,
+}
+
+/// Builds the value of the `Received` header for `hop` (RFC 5321 4.4).
+pub fn received(hop: &Hop) -> String {
+    let ip = match hop.ip {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("IPv6:{}", v6),
+    };
+    let client = match hop.reverse_name {
+        Some(name) => format!("{} [{}]", name, ip),
+        None => format!("[{}]", ip),
+    };
+    let mut value = format!(
+        "from {} ({})\r\n\tby {} with {} id {}",
+        hop.helo, client, hop.by, hop.protocol, hop.queue_id
+    );
+    if let Some(recipient) = hop.recipient {
+        value += &format!("\r\n\tfor <{}>", recipient);
+    }
+    value + &format!(";\r\n\t{}", rfc5322_date(hop.time))
+}
+
+/// The value of the `Return-Path` header added at final delivery.
+pub fn return_path(sender: &str) -> String {
+    format!("<{}>", sender)
+}
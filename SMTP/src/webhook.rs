@@ -0,0 +1,190 @@
+//! Delivery to an HTTP endpoint: each accepted message is POSTed as JSON,
+//! with its envelope, decoded headers, text and HTML bodies and base64
+//! attachments.
+//!
+//! A 2xx response means the message was delivered. Connection failures,
+//! timeouts, 408, 429 and 5xx responses are retried with exponential
+//! backoff; any other response fails at once. When a secret is set, the body
+//! is signed with HMAC-SHA256 in the `X-Webhook-Signature` header as
+//! `sha256=<hex>`.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::json::Json;
+use crate::mime::{self, Part};
+use crate::server::Message;
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// An endpoint to POST accepted messages to.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    /// `host:port`, port 80 unless the URL gave one.
+    pub authority: String,
+    /// The request target, starting with `/`.
+    pub path: String,
+    /// Key for the `X-Webhook-Signature` HMAC, if payloads are signed.
+    pub secret: Option<Vec<u8>>,
+    /// How long to wait to connect, and for each read or write.
+    pub timeout: Duration,
+    /// How many times to try before giving up, at least once.
+    pub attempts: u32,
+    /// The wait before the first retry, doubled for each one after.
+    pub backoff: Duration,
+}
+
+impl Webhook {
+    /// An unsigned webhook for an `http://` URL, tried three times.
+    pub fn new(url: &str) -> Result<Webhook> {
+        let rest = match url.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &url[7..],
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("webhook URL must start with http://: {}", url))),
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("webhook URL has no host: {}", url)));
+        }
+        let has_port = match host.rfind(':') {
+            Some(i) => !host[i..].contains(']'),
+            None => false,
+        };
+        let authority = if has_port { host.to_string() } else { format!("{}:80", host) };
+        Ok(Webhook {
+            authority,
+            path: path.to_string(),
+            secret: None,
+            timeout: Duration::from_secs(10),
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+        })
+    }
+
+    /// The `X-Webhook-Signature` value for `body`, if there is a secret.
+    pub fn signature(&self, body: &[u8]) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+        mac.update(body);
+        let digest = mac.finalize().into_bytes();
+        Some(format!("sha256={}", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+    }
+
+    /// POSTs the message, retrying as needed. The error describes the last
+    /// failure.
+    pub fn deliver(&self, msg: &Message) -> Result<()> {
+        let body = payload(msg).to_string().into_bytes();
+        let mut delay = self.backoff;
+        let mut attempt = 1;
+        loop {
+            let error = match self.post(&msg.queue_id, &body) {
+                Ok(status) if (200..300).contains(&status) => return Ok(()),
+                Ok(status) => {
+                    let error = invalid(format!("webhook answered {}", status));
+                    if !matches!(status, 408 | 429 | 500..=599) {
+                        return Err(error);
+                    }
+                    error
+                }
+                Err(e) => e,
+            };
+            if attempt >= self.attempts {
+                return Err(error);
+            }
+            println!("[WARN] Webhook attempt {} for {} failed: {}", attempt, msg.queue_id, error);
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Sends one request and returns the response status.
+    pub fn post(&self, id: &str, body: &[u8]) -> Result<u16> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nX-Webhook-Id: {}\r\nConnection: close\r\n",
+            self.path,
+            self.authority,
+            body.len(),
+            id
+        );
+        if let Some(signature) = self.signature(body) {
+            head += &format!("X-Webhook-Signature: {}\r\n", signature);
+        }
+        head += "\r\n";
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        let mut words = status_line.split_whitespace();
+        match (words.next(), words.next().and_then(|s| s.parse::<u16>().ok())) {
+            (Some(version), Some(status)) if version.starts_with("HTTP/") => Ok(status),
+            _ => Err(invalid(format!("malformed webhook response: {:?}", status_line.trim_end()))),
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let mut last = Error::new(ErrorKind::NotFound, format!("no address for {}", self.authority));
+        for addr in self.authority.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last = e,
+            }
+        }
+        Err(last)
+    }
+}
+
+/// The JSON document POSTed for `msg`.
+pub fn payload(msg: &Message) -> Json {
+    let root = mime::parse_entity(&msg.headers, &String::from_utf8_lossy(&msg.body));
+    let header = |name: &str| msg.headers.get(name).map(|v| mime::decode_encoded_words(&v));
+    let headers: Vec<Json> = msg
+        .headers
+        .iter()
+        .map(|h| {
+            Json::object()
+                .field("name", h.name.trim())
+                .field("value", mime::decode_encoded_words(&h.unfolded()))
+        })
+        .collect();
+    let envelope = Json::object()
+        .field("mail_from", msg.mail_from())
+        .field("recipients", msg.recipients())
+        .field("helo", &msg.client_domain)
+        .field("client_ip", msg.client_ip.map(|ip| ip.to_string()));
+    Json::object()
+        .field("id", &msg.queue_id)
+        .field("received_at", SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+        .field("envelope", envelope)
+        .field("from", header("From"))
+        .field("to", header("To"))
+        .field("subject", header("Subject"))
+        .field("headers", headers)
+        .field("text", root.find_text("text/plain"))
+        .field("html", root.find_text("text/html"))
+        .field("attachments", root.leaves().into_iter().filter(|p| p.is_attachment()).map(attachment).collect::<Vec<_>>())
+}
+
+fn attachment(part: &Part) -> Json {
+    Json::object()
+        .field("filename", part.filename())
+        .field("content_type", &part.content_type)
+        .field("size", part.body.len())
+        .field("content", BASE64.encode(&part.body))
+}
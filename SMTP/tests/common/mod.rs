@@ -0,0 +1,231 @@
+//! Scripted SMTP conversations for integration tests.
+//!
+//! A script lists what the client sends and the replies it expects, one
+//! per line, against a server started in-process on an ephemeral port:
+//!
+//! ```text
+//! S: 220*
+//! C: EHLO client.test
+//! S: 250-*250 SMTPUTF8
+//! C: MAIL FROM:<app@client.test>
+//! C: RCPT TO:<qa@sink.test>
+//! S: 250 2.1.0 *
+//! S: 250 2.1.5 *
+//! C: QUIT
+//! S: 221 *
+//! S: <closed>
+//! ```
+//!
+//! `C:` lines are sent as soon as they are reached, so consecutive ones are
+//! pipelined. Each `S:` line is matched against one whole reply; the lines
+//! of a multi-line reply are joined without separators. In patterns `*`
+//! matches any run of characters and `?` any single one. `S: <closed>`
+//! expects the server to close the connection. Blank lines and lines
+//! starting with `#` are skipped.
+//!
+//! On a mismatch the transcript so far is shown with the expected reply
+//! marked `-` and the one received marked `+`.
+//!
+//! The fixtures several test files need live here too.
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use smtp_server::dns::StaticResolver;
+use smtp_server::mailbox::MailboxStore;
+use smtp_server::server::{self, Config};
+use smtp_server::time::Clock;
+
+/// How long to wait for a reply before calling it missing.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a server with `config` on an ephemeral port.
+pub fn start(config: Config) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+    addr
+}
+
+/// A config whose DNS lookups all come up empty.
+pub fn offline_config() -> Config {
+    Config {
+        hostname: "mx.sink.test".to_string(),
+        resolver: Arc::new(StaticResolver::new()),
+        ..Config::default()
+    }
+}
+
+/// Runs `script` on a new connection to `addr`, panicking with a diff on
+/// the first mismatch.
+pub fn run(addr: SocketAddr, script: &str) {
+    if let Err(diff) = check(addr, script) {
+        panic!("SMTP conversation did not go as scripted:\n{}", diff);
+    }
+}
+
+/// Runs `script` on a new connection to `addr`; on a mismatch, returns the
+/// transcript with the difference marked.
+pub fn check(addr: SocketAddr, script: &str) -> Result<(), String> {
+    let stream = TcpStream::connect(addr).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT)).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut transcript = String::new();
+
+    for (number, step) in script.lines().map(str::trim_start).enumerate() {
+        if step.trim().is_empty() || step.starts_with('#') {
+            continue;
+        }
+        if let Some(line) = step.strip_prefix("C:") {
+            let line = line.strip_prefix(' ').unwrap_or(line);
+            // The server may already have hung up, which the next S: line reports.
+            let _ = writer.write_all(format!("{}\r\n", line).as_bytes());
+            transcript += &format!("  C: {}\n", line);
+        } else if let Some(pattern) = step.strip_prefix("S:") {
+            let pattern = pattern.trim();
+            let reply = next_reply(&mut reader);
+            let matched = match &reply {
+                Reply::Lines(lines) => matches(pattern, &lines.concat()),
+                Reply::Closed => pattern == "<closed>",
+                Reply::Silent => false,
+            };
+            if !matched {
+                transcript += &format!("- S: {}\n", pattern);
+                transcript += &reply.describe("+ S: ");
+                return Err(transcript);
+            }
+            transcript += &reply.describe("  S: ");
+        } else {
+            panic!("script line {} is neither C: nor S:: {:?}", number + 1, step);
+        }
+    }
+    Ok(())
+}
+
+enum Reply {
+    Lines(Vec<String>),
+    Closed,
+    /// Nothing arrived within `REPLY_TIMEOUT`.
+    Silent,
+}
+
+impl Reply {
+    fn describe(&self, prefix: &str) -> String {
+        match self {
+            Reply::Lines(lines) => lines.iter().map(|line| format!("{}{}\n", prefix, line)).collect(),
+            Reply::Closed => format!("{}<closed>\n", prefix),
+            Reply::Silent => format!("{}<no reply within {:?}>\n", prefix, REPLY_TIMEOUT),
+        }
+    }
+}
+
+/// Reads one reply, all lines of a multi-line one included.
+fn next_reply(reader: &mut BufReader<TcpStream>) -> Reply {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) if lines.is_empty() => return Reply::Closed,
+            Ok(0) => return Reply::Lines(lines),
+            Ok(_) => {}
+            Err(_) if lines.is_empty() => return Reply::Silent,
+            Err(_) => return Reply::Lines(lines),
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        let more = line.as_bytes().get(3) == Some(&b'-');
+        lines.push(line);
+        if !more {
+            return Reply::Lines(lines);
+        }
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` is any run of characters and
+/// `?` any single character.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Classic wildcard matching, backtracking to the last `*` on failure.
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Reads one reply for a test that talks to a server itself, all of its
+/// lines with their CRLFs, a byte at a time so that nothing past it is
+/// consumed.
+pub fn read_reply(stream: &mut impl Read) -> String {
+    let mut reply = Vec::new();
+    let mut byte = [0];
+    loop {
+        assert_eq!(stream.read(&mut byte).unwrap(), 1, "closed after {:?}", String::from_utf8_lossy(&reply));
+        reply.push(byte[0]);
+        let last_line = reply[..reply.len() - 1].iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        if reply.ends_with(b"\r\n") && reply.get(last_line + 3) != Some(&b'-') {
+            return String::from_utf8(reply).unwrap();
+        }
+    }
+}
+
+/// The path of a file under `tests/fixtures`.
+pub fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// A directory of this test run called after `name`, not yet created;
+/// whatever an earlier run left there is removed.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("smtp_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// An empty mailbox store in a directory of its own.
+pub fn mailboxes(name: &str) -> Arc<MailboxStore> {
+    Arc::new(MailboxStore::new(temp_dir(name)).unwrap())
+}
+
+/// A clock that only moves when told to.
+pub struct ManualClock(Mutex<SystemTime>);
+
+impl ManualClock {
+    pub fn new() -> Arc<ManualClock> {
+        Arc::new(ManualClock(Mutex::new(UNIX_EPOCH + Duration::from_secs(1_800_000_000))))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
@@ -0,0 +1,72 @@
+use smtp_server::address::{self, ForwardPath, Mailbox, Parameter, ReversePath};
+
+fn mailbox(local_part: &str, domain: &str) -> Mailbox {
+    Mailbox { local_part: local_part.to_string(), domain: domain.to_string() }
+}
+
+#[test]
+fn test_reverse_paths(){
+    let (path, params) = address::parse_reverse_path("<Sender@Example.COM> SIZE=1000 body=8BITMIME SMTPUTF8").unwrap();
+    assert_eq!(path, ReversePath::Mailbox(mailbox("Sender", "example.com")));
+    assert_eq!(params.get("SIZE"), Some("1000"));
+    assert_eq!(params.get("BODY"), Some("8BITMIME"));
+    assert!(params.has("smtputf8"));
+    assert_eq!(params.0[2], Parameter { keyword: "SMTPUTF8".to_string(), value: None });
+
+    let (path, params) = address::parse_reverse_path("<>").unwrap();
+    assert_eq!(path, ReversePath::Null);
+    assert_eq!(path.to_string(), "");
+    assert!(params.0.is_empty());
+
+    // Source routes are accepted and dropped.
+    let (path, _) = address::parse_reverse_path("<@relay.example,@hub.example:joe@example.org>").unwrap();
+    assert_eq!(path.to_string(), "joe@example.org");
+}
+
+#[test]
+fn test_forward_paths(){
+    let (path, _) = address::parse_forward_path("<\"john doe\\\"x\"@example.com>").unwrap();
+    assert_eq!(path, ForwardPath::Mailbox(mailbox("john doe\"x", "example.com")));
+    assert_eq!(path.to_string(), "\"john doe\\\"x\"@example.com");
+
+    let (path, _) = address::parse_forward_path("<\"plain\"@example.com>").unwrap();
+    assert_eq!(path.to_string(), "plain@example.com");
+
+    let (path, _) = address::parse_forward_path("<admin@[192.0.2.1]>").unwrap();
+    assert!(path.mailbox().unwrap().is_literal());
+    assert_eq!(address::parse_forward_path("<a@[IPv6:2001:db8::1]>").unwrap().0.to_string(), "a@[IPv6:2001:db8::1]");
+
+    let (path, params) = address::parse_forward_path("<postmaster> NOTIFY=NEVER").unwrap();
+    assert_eq!(path, ForwardPath::Postmaster);
+    assert_eq!(params.get("notify"), Some("NEVER"));
+}
+
+#[test]
+fn test_malformed_paths(){
+    let malformed = [
+        "sender@example.com",
+        "<sender@example.com",
+        "<sender>",
+        "<@example.com>",
+        "<a..b@example.com>",
+        "<a@-example.com>",
+        "<a@example..com>",
+        "<a@[192.0.2.300]>",
+        "<a@[IPv6:nonsense]>",
+        "<\"unterminated@example.com>",
+        "<a@example.com>junk",
+        "<a@example.com> =1",
+        "<a@example.com> SIZE=",
+    ];
+    for value in malformed {
+        assert!(address::parse_reverse_path(value).is_err(), "{}", value);
+    }
+    assert!(address::parse_forward_path("<>").is_err());
+    let long_local = format!("<{}@example.com>", "a".repeat(65));
+    assert!(address::parse_forward_path(&long_local).is_err());
+}
+
+#[test]
+fn test_address_lists(){
+    assert_eq!(address::parse_address_list("\"Doe, J\" <j@a.test>, k@b.test (Kay), team: l@c.test, m@d.test;"), ["j@a.test", "k@b.test", "l@c.test", "m@d.test"]);
+}
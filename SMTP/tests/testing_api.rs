@@ -0,0 +1,91 @@
+#![cfg(feature = "http-api")]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use smtp_server::api;
+use smtp_server::dns::StaticResolver;
+use smtp_server::server::{self, Config};
+use smtp_server::store::{MemoryStore, MessageStore};
+
+fn start(store: Arc<MemoryStore>) -> (u16, SocketAddr) {
+    let config = Config {
+        resolver: Arc::new(StaticResolver::new()),
+        store: Some(store.clone()),
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+    let api_addr = api::spawn("127.0.0.1:0", store).unwrap();
+    (port, api_addr)
+}
+
+fn send_mail(port: u16, to: &str, subject: &str) {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut reply = String::new();
+    writer
+        .write_all(format!(
+            "EHLO client.test\r\nMAIL FROM:<app@client.test>\r\nRCPT TO:<{to}>\r\nDATA\r\n\
+             From: app@client.test\r\nTo: {to}\r\nSubject: {subject}\r\n\r\nHello {to}\r\n.\r\n"
+        ).as_bytes())
+        .unwrap();
+    while !reply.starts_with("250 OK") {
+        reply.clear();
+        reader.read_line(&mut reply).unwrap();
+    }
+}
+
+fn http(addr: SocketAddr, method: &str, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: test\r\n\r\n", method, path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+    (status, body)
+}
+
+#[test]
+fn test_api_lists_and_searches(){
+    let store = Arc::new(MemoryStore::new());
+    let (port, api_addr) = start(store.clone());
+    send_mail(port, "qa@sink.test", "Welcome aboard");
+    send_mail(port, "ops@sink.test", "Password reset");
+
+    let (status, body) = http(api_addr, "GET", "/api/messages");
+    assert_eq!(status, 200);
+    assert!(body.starts_with("{\"total\":2,"), "{}", body);
+
+    let (_, body) = http(api_addr, "GET", "/api/messages?to=ops%40sink.test");
+    assert!(body.contains("\"subject\":\"Password reset\""), "{}", body);
+    assert!(!body.contains("Welcome"));
+
+    let (_, body) = http(api_addr, "GET", "/api/search?subject=welcome");
+    assert!(body.starts_with("{\"total\":1,"), "{}", body);
+}
+
+#[test]
+fn test_api_message_details_and_delete(){
+    let store = Arc::new(MemoryStore::new());
+    let (port, api_addr) = start(store.clone());
+    send_mail(port, "qa@sink.test", "Details");
+    let id = store.list().unwrap()[0].id.clone();
+
+    let (status, body) = http(api_addr, "GET", &format!("/api/messages/{}", id));
+    assert_eq!(status, 200);
+    assert!(body.contains("\"mail_from\":\"app@client.test\""));
+    assert!(body.contains("\"text\":\"Hello qa@sink.test\\r\\n\""), "{}", body);
+    assert!(body.contains("{\"name\":\"Subject\",\"value\":\"Details\"}"));
+
+    let (status, raw) = http(api_addr, "GET", &format!("/api/messages/{}/raw", id));
+    assert_eq!(status, 200);
+    assert!(raw.contains("Subject: Details\r\n"));
+
+    assert_eq!(http(api_addr, "DELETE", &format!("/api/messages/{}", id)).0, 204);
+    assert_eq!(http(api_addr, "GET", &format!("/api/messages/{}", id)).0, 404);
+    assert_eq!(http(api_addr, "GET", "/nowhere").0, 404);
+}
@@ -0,0 +1,145 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use smtp_server::dns::StaticResolver;
+use smtp_server::server::{self, Config};
+use smtp_server::store::{MemoryStore, MessageStore};
+
+fn start_server(store: Arc<MemoryStore>, max_message_size: Option<usize>) -> u16 {
+    let config = Config {
+        resolver: Arc::new(StaticResolver::new()),
+        store: Some(store),
+        max_message_size,
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+    port
+}
+
+struct Client {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(port: u16) -> Client {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut client = Client { writer: stream.try_clone().unwrap(), reader: BufReader::new(stream) };
+        assert!(client.reply().starts_with("220"));
+        client
+    }
+
+    /// Reads one reply, all lines of a multi-line one included.
+    fn reply(&mut self) -> String {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            reply += &line;
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                return reply;
+            }
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> String {
+        self.writer.write_all(bytes).unwrap();
+        self.reply()
+    }
+}
+
+#[test]
+fn test_bdat_keeps_binary_body(){
+    let store = Arc::new(MemoryStore::new());
+    let port = start_server(store.clone(), None);
+    let mut client = Client::connect(port);
+
+    let ehlo = client.send(b"EHLO client.test\r\n");
+    assert!(ehlo.contains("250-CHUNKING\r\n") && ehlo.contains("250-BINARYMIME\r\n"), "{}", ehlo);
+    assert!(client.send(b"MAIL FROM:<app@client.test> BODY=BINARYMIME\r\n").starts_with("250"));
+    assert!(client.send(b"RCPT TO:<qa@sink.test>\r\n").starts_with("250"));
+    assert!(client.send(b"DATA\r\n").starts_with("503"));
+
+    let headers = b"Subject: binary\r\nContent-Type: application/octet-stream\r\n\r\n";
+    let body: &[u8] = b"\x00\xff\r\n.\r\n..no stuffing\r\n\xc3";
+    let reply = client.send(&[format!("BDAT {}\r\n", headers.len()).as_bytes(), headers].concat());
+    assert_eq!(reply, format!("250 2.0.0 {} octets received\r\n", headers.len()));
+    let reply = client.send(&[format!("BDAT {} LAST\r\n", body.len()).as_bytes(), body].concat());
+    assert!(reply.starts_with("250 OK"), "{}", reply);
+
+    let stored = &store.list().unwrap()[0];
+    assert_eq!(stored.body(), body);
+    assert!(stored.content.ends_with(&[b"\r\n\r\n".as_slice(), body].concat()));
+    assert_eq!(stored.header("Subject"), "binary");
+}
+
+#[test]
+fn test_bdat_sequencing(){
+    let store = Arc::new(MemoryStore::new());
+    let port = start_server(store.clone(), None);
+    let mut client = Client::connect(port);
+
+    client.send(b"EHLO client.test\r\n");
+    assert!(client.send(b"MAIL FROM:<app@client.test> BODY=9BIT\r\n").starts_with("501 5.5.4"));
+    // A chunk sent too early is still consumed, so the session stays in step.
+    assert!(client.send(b"MAIL FROM:<app@client.test>\r\nBDAT 4\r\nRSET").starts_with("250"));
+    assert!(client.reply().starts_with("503 5.5.1"));
+    assert!(client.send(b"BDAT many\r\n").starts_with("501"));
+    assert!(client.send(b"RCPT TO:<qa@sink.test>\r\n").starts_with("250"));
+    assert!(client.send(b"BDAT 0 LAST\r\n").starts_with("250 OK"));
+    assert!(store.list().unwrap()[0].body().is_empty());
+}
+
+#[test]
+fn test_data_keeps_8bit_body(){
+    let store = Arc::new(MemoryStore::new());
+    let port = start_server(store.clone(), None);
+    let mut client = Client::connect(port);
+
+    client.send(b"EHLO client.test\r\n");
+    client.send(b"MAIL FROM:<app@client.test> BODY=8BITMIME\r\n");
+    client.send(b"RCPT TO:<qa@sink.test>\r\n");
+    assert!(client.send(b"DATA\r\n").starts_with("354"));
+    let reply = client.send(b"Subject: latin-1\r\n\r\ncaf\xe9\r\n..dot\r\n.\r\n");
+    assert!(reply.starts_with("250 OK"), "{}", reply);
+    assert_eq!(store.list().unwrap()[0].body(), b"caf\xe9\r\n.dot\r\n");
+}
+
+#[test]
+fn test_size_limit_before_the_end(){
+    let store = Arc::new(MemoryStore::new());
+    let port = start_server(store.clone(), Some(1000));
+    let mut client = Client::connect(port);
+
+    client.send(b"EHLO client.test\r\n");
+    client.send(b"MAIL FROM:<app@client.test>\r\n");
+    client.send(b"RCPT TO:<qa@sink.test>\r\n");
+    assert!(client.send(b"DATA\r\n").starts_with("354"));
+    // Far over the limit, so it is dropped as it arrives, up to the end.
+    let body = "Subject: big\r\n\r\n".to_string() + &"0123456789\r\n..stuffed\r\n".repeat(20_000) + ".\r\n";
+    let reply = client.send(body.as_bytes());
+    assert_eq!(reply, "552 5.3.4 Message size exceeds fixed maximum message size\r\n");
+    // The transaction is over.
+    assert!(client.send(b"RCPT TO:<qa@sink.test>\r\n").starts_with("503 5.5.1 MAIL FROM first"));
+
+    client.send(b"MAIL FROM:<app@client.test>\r\n");
+    client.send(b"RCPT TO:<qa@sink.test>\r\n");
+    let chunk = [b"BDAT 600\r\n".as_slice(), &[b'a'; 600]].concat();
+    assert_eq!(client.send(&chunk), "250 2.0.0 600 octets received\r\n");
+    // Refused before it is read, and still consumed, so the session stays in step.
+    let chunk = [b"BDAT 600 LAST\r\n".as_slice(), &[b'a'; 600]].concat();
+    assert_eq!(client.send(&chunk), "552 5.3.4 Message size exceeds fixed maximum message size\r\n");
+    assert!(client.send(b"NOOP\r\n").starts_with("250"));
+    assert!(client.send(b"BDAT 1 LAST\r\na").starts_with("503 5.5.1 RCPT TO first"));
+
+    // Dot-stuffing does not count towards the size.
+    client.send(b"MAIL FROM:<app@client.test>\r\n");
+    client.send(b"RCPT TO:<qa@sink.test>\r\n");
+    client.send(b"DATA\r\n");
+    let reply = client.send(("\r\n".to_string() + &"..\r\n".repeat(300) + ".\r\n").as_bytes());
+    assert!(reply.starts_with("250 OK"), "{}", reply);
+    assert_eq!(store.list().unwrap().len(), 1);
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use std::sync::Arc;
+use smtp_server::address::Mailbox;
+use smtp_server::directory::{Directory, Entry, MemoryDirectory};
+use smtp_server::server::Config;
+
+fn directory() -> Arc<MemoryDirectory> {
+    let mut directory = MemoryDirectory::new();
+    directory.add_user("qa@mx.sink.test", Some("Quality Assurance"));
+    directory.add_user("ops@mx.sink.test", None);
+    directory.add_list("staff@mx.sink.test", &["qa@mx.sink.test", "ops@mx.sink.test", "boss@elsewhere.test"]);
+    directory.add_list("empty@mx.sink.test", &[]);
+    Arc::new(directory)
+}
+
+#[test]
+fn test_memory_directory(){
+    let directory = directory();
+    let lookup = |address: &str| directory.lookup(&Mailbox::parse(address).unwrap());
+    match lookup("QA@MX.sink.test") {
+        Some(Entry::User(user)) => assert_eq!(user.to_string(), "Quality Assurance <qa@mx.sink.test>"),
+        other => panic!("{:?}", other),
+    }
+    match lookup("staff@mx.sink.test") {
+        Some(Entry::List(members)) => {
+            let members: Vec<String> = members.iter().map(|member| member.to_string()).collect();
+            assert_eq!(members, ["Quality Assurance <qa@mx.sink.test>", "<ops@mx.sink.test>", "<boss@elsewhere.test>"]);
+        }
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(lookup("nobody@mx.sink.test"), None);
+}
+
+#[test]
+fn test_vrfy_and_expn_default_to_252(){
+    // Even with a directory, nothing is disclosed until they are enabled.
+    let addr = common::start(Config {
+        directory: Some(directory()),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: VRFY qa
+        S: 252 2.5.2 *
+        C: VRFY nobody
+        S: 252 2.5.2 *
+        C: EXPN staff
+        S: 252 2.5.2 *
+    ");
+}
+
+#[test]
+fn test_vrfy_and_expn(){
+    let addr = common::start(Config {
+        directory: Some(directory()),
+        vrfy: true,
+        expn: true,
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: VRFY qa
+        S: 250 2.1.5 Quality Assurance <qa@mx.sink.test>
+        C: VRFY <ops@MX.SINK.TEST>
+        S: 250 2.1.5 <ops@mx.sink.test>
+        C: VRFY nobody
+        S: 550 5.1.1 <nobody@mx.sink.test>: User unknown
+        C: VRFY staff
+        S: 550 5.1.1 <staff@mx.sink.test> is a mailing list, use EXPN
+        C: VRFY
+        S: 501 5.5.4 Syntax: VRFY <address>
+        C: EXPN staff@mx.sink.test
+        S: 250-2.1.5 Quality Assurance <qa@mx.sink.test>250-2.1.5 <ops@mx.sink.test>250 2.1.5 <boss@elsewhere.test>
+        C: EXPN empty
+        S: 250 2.1.5 <empty@mx.sink.test> has no members
+        C: EXPN qa
+        S: 550 5.1.1 <qa@mx.sink.test> is not a mailing list
+        C: EXPN a@@b
+        S: 501 5.5.4 Syntax: EXPN <address>
+    ");
+
+    // Only the one that is enabled answers.
+    let addr = common::start(Config {
+        directory: Some(directory()),
+        vrfy: true,
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: VRFY qa
+        S: 250 2.1.5 *
+        C: EXPN staff
+        S: 252 2.5.2 *
+    ");
+}
+
+#[test]
+fn test_rcpt_checked_against_directory(){
+    let addr = common::start(Config {
+        directory: Some(directory()),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 *
+        C: RCPT TO:<nobody@mx.sink.test>
+        S: 550 5.1.1 <nobody@mx.sink.test>: User unknown
+        C: RCPT TO:<Qa@mx.sink.test>
+        S: 250 2.1.5 *
+        C: RCPT TO:<staff@mx.sink.test>
+        S: 250 2.1.5 *
+        C: RCPT TO:<postmaster>
+        S: 250 2.1.5 *
+        C: DATA
+        S: 354
+        C: Subject: hi
+        C:
+        C: .
+        S: 250 OK queued as *
+    ");
+
+    // Without a directory every recipient is accepted.
+    let addr = common::start(common::offline_config());
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 *
+        C: RCPT TO:<nobody@mx.sink.test>
+        S: 250 2.1.5 *
+    ");
+}
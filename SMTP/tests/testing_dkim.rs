@@ -0,0 +1,130 @@
+use smtp_server::dkim::{self, Canonicalization, DkimResult, Signer, SigningKey};
+use smtp_server::dns::StaticResolver;
+use smtp_server::headers::{Header, Headers};
+
+const HEADERS: &str = "From: Joe <joe@example.com>\r\n\
+To: sally@example.org\r\n\
+Subject: Is dinner ready?\r\n\
+Date: Fri, 11 Jul 2003 21:00:37 -0700\r\n\
+Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n";
+
+const BODY: &str = "Hi.\r\n\r\nWe lost the game.  Are you hungry yet?\r\n\r\nJoe.\r\n\r\n";
+
+fn signer(key_file: &str) -> Signer {
+    let key = SigningKey::load(&format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), key_file)).unwrap();
+    Signer::new("example.com", "brisbane", key)
+}
+
+fn resolver_for(signer: &Signer) -> StaticResolver {
+    let mut dns = StaticResolver::new();
+    dns.add_txt("brisbane._domainkey.example.com", &signer.key.dns_record().unwrap());
+    dns
+}
+
+/// Signs HEADERS/BODY and returns the signed header section.
+fn signed_headers(signer: &Signer) -> Headers {
+    let mut headers = Headers::parse(HEADERS);
+    let signature = signer.sign(&headers, BODY.as_bytes()).unwrap();
+    headers.prepend("DKIM-Signature", &signature);
+    headers
+}
+
+#[test]
+fn test_dkim_rsa_round_trip(){
+    let signer = signer("dkim_rsa.pem");
+    let dns = resolver_for(&signer);
+    let headers = signed_headers(&signer);
+
+    let outcomes = dkim::verify(&dns, &headers, BODY.as_bytes());
+    assert_eq!(outcomes.len(), 1);
+    assert_eq!(outcomes[0].result, DkimResult::Pass, "{:?}", outcomes[0].reason);
+    assert_eq!(outcomes[0].domain, "example.com");
+    assert_eq!(outcomes[0].selector, "brisbane");
+}
+
+#[test]
+fn test_dkim_ed25519_round_trip(){
+    let signer = signer("dkim_ed25519.pem");
+    let dns = resolver_for(&signer);
+    let headers = signed_headers(&signer);
+
+    let outcomes = dkim::verify(&dns, &headers, BODY.as_bytes());
+    assert_eq!(outcomes[0].result, DkimResult::Pass, "{:?}", outcomes[0].reason);
+}
+
+#[test]
+fn test_dkim_simple_canonicalization(){
+    let mut signer = signer("dkim_rsa.pem");
+    signer.header_canonicalization = Canonicalization::Simple;
+    signer.body_canonicalization = Canonicalization::Simple;
+    let dns = resolver_for(&signer);
+    let headers = signed_headers(&signer);
+
+    assert_eq!(dkim::verify(&dns, &headers, BODY.as_bytes())[0].result, DkimResult::Pass);
+    // Simple body canonicalization ignores trailing empty lines only.
+    assert_eq!(dkim::verify(&dns, &headers, format!("{}\r\n\r\n", BODY).as_bytes())[0].result, DkimResult::Pass);
+    let respaced = BODY.replace("game.  Are", "game. Are");
+    assert_eq!(dkim::verify(&dns, &headers, respaced.as_bytes())[0].result, DkimResult::Fail);
+}
+
+#[test]
+fn test_dkim_detects_tampering(){
+    let signer = signer("dkim_rsa.pem");
+    let dns = resolver_for(&signer);
+    let headers = signed_headers(&signer);
+
+    let body = BODY.replace("hungry", "thirsty");
+    let outcome = &dkim::verify(&dns, &headers, body.as_bytes())[0];
+    assert_eq!(outcome.result, DkimResult::Fail);
+    assert_eq!(outcome.reason.as_deref(), Some("body hash did not verify"));
+
+    let mut tampered = headers.clone();
+    for field in tampered.fields.iter_mut().filter(|h| h.is("Subject")) {
+        field.value = " Dinner is off".to_string();
+    }
+    let outcome = &dkim::verify(&dns, &tampered, BODY.as_bytes())[0];
+    assert_eq!(outcome.result, DkimResult::Fail);
+    assert_eq!(outcome.reason.as_deref(), Some("signature did not verify"));
+}
+
+#[test]
+fn test_dkim_key_errors(){
+    let signer = signer("dkim_ed25519.pem");
+    let headers = signed_headers(&signer);
+
+    let empty = StaticResolver::new();
+    assert_eq!(dkim::verify(&empty, &headers, BODY.as_bytes())[0].result, DkimResult::PermError);
+
+    let mut failing = StaticResolver::new();
+    failing.add_failure("brisbane._domainkey.example.com");
+    assert_eq!(dkim::verify(&failing, &headers, BODY.as_bytes())[0].result, DkimResult::TempError);
+
+    let mut revoked = StaticResolver::new();
+    revoked.add_txt("brisbane._domainkey.example.com", "v=DKIM1; k=ed25519; p=");
+    assert_eq!(dkim::verify(&revoked, &headers, BODY.as_bytes())[0].result, DkimResult::Fail);
+
+    assert!(dkim::verify(&empty, &Headers::parse(HEADERS), BODY.as_bytes()).is_empty());
+}
+
+#[test]
+fn test_dkim_canonicalization_examples(){
+    // RFC 6376 section 3.4.6.
+    let headers = Headers::parse("A: X\r\nB : Y\t\r\n\tZ  \r\n");
+    let relaxed: String = headers
+        .iter()
+        .map(|h| dkim::canonicalize_header(h, Canonicalization::Relaxed))
+        .collect();
+    assert_eq!(relaxed, "a:X\r\nb:Y Z\r\n");
+    let simple: String = headers
+        .iter()
+        .map(|h| dkim::canonicalize_header(h, Canonicalization::Simple))
+        .collect();
+    assert_eq!(simple, "A: X\r\nB : Y\t\r\n\tZ  \r\n");
+
+    let body = b" C \r\nD \t E\r\n\r\n\r\n";
+    assert_eq!(dkim::canonicalize_body(body, Canonicalization::Relaxed), b" C\r\nD E\r\n");
+    assert_eq!(dkim::canonicalize_body(body, Canonicalization::Simple), b" C \r\nD \t E\r\n");
+    assert_eq!(dkim::canonicalize_body(b"", Canonicalization::Simple), b"\r\n");
+    assert_eq!(dkim::canonicalize_body(b"", Canonicalization::Relaxed), b"");
+    assert_eq!(dkim::canonicalize_header(&Header::new("X-Test", "a  b"), Canonicalization::Relaxed), "x-test:a b\r\n");
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use std::io::{BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use smtp_server::dkim::{DkimOutcome, DkimResult};
+use smtp_server::dmarc::{self, DmarcResult, Policy, Record};
+use smtp_server::dns::StaticResolver;
+use smtp_server::server::{self, Config};
+use smtp_server::spf::SpfResult;
+
+fn fixture() -> StaticResolver {
+    let mut dns = StaticResolver::new();
+    dns.add_txt("_dmarc.bank.example", "v=DMARC1; p=reject; sp=quarantine; rua=mailto:d@bank.example");
+    dns.add_txt("bank.example", "v=spf1 ip4:192.0.2.0/24 -all");
+    dns.add_txt("_dmarc.strict.example", "v=DMARC1; p=quarantine; adkim=s; aspf=s");
+    dns.add_txt("_dmarc.sample.example", "v=DMARC1; p=reject; pct=0");
+    dns.add_txt("_dmarc.relaxed.example", "v=DMARC1; p=none");
+    dns.add_failure("_dmarc.down.example");
+    dns
+}
+
+fn dkim(result: DkimResult, domain: &str) -> DkimOutcome {
+    DkimOutcome {
+        result,
+        domain: domain.to_string(),
+        selector: "s1".to_string(),
+        signature: "abcdefgh".to_string(),
+        reason: None,
+    }
+}
+
+#[test]
+fn test_dmarc_helpers(){
+    assert_eq!(dmarc::organizational_domain("mail.eu.bank.example"), "bank.example");
+    assert_eq!(dmarc::organizational_domain("shop.example.co.uk"), "example.co.uk");
+    assert_eq!(dmarc::organizational_domain("example"), "example");
+    assert_eq!(dmarc::from_domain("Joe <Joe@Mail.Example.COM>").as_deref(), Some("mail.example.com"));
+    assert_eq!(dmarc::from_domain("joe@example.com (Joe)").as_deref(), Some("example.com"));
+    assert_eq!(dmarc::from_domain("undisclosed-recipients:;"), None);
+
+    let record = Record::parse("v=DMARC1; p=quarantine; adkim=s; pct=50").unwrap();
+    assert_eq!(record.policy, Policy::Quarantine);
+    assert_eq!(record.percent, 50);
+    assert!(Record::parse("p=reject; v=DMARC1").is_none());
+    assert_eq!(Record::parse("v=DMARC1; p=bogus; rua=mailto:x@y").unwrap().policy, Policy::None);
+}
+
+#[test]
+fn test_dmarc_alignment(){
+    let dns = fixture();
+    // Relaxed SPF alignment: the envelope is a subdomain of the From domain.
+    let outcome = dmarc::evaluate(&dns, "bank.example", Some(SpfResult::Pass), "bounces.bank.example", &[]);
+    assert_eq!(outcome.result, DmarcResult::Pass);
+    assert!(outcome.spf_aligned);
+
+    let outcome = dmarc::evaluate(&dns, "bank.example", Some(SpfResult::Fail), "bank.example", &[dkim(DkimResult::Pass, "bank.example")]);
+    assert_eq!(outcome.result, DmarcResult::Pass);
+    assert!(outcome.dkim_aligned);
+
+    // Passing DKIM for an unrelated domain does not help.
+    let outcome = dmarc::evaluate(&dns, "bank.example", Some(SpfResult::Pass), "evil.example", &[dkim(DkimResult::Pass, "evil.example")]);
+    assert_eq!(outcome.result, DmarcResult::Fail);
+    assert_eq!(outcome.disposition, Policy::Reject);
+
+    let outcome = dmarc::evaluate(&dns, "strict.example", None, "", &[dkim(DkimResult::Pass, "mail.strict.example")]);
+    assert_eq!(outcome.result, DmarcResult::Fail);
+    assert_eq!(outcome.disposition, Policy::Quarantine);
+}
+
+#[test]
+fn test_dmarc_policies(){
+    let dns = fixture();
+    let outcome = dmarc::evaluate(&dns, "shop.bank.example", None, "", &[]);
+    assert_eq!(outcome.policy, Policy::Quarantine);
+
+    let outcome = dmarc::evaluate(&dns, "sample.example", None, "", &[]);
+    assert_eq!(outcome.policy, Policy::Reject);
+    assert_eq!(outcome.disposition, Policy::Quarantine);
+
+    assert_eq!(dmarc::evaluate(&dns, "relaxed.example", None, "", &[]).disposition, Policy::None);
+    assert_eq!(dmarc::evaluate(&dns, "nothing.example", None, "", &[]).result, DmarcResult::None);
+    assert_eq!(dmarc::evaluate(&dns, "down.example", None, "", &[]).result, DmarcResult::TempError);
+}
+
+fn deliver(port: u16, from: &str) -> String {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    assert!(common::read_reply(&mut reader).starts_with("220"));
+    writer.write_all(b"EHLO client.test\r\n").unwrap();
+    assert!(common::read_reply(&mut reader).starts_with("250"));
+    writer.write_all(format!("MAIL FROM:<{}>\r\nRCPT TO:<qa@sink.test>\r\nDATA\r\n", from).as_bytes()).unwrap();
+    assert!(common::read_reply(&mut reader).starts_with("250"));
+    assert!(common::read_reply(&mut reader).starts_with("250"));
+    assert!(common::read_reply(&mut reader).starts_with("354"));
+    writer.write_all(format!("From: {}\r\nSubject: urgent\r\n\r\nPlease wire money.\r\n.\r\n", from).as_bytes()).unwrap();
+    common::read_reply(&mut reader)
+}
+
+#[test]
+fn test_dmarc_rejects_spoofed_mail(){
+    let mut dns = fixture();
+    dns.add_a("client.test", Ipv4Addr::LOCALHOST);
+    let config = Config {
+        resolver: Arc::new(dns),
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+
+    assert!(deliver(port, "ceo@bank.example").starts_with("550 5.7.1"));
+    assert!(deliver(port, "friend@relaxed.example").starts_with("250"));
+}
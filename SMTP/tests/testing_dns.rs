@@ -0,0 +1,87 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::thread;
+use std::time::Duration;
+use smtp_server::dns::{Resolver, SystemResolver};
+
+/// A reply to `query` with one TXT answer holding `text`, split into
+/// strings of at most 255 octets.
+fn txt_reply(query: &[u8], text: &str, truncated: bool) -> Vec<u8> {
+    let mut rdata = Vec::new();
+    for chunk in text.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+    let mut reply = query[..2].to_vec();
+    reply.extend_from_slice(&[if truncated { 0x82 } else { 0x80 }, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+    reply.extend_from_slice(&query[12..]);
+    reply.extend_from_slice(&[0xc0, 0x0c, 0, 16, 0, 1, 0, 0, 0x0e, 0x10]);
+    reply.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    reply.extend_from_slice(&rdata);
+    reply
+}
+
+/// Binds UDP and TCP on the same local port.
+fn bind_server() -> (UdpSocket, TcpListener) {
+    loop {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        if let Ok(tcp) = TcpListener::bind(udp.local_addr().unwrap()) {
+            return (udp, tcp);
+        }
+    }
+}
+
+fn resolver(server: SocketAddr) -> SystemResolver {
+    SystemResolver { servers: vec![server], timeout: Duration::from_secs(2) }
+}
+
+#[test]
+fn test_ignores_forged_replies_and_retries_truncated_over_tcp() {
+    let (udp, tcp) = bind_server();
+    let server = udp.local_addr().unwrap();
+    let record = format!("v=spf1 {} -all", "ip4:192.0.2.1 ".repeat(40));
+    let full = record.clone();
+    thread::spawn(move || {
+        let mut query = vec![0; 512];
+        let (n, client) = udp.recv_from(&mut query).unwrap();
+        query.truncate(n);
+
+        // Right ID and question, wrong source address.
+        let forger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        forger.send_to(&txt_reply(&query, "v=spf1 +all", false), client).unwrap();
+        // Right source, wrong question type.
+        let mut other = query.clone();
+        other[n - 3] ^= 0x01;
+        udp.send_to(&txt_reply(&other, "v=spf1 +all", false), client).unwrap();
+        // Right source, wrong ID.
+        let mut other = query.clone();
+        other[0] ^= 0xff;
+        udp.send_to(&txt_reply(&other, "v=spf1 +all", false), client).unwrap();
+        udp.send_to(&txt_reply(&query, "v=spf1", true), client).unwrap();
+
+        let (mut stream, _) = tcp.accept().unwrap();
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut query = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut query).unwrap();
+        let reply = txt_reply(&query, &full, false);
+        stream.write_all(&(reply.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&reply).unwrap();
+    });
+
+    assert_eq!(resolver(server).txt("example.com").unwrap(), [record]);
+}
+
+#[test]
+fn test_times_out_without_a_matching_reply() {
+    let (udp, _tcp) = bind_server();
+    let server = udp.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut query = vec![0; 512];
+        let (n, client) = udp.recv_from(&mut query).unwrap();
+        query[0] ^= 0xff;
+        udp.send_to(&txt_reply(&query[..n], "v=spf1 +all", false), client).unwrap();
+    });
+
+    assert!(resolver(server).txt("example.com").is_err());
+}
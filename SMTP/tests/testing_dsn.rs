@@ -0,0 +1,145 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::UNIX_EPOCH;
+use smtp_server::address;
+use smtp_server::dns::StaticResolver;
+use smtp_server::dsn::{self, Action, MailDsn, Notify, RcptDsn, RecipientStatus, Report, Ret};
+use smtp_server::mime;
+use smtp_server::server::{self, Config};
+use smtp_server::store::{MemoryStore, MessageStore};
+
+fn start_server(store: Arc<MemoryStore>) -> u16 {
+    let config = Config {
+        hostname: "mx.sink.test".to_string(),
+        resolver: Arc::new(StaticResolver::new()),
+        store: Some(store),
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+    port
+}
+
+/// Sends `commands` one at a time and returns each reply, greeting first.
+fn converse(port: u16, commands: &[&str]) -> Vec<String> {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut replies = Vec::new();
+    for command in [""].iter().chain(commands) {
+        if !command.is_empty() {
+            writer.write_all(command.as_bytes()).unwrap();
+        }
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            reply += &line;
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                break;
+            }
+        }
+        replies.push(reply);
+    }
+    replies
+}
+
+#[test]
+fn test_dsn_parameters(){
+    assert_eq!(dsn::xtext_decode("a+2Bb+3Dc").unwrap(), "a+b=c");
+    assert_eq!(dsn::xtext_encode("a+b=c d"), "a+2Bb+3Dc+20d");
+    assert!(dsn::xtext_decode("a+2b").is_err());
+    assert!(dsn::xtext_decode("a=b").is_err());
+
+    let (_, params) = address::parse_reverse_path("<a@example.com> RET=hdrs ENVID=QQ+2B314159").unwrap();
+    let mail = MailDsn::from_params(&params).unwrap();
+    assert_eq!(mail.ret, Some(Ret::Hdrs));
+    assert_eq!(mail.envid.as_deref(), Some("QQ+314159"));
+    let (_, params) = address::parse_reverse_path("<a@example.com> RET=BODY").unwrap();
+    assert!(MailDsn::from_params(&params).is_err());
+
+    let (_, params) = address::parse_forward_path("<b@example.com> NOTIFY=SUCCESS,DELAY ORCPT=rfc822;B+2Bx@example.com").unwrap();
+    let rcpt = RcptDsn::from_params(&params).unwrap();
+    assert_eq!(rcpt.notify, Some(Notify { success: true, failure: false, delay: true }));
+    assert_eq!(rcpt.orcpt.as_ref().unwrap().address, "B+x@example.com");
+    assert!(rcpt.wants(Action::Delivered) && !rcpt.wants(Action::Failed));
+    assert!(!RcptDsn::default().wants(Action::Delivered));
+    assert!(RcptDsn::default().wants(Action::Failed));
+    assert!(Notify::parse("NEVER,SUCCESS").is_err());
+}
+
+#[test]
+fn test_failure_report(){
+    let orcpt = dsn::OriginalRecipient { addr_type: "rfc822".to_string(), address: "old@example.com".to_string() };
+    let report = Report {
+        reporting_mta: "mx.sink.test",
+        to: "sender@example.com",
+        envid: Some("id 1"),
+        ret: Ret::Full,
+        arrival: UNIX_EPOCH,
+        recipients: vec![RecipientStatus {
+            final_recipient: "new@example.com",
+            original: Some(&orcpt),
+            action: Action::Failed,
+            status: "5.1.1",
+            diagnostic: Some("550 5.1.1 No such user"),
+        }],
+        original_message: b"Subject: hello\r\n\r\nbody\r\n",
+    };
+    let message = String::from_utf8(report.to_message()).unwrap();
+    let root = mime::parse_message(&message);
+    assert_eq!(root.content_type, "multipart/report");
+    assert_eq!(root.param("report-type"), Some("delivery-status"));
+    assert_eq!(root.parts.len(), 3);
+
+    let status = String::from_utf8(root.parts[1].body.clone()).unwrap();
+    assert!(status.starts_with("Reporting-MTA: dns; mx.sink.test\r\nOriginal-Envelope-Id: id+201\r\nArrival-Date: Thu, 01 Jan 1970"), "{}", status);
+    assert!(status.contains("\r\n\r\nOriginal-Recipient: rfc822;old@example.com\r\nFinal-Recipient: rfc822; new@example.com\r\nAction: failed\r\nStatus: 5.1.1\r\nDiagnostic-Code: smtp; 550 5.1.1 No such user\r\n"), "{}", status);
+    assert_eq!(root.parts[2].content_type, "message/rfc822");
+    assert!(message.contains("Subject: Undelivered Mail Returned to Sender\r\n"));
+}
+
+#[test]
+fn test_success_dsn_stored(){
+    let store = Arc::new(MemoryStore::new());
+    let port = start_server(store.clone());
+    let replies = converse(port, &[
+        "EHLO client.test\r\n",
+        "MAIL FROM:<app@client.test> RET=FULL ENVID=order-42\r\n",
+        "RCPT TO:<qa@sink.test> NOTIFY=SUCCESS ORCPT=rfc822;QA@sink.test\r\n",
+        "RCPT TO:<ops@sink.test>\r\n",
+        "DATA\r\n",
+        "Subject: Your order\r\n\r\nThanks\r\n.\r\n",
+    ]);
+    assert!(replies[1].contains("\r\n250-DSN\r\n"), "{}", replies[1]);
+    assert!(replies[6].starts_with("250 OK"), "{:?}", replies);
+
+    let messages = store.list().unwrap();
+    assert_eq!(messages.len(), 2);
+    let report = messages.iter().find(|m| m.mail_from.is_empty()).unwrap();
+    let content = report.text();
+    assert_eq!(report.recipients, vec!["app@client.test".to_string()]);
+    assert!(content.contains("Original-Envelope-Id: order-42\r\n"));
+    assert!(content.contains("Original-Recipient: rfc822;QA@sink.test\r\nFinal-Recipient: rfc822; qa@sink.test\r\nAction: delivered\r\nStatus: 2.0.0\r\n"));
+    assert!(!content.contains("ops@sink.test"));
+    assert!(content.contains("Content-Type: text/rfc822-headers\r\n\r\n"));
+    assert!(!content.contains("Thanks"));
+
+    // Bounces never get a DSN, and malformed DSN parameters are refused.
+    let replies = converse(port, &[
+        "EHLO client.test\r\n",
+        "MAIL FROM:<> RET=SOME\r\n",
+        "MAIL FROM:<>\r\n",
+        "RCPT TO:<qa@sink.test> NOTIFY=NEVER,SUCCESS\r\n",
+        "RCPT TO:<qa@sink.test> NOTIFY=SUCCESS\r\n",
+        "DATA\r\n",
+        "Subject: bounce\r\n\r\nbody\r\n.\r\n",
+    ]);
+    assert!(replies[2].starts_with("501 5.5.4"), "{:?}", replies);
+    assert!(replies[4].starts_with("501 5.5.4"), "{:?}", replies);
+    assert!(replies[7].starts_with("250 OK"), "{:?}", replies);
+    assert_eq!(store.list().unwrap().len(), 3);
+}
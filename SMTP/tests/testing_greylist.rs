@@ -0,0 +1,136 @@
+mod common;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use smtp_server::greylist::{self, Decision, Greylist};
+use smtp_server::server::Config;
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn test_networks(){
+    assert_eq!(greylist::network(ip("192.0.2.77")), "192.0.2.0/24");
+    assert_eq!(greylist::network(ip("2001:db8:1:2:3:4:5:6")), "2001:db8:1:2::/64");
+}
+
+#[test]
+fn test_retry_after_delay(){
+    let clock = common::ManualClock::new();
+    let mut list = Greylist::new();
+    list.delay = Duration::from_secs(300);
+    list.clock = clock.clone();
+
+    assert_eq!(list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test"), Decision::Defer(Duration::from_secs(300)));
+    clock.advance(Duration::from_secs(100));
+    // Too early: the wait counts from the first attempt.
+    assert_eq!(list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test"), Decision::Defer(Duration::from_secs(200)));
+    // Another triplet waits on its own.
+    assert!(matches!(list.check(ip("192.0.2.1"), "app@client.test", "ops@sink.test"), Decision::Defer(_)));
+    clock.advance(Duration::from_secs(200));
+    // A retry from elsewhere in the /24, with the sender in another case.
+    assert_eq!(list.check(ip("192.0.2.200"), "App@client.test", "qa@sink.test"), Decision::Pass);
+
+    // The sender is now whitelisted from that network for any recipient...
+    assert_eq!(list.check(ip("192.0.2.9"), "app@client.test", "new@sink.test"), Decision::Pass);
+    // ...but not from another one.
+    assert!(matches!(list.check(ip("198.51.100.1"), "app@client.test", "new@sink.test"), Decision::Defer(_)));
+}
+
+#[test]
+fn test_expiry(){
+    let clock = common::ManualClock::new();
+    let mut list = Greylist::new();
+    list.clock = clock.clone();
+
+    list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test");
+    clock.advance(list.retry_window + Duration::from_secs(1));
+    // Retried too late, so it starts over.
+    assert_eq!(list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test"), Decision::Defer(list.delay));
+    clock.advance(list.delay);
+    assert_eq!(list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test"), Decision::Pass);
+
+    clock.advance(list.whitelist_for - Duration::from_secs(1));
+    // Each message renews the whitelisting.
+    assert_eq!(list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test"), Decision::Pass);
+    clock.advance(list.whitelist_for + Duration::from_secs(1));
+    assert!(matches!(list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test"), Decision::Defer(_)));
+}
+
+#[test]
+fn test_persistence(){
+    let path = std::env::temp_dir().join(format!("smtp_greylist_{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let clock = common::ManualClock::new();
+    let open = || {
+        let mut list = Greylist::open(&path).unwrap();
+        list.clock = clock.clone();
+        list
+    };
+
+    let list = open();
+    list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test");
+    list.check(ip("198.51.100.1"), "", "postmaster");
+    clock.advance(list.delay);
+    drop(list);
+
+    // A restart remembers both the first attempts and, later, the whitelist.
+    let list = open();
+    assert_eq!(list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test"), Decision::Pass);
+    let list = open();
+    assert_eq!(list.check(ip("192.0.2.1"), "app@client.test", "other@sink.test"), Decision::Pass);
+    assert_eq!(list.check(ip("198.51.100.1"), "", "postmaster"), Decision::Pass);
+
+    // Retries that change nothing leave the file alone.
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(list.check(ip("192.0.2.1"), "app@client.test", "qa@sink.test"), Decision::Pass));
+    assert!(!path.exists());
+    list.check(ip("203.0.113.1"), "app@client.test", "qa@sink.test");
+    assert!(path.exists());
+
+    std::fs::write(&path, "X\tnonsense\n").unwrap();
+    assert!(Greylist::open(&path).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_greylisting_at_rcpt(){
+    let clock = common::ManualClock::new();
+    let mut list = Greylist::new();
+    list.clock = clock.clone();
+    let addr = common::start(Config {
+        greylist: Some(Arc::new(list)),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 *
+        C: RCPT TO:<qa@sink.test>
+        S: 451 4.7.1 Greylisted, please try again in 300 seconds
+        C: DATA
+        S: 503 5.5.1 RCPT TO first
+    ");
+    clock.advance(Duration::from_secs(300));
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 *
+        C: RCPT TO:<qa@sink.test>
+        S: 250 2.1.5 *
+        C: RCPT TO:<ops@sink.test>
+        S: 250 2.1.5 *
+        C: DATA
+        S: 354
+        C: Subject: hi
+        C:
+        C: .
+        S: 250 OK queued as *
+    ");
+}
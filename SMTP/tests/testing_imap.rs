@@ -0,0 +1,317 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use smtp_server::auth::MemoryCredentials;
+use smtp_server::imap;
+use smtp_server::mailbox::{MailboxStore, INBOX};
+
+const REPORT: &str = "From: Ann Example <ann@client.test>\r\n\
+    To: qa@sink.test, \"Dev Team\" <dev@sink.test>\r\n\
+    Subject: Build report\r\n\
+    Date: Mon, 19 Oct 2026 08:11:00 +0000\r\n\
+    Message-ID: <r1@client.test>\r\n\
+    \r\n\
+    All green.\r\n";
+
+const LOGS: &str = "From: ops@client.test\r\n\
+    Subject: Logs\r\n\
+    Content-Type: multipart/mixed; boundary=\"b1\"\r\n\
+    \r\n\
+    --b1\r\n\
+    Content-Type: text/plain; charset=utf-8\r\n\
+    \r\n\
+    See attached.\r\n\
+    --b1\r\n\
+    Content-Type: application/octet-stream\r\n\
+    Content-Disposition: attachment; filename=\"log.txt\"\r\n\
+    Content-Transfer-Encoding: base64\r\n\
+    \r\n\
+    aGVsbG8=\r\n\
+    --b1--\r\n";
+
+/// A mailbox store in a directory of its own, holding the two messages
+/// above in qa@sink.test's inbox.
+fn mailboxes(name: &str) -> Arc<MailboxStore> {
+    let store = common::mailboxes(&format!("imap_{}", name));
+    store.deliver("qa@sink.test", INBOX, REPORT.as_bytes()).unwrap();
+    store.deliver("qa@sink.test", INBOX, LOGS.as_bytes()).unwrap();
+    store
+}
+
+fn start_imap(mailboxes: Arc<MailboxStore>) -> SocketAddr {
+    let mut credentials = MemoryCredentials::new();
+    credentials.add("qa@sink.test", "secret");
+    let config = imap::Config {
+        hostname: "mx.sink.test".to_string(),
+        mailboxes,
+        credentials: Arc::new(credentials),
+        tls: None,
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || imap::serve(listener, Arc::new(config)));
+    addr
+}
+
+/// Logs in and selects the inbox before `script`.
+fn run_selected(addr: SocketAddr, script: &str) {
+    common::run(addr, &format!("
+        S: * OK *
+        C: a LOGIN qa@sink.test secret
+        S: a OK LOGIN completed
+        C: s SELECT INBOX
+        S: * FLAGS *
+        S: * * EXISTS
+        S: * 0 RECENT
+        S: * OK [UNSEEN *
+        S: * OK [PERMANENTFLAGS *
+        S: * OK [UIDVALIDITY *
+        S: * OK [UIDNEXT *
+        S: s OK [READ-WRITE] SELECT completed
+        {}
+    ", script));
+}
+
+#[test]
+fn test_login_and_mailboxes(){
+    let store = mailboxes("login");
+    store.deliver("qa@sink.test", "Reports", b"Subject: r\r\n\r\n").unwrap();
+    store.deliver("qa@sink.test", "Lists/dev", b"Subject: l\r\n\r\n").unwrap();
+    let addr = start_imap(store.clone());
+    common::run(addr, "
+        S: * OK [CAPABILITY IMAP4rev1 LITERAL+ IDLE] mx.sink.test IMAP4rev1 ready
+        C: a1 SELECT INBOX
+        S: a1 BAD Log in first
+        C: a2 LOGIN qa@sink.test wrong
+        S: a2 NO [AUTHENTICATIONFAILED] Invalid user name or password
+        C: a3 LOGIN {12}
+        S: + Ready for literal data
+        C: QA@sink.test {6+}
+        C: secret
+        S: a3 OK LOGIN completed
+        C: a4 LIST \"\" \"*\"
+        S: * LIST (\\HasNoChildren) \"/\" \"INBOX\"
+        S: * LIST (\\Noselect \\HasChildren) \"/\" \"Lists\"
+        S: * LIST (\\HasNoChildren) \"/\" \"Lists/dev\"
+        S: * LIST (\\HasNoChildren) \"/\" \"Reports\"
+        S: a4 OK LIST completed
+        C: a5 LIST \"\" %
+        S: * LIST (\\HasNoChildren) \"/\" \"INBOX\"
+        S: * LIST (\\Noselect \\HasChildren) \"/\" \"Lists\"
+        S: * LIST (\\HasNoChildren) \"/\" \"Reports\"
+        S: a5 OK LIST completed
+        C: a6 LSUB Lists/ %
+        S: * LSUB (\\HasNoChildren) \"/\" \"Lists/dev\"
+        S: a6 OK LSUB completed
+        C: a7 LIST \"\" \"\"
+        S: * LIST (\\Noselect) \"/\" \"\"
+        S: a7 OK LIST completed
+        C: b1 LIST \"\" \"%/d%\"
+        S: * LIST (\\HasNoChildren) \"/\" \"Lists/dev\"
+        S: b1 OK LIST completed
+        C: b2 LIST \"\" \"*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%*%v\"
+        S: * LIST (\\HasNoChildren) \"/\" \"Lists/dev\"
+        S: b2 OK LIST completed
+        C: a8 STATUS inbox (MESSAGES UNSEEN UIDNEXT)
+        S: * STATUS \"INBOX\" (MESSAGES 2 UNSEEN 2 UIDNEXT 3)
+        S: a8 OK STATUS completed
+        C: a9 CREATE Archive
+        S: a9 OK CREATE completed
+        C: a10 CREATE Archive
+        S: a10 NO [ALREADYEXISTS] Mailbox already exists
+        C: a11 SELECT Nowhere
+        S: a11 NO [NONEXISTENT] No such mailbox
+        C: a12 FETCH 1 FLAGS
+        S: a12 BAD Select a mailbox first
+        C: a13 LOGOUT
+        S: * BYE mx.sink.test IMAP4rev1 server logging out
+        S: a13 OK LOGOUT completed
+        S: <closed>
+    ");
+    assert!(store.exists("qa@sink.test", "Archive"));
+}
+
+#[test]
+fn test_fetch(){
+    let addr = start_imap(mailboxes("fetch"));
+    run_selected(addr, &format!("
+        C: f1 FETCH 1 (FLAGS UID RFC822.SIZE ENVELOPE)
+        S: * 1 FETCH (FLAGS () UID 1 RFC822.SIZE {} ENVELOPE (\"Mon, 19 Oct 2026 08:11:00 +0000\" \"Build report\" \
+            ((\"Ann Example\" NIL \"ann\" \"client.test\")) ((\"Ann Example\" NIL \"ann\" \"client.test\")) \
+            ((\"Ann Example\" NIL \"ann\" \"client.test\")) ((NIL NIL \"qa\" \"sink.test\")(\"Dev Team\" NIL \"dev\" \"sink.test\")) \
+            NIL NIL NIL \"<r1@client.test>\"))
+        S: f1 OK FETCH completed
+        C: f2 FETCH 2 BODYSTRUCTURE
+        S: * 2 FETCH (BODYSTRUCTURE ((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 13 0 NIL NIL NIL NIL)\
+            (\"APPLICATION\" \"OCTET-STREAM\" NIL NIL NIL \"BASE64\" 8 NIL (\"ATTACHMENT\" (\"FILENAME\" \"log.txt\")) NIL NIL) \
+            \"MIXED\" (\"BOUNDARY\" \"b1\") NIL NIL NIL))
+        S: f2 OK FETCH completed
+        C: f3 FETCH 1 BODY
+        S: * 1 FETCH (BODY (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"us-ascii\") NIL NIL \"7BIT\" 12 1))
+        S: f3 OK FETCH completed
+        C: f4 FETCH 2 (BODY.PEEK[1] BODY.PEEK[2.MIME])
+        S: * 2 FETCH (BODY[1] {{13}}
+        S: See attached. BODY[2.MIME] {{130}}
+        S: Content-Type: application/octet-stream
+        S: Content-Disposition: attachment; filename=\"log.txt\"
+        S: Content-Transfer-Encoding: base64
+        S:
+        S: )
+        S: f4 OK FETCH completed
+        C: f5 UID FETCH 1 (BODY.PEEK[HEADER.FIELDS (Subject)] BODY.PEEK[TEXT]<4.5>)
+        S: * 1 FETCH (UID 1 BODY[HEADER.FIELDS (Subject)] {{25}}
+        S: Subject: Build report
+        S:
+        S: * BODY[TEXT]<4> {{5}}
+        S: green)
+        S: f5 OK UID FETCH completed
+        C: f6 FETCH 2 BODY.PEEK[3]
+        S: * 2 FETCH (BODY[3] NIL)
+        S: f6 OK FETCH completed
+        # Reading the body marks the message as seen.
+        C: f7 FETCH 1 BODY[TEXT]
+        S: * 1 FETCH (BODY[TEXT] {{12}}
+        S: All green.
+        S: * FLAGS (\\Seen))
+        S: f7 OK FETCH completed
+        C: f8 FETCH 1:* FLAGS
+        S: * 1 FETCH (FLAGS (\\Seen))
+        S: * 2 FETCH (FLAGS ())
+        S: f8 OK FETCH completed
+        C: f9 FETCH 1 BOGUS
+        S: f9 BAD Unknown FETCH data item
+        C: f10 FETCH 1 BODY.PEEK[TEXT]<4.18446744073709551615>
+        S: * 1 FETCH (BODY[TEXT]<4> {{8}}
+        S: green.
+        S: )
+        S: f10 OK FETCH completed
+    ", REPORT.len()));
+}
+
+#[test]
+fn test_oversized_literal(){
+    let addr = start_imap(mailboxes("literal"));
+    for literal in ["{18446744073709551615}", "{18446744073709551615+}", "{70000}"] {
+        common::run(addr, &format!("
+            S: * OK *
+            C: a LOGIN {}
+            S: * BYE Server error
+            S: <closed>
+        ", literal));
+    }
+}
+
+#[test]
+fn test_store_search_and_expunge(){
+    let store = mailboxes("store");
+    store.deliver("qa@sink.test", INBOX, b"Subject: third\r\nDate: Tue, 20 Oct 2026 10:00:00 +0000\r\n\r\nBig news\r\n").unwrap();
+    let addr = start_imap(store.clone());
+    run_selected(addr, "
+        C: t1 STORE 1 +FLAGS (\\Flagged $Important)
+        S: * 1 FETCH (FLAGS (\\Flagged $Important))
+        S: t1 OK STORE completed
+        C: t2 UID STORE 2:* +FLAGS.SILENT \\seen
+        S: t2 OK UID STORE completed
+        C: t3 STORE 1 -FLAGS ($Important)
+        S: * 1 FETCH (FLAGS (\\Flagged))
+        S: t3 OK STORE completed
+        C: t4 STORE 1 FLAGS (\\Recent)
+        S: t4 BAD Cannot set \\Recent
+        C: t5 SEARCH UNSEEN
+        S: * SEARCH 1
+        S: t5 OK SEARCH completed
+        C: t6 SEARCH OR FLAGGED SUBJECT logs
+        S: * SEARCH 1 2
+        S: t6 OK SEARCH completed
+        C: t7 UID SEARCH CHARSET UTF-8 BODY \"big NEWS\"
+        S: * SEARCH 3
+        S: t7 OK UID SEARCH completed
+        C: t8 SEARCH NOT (FROM ann) SENTSINCE 20-Oct-2026
+        S: * SEARCH 3
+        S: t8 OK SEARCH completed
+        C: t9 SEARCH 2:* HEADER Content-Type multipart LARGER 100
+        S: * SEARCH 2
+        S: t9 OK SEARCH completed
+        C: t10 SEARCH SINCE 1-Jan-2000 UID 1,3 TEXT green
+        S: * SEARCH 1
+        S: t10 OK SEARCH completed
+        C: t11 SEARCH FLUFFY
+        S: t11 BAD Unknown search key FLUFFY
+        C: t12 STORE 1:2 +FLAGS (\\Deleted)
+        S: * 1 FETCH (FLAGS (\\Flagged \\Deleted))
+        S: * 2 FETCH (FLAGS (\\Seen \\Deleted))
+        S: t12 OK STORE completed
+        C: t13 EXPUNGE
+        S: * 2 EXPUNGE
+        S: * 1 EXPUNGE
+        S: t13 OK EXPUNGE completed
+        C: t14 UID FETCH 1:* FLAGS
+        S: * 1 FETCH (UID 3 FLAGS (\\Seen))
+        S: t14 OK UID FETCH completed
+        C: t15 EXAMINE INBOX
+        S: * FLAGS *
+        S: * 1 EXISTS
+        S: * 0 RECENT
+        S: * OK [PERMANENTFLAGS ()] Flags permitted
+        S: * OK [UIDVALIDITY *
+        S: * OK [UIDNEXT 4] Predicted next UID
+        S: t15 OK [READ-ONLY] EXAMINE completed
+        C: t16 STORE 1 +FLAGS (\\Deleted)
+        S: t16 NO Mailbox is read-only
+        C: t17 CLOSE
+        S: t17 OK CLOSE completed
+    ");
+    let left = store.list("qa@sink.test", INBOX).unwrap();
+    assert_eq!(left.iter().map(|e| (e.uid, e.flags.clone())).collect::<Vec<_>>(), [(3, vec!["\\Seen".to_string()])]);
+}
+
+/// Reads one line, without its CRLF.
+fn read_line(stream: &mut TcpStream) -> String {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        assert_eq!(stream.read(&mut byte).unwrap(), 1, "closed after {:?}", String::from_utf8_lossy(&line));
+        line.push(byte[0]);
+    }
+    String::from_utf8(line[..line.len() - 2].to_vec()).unwrap()
+}
+
+/// Sends a command and reads up to its tagged reply.
+fn command(stream: &mut TcpStream, line: &str) -> Vec<String> {
+    stream.write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+    let tag = line.split(' ').next().unwrap();
+    let mut lines = vec![read_line(stream)];
+    while !lines.last().unwrap().starts_with(&format!("{} ", tag)) {
+        lines.push(read_line(stream));
+    }
+    lines
+}
+
+#[test]
+fn test_idle(){
+    let store = mailboxes("idle");
+    let addr = start_imap(store.clone());
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    read_line(&mut stream);
+    command(&mut stream, "a LOGIN qa@sink.test secret");
+    assert!(command(&mut stream, "s SELECT INBOX").contains(&"* 2 EXISTS".to_string()));
+
+    stream.write_all(b"i IDLE\r\n").unwrap();
+    assert_eq!(read_line(&mut stream), "+ idling");
+    store.deliver("qa@sink.test", INBOX, b"Subject: new\r\n\r\n").unwrap();
+    assert_eq!(read_line(&mut stream), "* 3 EXISTS");
+    store.remove("qa@sink.test", INBOX, 1).unwrap();
+    assert_eq!(read_line(&mut stream), "* 1 EXPUNGE");
+    stream.write_all(b"DONE\r\n").unwrap();
+    assert_eq!(read_line(&mut stream), "i OK IDLE terminated");
+
+    assert_eq!(command(&mut stream, "n NOOP"), ["n OK NOOP completed"]);
+    store.deliver("qa@sink.test", INBOX, b"Subject: newer\r\n\r\n").unwrap();
+    assert_eq!(command(&mut stream, "n NOOP"), ["* 3 EXISTS", "n OK NOOP completed"]);
+    assert_eq!(command(&mut stream, "f UID FETCH * UID"), ["* 3 FETCH (UID 4)", "f OK UID FETCH completed"]);
+}
@@ -0,0 +1,98 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use smtp_server::dns::StaticResolver;
+use smtp_server::handler::{MessageHandler, Verdict};
+use smtp_server::server::{self, Config, Message};
+use smtp_server::trace;
+
+/// Keeps the Received header of each message and rejects those with "spam" in the subject.
+struct Capture(Mutex<Vec<String>>);
+
+impl MessageHandler for Capture {
+    fn handle(&self, msg: &mut Message) -> Verdict {
+        self.0.lock().unwrap().push(msg.headers.get("Received").unwrap_or_default());
+        if msg.subject.contains("spam") {
+            Verdict::Reject("5.7.1 Looks like spam".to_string())
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+fn config(capture: Arc<Capture>) -> Arc<Config> {
+    Arc::new(Config {
+        hostname: "lmtp.sink.test".to_string(),
+        resolver: Arc::new(StaticResolver::new()),
+        handler: capture,
+        lmtp: true,
+        ..Config::default()
+    })
+}
+
+/// Delivers one message to two recipients and returns every reply line
+/// before the one to QUIT.
+fn deliver<S: Read + Write>(stream: S, subject: &str) -> Vec<String> {
+    let mut reader = BufReader::new(stream);
+    let commands = format!(
+        "LHLO mta.sink.test\r\nMAIL FROM:<app@client.test>\r\nRCPT TO:<qa@sink.test>\r\nRCPT TO:<ops@sink.test>\r\nDATA\r\n\
+         Subject: {}\r\n\r\nHello\r\n.\r\nQUIT\r\n",
+        subject
+    );
+    reader.get_mut().write_all(commands.as_bytes()).unwrap();
+    let mut replies: Vec<String> = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 {
+            assert!(replies.pop().unwrap().starts_with("221 "));
+            return replies;
+        }
+        replies.push(line.trim_end().to_string());
+    }
+}
+
+#[test]
+fn test_lmtp_replies_per_recipient(){
+    assert_eq!(trace::lmtp_protocol("ESMTPSA"), "LMTPSA");
+    let capture = Arc::new(Capture(Mutex::new(Vec::new())));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let config = config(capture.clone());
+    thread::spawn(move || server::serve(listener, config));
+
+    let replies = deliver(TcpStream::connect(("127.0.0.1", port)).unwrap(), "Hello");
+    assert!(replies.contains(&"250-lmtp.sink.test".to_string()), "{:?}", replies);
+    assert_eq!(replies[replies.len() - 3], "354");
+    assert!(replies[replies.len() - 2].starts_with("250 OK queued as "));
+    assert_eq!(replies[replies.len() - 2], replies[replies.len() - 1]);
+    assert!(capture.0.lock().unwrap()[0].contains("with LMTP id"));
+
+    let replies = deliver(TcpStream::connect(("127.0.0.1", port)).unwrap(), "spam");
+    assert_eq!(replies[replies.len() - 2..], ["550 5.7.1 Looks like spam", "550 5.7.1 Looks like spam"]);
+
+    // SMTP greetings are refused in LMTP mode.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(b"EHLO client.test\r\n").unwrap();
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "220\r\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_lmtp_over_unix_socket(){
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let path = std::env::temp_dir().join(format!("smtp_lmtp_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let capture = Arc::new(Capture(Mutex::new(Vec::new())));
+    let config = config(capture.clone());
+    thread::spawn(move || server::serve_unix(listener, config));
+
+    let replies = deliver(UnixStream::connect(&path).unwrap(), "Hello");
+    assert!(replies.last().unwrap().starts_with("250 OK"), "{:?}", replies);
+    assert!(capture.0.lock().unwrap()[0].starts_with("from mta.sink.test ([127.0.0.1])"));
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,120 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use smtp_server::dns::StaticResolver;
+use smtp_server::handler::{MessageHandler, Verdict};
+use smtp_server::metrics::{self, Metrics};
+use smtp_server::server::{self, Config, Message};
+
+/// Rejects messages with "spam" in the subject.
+struct SpamFilter;
+
+impl MessageHandler for SpamFilter {
+    fn handle(&self, msg: &mut Message) -> Verdict {
+        if msg.subject.contains("spam") {
+            Verdict::Reject("5.7.1 Looks like spam".to_string())
+        } else {
+            Verdict::Accept
+        }
+    }
+}
+
+fn start_server(metrics: Arc<Metrics>) -> u16 {
+    let config = Config {
+        resolver: Arc::new(StaticResolver::new()),
+        handler: Arc::new(SpamFilter),
+        metrics,
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+    port
+}
+
+/// Runs a whole session and waits for the server to close it.
+fn deliver(port: u16, subject: &str) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let commands = format!(
+        "EHLO client.test\r\nNOOP\r\nMAIL FROM:<app@client.test>\r\nRCPT TO:<bad address>\r\nRCPT TO:<qa@sink.test>\r\n\
+         DATA\r\nSubject: {}\r\n\r\nHello\r\n.\r\nQUIT\r\n",
+        subject
+    );
+    stream.write_all(commands.as_bytes()).unwrap();
+    for line in BufReader::new(stream).lines() {
+        line.unwrap();
+    }
+}
+
+#[test]
+fn test_command_names(){
+    assert_eq!(metrics::command_name("MAIL FROM:<a@b.test>"), "MAIL");
+    assert_eq!(metrics::command_name("rcpt to:<a@b.test>"), "RCPT");
+    assert_eq!(metrics::command_name("EHLO client.test"), "EHLO");
+    assert_eq!(metrics::command_name("DATA"), "DATA");
+    assert_eq!(metrics::command_name("X-ANYTHING"), "UNKNOWN");
+    assert_eq!(metrics::command_name(""), "UNKNOWN");
+}
+
+#[test]
+fn test_session_metrics(){
+    let metrics = Arc::new(Metrics::new());
+    let port = start_server(metrics.clone());
+    deliver(port, "Hello");
+    deliver(port, "spam");
+
+    // The session is counted once the server thread has finished with it.
+    for _ in 0..100 {
+        if metrics.session_duration.count() == 2 {
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(metrics.connections(), 2);
+    assert_eq!(metrics.session_duration.count(), 2);
+    assert_eq!(metrics.replies("CONNECT", "220"), 2);
+    assert_eq!(metrics.replies("EHLO", "250"), 2);
+    assert_eq!(metrics.replies("RCPT", "501"), 2);
+    assert_eq!(metrics.replies("RCPT", "250"), 2);
+    assert_eq!(metrics.replies("DATA", "354"), 2);
+    assert_eq!(metrics.replies("DATA", "250"), 1);
+    assert_eq!(metrics.replies("DATA", "550"), 1);
+    assert_eq!(metrics.messages("accepted"), 1);
+    assert_eq!(metrics.messages("rejected"), 1);
+    assert_eq!(metrics.message_size.count(), 2);
+    assert_eq!(metrics.handler_latency.count(), 2);
+
+    let text = metrics.render();
+    assert!(text.contains("# TYPE smtp_connections_total counter\nsmtp_connections_total 2\n"), "{}", text);
+    assert!(text.contains("smtp_connections_active 0\n"));
+    assert!(text.contains("smtp_replies_total{command=\"RCPT\",code=\"501\"} 2\n"));
+    assert!(text.contains("smtp_messages_total{outcome=\"rejected\"} 1\n"));
+    assert!(text.contains("smtp_message_size_bytes_bucket{le=\"1000\"} 2\n"));
+    assert!(text.contains("smtp_message_size_bytes_bucket{le=\"+Inf\"} 2\nsmtp_message_size_bytes_sum "));
+    assert!(text.contains("smtp_session_duration_seconds_count 2\n"));
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn test_metrics_endpoint(){
+    use std::io::Read;
+
+    let metrics = Arc::new(Metrics::new());
+    metrics.connection_opened();
+    let addr = metrics::spawn("127.0.0.1:0", metrics).unwrap();
+
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    assert!(response.contains("\r\n\r\n# HELP smtp_connections_total"));
+    assert!(response.contains("smtp_connections_active 1\n"));
+    assert!(get("/other").starts_with("HTTP/1.1 404"));
+}
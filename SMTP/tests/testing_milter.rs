@@ -0,0 +1,330 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use smtp_server::milter::{self, Milter, MilterAddr};
+use smtp_server::server::Config;
+use smtp_server::store::{MemoryStore, MessageStore};
+
+/// Decides the replies to each command from the MTA, given its strings.
+/// No replies means continue.
+type Filter = dyn Fn(u8, &[String]) -> Vec<(u8, Vec<u8>)> + Send + Sync;
+
+/// Everything the stand-in filter was told, one entry per command: its
+/// letter and its strings joined with `|`.
+type Log = Arc<Mutex<Vec<String>>>;
+
+/// Plays the filter side of one milter session.
+fn serve_milter(mut stream: impl Read + Write, protocol: u32, filter: Arc<Filter>, log: Log) {
+    loop {
+        let (command, data) = match milter::read_packet(&mut stream) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        if command == b'O' {
+            let mut reply = 6u32.to_be_bytes().to_vec();
+            reply.extend_from_slice(&data[4..8]);
+            reply.extend_from_slice(&protocol.to_be_bytes());
+            milter::write_packet(&mut stream, b'O', &reply).unwrap();
+            continue;
+        }
+        let fields = match command {
+            // Only the hostname; the rest is binary.
+            b'C' => milter::strings(&data)[..1].to_vec(),
+            _ => milter::strings(&data),
+        };
+        log.lock().unwrap().push(format!("{} {}", command as char, fields.join("|")).trim_end().to_string());
+        if matches!(command, b'A' | b'Q') {
+            continue;
+        }
+        let mut replies = filter(command, &fields);
+        if replies.is_empty() {
+            replies.push((b'c', Vec::new()));
+        }
+        for (reply, data) in replies {
+            milter::write_packet(&mut stream, reply, &data).unwrap();
+        }
+    }
+}
+
+/// Starts a stand-in filter on an ephemeral TCP port.
+fn start_milter(protocol: u32, filter: impl Fn(u8, &[String]) -> Vec<(u8, Vec<u8>)> + Send + Sync + 'static) -> (Milter, Log) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let filter: Arc<Filter> = Arc::new(filter);
+    let log = Log::default();
+    let session_log = log.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let (filter, log) = (filter.clone(), session_log.clone());
+            thread::spawn(move || serve_milter(stream.unwrap(), protocol, filter, log));
+        }
+    });
+    (Milter::new(MilterAddr::Tcp(addr.to_string())), log)
+}
+
+/// The log once the filter has been told to quit.
+fn entries(log: &Log) -> Vec<String> {
+    for _ in 0..50 {
+        let entries = log.lock().unwrap().clone();
+        if entries.last().map(String::as_str) == Some("Q") {
+            return entries;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("no QUIT in {:?}", log.lock().unwrap());
+}
+
+/// A reply code packet.
+fn reply(text: &str) -> (u8, Vec<u8>) {
+    (b'y', milter::pack(&[text]))
+}
+
+fn deliver(addr: std::net::SocketAddr, subject: &str) {
+    common::run(addr, &format!("
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<app@client.test> BODY=8BITMIME
+        S: 250 2.1.0 *
+        C: RCPT TO:<qa@sink.test>
+        S: 250 2.1.5 *
+        C: DATA
+        S: 354
+        C: Subject: {}
+        C: X-Remove: me
+        C:
+        C: Original body
+        C: .
+        S: 250 OK queued as *
+        C: QUIT
+        S: 221 *
+    ", subject));
+}
+
+#[test]
+fn test_stages_and_modifications(){
+    let (milter, log) = start_milter(0, |command, _| {
+        if command != b'E' {
+            return Vec::new();
+        }
+        let index = |i: u32, name: &str, value: &str| {
+            let mut data = i.to_be_bytes().to_vec();
+            data.extend_from_slice(&milter::pack(&[name, value]));
+            data
+        };
+        vec![
+            (b'h', milter::pack(&["X-Filtered", "yes"])),
+            (b'm', index(1, "Subject", "[checked] hi")),
+            (b'm', index(1, "X-Remove", "")),
+            (b'i', index(0, "X-First", "folded\n\tvalue")),
+            (b'b', b"New body\r\n".to_vec()),
+            (b'a', Vec::new()),
+        ]
+    });
+    let store = Arc::new(MemoryStore::new());
+    let addr = common::start(Config {
+        milter: Some(milter),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    deliver(addr, "hi");
+
+    let log = entries(&log);
+    assert_eq!(log[..4], ["C [127.0.0.1]", "H client.test", "M <app@client.test>|BODY=8BITMIME", "R <qa@sink.test>"]);
+    assert!(log.iter().any(|entry| entry == "L Subject|hi"), "{:?}", log);
+    assert_eq!(log[log.len() - 4..], ["N", "B Original body", "E", "Q"]);
+
+    let stored = store.list().unwrap();
+    let message = &stored[0];
+    assert!(!message.quarantined);
+    assert_eq!(message.header("Subject"), "[checked] hi");
+    assert_eq!(message.header("X-Filtered"), "yes");
+    assert_eq!(message.header("X-Remove"), "");
+    assert!(message.text().contains("X-First: folded\r\n\tvalue\r\n"), "{}", message.text());
+    assert_eq!(message.body(), b"New body\r\n");
+}
+
+#[test]
+fn test_refusals(){
+    let (milter, _) = start_milter(0, |command, fields| {
+        let field = fields.first().map(String::as_str).unwrap_or("");
+        match (command, field) {
+            (b'M', "<spam@client.test>") => vec![(b't', Vec::new())],
+            (b'R', "<nobody@sink.test>") => vec![reply("550 5.7.1 No thanks")],
+            (b'R', "<later@sink.test>") => vec![reply("450 4.2.0 Mailbox busy")],
+            (b'L', "Subject") if fields[1] == "bad" => vec![(b'r', Vec::new())],
+            (b'L', "Subject") if fields[1] == "drop" => vec![(b'd', Vec::new())],
+            (b'E', _) => vec![(b'q', milter::pack(&["looks odd"])), (b'c', Vec::new())],
+            _ => Vec::new(),
+        }
+    });
+    let store = Arc::new(MemoryStore::new());
+    let addr = common::start(Config {
+        milter: Some(milter),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<spam@client.test>
+        S: 451 4.7.1 *
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 *
+        C: RCPT TO:<nobody@sink.test>
+        S: 550 5.7.1 No thanks
+        C: RCPT TO:<later@sink.test>
+        S: 450 4.2.0 Mailbox busy
+        C: RCPT TO:<qa@sink.test>
+        S: 250 2.1.5 *
+        C: DATA
+        S: 354
+        C: Subject: bad
+        C:
+        C: .
+        S: 550 5.7.1 Message rejected by content filter
+        C: MAIL FROM:<app@client.test>
+        C: RCPT TO:<qa@sink.test>
+        C: DATA
+        S: 250 2.1.0 *
+        S: 250 2.1.5 *
+        S: 354
+        C: Subject: drop
+        C:
+        C: .
+        S: 250 OK queued as *
+        C: MAIL FROM:<app@client.test>
+        C: RCPT TO:<qa@sink.test>
+        C: DATA
+        S: 250 2.1.0 *
+        S: 250 2.1.5 *
+        S: 354
+        C: Subject: fine
+        C:
+        C: .
+        S: 250 OK queued as *
+        C: QUIT
+        S: 221 *
+    ");
+    // Only the last one was kept, and the filter had it quarantined.
+    let stored = store.list().unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].header("Subject"), "fine");
+    assert!(stored[0].quarantined);
+}
+
+#[test]
+fn test_connect_and_helo(){
+    let (milter, _) = start_milter(0, |command, _| match command {
+        b'C' => vec![reply("554 5.7.1 Go away")],
+        _ => Vec::new(),
+    });
+    let addr = common::start(Config { milter: Some(milter), ..common::offline_config() });
+    common::run(addr, "
+        S: 554 5.7.1 Go away
+        S: <closed>
+    ");
+
+    let (milter, _) = start_milter(0, |command, _| match command {
+        b'C' => vec![(b't', Vec::new())],
+        _ => Vec::new(),
+    });
+    let addr = common::start(Config { milter: Some(milter), ..common::offline_config() });
+    common::run(addr, "
+        S: 421 4.7.1 *
+        S: <closed>
+    ");
+
+    // A reply code too short to be one counts as a broken filter.
+    let (milter, _) = start_milter(0, |command, _| match command {
+        b'C' => vec![reply("4")],
+        _ => Vec::new(),
+    });
+    let addr = common::start(Config { milter: Some(milter), ..common::offline_config() });
+    common::run(addr, "
+        S: 421 4.3.0 Mail filter unavailable
+        S: <closed>
+    ");
+
+    let (milter, log) = start_milter(0, |command, fields| match (command, fields[0].as_str()) {
+        (b'H', "bad.test") => vec![reply("550 5.7.1 Bad greeting")],
+        (b'H', _) => vec![(b'a', Vec::new())],
+        _ => Vec::new(),
+    });
+    let addr = common::start(Config { milter: Some(milter), ..common::offline_config() });
+    common::run(addr, "
+        S: 220
+        C: HELO bad.test
+        S: 250 mx.sink.test
+        C: MAIL FROM:<app@client.test>
+        S: 550 5.7.1 Bad greeting
+        C: HELO good.test
+        S: 250 mx.sink.test
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 *
+        C: RSET
+        S: 250 2.0.0 OK
+        C: QUIT
+        S: 221 *
+    ");
+    // Once the filter accepted the session it heard nothing more of it, bar
+    // the abort and goodbye.
+    assert_eq!(entries(&log)[1..], ["H bad.test", "H good.test", "A", "Q"]);
+}
+
+#[test]
+fn test_unavailable_milter(){
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed = listener.local_addr().unwrap().to_string();
+    drop(listener);
+
+    let addr = common::start(Config {
+        milter: Some(Milter::new(MilterAddr::Tcp(closed.clone()))),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 421 4.3.0 Mail filter unavailable
+        S: <closed>
+    ");
+
+    let mut milter = Milter::new(MilterAddr::Tcp(closed));
+    milter.fail_open = true;
+    let store = Arc::new(MemoryStore::new());
+    let addr = common::start(Config {
+        milter: Some(milter),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    deliver(addr, "hi");
+    assert_eq!(store.list().unwrap().len(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket_and_skipped_stages(){
+    let path = std::env::temp_dir().join(format!("smtp_milter_{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let log = Log::default();
+    let session_log = log.clone();
+    let protocol = milter::SMFIP_NOCONNECT | milter::SMFIP_NOHELO | milter::SMFIP_NOHDRS | milter::SMFIP_NOBODY;
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        serve_milter(stream, protocol, Arc::new(|_, _| Vec::new()), session_log);
+    });
+
+    let addr = common::start(Config {
+        milter: Some(Milter::new(MilterAddr::Unix(path.clone()))),
+        ..common::offline_config()
+    });
+    deliver(addr, "hi");
+    assert_eq!(entries(&log), ["M <app@client.test>|BODY=8BITMIME", "R <qa@sink.test>", "N", "E", "Q"]);
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,277 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use rustls::crypto::ring;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use smtp_server::auth::{self, MemoryCredentials};
+use smtp_server::mailbox::{MailboxStore, INBOX};
+use smtp_server::pop3;
+use smtp_server::server::Config;
+use smtp_server::sieve::{MemoryScripts, Script, Sieve};
+use smtp_server::tls;
+
+fn start_pop3(mailboxes: Arc<MailboxStore>, tls: bool) -> SocketAddr {
+    let mut credentials = MemoryCredentials::new();
+    credentials.add("qa@sink.test", "secret");
+    credentials.add("dev@sink.test", "tanstaaf");
+    let config = pop3::Config {
+        hostname: "mx.sink.test".to_string(),
+        mailboxes,
+        credentials: Arc::new(credentials),
+        tls: tls.then(|| tls::load(common::fixture("tls_cert.pem"), common::fixture("tls_key.pem")).unwrap()),
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || pop3::serve(listener, Arc::new(config)));
+    addr
+}
+
+/// Reads one line, without its CRLF, a byte at a time so that nothing past
+/// it is consumed.
+fn read_line(stream: &mut impl Read) -> String {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        assert_eq!(stream.read(&mut byte).unwrap(), 1, "closed after {:?}", String::from_utf8_lossy(&line));
+        line.push(byte[0]);
+    }
+    String::from_utf8(line[..line.len() - 2].to_vec()).unwrap()
+}
+
+fn command(stream: &mut (impl Read + Write), line: &str) -> String {
+    stream.write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+    read_line(stream)
+}
+
+#[test]
+fn test_mailbox_store(){
+    let store = common::mailboxes("pop3_store");
+    assert_eq!(store.mailboxes("qa@sink.test").unwrap(), Vec::<String>::new());
+    assert_eq!(store.deliver("QA@sink.test", "inbox", b"Subject: one\r\n\r\n").unwrap(), 1);
+    assert_eq!(store.deliver("qa@sink.test", "Lists/dev", b"Subject: list\r\n\r\n").unwrap(), 1);
+    assert_eq!(store.deliver("qa@sink.test", INBOX, b"Subject: two\r\n\r\n").unwrap(), 2);
+    store.create("qa@sink.test", "Archive").unwrap();
+    assert_eq!(store.mailboxes("qa@sink.test").unwrap(), ["INBOX", "Archive", "Lists/dev"]);
+
+    let (validity, next) = store.uids("qa@sink.test", INBOX).unwrap();
+    assert!(validity > 0);
+    assert_eq!(next, 3);
+    store.set_flags("qa@sink.test", INBOX, 2, &["\\Seen".to_string(), "$Label".to_string()]).unwrap();
+    let entries = store.list("qa@sink.test", INBOX).unwrap();
+    assert_eq!(entries.iter().map(|e| (e.uid, e.size)).collect::<Vec<_>>(), [(1, 16), (2, 16)]);
+    assert_eq!(entries[1].flags, ["\\Seen", "$Label"]);
+    assert_eq!(store.read("qa@sink.test", INBOX, 1).unwrap(), b"Subject: one\r\n\r\n");
+
+    // UIDs are never reused, even for the last message.
+    assert!(store.remove("qa@sink.test", INBOX, 2).unwrap());
+    assert!(!store.remove("qa@sink.test", INBOX, 2).unwrap());
+    assert_eq!(store.deliver("qa@sink.test", INBOX, b"Subject: three\r\n\r\n").unwrap(), 3);
+    assert!(store.set_flags("qa@sink.test", INBOX, 2, &[]).is_err());
+
+    for (user, mailbox) in [("../qa", INBOX), ("qa@sink.test", "../other"), ("qa@sink.test", "a//b"), ("qa@sink.test", ".hidden")] {
+        assert!(store.deliver(user, mailbox, b"").is_err(), "{} {}", user, mailbox);
+    }
+}
+
+#[test]
+fn test_session(){
+    let store = common::mailboxes("pop3_session");
+    store.deliver("qa@sink.test", INBOX, b"Subject: one\r\n\r\nHello\r\n.hidden\r\n").unwrap();
+    store.deliver("qa@sink.test", INBOX, b"Subject: two\r\n\r\nBye\r\n").unwrap();
+    let addr = start_pop3(store.clone(), false);
+    common::run(addr, "
+        S: +OK POP3 mx.sink.test ready <*@mx.sink.test>
+        C: STAT
+        S: -ERR Command not valid in this state
+        C: CAPA
+        S: +OK Capability list follows
+        S: TOP
+        S: UIDL
+        S: RESP-CODES
+        S: AUTH-RESP-CODE
+        S: PIPELINING
+        S: USER
+        S: .
+        C: PASS secret
+        S: -ERR USER first
+        C: USER QA@sink.test
+        S: +OK QA@sink.test selected
+        C: PASS wrong
+        S: -ERR [AUTH] Invalid user name or password
+        C: USER QA@sink.test
+        S: +OK *
+        C: PASS secret
+        S: +OK Maildrop has 2 messages (53 octets)
+        C: STAT
+        S: +OK 2 53
+        C: LIST
+        S: +OK
+        S: 1 32
+        S: 2 21
+        S: .
+        C: UIDL 2
+        S: +OK 2 *.2
+        C: TOP 1 1
+        S: +OK 23 octets
+        S: Subject: one
+        S:
+        S: Hello
+        S: .
+        C: RETR 1
+        S: +OK 32 octets
+        S: Subject: one
+        S:
+        S: Hello
+        S: ..hidden
+        S: .
+        C: DELE 1
+        S: +OK Message 1 deleted
+        C: RETR 1
+        S: -ERR Message 1 already deleted
+        C: LIST 3
+        S: -ERR No such message 3
+        C: STAT
+        S: +OK 1 21
+        C: RSET
+        S: +OK Maildrop has 2 messages (53 octets)
+        C: DELE 2
+        S: +OK Message 2 deleted
+        C: QUIT
+        S: +OK mx.sink.test POP3 server signing off (1 messages left)
+        S: <closed>
+    ");
+    let left = store.list("qa@sink.test", INBOX).unwrap();
+    assert_eq!(left.iter().map(|e| e.uid).collect::<Vec<_>>(), [1]);
+}
+
+#[test]
+fn test_apop_and_locking(){
+    let store = common::mailboxes("pop3_apop");
+    let addr = start_pop3(store, false);
+    let mut first = TcpStream::connect(addr).unwrap();
+    let greeting = read_line(&mut first);
+    let timestamp = &greeting[greeting.find('<').unwrap()..];
+    assert_eq!(command(&mut first, "APOP dev@sink.test 0123456789abcdef0123456789abcdef"), "-ERR [AUTH] Invalid user name or password");
+    let digest = auth::apop_digest(timestamp, "tanstaaf");
+    assert_eq!(command(&mut first, &format!("APOP dev@sink.test {}", digest)), "+OK Maildrop has 0 messages (0 octets)");
+
+    // The maildrop stays locked until the first session ends.
+    common::run(addr, "
+        S: +OK*
+        C: USER dev@sink.test
+        S: +OK*
+        C: PASS tanstaaf
+        S: -ERR [IN-USE] Maildrop already locked
+        C: PASS tanstaaf
+        S: -ERR USER first
+        C: QUIT
+        S: +OK mx.sink.test POP3 server signing off
+    ");
+    assert_eq!(command(&mut first, "QUIT"), "+OK mx.sink.test POP3 server signing off (0 messages left)");
+    common::run(addr, "
+        S: +OK*
+        C: USER dev@sink.test
+        S: +OK*
+        C: PASS tanstaaf
+        S: +OK Maildrop has 0 messages (0 octets)
+        C: APOP dev@sink.test x
+        S: -ERR Command not valid in this state
+        C: FETCH 1
+        S: -ERR Unknown command
+    ");
+}
+
+#[test]
+fn test_stls(){
+    let addr = start_pop3(common::mailboxes("pop3_plain"), false);
+    common::run(addr, "
+        S: +OK*
+        C: STLS
+        S: -ERR STLS not available
+    ");
+
+    let store = common::mailboxes("pop3_stls");
+    store.deliver("qa@sink.test", INBOX, b"Subject: secret\r\n\r\n").unwrap();
+    let addr = start_pop3(store, true);
+    let mut tcp = TcpStream::connect(addr).unwrap();
+    read_line(&mut tcp);
+    assert!(command(&mut tcp, "CAPA").starts_with("+OK"));
+    let mut capabilities = Vec::new();
+    while capabilities.last().map(String::as_str) != Some(".") {
+        capabilities.push(read_line(&mut tcp));
+    }
+    assert!(capabilities.contains(&"STLS".to_string()));
+    assert_eq!(command(&mut tcp, "STLS"), "+OK Begin TLS negotiation");
+
+    let mut roots = RootCertStore::empty();
+    let cert = std::fs::read(common::fixture("tls_cert.pem")).unwrap();
+    for cert in rustls_pemfile::certs(&mut &cert[..]) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from("mx.sink.test").unwrap();
+    let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut tls = StreamOwned::new(connection, tcp);
+    assert_eq!(command(&mut tls, "STLS"), "-ERR STLS not available");
+    assert!(command(&mut tls, "USER qa@sink.test").starts_with("+OK"));
+    assert_eq!(command(&mut tls, "PASS secret"), "+OK Maildrop has 1 messages (19 octets)");
+    assert_eq!(command(&mut tls, "RETR 1"), "+OK 19 octets");
+    assert_eq!([read_line(&mut tls), read_line(&mut tls), read_line(&mut tls)], ["Subject: secret", "", "."]);
+    assert!(command(&mut tls, "QUIT").starts_with("+OK"));
+}
+
+#[test]
+fn test_delivery_into_mailboxes(){
+    let store = common::mailboxes("pop3_delivery");
+    let mut scripts = MemoryScripts::new();
+    scripts.add("qa@sink.test", Script::parse("require \"fileinto\"; if header :contains \"subject\" \"report\" { fileinto \"Reports\"; }").unwrap());
+    let addr = common::start(Config {
+        mailboxes: Some(store.clone()),
+        sieve: Some(Arc::new(Sieve::new(Arc::new(scripts)))),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<app@client.test>
+        C: RCPT TO:<qa@sink.test>
+        C: RCPT TO:<dev@sink.test>
+        C: DATA
+        S: 250 2.1.0 *
+        S: 250 2.1.5 *
+        S: 250 2.1.5 *
+        S: 354
+        C: Subject: Daily report
+        C:
+        C: All green.
+        C: .
+        S: 250 OK queued as *
+        C: QUIT
+        S: 221 *
+    ");
+    assert_eq!(store.mailboxes("qa@sink.test").unwrap(), ["Reports"]);
+    assert_eq!(store.mailboxes("dev@sink.test").unwrap(), ["INBOX"]);
+    let content = store.read("dev@sink.test", INBOX, 1).unwrap();
+    let text = String::from_utf8(content).unwrap();
+    assert!(text.starts_with("Return-Path: <app@client.test>\r\nReceived: from client.test"), "{}", text);
+    assert!(text.ends_with("Subject: Daily report\r\n\r\nAll green.\r\n"), "{}", text);
+
+    // And the client can pick it up.
+    let addr = start_pop3(store, false);
+    common::run(addr, "
+        S: +OK*
+        C: USER qa@sink.test
+        S: +OK*
+        C: PASS secret
+        S: +OK Maildrop has 0 messages (0 octets)
+    ");
+}
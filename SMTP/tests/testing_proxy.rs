@@ -0,0 +1,130 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use smtp_server::dns::StaticResolver;
+use smtp_server::handler::{MessageHandler, Verdict};
+use smtp_server::proxy::{self, ProxyHeader};
+use smtp_server::server::{self, Config, Message};
+
+/// Keeps the Received header of each message.
+struct Capture(Mutex<Vec<String>>);
+
+impl MessageHandler for Capture {
+    fn handle(&self, msg: &mut Message) -> Verdict {
+        self.0.lock().unwrap().push(msg.headers.get("Received").unwrap_or_default());
+        Verdict::Accept
+    }
+}
+
+fn start_server(capture: Arc<Capture>) -> u16 {
+    let config = Config {
+        resolver: Arc::new(StaticResolver::new()),
+        handler: capture,
+        proxy_protocol: true,
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+    port
+}
+
+/// Sends `header` and then a whole session, returning the reply to the message.
+fn deliver(port: u16, header: &[u8]) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(header).unwrap();
+    stream
+        .write_all(b"EHLO client.test\r\nMAIL FROM:<>\r\nRCPT TO:<qa@sink.test>\r\nDATA\r\nSubject: hi\r\n\r\nHello\r\n.\r\nQUIT\r\n")
+        .unwrap();
+    let lines: Vec<String> = BufReader::new(stream).lines().map(|line| line.unwrap()).collect();
+    assert!(lines.last().unwrap().starts_with("221 "), "{:?}", lines);
+    lines[lines.len() - 2].clone()
+}
+
+fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    header.extend_from_slice(&[0x20 | command, family]);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(addresses);
+    header
+}
+
+#[test]
+fn test_parse_v1(){
+    let line = b"PROXY TCP4 203.0.113.7 192.0.2.1 40000 25\r\nEHLO";
+    let (header, len) = proxy::parse(line).unwrap().unwrap();
+    assert_eq!(len, line.len() - 4);
+    assert_eq!(header.source, Some("203.0.113.7:40000".parse().unwrap()));
+    assert_eq!(header.destination, Some("192.0.2.1:25".parse().unwrap()));
+
+    let (header, _) = proxy::parse(b"PROXY TCP6 2001:db8::7 2001:db8::1 40000 25\r\n").unwrap().unwrap();
+    assert_eq!(header.source, Some("[2001:db8::7]:40000".parse().unwrap()));
+    let unknown = ProxyHeader { source: None, destination: None };
+    assert_eq!(proxy::parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap().0, unknown);
+
+    // Partial headers wait for more bytes.
+    assert!(proxy::parse(b"PRO").unwrap().is_none());
+    assert!(proxy::parse(b"PROXY TCP4 203.0.113.7").unwrap().is_none());
+
+    for bad in [
+        &b"EHLO client.test\r\n"[..],
+        b"PROXY TCP4 2001:db8::7 192.0.2.1 40000 25\r\n",
+        b"PROXY TCP4 203.0.113.7 192.0.2.1 70000 25\r\n",
+        b"PROXY UDP4 203.0.113.7 192.0.2.1 40000 25\r\n",
+        b"PROXY TCP4 203.0.113.7\r\n",
+        &[b"PROXY ".as_slice(), &[b'x'; 120]].concat(),
+    ] {
+        assert!(proxy::parse(bad).is_err(), "{:?}", String::from_utf8_lossy(bad));
+    }
+}
+
+#[test]
+fn test_parse_v2(){
+    let header = v2_header(1, 0x11, &[203, 0, 113, 7, 192, 0, 2, 1, 0x9c, 0x40, 0, 25]);
+    let (parsed, len) = proxy::parse(&header).unwrap().unwrap();
+    assert_eq!(len, header.len());
+    assert_eq!(parsed.source, Some("203.0.113.7:40000".parse().unwrap()));
+    assert_eq!(parsed.destination, Some("192.0.2.1:25".parse().unwrap()));
+    assert!(proxy::parse(&header[..20]).unwrap().is_none());
+
+    let mut addresses = "2001:db8::7".parse::<std::net::Ipv6Addr>().unwrap().octets().to_vec();
+    addresses.extend_from_slice(&"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+    addresses.extend_from_slice(&[0x9c, 0x40, 0, 25]);
+    // TLVs after the addresses are skipped.
+    addresses.extend_from_slice(&[0x04, 0, 1, 0]);
+    let header = v2_header(1, 0x21, &addresses);
+    let (parsed, len) = proxy::parse(&header).unwrap().unwrap();
+    assert_eq!(len, header.len());
+    assert_eq!(parsed.source, Some("[2001:db8::7]:40000".parse::<SocketAddr>().unwrap()));
+
+    assert_eq!(proxy::parse(&v2_header(0, 0, &[])).unwrap().unwrap().0.source, None);
+    assert!(proxy::parse(&v2_header(1, 0x11, &[203, 0, 113, 7])).is_err());
+    assert!(proxy::parse(&v2_header(2, 0x11, &[])).is_err());
+}
+
+#[test]
+fn test_proxied_client_address(){
+    let capture = Arc::new(Capture(Mutex::new(Vec::new())));
+    let port = start_server(capture.clone());
+
+    let reply = deliver(port, b"PROXY TCP4 203.0.113.7 192.0.2.1 40000 25\r\n");
+    assert!(reply.starts_with("250 OK"), "{}", reply);
+    assert!(capture.0.lock().unwrap()[0].starts_with("from client.test ([203.0.113.7])"));
+
+    let header = v2_header(1, 0x11, &[198, 51, 100, 9, 192, 0, 2, 1, 0x9c, 0x40, 0, 25]);
+    let reply = deliver(port, &header);
+    assert!(reply.starts_with("250 OK"), "{}", reply);
+    assert!(capture.0.lock().unwrap()[1].starts_with("from client.test ([198.51.100.9])"));
+
+    // A LOCAL header keeps the socket's own peer.
+    deliver(port, &v2_header(0, 0, &[]));
+    assert!(capture.0.lock().unwrap()[2].starts_with("from client.test ([127.0.0.1])"));
+
+    // Without a header the connection is dropped before the greeting.
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(b"EHLO client.test\r\n").unwrap();
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "");
+}
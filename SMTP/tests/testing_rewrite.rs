@@ -0,0 +1,210 @@
+mod common;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use smtp_server::address::Mailbox;
+use smtp_server::directory::{Directory, MemoryDirectory};
+use smtp_server::handler::{MessageHandler, Verdict};
+use smtp_server::rewrite::{Rewriter, Tables};
+use smtp_server::server::{Config, Message};
+use smtp_server::store::{MemoryStore, MessageStore};
+
+const TABLES: &str = "
+# Sales reach two people.
+alias     sales@sink.test      alice@sink.test, bob@sink.test
+alias     team@sink.test       sales@sink.test carol@sink.test   # aliases nest
+alias     keep@sink.test       keep@sink.test, archive@sink.test
+alias     ping@sink.test       pong@sink.test
+alias     pong@sink.test       ping@sink.test
+catchall  Catch.test           inbox@sink.test
+separator +-
+canonical alice@internal.test  alice.smith@sink.test
+canonical @internal.test       @sink.test
+";
+
+fn recipients(tables: &Tables, address: &str, directory: Option<&MemoryDirectory>) -> Vec<String> {
+    let directory = directory.map(|d| d as &dyn Directory);
+    tables.recipients(&Mailbox::parse(address).unwrap(), directory).iter().map(Mailbox::to_string).collect()
+}
+
+#[test]
+fn test_tables() {
+    let tables = Tables::parse(TABLES).unwrap();
+    assert_eq!(recipients(&tables, "Sales@SINK.test", None), ["alice@sink.test", "bob@sink.test"]);
+    assert_eq!(recipients(&tables, "team@sink.test", None), ["alice@sink.test", "bob@sink.test", "carol@sink.test"]);
+    assert_eq!(recipients(&tables, "keep@sink.test", None), ["keep@sink.test", "archive@sink.test"]);
+    assert_eq!(recipients(&tables, "ping@sink.test", None), ["ping@sink.test"]);
+    assert_eq!(recipients(&tables, "dave@sink.test", None), ["dave@sink.test"]);
+
+    // Tags come off, and the untagged address is looked up too.
+    assert_eq!(recipients(&tables, "dave+lists@sink.test", None), ["dave@sink.test"]);
+    assert_eq!(recipients(&tables, "dave-lists@sink.test", None), ["dave@sink.test"]);
+    assert_eq!(recipients(&tables, "sales+web@sink.test", None), ["alice@sink.test", "bob@sink.test"]);
+    assert_eq!(recipients(&tables, "+only@sink.test", None), ["+only@sink.test"]);
+
+    // Catch-alls take what the directory does not know.
+    assert_eq!(recipients(&tables, "anyone@catch.test", None), ["inbox@sink.test"]);
+    let mut directory = MemoryDirectory::new();
+    directory.add_user("known@catch.test", None);
+    assert_eq!(recipients(&tables, "known+x@catch.test", Some(&directory)), ["known@catch.test"]);
+    assert_eq!(recipients(&tables, "other@catch.test", Some(&directory)), ["inbox@sink.test"]);
+
+    let sender = |address: &str| tables.sender(&Mailbox::parse(address).unwrap()).to_string();
+    assert_eq!(sender("ALICE@internal.test"), "alice.smith@sink.test");
+    assert_eq!(sender("Bob@internal.test"), "Bob@sink.test");
+    assert_eq!(sender("bob@elsewhere.test"), "bob@elsewhere.test");
+
+    assert_eq!(Tables::parse("# nothing\n\n").unwrap(), Tables::new());
+    for (text, error) in [
+        ("\nalias sales@sink.test", "rewrite table line 2: expected alias <address> <target>..."),
+        ("alias sales@sink.test not-an-address", "rewrite table line 1: not-an-address: *"),
+        ("canonical @internal.test alice@sink.test", "rewrite table line 1: canonical maps *"),
+        ("separator", "rewrite table line 1: expected separator <characters>"),
+        ("relay sink.test", "rewrite table line 1: unknown directive \"relay\""),
+    ] {
+        let message = Tables::parse(text).unwrap_err().to_string();
+        assert!(common::matches(error, &message), "{}", message);
+    }
+}
+
+#[test]
+fn test_reload() {
+    let path = std::env::temp_dir().join(format!("smtp_rewrite_{}", std::process::id()));
+    fs::write(&path, "alias a@sink.test b@sink.test\n").unwrap();
+    let rewriter = Rewriter::open(&path).unwrap();
+    let target = |rewriter: &Rewriter| recipients(&rewriter.tables(), "a@sink.test", None);
+    assert_eq!(target(&rewriter), ["b@sink.test"]);
+
+    fs::write(&path, "alias a@sink.test c@sink.test, d@sink.test\n").unwrap();
+    assert_eq!(target(&rewriter), ["c@sink.test", "d@sink.test"]);
+
+    // A broken edit leaves the previous tables in place.
+    fs::write(&path, "alias a@sink.test\n").unwrap();
+    assert_eq!(target(&rewriter), ["c@sink.test", "d@sink.test"]);
+
+    fs::write(&path, "bogus\n").unwrap();
+    assert!(Rewriter::open(&path).is_err());
+    fs::remove_file(&path).unwrap();
+    assert_eq!(target(&rewriter), ["c@sink.test", "d@sink.test"]);
+}
+
+/// Records each message's envelope as the handler sees it.
+struct Envelope(Mutex<Vec<(String, Vec<String>)>>);
+
+impl MessageHandler for Envelope {
+    fn handle(&self, msg: &mut Message) -> Verdict {
+        self.0.lock().unwrap().push((msg.mail_from(), msg.recipients()));
+        Verdict::Accept
+    }
+}
+
+#[test]
+fn test_rewriting_in_session() {
+    let store = Arc::new(MemoryStore::new());
+    let envelopes = Arc::new(Envelope(Default::default()));
+    let mut directory = MemoryDirectory::new();
+    for user in ["alice@sink.test", "bob@sink.test", "carol@sink.test", "inbox@sink.test"] {
+        directory.add_user(user, None);
+    }
+    let addr = common::start(Config {
+        rewriter: Some(Arc::new(Rewriter::new(Tables::parse(TABLES).unwrap()))),
+        directory: Some(Arc::new(directory)),
+        store: Some(store.clone()),
+        handler: envelopes.clone(),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220*
+        C: EHLO client.test
+        S: 250-*
+        C: MAIL FROM:<alice@internal.test>
+        S: 250 2.1.0 Sender OK
+        C: RCPT TO:<team@sink.test>
+        S: 250 2.1.5 Recipient OK
+        C: RCPT TO:<bob+x@sink.test>
+        S: 250 2.1.5 Recipient OK
+        C: RCPT TO:<nobody@catch.test>
+        S: 250 2.1.5 Recipient OK
+        C: RCPT TO:<dave@sink.test>
+        S: 550 5.1.1 <dave@sink.test>: User unknown
+        C: RCPT TO:<keep@sink.test>
+        S: 550 5.1.1 <keep@sink.test>: User unknown
+        C: DATA
+        S: 354*
+        C: Subject: rewritten
+        C:
+        C: Hi
+        C: .
+        S: 250 OK queued as *
+    ");
+
+    let expected = ["alice@sink.test", "bob@sink.test", "carol@sink.test", "inbox@sink.test"];
+    let envelopes = envelopes.0.lock().unwrap();
+    assert_eq!(envelopes[0], ("alice.smith@sink.test".to_string(), expected.map(String::from).to_vec()));
+    let stored = &store.list().unwrap()[0];
+    assert_eq!(stored.mail_from, "alice.smith@sink.test");
+    assert_eq!(stored.recipients, expected);
+    assert_eq!(stored.header("Return-Path"), "<alice.smith@sink.test>");
+    // SPF judged the address the client gave.
+    assert!(stored.header("Received-SPF").contains("alice@internal.test"), "{}", stored.header("Received-SPF"));
+}
+
+#[test]
+fn test_aliases_over_lmtp() {
+    let store = Arc::new(MemoryStore::new());
+    let addr = common::start(Config {
+        lmtp: true,
+        rewriter: Some(Arc::new(Rewriter::new(Tables::parse(TABLES).unwrap()))),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    // One reply per RCPT, however many recipients the aliases gave.
+    common::run(addr, "
+        S: 220*
+        C: LHLO client.test
+        S: 250-*
+        C: MAIL FROM:<app@client.test>
+        S: 250*
+        C: RCPT TO:<sales@sink.test>
+        S: 250*
+        C: DATA
+        S: 354*
+        C: Subject: one reply
+        C:
+        C: Hi
+        C: .
+        S: 250 OK queued as *
+        C: QUIT
+        S: 221*
+    ");
+    assert_eq!(store.list().unwrap()[0].recipients, ["alice@sink.test", "bob@sink.test"]);
+}
+
+#[test]
+fn test_reports_name_the_alias() {
+    let store = Arc::new(MemoryStore::new());
+    let addr = common::start(Config {
+        rewriter: Some(Arc::new(Rewriter::new(Tables::parse(TABLES).unwrap()))),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220*
+        C: EHLO client.test
+        S: 250-*
+        C: MAIL FROM:<app@client.test>
+        S: 250*
+        C: RCPT TO:<sales@sink.test> NOTIFY=SUCCESS
+        S: 250*
+        C: DATA
+        S: 354*
+        C: Subject: tracked
+        C:
+        C: Hi
+        C: .
+        S: 250 OK queued as *
+    ");
+    let report = store.list().unwrap().into_iter().find(|msg| msg.recipients == ["app@client.test"]).unwrap();
+    let text = report.text();
+    assert!(text.contains("Original-Recipient: rfc822;sales@sink.test\r\nFinal-Recipient: rfc822; alice@sink.test"), "{}", text);
+}
@@ -0,0 +1,207 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use std::thread;
+use std::time::Duration;
+use proptest::prelude::*;
+use smtp_server::address::{self, Mailbox};
+use smtp_server::command::Command;
+use smtp_server::dns::StaticResolver;
+use smtp_server::headers::{self, Headers};
+use smtp_server::server::{self, Config};
+use smtp_server::{dsn, idna, mime, proxy};
+
+/// Panics on any thread of this test binary, connection threads included.
+static PANICS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_panics() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let default = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANICS.fetch_add(1, Ordering::SeqCst);
+            default(info);
+        }));
+    });
+}
+
+fn start_server() -> u16 {
+    let config = Config {
+        resolver: Arc::new(StaticResolver::new()),
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+    port
+}
+
+/// Dot-stuffs `body` and terminates it, as a client sends it after DATA.
+fn stuff(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for line in body.split_inclusive(|&b| b == b'\n') {
+        if line.starts_with(b".") {
+            out.push(b'.');
+        }
+        out.extend_from_slice(line);
+    }
+    out.extend_from_slice(b".\r\n");
+    out
+}
+
+fn command_line() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "(?i)(ehlo|helo|lhlo|mail from:|rcpt to:|data|bdat|rset|noop|quit|vrfy|expn|help|auth|starttls)[ -~]{0,40}",
+        "\\PC{0,60}",
+    ]
+}
+
+/// Lines of CRLF terminated text, some starting with `.`.
+fn body_lines() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec("[^\r\n]{0,20}", 0..8)
+        .prop_map(|lines| lines.iter().flat_map(|line| format!("{}\r\n", line).into_bytes()).collect())
+}
+
+proptest! {
+    #[test]
+    fn command_parse_round_trips(line in command_line()) {
+        let command = Command::parse(&line);
+        prop_assert_eq!(Command::parse(&command.to_string()), command);
+    }
+
+    #[test]
+    fn paths_never_panic(value in "\\PC{0,80}") {
+        let _ = address::parse_reverse_path(&value);
+        let _ = address::parse_forward_path(&value);
+        let _ = server::normalize_address(&value);
+    }
+
+    #[test]
+    fn mailbox_round_trips(local in "[a-zA-Z0-9.!#+_ \"é]{1,20}", domain in "[a-z0-9-]{1,10}\\.(test|例え)") {
+        if let Ok(mailbox) = Mailbox::parse(&format!("{}@{}", local, domain)) {
+            prop_assert_eq!(Mailbox::parse(&mailbox.to_string()).unwrap(), mailbox);
+        }
+    }
+
+    #[test]
+    fn headers_round_trip(fields in prop::collection::vec(("[A-Za-z-]{1,12}", "[ -~]{0,30}"), 0..6)) {
+        let mut headers = Headers::new();
+        for (name, value) in &fields {
+            headers.push(name, value);
+        }
+        let reparsed = Headers::parse(&headers.to_string());
+        prop_assert_eq!(reparsed.to_string(), headers.to_string());
+    }
+
+    #[test]
+    fn header_parsing_never_panics(raw in prop::collection::vec(any::<u8>(), 0..200)) {
+        let text = String::from_utf8_lossy(&raw);
+        let _ = Headers::parse(&text);
+        let _ = headers::field_end(&raw);
+        let _ = headers::split_message_bytes(&raw);
+        let _ = mime::parse_message(&text);
+        let _ = mime::decode_encoded_words(&text);
+    }
+
+    #[test]
+    fn dot_stuffing_round_trips(body in body_lines(), rest in prop::collection::vec(any::<u8>(), 0..10)) {
+        let mut sent = stuff(&body);
+        let len = sent.len();
+        sent.extend_from_slice(&rest);
+        let (content, consumed) = server::split_data_body(&sent).unwrap();
+        prop_assert_eq!(content, body);
+        prop_assert_eq!(consumed, len);
+    }
+
+    #[test]
+    fn data_reader_never_panics(raw in prop::collection::vec(any::<u8>(), 0..200)) {
+        if let Some((content, consumed)) = server::split_data_body(&raw) {
+            prop_assert!(consumed <= raw.len() && content.len() <= consumed);
+        }
+    }
+
+    #[test]
+    fn xtext_round_trips(value in "[ -~]{0,40}") {
+        prop_assert_eq!(dsn::xtext_decode(&dsn::xtext_encode(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn punycode_round_trips(label in "\\PC{1,20}") {
+        if let Ok(encoded) = idna::punycode_encode(&label) {
+            prop_assert_eq!(idna::punycode_decode(&encoded).unwrap(), label);
+        }
+    }
+
+    #[test]
+    fn proxy_header_never_panics(raw in prop::collection::vec(any::<u8>(), 0..120)) {
+        let _ = proxy::parse(&raw);
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        v2.extend_from_slice(&raw);
+        let _ = proxy::parse(&v2);
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    /// Whatever a client sends, the connection thread does not panic.
+    #[test]
+    fn sessions_never_panic(lines in prop::collection::vec(command_line(), 0..12), tail in prop::collection::vec(any::<u8>(), 0..64)) {
+        count_panics();
+        let port = start_server();
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut script = b"EHLO client.test\r\n".to_vec();
+        for line in &lines {
+            script.extend_from_slice(line.as_bytes());
+            script.extend_from_slice(b"\r\n");
+        }
+        script.extend_from_slice(&tail);
+        let _ = stream.write_all(&script);
+        let _ = stream.shutdown(std::net::Shutdown::Write);
+        let mut replies = Vec::new();
+        let _ = stream.read_to_end(&mut replies);
+        prop_assert_eq!(PANICS.load(Ordering::SeqCst), 0);
+    }
+}
+
+#[test]
+fn test_short_greetings(){
+    count_panics();
+    let port = start_server();
+    for greeting in ["EHLO\r\n", "EHLO \r\n", "E\r\n", "\r\n", "\u{e9}\u{e9}\u{e9}\r\n"] {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(greeting.as_bytes()).unwrap();
+        stream.write_all(b"MAIL FROM:<a@b.test>\r\nQUIT\r\n").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        assert!(replies.starts_with("220\r\n"), "{:?}", replies);
+    }
+    assert_eq!(PANICS.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn test_long_line(){
+    let port = start_server();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(b"EHLO client.test\r\n").unwrap();
+    // A line that never ends is cut off rather than buffered for ever.
+    stream.write_all(format!("NOOP {}", "a".repeat(5000)).as_bytes()).unwrap();
+    let mut replies = Vec::new();
+    let _ = stream.read_to_end(&mut replies);
+    let replies = String::from_utf8_lossy(&replies);
+    assert!(replies.ends_with("\r\n500 5.5.2 Line too long\r\n"), "{:?}", replies);
+}
+
+#[test]
+fn test_field_end(){
+    assert_eq!(headers::field_end(b"Subject: a\r\nTo: b\r\n"), Some(12));
+    assert_eq!(headers::field_end(b"Subject: a\r\n b\r\n\tc\r\n\r\n"), Some(20));
+    assert_eq!(headers::field_end(b"\r\nbody"), Some(2));
+    // The next line may still turn out to be a continuation.
+    assert_eq!(headers::field_end(b"Subject: a\r\n"), None);
+    assert_eq!(headers::field_end(b"Subject"), None);
+    assert_eq!(headers::field_end(b""), None);
+}
@@ -0,0 +1,320 @@
+mod common;
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use smtp_server::client::{self, Submission};
+use smtp_server::settings::{Backend, Protocol, Server, Settings};
+use smtp_server::trace;
+
+fn local(server: &Server, listener: usize) -> SocketAddr {
+    server.addrs[listener].parse().unwrap()
+}
+
+fn greeting(addr: SocketAddr) -> String {
+    let mut line = String::new();
+    BufReader::new(TcpStream::connect(addr).unwrap()).read_line(&mut line).unwrap();
+    line.trim_end().to_string()
+}
+
+#[test]
+fn test_parse() {
+    let settings = Settings::parse("
+        hostname = \"mx.sink.test\"
+
+        [[listener]]
+        address = \"127.0.0.1:25\"
+
+        [[listener]]
+        path = \"/run/smtp/lmtp.sock\"
+        protocol = \"lmtp\"
+
+        [storage]
+        backend = \"memory\"
+
+        [directory]
+        users = [\"qa@sink.test\"]
+        lists = { \"team@sink.test\" = [\"qa@sink.test\", \"ops@sink.test\"] }
+
+        [limits]
+        max_message_size = 1000
+    ").unwrap();
+    assert_eq!(settings.hostname, "mx.sink.test");
+    assert_eq!(settings.listeners.len(), 2);
+    assert_eq!(settings.listeners[1].protocol, Protocol::Lmtp);
+    assert_eq!(settings.listeners[1].path, Some(PathBuf::from("/run/smtp/lmtp.sock")));
+    assert_eq!(settings.storage.backend, Backend::Memory);
+    assert_eq!(settings.directory.lists["team@sink.test"], ["qa@sink.test", "ops@sink.test"]);
+    assert_eq!(settings.limits.max_message_size, Some(1000));
+    assert_eq!(settings.limits.max_hops, trace::DEFAULT_MAX_HOPS);
+
+    assert_eq!(Settings::default().listeners[0].address.as_deref(), Some("0.0.0.0:2525"));
+
+    for (text, error) in [
+        ("hostname = \"a\"", "no [[listener]]"),
+        ("[[listener]]\naddress = \":25\"\nport = 25", "*unknown field `port`*"),
+        ("[[listener]]\naddress = \":25\"\nprotocol = \"smtps\"", "*unknown variant `smtps`*"),
+        ("[[listener]]", "listener 1: needs an address or a path, not both"),
+        ("[[listener]]\npath = \"/tmp/pop3\"\nprotocol = \"pop3\"", "listener 1: only smtp and lmtp can listen on a Unix socket"),
+        ("[[listener]]\naddress = \":110\"\nprotocol = \"pop3\"", "listener 1: pop3 and imap need storage.mailboxes"),
+        ("[[listener]]\naddress = \":587\"\nprotocol = \"submission\"", "listener 1: pop3, imap and submission need [auth] users"),
+        ("[[listener]]\naddress = \":25\"\n[storage]\nbackend = \"directory\"", "storage: the directory backend needs a path"),
+    ] {
+        let message = Settings::parse(text).unwrap_err().to_string();
+        assert!(common::matches(error, &message), "{}", message);
+    }
+}
+
+#[test]
+fn test_check() {
+    let spool = common::temp_dir("settings_check");
+    let settings = Settings::parse(&format!("
+        [[listener]]
+        address = \"127.0.0.1:0\"
+        protocol = \"submission\"
+
+        [tls]
+        cert = \"{}\"
+        key = \"{}\"
+
+        [auth]
+        users = {{ qa = \"secret\" }}
+
+        [storage]
+        backend = \"directory\"
+        path = \"{}\"
+
+        [policy.dkim]
+        domain = \"sink.test\"
+        selector = \"s1\"
+        key = \"{}\"
+    ", common::fixture("tls_cert.pem"), common::fixture("tls_key.pem"), spool.display(), common::fixture("dkim_rsa.pem"))).unwrap();
+    settings.check().unwrap();
+    // Checking creates nothing.
+    assert!(!spool.exists());
+
+    let mut broken = settings.clone();
+    broken.tls.as_mut().unwrap().cert = PathBuf::from("/nonexistent/cert.pem");
+    let message = broken.check().unwrap_err().to_string();
+    assert!(message.starts_with("tls: "), "{}", message);
+
+    let mut broken = settings.clone();
+    broken.policy.spam_rules = Some(PathBuf::from("/nonexistent/spam.rules"));
+    let message = broken.check().unwrap_err().to_string();
+    assert!(message.starts_with("policy.spam_rules /nonexistent/spam.rules: "), "{}", message);
+
+    let mut broken = settings.clone();
+    broken.storage.path = Some(PathBuf::from(common::fixture("tls_cert.pem")));
+    let message = broken.check().unwrap_err().to_string();
+    assert!(message.starts_with("storage.path: "), "{}", message);
+
+    let missing = Settings::load("/nonexistent/smtp.toml").unwrap_err().to_string();
+    assert!(missing.starts_with("/nonexistent/smtp.toml: "), "{}", missing);
+}
+
+#[test]
+fn test_start_and_send() {
+    let settings = Settings::parse("
+        hostname = \"mx.sink.test\"
+
+        [[listener]]
+        address = \"127.0.0.1:0\"
+
+        [[listener]]
+        address = \"127.0.0.1:0\"
+        protocol = \"submission\"
+
+        [auth]
+        users = { \"qa@sink.test\" = \"secret\" }
+
+        [storage]
+        backend = \"memory\"
+    ").unwrap();
+    let server = Server::start(&settings).unwrap();
+    let store = server.shared().store.clone().unwrap();
+    let to = vec!["ops@sink.test".to_string()];
+    let submission = Submission {
+        helo: "client.test".to_string(),
+        from: "qa@sink.test".to_string(),
+        to: to.clone(),
+        content: client::compose("qa@sink.test", &to, "From the client", ".leading dot\nsecond line"),
+        auth: None,
+    };
+    let reply = client::send(local(&server, 0), &submission).unwrap();
+    assert!(reply.starts_with("250 OK queued as"), "{}", reply);
+    let stored = &store.list().unwrap()[0];
+    assert_eq!(stored.header("Subject"), "From the client");
+    assert!(stored.text().contains(".leading dot\r\nsecond line"), "{:?}", stored.text());
+
+    // The submission port wants a login.
+    let refused = client::send(local(&server, 1), &submission).unwrap_err().to_string();
+    assert_eq!(refused, "server replied: 530 5.7.0 Authentication required");
+    let submission = Submission { auth: Some(("qa@sink.test".to_string(), "secret".to_string())), ..submission };
+    assert!(client::send(local(&server, 1), &submission).unwrap().starts_with("250"));
+    assert_eq!(store.list().unwrap().len(), 2);
+}
+
+const EHLO_MX: &str = "
+    S: 220*
+    C: EHLO client.test
+    S: 250-mx.sink.test*
+";
+
+const EHLO_MX2: &str = "
+    S: 220*
+    C: EHLO client.test
+    S: 250-mx2.sink.test*
+";
+
+#[test]
+fn test_reload() {
+    let text = "
+        hostname = \"mx.sink.test\"
+
+        [[listener]]
+        address = \"127.0.0.1:0\"
+    ";
+    let mut server = Server::start(&Settings::parse(text).unwrap()).unwrap();
+    let addr = local(&server, 0);
+    common::run(addr, EHLO_MX);
+
+    let changed = Settings::parse(&text.replace("mx.sink.test", "mx2.sink.test")).unwrap();
+    server.reload(&changed).unwrap();
+    common::run(addr, EHLO_MX2);
+
+    // A reload that cannot be applied changes nothing.
+    let mut broken = Settings::parse(&text.replace("mx.sink.test", "mx3.sink.test")).unwrap();
+    broken.policy.rewrite_table = Some(PathBuf::from("/nonexistent/aliases"));
+    assert!(server.reload(&broken).is_err());
+    common::run(addr, EHLO_MX2);
+}
+
+#[test]
+fn test_limits() {
+    let mut server = Server::start(&Settings::parse("
+        [[listener]]
+        address = \"127.0.0.1:0\"
+
+        [limits]
+        max_message_size = 100
+    ").unwrap()).unwrap();
+    let addr = local(&server, 0);
+    common::run(addr, "
+        S: 220*
+        C: EHLO client.test
+        S: 250-*250 SIZE 100
+        C: MAIL FROM:<app@client.test> SIZE=101
+        S: 552 5.3.4 Message size exceeds fixed maximum message size
+        C: MAIL FROM:<app@client.test> SIZE=big
+        S: 501 5.5.4 SIZE must be a number
+        C: MAIL FROM:<app@client.test> SIZE=50
+        S: 250*
+        C: RCPT TO:<qa@sink.test>
+        S: 250*
+        C: DATA
+        S: 354*
+        C: Subject: longer than declared
+        C:
+        C: 0123456789012345678901234567890123456789012345678901234567890123456789
+        C: .
+        S: 552 5.3.4 Message size exceeds fixed maximum message size
+    ");
+
+    let mut settings = Settings::parse("[[listener]]\naddress = \"127.0.0.1:0\"").unwrap();
+    settings.limits.timeout = Some(1);
+    server.reload(&settings).unwrap();
+    common::run(addr, "
+        S: 220*
+        S: <closed>
+    ");
+}
+
+#[test]
+fn test_pop3_listener() {
+    let mailboxes = common::temp_dir("settings_pop3");
+    let server = Server::start(&Settings::parse(&format!("
+        hostname = \"mx.sink.test\"
+
+        [[listener]]
+        address = \"127.0.0.1:0\"
+        protocol = \"pop3\"
+
+        [[listener]]
+        address = \"127.0.0.1:0\"
+        protocol = \"imap\"
+
+        [auth]
+        users = {{ qa = \"secret\" }}
+
+        [storage]
+        mailboxes = \"{}\"
+    ", mailboxes.display())).unwrap()).unwrap();
+    assert!(greeting(local(&server, 0)).starts_with("+OK POP3 mx.sink.test ready"));
+    assert!(greeting(local(&server, 1)).starts_with("* OK"));
+    fs::remove_dir_all(&mailboxes).unwrap();
+}
+
+#[test]
+fn test_reload_greylist() {
+    let text = "
+        [[listener]]
+        address = \"127.0.0.1:0\"
+    ";
+    let mut server = Server::start(&Settings::parse(text).unwrap()).unwrap();
+    let addr = local(&server, 0);
+    let send = |rcpt_reply: &str| format!("
+        S: 220*
+        C: EHLO client.test
+        S: 250-*
+        C: MAIL FROM:<app@client.test>
+        S: 250*
+        C: RCPT TO:<qa@sink.test>
+        S: {}
+    ", rcpt_reply);
+    common::run(addr, &send("250 2.1.5 Recipient OK"));
+
+    let mut settings = Settings::parse(text).unwrap();
+    settings.policy.greylist = true;
+    settings.policy.greylist_delay = Some(60);
+    server.reload(&settings).unwrap();
+    assert!(server.shared().greylist.as_ref().is_some_and(|greylist| greylist.delay.as_secs() == 60));
+    common::run(addr, &send("451 4.7.1 Greylisted, please try again in 60 seconds"));
+
+    // Unrelated changes keep the greylist and what it has seen.
+    let greylist = server.shared().greylist.clone().unwrap();
+    settings.hostname = "mx2.sink.test".to_string();
+    server.reload(&settings).unwrap();
+    assert!(Arc::ptr_eq(&greylist, server.shared().greylist.as_ref().unwrap()));
+
+    server.reload(&Settings::parse(text).unwrap()).unwrap();
+    assert!(server.shared().greylist.is_none());
+    common::run(addr, &send("250 2.1.5 Recipient OK"));
+}
+
+#[test]
+fn test_reload_sieve() {
+    let scripts = common::temp_dir("settings_sieve");
+    fs::create_dir_all(scripts.join("other")).unwrap();
+    let mut settings = Settings::parse("
+        [[listener]]
+        address = \"127.0.0.1:0\"
+    ").unwrap();
+    settings.policy.sieve_dir = Some(scripts.clone());
+    let mut server = Server::start(&settings).unwrap();
+
+    // Unrelated changes keep the scripts and whom vacation has answered.
+    let sieve = server.shared().sieve.clone().unwrap();
+    settings.hostname = "mx2.sink.test".to_string();
+    server.reload(&settings).unwrap();
+    assert!(Arc::ptr_eq(&sieve, server.shared().sieve.as_ref().unwrap()));
+
+    settings.policy.sieve_dir = Some(scripts.join("other"));
+    server.reload(&settings).unwrap();
+    assert!(!Arc::ptr_eq(&sieve, server.shared().sieve.as_ref().unwrap()));
+
+    settings.policy.sieve_dir = Some(scripts.join("missing"));
+    assert!(server.reload(&settings).is_err());
+    fs::remove_dir_all(scripts).unwrap();
+}
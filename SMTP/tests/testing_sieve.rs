@@ -0,0 +1,274 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+use smtp_server::address;
+use smtp_server::server::{Config, Message};
+use smtp_server::sieve::{self, Action, MemoryScripts, Script, ScriptDir, Sieve, Vacation};
+use smtp_server::store::{MemoryStore, MessageStore};
+
+fn message(sender: &str, content: &str) -> Message {
+    let mut msg = Message::new("client.test");
+    msg.reverse_path = Some(address::parse_reverse_path(&format!("<{}>", sender)).unwrap().0);
+    msg.set_content(content.as_bytes());
+    msg
+}
+
+const MAIL: &str = "From: \"Ann, A.\" <ann@Lists.Example.org>\r\n\
+    To: qa@sink.test, Ops <ops@sink.test>\r\n\
+    Subject: =?utf-8?q?Weekly_report?=\r\n\
+    Message-ID: <1@client.test>\r\n\
+    \r\n\
+    Numbers inside.\r\n";
+
+fn run(script: &str, msg: &Message) -> Vec<Action> {
+    Script::parse(script).unwrap().evaluate(msg, "qa@sink.test")
+}
+
+fn error(script: &str) -> String {
+    Script::parse(script).unwrap_err().to_string()
+}
+
+#[test]
+fn test_tests(){
+    let msg = message("ann@lists.example.org", MAIL);
+    let filed = |test: &str| run(&format!("require [\"fileinto\", \"envelope\"]; if {} {{ fileinto \"Hit\"; }}", test), &msg)
+        == [Action::FileInto("Hit".to_string())];
+
+    assert!(filed(r#"header :contains "subject" "weekly""#));
+    assert!(!filed(r#"header :contains :comparator "i;octet" "subject" "weekly""#));
+    assert!(filed(r#"header :is "Subject" ["other", "Weekly report"]"#));
+    assert!(filed(r#"header :matches "subject" "W??kly *""#));
+    assert!(!filed(r#"header :matches "subject" "report*""#));
+    assert!(filed(r#"address :domain "from" "lists.example.org""#));
+    assert!(filed(r#"address :localpart :is "to" "ops""#));
+    assert!(filed(r#"address :all :is ["to", "cc"] "qa@sink.test""#));
+    assert!(!filed(r#"address :is "from" "ann""#));
+    assert!(filed(r#"envelope :domain "from" "lists.example.org""#));
+    assert!(filed(r#"envelope "to" "QA@sink.test""#));
+    assert!(filed(r#"exists ["From", "Message-ID"]"#));
+    assert!(!filed(r#"exists ["From", "Cc"]"#));
+    assert!(filed("size :over 100"));
+    assert!(filed("size :under 1K"));
+    assert!(!filed("size :under 100"));
+    assert!(filed(r#"allof (true, not exists "Cc")"#));
+    assert!(!filed("anyof (false, not true)"));
+    assert!(!filed(r#"header :matches "message-id" "<\\*1@*""#));
+    assert!(filed(r#"header :matches "message-id" "\<1@*""#));
+}
+
+#[test]
+fn test_actions(){
+    let msg = message("ann@lists.example.org", MAIL);
+    // Nothing decided means keep.
+    assert_eq!(run("if false { discard; }", &msg), [Action::Keep]);
+    assert_eq!(run("discard;", &msg), [Action::Discard]);
+    assert_eq!(run("redirect \"pager@sink.test\"; keep; keep;", &msg), [Action::Redirect("pager@sink.test".to_string()), Action::Keep]);
+    assert_eq!(run("require \"fileinto\";\nif header :contains \"subject\" \"report\" {\n  fileinto \"Reports\";\n  stop;\n}\nfileinto \"Other\";", &msg), [Action::FileInto("Reports".to_string())]);
+    assert_eq!(run("if false { keep; } elsif true { discard; } else { keep; }", &msg), [Action::Discard]);
+    assert_eq!(run("if false { keep; } elsif false { discard; } else { redirect \"a@b.test\"; }", &msg), [Action::Redirect("a@b.test".to_string())]);
+    assert_eq!(run("require \"reject\"; reject text:\r\nNot here.\r\n..dot\r\n.\r\n;", &msg), [Action::Reject("Not here.\r\n.dot\r\n".to_string())]);
+    // Rejecting and keeping at once is an error, so the message is kept.
+    assert_eq!(run("require \"reject\"; reject \"no\"; keep;", &msg), [Action::Keep]);
+
+    let actions = run("require \"vacation\"; vacation :days 0 :subject \"Away\" :addresses [\"q@sink.test\"] :handle \"h\" \"Back soon.\";", &msg);
+    assert_eq!(actions, [
+        Action::Vacation(Vacation {
+            days: 1,
+            subject: Some("Away".to_string()),
+            from: None,
+            addresses: vec!["q@sink.test".to_string()],
+            mime: false,
+            handle: Some("h".to_string()),
+            reason: "Back soon.".to_string(),
+        }),
+        Action::Keep,
+    ]);
+    // The site caps how long a sender goes unanswered.
+    let actions = run("require \"vacation\"; vacation :days 18446744073709551615 \"Gone.\";", &msg);
+    assert!(matches!(&actions[0], Action::Vacation(v) if v.days == sieve::MAX_VACATION_DAYS), "{:?}", actions);
+}
+
+#[test]
+fn test_syntax_errors(){
+    assert_eq!(error("fileinto \"x\";"), "sieve line 1: fileinto needs require \"fileinto\"");
+    assert_eq!(error("require \"imap4flags\";"), "sieve line 1: unsupported extension \"imap4flags\"");
+    assert_eq!(error("keep;\nrequire \"fileinto\";"), "sieve line 2: require must come first");
+    assert_eq!(error("\n\nfrobnicate;"), "sieve line 3: unknown command \"frobnicate\"");
+    assert_eq!(error("elsif true { keep; }"), "sieve line 1: elsif without if");
+    assert_eq!(error("if true;"), "sieve line 1: if needs a block");
+    assert_eq!(error("if header :frob \"a\" \"b\" { keep; }"), "sieve line 1: unknown tag :frob");
+    assert_eq!(error("if header :is :contains \"a\" \"b\" { keep; }"), "sieve line 1: more than one match type");
+    assert_eq!(error("if header :domain \"a\" \"b\" { keep; }"), "sieve line 1: unknown tag :domain");
+    assert_eq!(error("if envelope \"to\" \"b\" { keep; }"), "sieve line 1: envelope needs require \"envelope\"");
+    assert_eq!(error("keep"), "sieve line 1: expected ';'");
+    assert_eq!(error("redirect \"x\n"), "sieve line 1: unterminated string");
+    assert_eq!(error("/* never closed"), "sieve line 1: unterminated comment");
+    assert_eq!(error("keep; }"), "sieve line 1: unexpected '}'");
+    assert_eq!(error("redirect [\"a@b.test\", \"c@d.test\"];"), "sieve line 1: expected a single string");
+    // Comments and quantified numbers are fine.
+    assert!(Script::parse("# hash\n/* block\ncomment */ if size :over 1M { discard; }").is_ok());
+}
+
+#[test]
+fn test_vacation_replies(){
+    let clock = common::ManualClock::new();
+    let mut sieve = Sieve::new(Arc::new(MemoryScripts::new()));
+    sieve.clock = clock.clone();
+    let vacation = Vacation {
+        days: 2,
+        subject: None,
+        from: None,
+        addresses: vec!["ops@sink.test".to_string()],
+        mime: false,
+        handle: None,
+        reason: "Away until Monday.".to_string(),
+    };
+    let msg = message("ann@lists.example.org", MAIL);
+
+    let reply = String::from_utf8(sieve.vacation_reply(&msg, "qa@sink.test", &vacation, "mx.sink.test").unwrap()).unwrap();
+    assert!(reply.starts_with("From: <qa@sink.test>\r\nTo: <ann@lists.example.org>\r\nSubject: Auto: =?utf-8?q?Weekly_report?=\r\n"), "{}", reply);
+    assert!(reply.contains("\r\nIn-Reply-To: <1@client.test>\r\nReferences: <1@client.test>\r\nAuto-Submitted: auto-replied (vacation)\r\n"), "{}", reply);
+    assert!(reply.ends_with("\r\n\r\nAway until Monday.\r\n"), "{}", reply);
+
+    // Once per sender every `days`.
+    assert!(sieve.vacation_reply(&msg, "qa@sink.test", &vacation, "mx.sink.test").is_none());
+    clock.advance(Duration::from_secs(2 * 24 * 3600));
+    assert!(sieve.vacation_reply(&msg, "qa@sink.test", &vacation, "mx.sink.test").is_some());
+    // Listed in :addresses.
+    assert!(sieve.vacation_reply(&msg, "ops@sink.test", &vacation, "mx.sink.test").is_some());
+
+    let quiet = |sender: &str, content: &str, recipient: &str| {
+        sieve.vacation_reply(&message(sender, content), recipient, &vacation, "mx.sink.test").is_none()
+    };
+    assert!(quiet("MAILER-DAEMON@client.test", MAIL, "qa@sink.test"));
+    assert!(quiet("owner-staff@client.test", MAIL, "qa@sink.test"));
+    assert!(quiet("bob@client.test", "To: qa@sink.test\r\nAuto-Submitted: auto-generated\r\n\r\n", "qa@sink.test"));
+    assert!(quiet("bob@client.test", "To: qa@sink.test\r\nPrecedence: bulk\r\n\r\n", "qa@sink.test"));
+    assert!(quiet("bob@client.test", "To: qa@sink.test\r\nList-Id: <staff.client.test>\r\n\r\n", "qa@sink.test"));
+    // Only Bcc'd through a list, so not addressed to the recipient.
+    assert!(quiet("bob@client.test", "To: staff@client.test\r\n\r\n", "qa@sink.test"));
+    assert!(!quiet("bob@client.test", "To: staff@client.test\r\nCc: <QA@sink.test>\r\n\r\n", "qa@sink.test"));
+}
+
+#[test]
+fn test_script_dir(){
+    let dir = std::env::temp_dir().join(format!("smtp_sieve_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("qa@sink.test.sieve"), "discard;").unwrap();
+    std::fs::write(dir.join("ops@sink.test.sieve"), "discard").unwrap();
+    let scripts = Sieve::new(Arc::new(ScriptDir { dir: dir.clone() }));
+    let msg = message("ann@lists.example.org", MAIL);
+    assert_eq!(scripts.run(&msg, "QA@sink.test").unwrap(), [Action::Discard]);
+    assert_eq!(scripts.run(&msg, "nobody@sink.test").unwrap(), [Action::Keep]);
+    assert!(scripts.run(&msg, "ops@sink.test").is_err());
+    assert_eq!(scripts.run(&msg, "../qa@sink.test").unwrap(), [Action::Keep]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_delivery(){
+    let mut scripts = MemoryScripts::new();
+    scripts.add("qa@sink.test", Script::parse("require \"fileinto\"; if header :contains \"subject\" \"report\" { fileinto \"Reports\"; }").unwrap());
+    scripts.add("ops@sink.test", Script::parse("require \"vacation\"; redirect \"pager@sink.test\"; vacation \"On leave.\";").unwrap());
+    scripts.add("gone@sink.test", Script::parse("require \"reject\"; reject \"I have left the company.\";").unwrap());
+    scripts.add("trash@sink.test", Script::parse("discard;").unwrap());
+    let store = Arc::new(MemoryStore::new());
+    let addr = common::start(Config {
+        sieve: Some(Arc::new(Sieve::new(Arc::new(scripts)))),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<ann@client.test>
+        C: RCPT TO:<qa@sink.test>
+        C: RCPT TO:<ops@sink.test>
+        C: RCPT TO:<gone@sink.test>
+        C: RCPT TO:<trash@sink.test>
+        C: RCPT TO:<new@sink.test>
+        C: DATA
+        S: 250 2.1.0 *
+        S: 250 2.1.5 *
+        S: 250 2.1.5 *
+        S: 250 2.1.5 *
+        S: 250 2.1.5 *
+        S: 250 2.1.5 *
+        S: 354
+        C: To: qa@sink.test, ops@sink.test
+        C: Subject: Weekly report
+        C:
+        C: Numbers inside.
+        C: .
+        S: 250 OK queued as *
+        C: MAIL FROM:<ann@client.test>
+        C: RCPT TO:<gone@sink.test>
+        C: DATA
+        S: 250 2.1.0 *
+        S: 250 2.1.5 *
+        S: 354
+        C: Subject: Hello?
+        C:
+        C: .
+        S: 550 5.7.1 I have left the company.
+        C: QUIT
+        S: 221 *
+    ");
+
+    let stored = store.list().unwrap();
+    let find = |to: &str| stored.iter().filter(|m| m.recipients == [to]).collect::<Vec<_>>();
+    assert_eq!(stored.len(), 5, "{:?}", stored.iter().map(|m| &m.recipients).collect::<Vec<_>>());
+    assert_eq!(find("qa@sink.test")[0].folder.as_deref(), Some("Reports"));
+    assert_eq!(find("new@sink.test")[0].folder, None);
+    let redirected = find("pager@sink.test");
+    assert_eq!((redirected[0].mail_from.as_str(), redirected[0].header("Subject")), ("ann@client.test", "Weekly report".to_string()));
+    // The vacation reply and the rejection notice both go back to the sender.
+    let replies = find("ann@client.test");
+    assert_eq!(replies.len(), 2);
+    assert!(replies.iter().all(|m| m.mail_from.is_empty()));
+    assert!(replies.iter().any(|m| m.header("Subject") == "Auto: Weekly report" && m.text().ends_with("On leave.\r\n")));
+    assert!(replies.iter().any(|m| m.header("Subject") == "Rejected: Weekly report" && m.text().contains("I have left the company.")));
+}
+
+#[test]
+fn test_lmtp_reject_per_recipient(){
+    let mut scripts = MemoryScripts::new();
+    scripts.add("gone@sink.test", Script::parse("require \"reject\"; reject \"I have left the company.\";").unwrap());
+    let store = Arc::new(MemoryStore::new());
+    let addr = common::start(Config {
+        lmtp: true,
+        sieve: Some(Arc::new(Sieve::new(Arc::new(scripts)))),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220*
+        C: LHLO client.test
+        S: 250-*
+        C: MAIL FROM:<ann@client.test>
+        S: 250*
+        C: RCPT TO:<qa@sink.test>
+        S: 250*
+        C: RCPT TO:<gone@sink.test>
+        S: 250*
+        C: RCPT TO:<ops@sink.test>
+        S: 250*
+        C: DATA
+        S: 354*
+        C: Subject: Weekly report
+        C:
+        C: Numbers inside.
+        C: .
+        S: 250 OK queued as *
+        S: 550 5.7.1 I have left the company.
+        S: 250 OK queued as *
+    ");
+
+    // The LMTP client bounces the refused recipient, so no notice is sent here.
+    let stored = store.list().unwrap();
+    let mut recipients: Vec<_> = stored.iter().map(|m| m.recipients.clone()).collect();
+    recipients.sort();
+    assert_eq!(recipients, [["ops@sink.test"], ["qa@sink.test"]]);
+}
@@ -1,5 +1,5 @@
-use std::net::TcpStream;
-use std::io::{Read,Write,Result};
+mod common;
+
 use std::thread;
 use std::time::Duration;
 use smtp_server::server;
@@ -11,56 +11,175 @@ fn start_server(){
     thread::sleep(Duration::from_secs(1));
 }
 
-fn send_message(msg: &str, stream: &mut TcpStream) -> Result<String> {
-    
-    stream.write_all(msg.as_bytes())?;
+#[test]
+fn test_smtp_server(){
+    start_server();
+    common::run("127.0.0.1:2525".parse().unwrap(), "
+        S: 220*
+        C: EHLO example.com
+        S: 250-localhost*
+        # MAIL FROM and RCPT TO are each answered before DATA.
+        C: MAIL FROM:<sender@example.com>
+        C: RCPT TO:<recipient@example.com>
+        C: DATA
+        S: 250 2.1.0 *
+        S: 250 2.1.5 *
+        S: 354*
+    ");
+}
 
-    let mut buffer = [0; 1024];
-    let n = stream.read(&mut buffer)?;
-    Ok(String::from_utf8_lossy(&buffer[..n]).to_string())
+#[test]
+fn test_greetings(){
+    let addr = common::start(common::offline_config());
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250-mx.sink.test250-8BITMIME250-BINARYMIME250-CHUNKING250-DSN250-PIPELINING250 SMTPUTF8
+        C: HELO client.test
+        S: 250 mx.sink.test
+        C: LHLO client.test
+        S: 500 5.5.1 *
+        C: QUIT
+        S: 221 2.0.0 mx.sink.test closing connection
+        S: <closed>
+    ");
+    common::run(addr, "
+        S: 220
+        C: HELO client.test
+        S: 250 mx.sink.test
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 *
+    ");
+    // Anything but a greeting first ends the session.
+    common::run(addr, "
+        S: 220
+        C: MAIL FROM:<app@client.test>
+        S: <closed>
+    ");
 }
 
 #[test]
-fn test_smtp_server(){
-    start_server();
-    let mut stream = TcpStream::connect("127.0.0.1:2525").expect("Could not connect to server");
+fn test_invalid_greetings(){
+    let addr = common::start(common::offline_config());
+    common::run(addr, "
+        S: 220
+        C: EHLO
+        S: 501 5.5.4 Invalid domain name
+        C: EHLO client.test Bcc: victim@example.com
+        S: 501 5.5.4 Invalid domain name
+        C: HELO -client.test
+        S: 501 5.5.4 Invalid domain name
+        C: EHLO [300.1.1.1]
+        S: 501 5.5.4 Invalid domain name
+        C: EHLO [192.0.2.1]
+        S: 250-mx.sink.test*
+        C: HELO bücher.example
+        S: 250 mx.sink.test
+        C: EHLO client..test
+        S: 501 5.5.4 Invalid domain name
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 *
+    ");
+}
 
-    // //check connection
-    match send_message("",&mut stream) {
-        Ok(response) => {
-            assert!(response.starts_with("220"));
-            println!("Response: {}", response);
-        },
-        Err(e) => {
-            panic!("Failed to send message {}", e);
-        },
-    }
+#[test]
+fn test_transaction_commands(){
+    let addr = common::start(common::offline_config());
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: NOOP
+        S: 250 2.0.0 OK
+        C: noop anything
+        S: 250 2.0.0 OK
+        C: HELP
+        S: 214 2.0.0 Commands:*RSET*
+        C: VRFY qa
+        S: 252 *
+        C: EXPN staff
+        S: 252 *
+        C: RCPT TO:<qa@sink.test>
+        S: 503 5.5.1 MAIL FROM first
+        C: DATA
+        S: 503 5.5.1 RCPT TO first
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 Sender OK
+        C: MAIL FROM:<other@client.test>
+        S: 503 5.5.1 Sender already specified
+        C: RCPT TO:<qa@sink.test>
+        S: 250 2.1.5 Recipient OK
+        C: RSET
+        S: 250 2.0.0 OK
+        C: RCPT TO:<qa@sink.test>
+        S: 503 5.5.1 MAIL FROM first
+        C: MAIL FROM:<app@client.test>
+        S: 250 2.1.0 *
+        # A new greeting also abandons the transaction.
+        C: EHLO client.test
+        S: 250*
+        C: DATA
+        S: 503 5.5.1 RCPT TO first
+        C: FROB
+        S: 500 5.5.2 Command not recognized
+        C: DATA now
+        S: 500 5.5.2 Command not recognized
+        C:
+        S: 500 5.5.2 Empty command
+        C: QUIT
+        S: 221 *
+        S: <closed>
+    ");
+}
 
-//    check EHLO
-    match send_message("EHLO example.com\r\n", &mut stream) {
-        Ok(response) => {
-            assert!(response.starts_with("250"));
-            println!("Response: {}", response);
-        },
-        Err(e) => {
-            panic!("Failed to send message {}", e);
-        },
-    }
-    // println!("DONE WITH EHLO TEST");
-    // send_message("FROM:<sender@example.com>\r\n", &mut stream).expect("Couldn't send MAIL");
-    // println!("SENT MAIL");
-    // send_message("TO:<recipient@example.com>\r\n", &mut stream).expect("Couldn't send RCPT");
-    // println!("SENT RCPT");
-//    check for Data
-    match send_message("MAIL FROM:<sender@example.com>\r\nRCPT TO:<recipient@example.com>\r\nDATA\r\n", &mut stream) {
-        Ok(response) => {
-            assert!(response.starts_with("354"));
-            println!("Response: {}", response);
-        },
-        Err(e) => {
-            panic!("Failed to send message {}", e);
-        },
-    }
+#[test]
+fn test_several_messages_per_session(){
+    let addr = common::start(common::offline_config());
+    common::run(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<app@client.test>
+        C: RCPT TO:<qa@sink.test>
+        C: DATA
+        S: 250 2.1.0 *
+        S: 250 2.1.5 *
+        S: 354
+        C: Subject: first
+        C:
+        C: ..leading dot
+        C: .
+        S: 250 OK queued as *
+        C: MAIL FROM:<>
+        S: 250 2.1.0 *
+        C: RCPT TO:<Postmaster>
+        S: 250 2.1.5 *
+        C: DATA
+        S: 354
+        C: Subject: second
+        C:
+        C: .
+        S: 250 OK queued as *
+        C: QUIT
+        S: 221 *
+    ");
 }
 
+#[test]
+fn test_mismatch_diff(){
+    let addr = common::start(common::offline_config());
+    let diff = common::check(addr, "
+        S: 220
+        C: EHLO client.test
+        S: 250*
+        C: MAIL FROM:<not an address>
+        S: 250 2.1.0 *
+    ").unwrap_err();
+    assert!(diff.starts_with("  S: 220\n  C: EHLO client.test\n  S: 250-mx.sink.test\n  S: 250-8BITMIME\n"), "{}", diff);
+    assert!(diff.ends_with("  C: MAIL FROM:<not an address>\n- S: 250 2.1.0 *\n+ S: 501 5.1.7 Syntax error in sender address: missing domain\n"), "{}", diff);
 
+    assert!(common::matches("250 2.1.? *", "250 2.1.5 Recipient OK"));
+    assert!(common::matches("*SMTPUTF8", "250-a250 SMTPUTF8"));
+    assert!(!common::matches("250 *", "550 no"));
+    assert!(!common::matches("220", "220 ready"));
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use std::io::{BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use smtp_server::dns::StaticResolver;
+use smtp_server::handler::{MessageHandler, Verdict};
+use smtp_server::idna;
+use smtp_server::server::{self, Config, Message};
+
+/// Keeps the envelope and trace header of every message it is given.
+struct Capture(Mutex<Vec<(Vec<String>, bool, String)>>);
+
+impl MessageHandler for Capture {
+    fn handle(&self, msg: &mut Message) -> Verdict {
+        let received = msg.headers.get("Received").unwrap_or_default().to_string();
+        self.0.lock().unwrap().push((msg.recipients(), msg.smtputf8, received));
+        Verdict::Accept
+    }
+}
+
+fn start_server(capture: Arc<Capture>) -> u16 {
+    let config = Config {
+        hostname: "mx.sink.test".to_string(),
+        resolver: Arc::new(StaticResolver::new()),
+        handler: capture,
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+    port
+}
+
+fn connect(port: u16) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    assert!(common::read_reply(&mut reader).starts_with("220"));
+    (stream, reader)
+}
+
+#[test]
+fn test_punycode(){
+    assert_eq!(idna::punycode_encode("bücher").unwrap(), "bcher-kva");
+    assert_eq!(idna::punycode_encode("他们为什么不说中文").unwrap(), "ihqwcrb4cv8a8dqg056pqjye");
+    assert_eq!(idna::punycode_decode("ihqwcrb4cv8a8dqg056pqjye").unwrap(), "他们为什么不说中文");
+    assert!(idna::punycode_decode("a-!").is_err());
+
+    assert_eq!(idna::to_ascii("Bücher.Example.").unwrap(), "xn--bcher-kva.example");
+    assert_eq!(idna::to_unicode("xn--bcher-kva.example"), "bücher.example");
+    assert_eq!(idna::to_unicode(&idna::to_ascii("例子。广告").unwrap()), "例子.广告");
+    assert!(idna::to_ascii("a..example").is_err());
+
+    assert_eq!(server::normalize_address("Jörg@Bücher.Example").unwrap(), "Jörg@xn--bcher-kva.example");
+    assert_eq!(server::normalize_address("").unwrap(), "");
+    assert!(server::normalize_address("nobody").is_err());
+}
+
+#[test]
+fn test_utf8_envelope_accepted(){
+    let capture = Arc::new(Capture(Mutex::new(Vec::new())));
+    let port = start_server(capture.clone());
+    let (mut writer, mut reader) = connect(port);
+
+    writer.write_all(b"EHLO client.test\r\n").unwrap();
+    let ehlo = common::read_reply(&mut reader);
+    assert!(ehlo.starts_with("250-mx.sink.test\r\n"), "{}", ehlo);
+    assert!(ehlo.contains("250-8BITMIME\r\n") && ehlo.ends_with("250 SMTPUTF8\r\n"), "{}", ehlo);
+
+    writer.write_all("MAIL FROM:<jörg@bücher.example> SMTPUTF8\r\nRCPT TO:<δοκιμή@Bücher.Example>\r\nDATA\r\n".as_bytes()).unwrap();
+    assert!(common::read_reply(&mut reader).starts_with("250"));
+    assert!(common::read_reply(&mut reader).starts_with("250"));
+    assert!(common::read_reply(&mut reader).starts_with("354"));
+    writer.write_all("Subject: Grüße\r\n\r\nHallo\r\n.\r\n".as_bytes()).unwrap();
+    assert!(common::read_reply(&mut reader).starts_with("250"));
+
+    let (recipients, smtputf8, received) = capture.0.lock().unwrap()[0].clone();
+    assert_eq!(recipients, vec!["δοκιμή@xn--bcher-kva.example".to_string()]);
+    assert!(smtputf8);
+    assert!(received.contains("with UTF8SMTP id"), "{}", received);
+}
+
+#[test]
+fn test_utf8_requires_parameter(){
+    let capture = Arc::new(Capture(Mutex::new(Vec::new())));
+    let port = start_server(capture.clone());
+    let (mut writer, mut reader) = connect(port);
+
+    writer.write_all(b"EHLO client.test\r\n").unwrap();
+    common::read_reply(&mut reader);
+    writer.write_all("MAIL FROM:<jörg@bücher.example>\r\n".as_bytes()).unwrap();
+    assert!(common::read_reply(&mut reader).starts_with("553 5.6.7"));
+
+    // Invalid UTF-8 is answered rather than ending the session.
+    writer.write_all(b"NOOP \xff\xfe\r\n").unwrap();
+    assert!(common::read_reply(&mut reader).starts_with("500 5.6.7"));
+    writer.write_all(b"MAIL FROM:<a@client.test>\r\nRCPT TO:<\xce\xb4@sink.test>\r\n").unwrap();
+    assert!(common::read_reply(&mut reader).starts_with("250"));
+    assert!(common::read_reply(&mut reader).starts_with("553 5.6.7"));
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use std::sync::Arc;
+use smtp_server::handler::{DmarcHandler, MessageHandler, Verdict};
+use smtp_server::server::{Config, Message};
+use smtp_server::spam::{Action, SpamHandler, SpamRules};
+use smtp_server::spf::SpfResult;
+use smtp_server::store::{MemoryStore, MessageStore};
+
+const RULES: &str = "
+# name           score  test    arguments
+FREE_MONEY       2.5    header  Subject (?i)free money
+LOTTERY          3.0    body    (?i)you have won
+DYNAMIC_HELO     1.5    helo    ^(dsl|dyn|ppp)-
+SPF_SOFTFAIL     1.0    spf     softfail
+LOCAL_NET       -2      ip      127.0.0.0/8
+LOOPBACK_V6      9      ip      ::1
+
+threshold 5
+";
+
+fn message(helo: &str, content: &str) -> Message {
+    let mut msg = Message::new(helo);
+    msg.set_content(content.as_bytes());
+    msg
+}
+
+#[test]
+fn test_rules(){
+    let rules = SpamRules::parse(RULES).unwrap();
+    assert_eq!(rules.rules.len(), 6);
+    assert_eq!(rules.threshold, 5.0);
+    assert_eq!(rules.action, Action::Reject);
+
+    let clean = message("mail.client.test", "Subject: minutes\r\n\r\nSee attached.\r\n");
+    let score = rules.score(&clean);
+    assert_eq!((score.total, score.tests.len()), (0.0, 0));
+
+    // Encoded words and transfer encodings are undone before matching.
+    let mut spam = message("dsl-203-0-113-9.client.test", "Subject: =?utf-8?q?FREE_Money?=\r\n\
+        Content-Type: text/plain\r\n\
+        Content-Transfer-Encoding: base64\r\n\r\n\
+        WW91IGhhdmUgd29uIQ==\r\n");
+    spam.spf = Some(SpfResult::SoftFail);
+    let score = rules.score(&spam);
+    assert_eq!(score.tests, ["FREE_MONEY", "LOTTERY", "DYNAMIC_HELO", "SPF_SOFTFAIL"]);
+    assert_eq!(score.total, 8.0);
+
+    spam.client_ip = Some("127.0.0.9".parse().unwrap());
+    assert_eq!(rules.score(&spam).total, 6.0);
+    spam.client_ip = Some("128.0.0.1".parse().unwrap());
+    assert_eq!(rules.score(&spam).total, 8.0);
+}
+
+#[test]
+fn test_bad_rules(){
+    let error = |text: &str| SpamRules::parse(text).unwrap_err().to_string();
+    assert_eq!(error("\nX lots header Subject x"), "spam rules line 2: bad score \"lots\"");
+    assert_eq!(error("X 1 footer x"), "spam rules line 1: unknown test \"footer\"");
+    assert_eq!(error("X 1 header Subject"), "spam rules line 1: header needs a name and a pattern");
+    assert!(error("X 1 body (unclosed").starts_with("spam rules line 1: regex parse error"));
+    assert_eq!(error("X 1 spf maybe"), "spam rules line 1: unknown SPF result \"maybe\"");
+    assert_eq!(error("X 1 ip 10.0.0.0/x"), "spam rules line 1: bad prefix \"x\"");
+    assert_eq!(error("X 1 ip 10.0.0.0/99"), "spam rules line 1: bad prefix \"99\"");
+    assert_eq!(error("\n\nX 1 ip 2001:db8::/129"), "spam rules line 3: bad prefix \"129\"");
+    assert_eq!(error("action bounce"), "spam rules line 1: action must be reject or quarantine");
+    assert_eq!(error("threshold high"), "spam rules line 1: threshold must be a number");
+}
+
+#[test]
+fn test_headers_and_verdicts(){
+    let mut rules = SpamRules::parse(RULES).unwrap();
+    let handler = SpamHandler::new(rules.clone(), Arc::new(DmarcHandler));
+
+    let mut msg = message("mail.client.test", "Subject: Free money\r\nX-Spam-Status: No, forged\r\n\r\nHi\r\n");
+    assert_eq!(handler.handle(&mut msg), Verdict::Accept);
+    assert_eq!(msg.headers.get("X-Spam-Score").unwrap(), "2.5");
+    assert_eq!(msg.headers.get("X-Spam-Status").unwrap(), "No, score=2.5 required=5.0 tests=FREE_MONEY");
+    assert_eq!(msg.headers.count("X-Spam-Status"), 1);
+
+    let mut msg = message("ppp-1.client.test", "Subject: Free money\r\n\r\nYou have won\r\n");
+    assert_eq!(handler.handle(&mut msg), Verdict::Reject("5.7.1 Message rejected as spam (spam score 7.0 reaches 5.0)".to_string()));
+    assert_eq!(msg.headers.get("X-Spam-Status").unwrap(), "Yes, score=7.0 required=5.0 tests=FREE_MONEY,LOTTERY,DYNAMIC_HELO");
+
+    rules.action = Action::Quarantine;
+    let handler = SpamHandler::new(rules, Arc::new(DmarcHandler));
+    let mut msg = message("ppp-1.client.test", "Subject: Free money\r\n\r\nYou have won\r\n");
+    assert_eq!(handler.handle(&mut msg), Verdict::Quarantine("spam score 7.0 reaches 5.0".to_string()));
+}
+
+#[test]
+fn test_scoring_in_a_session(){
+    let store = Arc::new(MemoryStore::new());
+    let rules = SpamRules::parse(RULES).unwrap();
+    let addr = common::start(Config {
+        handler: Arc::new(SpamHandler::new(rules, Arc::new(DmarcHandler))),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    common::run(addr, "
+        S: 220
+        C: EHLO dyn-7.client.test
+        S: 250*
+        C: MAIL FROM:<app@client.test>
+        C: RCPT TO:<qa@sink.test>
+        C: DATA
+        S: 250 2.1.0 *
+        S: 250 2.1.5 *
+        S: 354
+        C: Subject: free money
+        C:
+        C: You have won
+        C: .
+        # The loopback client earns back 2 of the 7 points, which is not enough.
+        S: 550 5.7.1 Message rejected as spam (spam score 5.0 reaches 5.0)
+        C: MAIL FROM:<app@client.test>
+        C: RCPT TO:<qa@sink.test>
+        C: DATA
+        S: 250 2.1.0 *
+        S: 250 2.1.5 *
+        S: 354
+        C: Subject: free money
+        C:
+        C: .
+        S: 250 OK queued as *
+        C: QUIT
+        S: 221 *
+    ");
+    let stored = store.list().unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].header("X-Spam-Status"), "No, score=2.0 required=5.0 tests=FREE_MONEY,DYNAMIC_HELO,LOCAL_NET");
+}
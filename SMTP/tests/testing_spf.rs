@@ -0,0 +1,85 @@
+use std::net::{IpAddr, Ipv4Addr};
+use smtp_server::dns::StaticResolver;
+use smtp_server::spf::{self, SpfResult};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn fixture() -> StaticResolver {
+    let mut dns = StaticResolver::new();
+    dns.add_txt("example.com", "v=spf1 ip4:192.0.2.0/24 include:_spf.example.net mx -all");
+    dns.add_txt("example.com", "google-site-verification=abc");
+    dns.add_txt("_spf.example.net", "v=spf1 ip6:2001:db8::/32 a:relay.example.net ~all");
+    dns.add_a("relay.example.net", Ipv4Addr::new(198, 51, 100, 7));
+    dns.add_mx("example.com", 10, "mx.example.com");
+    dns.add_a("mx.example.com", Ipv4Addr::new(203, 0, 113, 25));
+    dns.add_txt("soft.example", "v=spf1 redirect=example.com");
+    dns.add_txt("neutral.example", "v=spf1 ?all");
+    dns.add_txt("two.example", "v=spf1 -all");
+    dns.add_txt("two.example", "v=spf1 +all");
+    dns.add_txt("broken.example", "v=spf1 ip4:300.1.1.1 -all");
+    dns.add_txt("exists.example", "v=spf1 exists:%{ir}.%{l1r+-}._spf.%{d} -all");
+    dns.add_a("7.2.0.192.strong._spf.exists.example", Ipv4Addr::new(127, 0, 0, 2));
+    dns.add_failure("down.example");
+    dns
+}
+
+#[test]
+fn test_spf_pass_and_fail(){
+    let dns = fixture();
+    assert_eq!(spf::check_sender(&dns, ip("192.0.2.10"), "user@example.com", "mail.example.com"), SpfResult::Pass);
+    assert_eq!(spf::check_sender(&dns, ip("203.0.113.25"), "user@example.com", "mail.example.com"), SpfResult::Pass);
+    assert_eq!(spf::check_sender(&dns, ip("198.51.100.7"), "user@example.com", "mail.example.com"), SpfResult::Pass);
+    assert_eq!(spf::check_sender(&dns, ip("2001:db8::1"), "user@example.com", "mail.example.com"), SpfResult::Pass);
+    assert_eq!(spf::check_sender(&dns, ip("10.0.0.1"), "user@example.com", "mail.example.com"), SpfResult::Fail);
+}
+
+#[test]
+fn test_spf_redirect_and_qualifiers(){
+    let dns = fixture();
+    assert_eq!(spf::check_sender(&dns, ip("192.0.2.10"), "user@soft.example", "helo.test"), SpfResult::Pass);
+    assert_eq!(spf::check_sender(&dns, ip("10.0.0.1"), "user@soft.example", "helo.test"), SpfResult::Fail);
+    assert_eq!(spf::check_sender(&dns, ip("10.0.0.1"), "user@neutral.example", "helo.test"), SpfResult::Neutral);
+}
+
+#[test]
+fn test_spf_errors(){
+    let dns = fixture();
+    assert_eq!(spf::check_sender(&dns, ip("10.0.0.1"), "user@nospf.example", "helo.test"), SpfResult::None);
+    assert_eq!(spf::check_sender(&dns, ip("10.0.0.1"), "user@two.example", "helo.test"), SpfResult::PermError);
+    assert_eq!(spf::check_sender(&dns, ip("10.0.0.1"), "user@broken.example", "helo.test"), SpfResult::PermError);
+    assert_eq!(spf::check_sender(&dns, ip("10.0.0.1"), "user@down.example", "helo.test"), SpfResult::TempError);
+}
+
+#[test]
+fn test_spf_macros(){
+    let dns = fixture();
+    assert_eq!(spf::check_sender(&dns, ip("192.0.2.7"), "strong-bad@exists.example", "helo.test"), SpfResult::Pass);
+    assert_eq!(spf::check_sender(&dns, ip("192.0.2.8"), "strong-bad@exists.example", "helo.test"), SpfResult::Fail);
+}
+
+#[test]
+fn test_spf_null_sender_uses_helo(){
+    let mut dns = StaticResolver::new();
+    dns.add_txt("mail.example.org", "v=spf1 a -all");
+    dns.add_a("mail.example.org", Ipv4Addr::new(192, 0, 2, 99));
+    assert_eq!(spf::check_sender(&dns, ip("192.0.2.99"), "", "mail.example.org"), SpfResult::Pass);
+}
+
+#[test]
+fn test_spf_lookup_limit(){
+    let mut dns = StaticResolver::new();
+    dns.add_txt("loop.example", "v=spf1 include:loop.example -all");
+    assert_eq!(spf::check_sender(&dns, ip("192.0.2.1"), "a@loop.example", "helo.test"), SpfResult::PermError);
+}
+
+#[test]
+fn test_received_spf_header(){
+    let header = spf::received_spf(SpfResult::Pass, "mx.local", ip("192.0.2.10"), "user@example.com", "mail.example.com");
+    assert_eq!(
+        header,
+        "pass (mx.local: domain of user@example.com designates 192.0.2.10 as permitted sender) \
+         receiver=mx.local; client-ip=192.0.2.10; envelope-from=\"user@example.com\"; helo=mail.example.com;"
+    );
+}
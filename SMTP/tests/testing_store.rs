@@ -0,0 +1,98 @@
+use std::fs;
+use smtp_server::mime;
+use smtp_server::store::{DirStore, MemoryStore, MessageStore, SearchQuery, StoredMessage};
+
+const MULTIPART: &str = "From: =?utf-8?B?SsO2cmc=?= <jorg@example.com>\r\n\
+To: QA Team <qa@sink.test>\r\n\
+Subject: =?iso-8859-1?Q?R=E9sum=E9?= attached\r\n\
+Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+\r\n\
+preamble\r\n\
+--outer\r\n\
+Content-Type: multipart/alternative; boundary=inner\r\n\
+\r\n\
+--inner\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Caf=C3=A9 at noon, long line that is soft =\r\n\
+wrapped\r\n\
+--inner\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>Cafe at noon</p>\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: application/pdf; name=\"cv.pdf\"\r\n\
+Content-Disposition: attachment; filename=\"cv.pdf\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+JVBERi0xLjQK\r\n\
+--outer--\r\n\
+epilogue\r\n";
+
+fn message(id: &str, to: &str, subject: &str) -> StoredMessage {
+    StoredMessage {
+        id: id.to_string(),
+        received_at: 1,
+        mail_from: "sender@example.com".to_string(),
+        recipients: vec![to.to_string()],
+        quarantined: false,
+        folder: None,
+        content: format!("To: {}\r\nSubject: {}\r\n\r\nHello\r\n", to, subject).into_bytes(),
+    }
+}
+
+#[test]
+fn test_mime_parsing(){
+    let root = mime::parse_message(MULTIPART);
+    assert_eq!(root.content_type, "multipart/mixed");
+    assert_eq!(root.parts.len(), 2);
+
+    let leaves = root.leaves();
+    assert_eq!(leaves.len(), 3);
+    assert_eq!(root.find_text("text/plain").unwrap(), "Café at noon, long line that is soft wrapped");
+    assert_eq!(root.find_text("text/html").unwrap(), "<p>Cafe at noon</p>");
+    assert!(leaves[2].is_attachment());
+    assert_eq!(leaves[2].filename().as_deref(), Some("cv.pdf"));
+    assert_eq!(leaves[2].body, b"%PDF-1.4\n");
+
+    assert_eq!(mime::decode_encoded_words("=?utf-8?B?SsO2cmc=?= <jorg@example.com>"), "Jörg <jorg@example.com>");
+    assert_eq!(mime::decode_encoded_words("=?iso-8859-1?Q?R=E9sum=E9?= =?utf-8?Q?_ok?="), "Résumé ok");
+    assert_eq!(mime::decode_encoded_words("plain =? text"), "plain =? text");
+}
+
+#[test]
+fn test_memory_store(){
+    let store = MemoryStore::new();
+    store.add(message("A1", "qa@sink.test", "First")).unwrap();
+    store.add(message("A2", "dev@sink.test", "Second report")).unwrap();
+
+    assert_eq!(store.list().unwrap().len(), 2);
+    let by_recipient = SearchQuery { recipient: Some("QA@".to_string()), ..SearchQuery::default() };
+    assert_eq!(store.search(&by_recipient).unwrap()[0].id, "A1");
+    let by_subject = SearchQuery { subject: Some("report".to_string()), ..SearchQuery::default() };
+    assert_eq!(store.search(&by_subject).unwrap()[0].id, "A2");
+
+    assert!(store.delete("A1").unwrap());
+    assert!(!store.delete("A1").unwrap());
+    store.delete_all().unwrap();
+    assert!(store.list().unwrap().is_empty());
+}
+
+#[test]
+fn test_dir_store(){
+    let dir = std::env::temp_dir().join(format!("smtp_dir_store_{}", std::process::id()));
+    let store = DirStore::new(&dir).unwrap();
+    let mut msg = message("B1", "qa@sink.test", "Stored");
+    msg.quarantined = true;
+    store.add(msg.clone()).unwrap();
+
+    // A second store over the same directory sees the message.
+    let reopened = DirStore::new(&dir).unwrap();
+    assert_eq!(reopened.get("B1").unwrap(), Some(msg));
+    assert_eq!(reopened.get("../B1").unwrap(), None);
+    assert!(reopened.delete("B1").unwrap());
+    assert!(reopened.list().unwrap().is_empty());
+    fs::remove_dir_all(dir).unwrap();
+}
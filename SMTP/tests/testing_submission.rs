@@ -0,0 +1,252 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rustls::crypto::ring;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use smtp_server::auth::MemoryCredentials;
+use smtp_server::server::Config;
+use smtp_server::store::{MemoryStore, MessageStore};
+use smtp_server::tls;
+
+fn credentials() -> Arc<MemoryCredentials> {
+    let mut credentials = MemoryCredentials::new();
+    credentials.add("qa@sink.test", "secret");
+    credentials.add("ops", "hunter2");
+    Arc::new(credentials)
+}
+
+fn plain(user: &str, password: &str) -> String {
+    BASE64.encode(format!("\0{}\0{}", user, password))
+}
+
+fn start_submission(store: Arc<MemoryStore>) -> SocketAddr {
+    common::start(Config {
+        credentials: Some(credentials()),
+        submission: true,
+        store: Some(store),
+        ..common::offline_config()
+    })
+}
+
+#[test]
+fn test_auth(){
+    let addr = common::start(common::offline_config());
+    common::run(addr, "
+        S: 220*
+        C: EHLO client.test
+        S: 250-mx.sink.test250-8BITMIME*250 SMTPUTF8
+        C: AUTH PLAIN
+        S: 502 5.5.1 AUTH not available
+    ");
+
+    let addr = common::start(Config { credentials: Some(credentials()), ..common::offline_config() });
+    common::run(addr, &format!("
+        S: 220*
+        C: EHLO client.test
+        S: 250-*250 AUTH PLAIN LOGIN
+        C: AUTH
+        S: 501 5.5.4 Syntax: AUTH mechanism [initial-response]
+        C: AUTH CRAM-MD5
+        S: 504 5.5.4 Unrecognized authentication type
+        C: AUTH PLAIN {}
+        S: 535 5.7.8 Authentication credentials invalid
+        C: AUTH PLAIN not-base64!
+        S: 501 5.5.2 Cannot decode response
+        C: AUTH PLAIN {}
+        S: 501 5.5.2 Malformed PLAIN response
+        C: AUTH LOGIN
+        S: 334 VXNlcm5hbWU6
+        C: *
+        S: 501 5.0.0 Authentication cancelled
+        C: AUTH PLAIN
+        S: 334*
+        C: {}
+        S: 235 2.7.0 Authentication successful
+        C: AUTH PLAIN {}
+        S: 503 5.5.1 Already authenticated
+    ", plain("qa@sink.test", "wrong"), BASE64.encode("someone\0qa@sink.test\0secret"), plain("qa@sink.test", "secret"), plain("qa@sink.test", "secret")));
+
+    common::run(addr, &format!("
+        S: 220*
+        C: EHLO client.test
+        S: 250-*
+        C: MAIL FROM:<app@client.test>
+        S: 250*
+        C: AUTH PLAIN {}
+        S: 503 5.5.1 AUTH not permitted during a mail transaction
+        C: RSET
+        S: 250*
+        C: AUTH LOGIN {}
+        S: 334 UGFzc3dvcmQ6
+        C: {}
+        S: 235 2.7.0 Authentication successful
+    ", plain("ops", "hunter2"), BASE64.encode("ops"), BASE64.encode("hunter2")));
+}
+
+fn command(stream: &mut (impl Read + Write), line: &str) -> String {
+    stream.write_all(format!("{}\r\n", line).as_bytes()).unwrap();
+    common::read_reply(stream).trim_end().to_string()
+}
+
+#[test]
+fn test_starttls(){
+    let store = Arc::new(MemoryStore::new());
+    let addr = common::start(Config {
+        credentials: Some(credentials()),
+        tls: Some(tls::load(common::fixture("tls_cert.pem"), common::fixture("tls_key.pem")).unwrap()),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    let mut tcp = TcpStream::connect(addr).unwrap();
+    common::read_reply(&mut tcp);
+    let ehlo = command(&mut tcp, "EHLO client.test");
+    assert!(ehlo.ends_with("250 STARTTLS"), "{}", ehlo);
+    // No passwords in the clear.
+    assert_eq!(command(&mut tcp, &format!("AUTH PLAIN {}", plain("qa@sink.test", "secret"))), "538 5.7.11 Encryption required for requested authentication mechanism");
+    assert_eq!(command(&mut tcp, "STARTTLS"), "220 2.0.0 Ready to start TLS");
+
+    let mut roots = RootCertStore::empty();
+    let cert = std::fs::read(common::fixture("tls_cert.pem")).unwrap();
+    for cert in rustls_pemfile::certs(&mut &cert[..]) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connection = ClientConnection::new(Arc::new(config), ServerName::try_from("mx.sink.test").unwrap()).unwrap();
+    let mut tls = StreamOwned::new(connection, tcp);
+    let ehlo = command(&mut tls, "EHLO client.test");
+    assert!(ehlo.ends_with("250 AUTH PLAIN LOGIN") && !ehlo.contains("STARTTLS"), "{}", ehlo);
+    assert_eq!(command(&mut tls, "STARTTLS"), "503 5.5.1 TLS already active");
+    assert_eq!(command(&mut tls, &format!("AUTH PLAIN {}", plain("qa@sink.test", "secret"))), "235 2.7.0 Authentication successful");
+    assert!(command(&mut tls, "MAIL FROM:<qa@sink.test>").starts_with("250"));
+    assert!(command(&mut tls, "RCPT TO:<ops@sink.test>").starts_with("250"));
+    assert!(command(&mut tls, "DATA").starts_with("354"));
+    assert!(command(&mut tls, "Subject: over TLS\r\n\r\nHi\r\n.").starts_with("250 OK queued as"));
+    assert!(command(&mut tls, "QUIT").starts_with("221"));
+
+    let stored = &store.list().unwrap()[0];
+    assert!(stored.header("Received").contains("with ESMTPSA"), "{}", stored.header("Received"));
+}
+
+#[test]
+fn test_submission_requires_auth(){
+    let addr = start_submission(Arc::new(MemoryStore::new()));
+    common::run(addr, "
+        S: 220*
+        C: EHLO client.test
+        S: 250-*250 AUTH PLAIN LOGIN
+        C: MAIL FROM:<qa@sink.test>
+        S: 530 5.7.0 Authentication required
+    ");
+}
+
+#[test]
+fn test_submission_fixups(){
+    let store = Arc::new(MemoryStore::new());
+    let addr = start_submission(store.clone());
+    common::run(addr, &format!("
+        S: 220*
+        C: EHLO client.test
+        S: 250-*
+        C: AUTH PLAIN {}
+        S: 235*
+        C: MAIL FROM:<qa@sink.test>
+        S: 250*
+        C: RCPT TO:<ops@sink.test>
+        S: 250*
+        C: RCPT TO:<hidden@sink.test>
+        S: 250*
+        C: DATA
+        S: 354*
+        C: To: ops@sink.test
+        C: Bcc: hidden@sink.test
+        C: Subject: bare
+        C:
+        C: Hi
+        C: .
+        S: 250 OK queued as *
+    ", plain("qa@sink.test", "secret")));
+
+    let stored = &store.list().unwrap()[0];
+    let headers = stored.headers();
+    assert_eq!(headers.get("From").as_deref(), Some("qa@sink.test"));
+    assert_eq!(headers.get("Message-ID"), Some(format!("<{}@mx.sink.test>", stored.id)));
+    assert!(common::matches("???, ?? ??? ???? ??:??:?? +0000", &headers.get("Date").unwrap()), "{:?}", headers.get("Date"));
+    assert!(!headers.contains("Bcc"));
+    assert_eq!(stored.recipients, ["ops@sink.test", "hidden@sink.test"]);
+}
+
+#[test]
+fn test_submission_keeps_headers(){
+    let store = Arc::new(MemoryStore::new());
+    let addr = start_submission(store.clone());
+    common::run(addr, &format!("
+        S: 220*
+        C: EHLO client.test
+        S: 250-*
+        C: AUTH PLAIN {}
+        S: 235*
+        C: MAIL FROM:<ops@mx.sink.test>
+        S: 250*
+        C: RCPT TO:<qa@sink.test>
+        S: 250*
+        C: DATA
+        S: 354*
+        C: From: \"Ops Team\" <OPS@MX.sink.test>
+        C: Date: Mon, 19 Oct 2026 08:11:00 +0000
+        C: Message-ID: <kept@client.test>
+        C:
+        C: Hi
+        C: .
+        S: 250 OK queued as *
+    ", plain("ops", "hunter2")));
+
+    let headers = store.list().unwrap()[0].headers();
+    assert_eq!(headers.get("From").as_deref(), Some("\"Ops Team\" <OPS@MX.sink.test>"));
+    assert_eq!(headers.get("Date").as_deref(), Some("Mon, 19 Oct 2026 08:11:00 +0000"));
+    assert_eq!(headers.get("Message-ID").as_deref(), Some("<kept@client.test>"));
+}
+
+#[test]
+fn test_submission_from_must_match(){
+    let store = Arc::new(MemoryStore::new());
+    let addr = start_submission(store.clone());
+    common::run(addr, &format!("
+        S: 220*
+        C: EHLO client.test
+        S: 250-*
+        C: AUTH PLAIN {}
+        S: 235*
+        C: MAIL FROM:<qa@sink.test>
+        S: 250*
+        C: RCPT TO:<ops@sink.test>
+        S: 250*
+        C: DATA
+        S: 354*
+        C: From: The Boss <boss@sink.test>
+        C:
+        C: Wire the money
+        C: .
+        S: 550 5.7.1 From header must be <qa@sink.test>
+        C: MAIL FROM:<qa@sink.test>
+        S: 250*
+        C: RCPT TO:<ops@sink.test>
+        S: 250*
+        C: DATA
+        S: 354*
+        C: From: qa@sink.test, boss@sink.test
+        C:
+        C: Hi
+        C: .
+        S: 550 5.7.1 *
+    ", plain("qa@sink.test", "secret")));
+    assert!(store.list().unwrap().is_empty());
+}
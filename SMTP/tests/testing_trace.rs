@@ -0,0 +1,139 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use smtp_server::dns::{self, StaticResolver};
+use smtp_server::handler::{MessageHandler, Verdict};
+use smtp_server::server::{self, Config, Message};
+use smtp_server::trace::{self, Hop};
+
+/// Keeps the header section of every message it is given.
+struct Capture(Mutex<Vec<String>>);
+
+impl MessageHandler for Capture {
+    fn handle(&self, msg: &mut Message) -> Verdict {
+        self.0.lock().unwrap().push(msg.headers.to_string());
+        Verdict::Accept
+    }
+}
+
+fn start_server(capture: Arc<Capture>) -> u16 {
+    let mut dns = StaticResolver::new();
+    dns.add_ptr("127.0.0.1".parse().unwrap(), "localhost.test");
+    dns.add_a("localhost.test", Ipv4Addr::LOCALHOST);
+    let config = Config {
+        hostname: "mx.sink.test".to_string(),
+        resolver: Arc::new(dns),
+        handler: capture,
+        max_hops: 5,
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || server::serve(listener, Arc::new(config)));
+    port
+}
+
+fn send(port: u16, content: &str) -> String {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut reply = String::new();
+    reader.read_line(&mut reply).unwrap();
+    writer.write_all(b"EHLO client.test\r\nMAIL FROM:<bounce@client.test>\r\nRCPT TO:<qa@sink.test>\r\nDATA\r\n").unwrap();
+    while reply.starts_with("2") {
+        reply.clear();
+        reader.read_line(&mut reply).unwrap();
+    }
+    assert!(reply.starts_with("354"), "{}", reply);
+    writer.write_all(content.as_bytes()).unwrap();
+    writer.write_all(b".\r\n").unwrap();
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    reply
+}
+
+#[test]
+fn test_rfc5322_date(){
+    assert_eq!(trace::rfc5322_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 +0000");
+    assert_eq!(trace::rfc5322_date(UNIX_EPOCH + Duration::from_secs(1_000_000_000)), "Sun, 09 Sep 2001 01:46:40 +0000");
+    assert_eq!(trace::rfc5322_date(UNIX_EPOCH + Duration::from_secs(951_782_400)), "Tue, 29 Feb 2000 00:00:00 +0000");
+}
+
+#[test]
+fn test_received_header(){
+    let hop = Hop {
+        helo: "client.test",
+        reverse_name: None,
+        ip: "2001:db8::1".parse().unwrap(),
+        by: "mx.sink.test",
+        protocol: trace::protocol(true, true, false),
+        queue_id: "ABC123",
+        recipient: Some("qa@sink.test"),
+        time: UNIX_EPOCH,
+    };
+    assert_eq!(
+        trace::received(&hop),
+        "from client.test ([IPv6:2001:db8::1])\r\n\tby mx.sink.test with ESMTPS id ABC123\r\n\tfor <qa@sink.test>;\r\n\tThu, 01 Jan 1970 00:00:00 +0000"
+    );
+    assert_eq!(trace::protocol(true, false, true), "ESMTPA");
+    assert_ne!(trace::new_queue_id(), trace::new_queue_id());
+}
+
+#[test]
+fn test_trace_headers_added(){
+    let capture = Arc::new(Capture(Mutex::new(Vec::new())));
+    let port = start_server(capture.clone());
+
+    let reply = send(port, "Return-Path: <forged@example.com>\r\nSubject: hi\r\n\r\nbody\r\n");
+    assert!(reply.starts_with("250"), "{}", reply);
+
+    let headers = capture.0.lock().unwrap()[0].clone();
+    assert!(headers.starts_with("Return-Path: <bounce@client.test>\r\nReceived: from client.test (localhost.test [127.0.0.1])\r\n\tby mx.sink.test with ESMTP id "), "{}", headers);
+    assert!(headers.contains("\tfor <qa@sink.test>;\r\n"));
+    assert!(!headers.contains("forged@example.com"));
+}
+
+#[test]
+fn test_mail_loop_detected(){
+    let capture = Arc::new(Capture(Mutex::new(Vec::new())));
+    let port = start_server(capture.clone());
+
+    let hops = "Received: from a by b; Thu, 01 Jan 1970 00:00:00 +0000\r\n".repeat(5);
+    let reply = send(port, &format!("{}Subject: loop\r\n\r\nbody\r\n", hops));
+    assert!(reply.starts_with("554 5.4.6"), "{}", reply);
+    assert!(capture.0.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_confirmed_name(){
+    let ip: IpAddr = "192.0.2.7".parse().unwrap();
+    let mut dns = StaticResolver::new();
+    dns.add_ptr(ip, "forged.example");
+    dns.add_ptr(ip, "bad\r\nname.example");
+    dns.add_ptr(ip, "mail.client.test.");
+    dns.add_a("forged.example", Ipv4Addr::new(198, 51, 100, 1));
+    dns.add_a("bad\r\nname.example", Ipv4Addr::new(192, 0, 2, 7));
+    dns.add_a("mail.client.test", Ipv4Addr::new(192, 0, 2, 7));
+    assert_eq!(dns::confirmed_name(&dns, ip).as_deref(), Some("mail.client.test"));
+
+    let mut dns = StaticResolver::new();
+    dns.add_ptr(ip, "forged.example");
+    assert_eq!(dns::confirmed_name(&dns, ip), None);
+}
+
+#[test]
+fn test_greeting_cannot_add_headers(){
+    let capture = Arc::new(Capture(Mutex::new(Vec::new())));
+    let port = start_server(capture);
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut reply = String::new();
+    reader.read_line(&mut reply).unwrap();
+    writer.write_all(b"EHLO client.test\nBcc: victim@example.com\r\n").unwrap();
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("501 5.5.4"), "{}", reply);
+}
@@ -0,0 +1,223 @@
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use smtp_server::server::Config;
+use smtp_server::sieve::{MemoryScripts, Script, Sieve};
+use smtp_server::store::{MemoryStore, MessageStore};
+use smtp_server::webhook::Webhook;
+
+/// One request as the stand-in endpoint received it.
+#[derive(Debug, Clone)]
+struct Request {
+    request_line: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+type Log = Arc<Mutex<Vec<Request>>>;
+
+/// Starts a stand-in endpoint that answers with `statuses` in turn, then
+/// keeps answering with the last one.
+fn start_endpoint(statuses: &[u16]) -> (String, Log) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let log = Log::default();
+    let requests = log.clone();
+    let statuses = statuses.to_vec();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(':').unwrap();
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+            let mut request = Request { request_line: request_line.trim_end().to_string(), headers, body: String::new() };
+            let length = request.header("Content-Length").unwrap().parse().unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            request.body = String::from_utf8(body).unwrap();
+
+            let mut requests = requests.lock().unwrap();
+            let status = statuses[requests.len().min(statuses.len() - 1)];
+            requests.push(request);
+            drop(requests);
+            write!(stream, "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+        }
+    });
+    (format!("http://{}/hooks/mail", addr), log)
+}
+
+fn webhook(url: &str) -> Webhook {
+    Webhook { backoff: Duration::from_millis(10), ..Webhook::new(url).unwrap() }
+}
+
+fn start_with(webhook: Webhook) -> std::net::SocketAddr {
+    common::start(Config { webhook: Some(Arc::new(webhook)), ..common::offline_config() })
+}
+
+const SEND: &str = "S: 220*
+C: EHLO client.test
+S: 250-*
+C: MAIL FROM:<app@client.test>
+S: 250*
+C: RCPT TO:<qa@sink.test>
+S: 250*
+C: DATA
+S: 354*";
+
+const MULTIPART: &str = "C: From: =?utf-8?B?SsO2cmc=?= <jorg@example.com>
+C: To: QA Team <qa@sink.test>
+C: Subject: =?iso-8859-1?Q?R=E9sum=E9?= attached
+C: Content-Type: multipart/mixed; boundary=\"outer\"
+C:
+C: --outer
+C: Content-Type: multipart/alternative; boundary=inner
+C:
+C: --inner
+C: Content-Type: text/plain; charset=utf-8
+C:
+C: Cafe at noon
+C: --inner
+C: Content-Type: text/html
+C:
+C: <p>Cafe at \"noon\"</p>
+C: --inner--
+C: --outer
+C: Content-Type: application/pdf; name=\"cv.pdf\"
+C: Content-Disposition: attachment; filename=\"cv.pdf\"
+C: Content-Transfer-Encoding: base64
+C:
+C: JVBERi0xLjQK
+C: --outer--
+C: .";
+
+#[test]
+fn test_webhook_payload() {
+    let (url, log) = start_endpoint(&[200]);
+    let addr = start_with(webhook(&url));
+    common::run(addr, &format!("{}\n{}\nS: 250 OK queued as *\nC: QUIT\nS: 221*", SEND, MULTIPART));
+
+    let requests = log.lock().unwrap();
+    assert_eq!(requests.len(), 1);
+    let request = &requests[0];
+    assert_eq!(request.request_line, "POST /hooks/mail HTTP/1.1");
+    assert_eq!(request.header("Content-Type"), Some("application/json"));
+    assert_eq!(request.header("X-Webhook-Signature"), None);
+    let id = request.header("X-Webhook-Id").unwrap();
+    assert!(request.body.starts_with(&format!("{{\"id\":\"{}\",\"received_at\":", id)), "{}", request.body);
+    for expected in [
+        "\"envelope\":{\"mail_from\":\"app@client.test\",\"recipients\":[\"qa@sink.test\"],\"helo\":\"client.test\",\"client_ip\":\"127.0.0.1\"}",
+        "\"from\":\"Jörg <jorg@example.com>\"",
+        "\"subject\":\"Résumé attached\"",
+        "{\"name\":\"Return-Path\",\"value\":\"<app@client.test>\"}",
+        "{\"name\":\"Subject\",\"value\":\"Résumé attached\"}",
+        "\"text\":\"Cafe at noon\"",
+        "\"html\":\"<p>Cafe at \\\"noon\\\"</p>\"",
+        "\"attachments\":[{\"filename\":\"cv.pdf\",\"content_type\":\"application/pdf\",\"size\":9,\"content\":\"JVBERi0xLjQK\"}]",
+    ] {
+        assert!(request.body.contains(expected), "{} not in {}", expected, request.body);
+    }
+}
+
+#[test]
+fn test_webhook_signature() {
+    // RFC 4231 test case 2.
+    let mut signer = Webhook::new("http://hooks.test/").unwrap();
+    assert_eq!(signer.signature(b"what do ya want for nothing?"), None);
+    signer.secret = Some(b"Jefe".to_vec());
+    assert_eq!(
+        signer.signature(b"what do ya want for nothing?").as_deref(),
+        Some("sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+    );
+
+    let (url, log) = start_endpoint(&[204]);
+    let addr = start_with(Webhook { secret: Some(b"Jefe".to_vec()), ..webhook(&url) });
+    common::run(addr, &format!("{}\nC: Subject: signed\nC:\nC: Hi\nC: .\nS: 250 OK queued as *", SEND));
+
+    let request = log.lock().unwrap()[0].clone();
+    assert_eq!(request.header("X-Webhook-Signature"), signer.signature(request.body.as_bytes()).as_deref());
+}
+
+#[test]
+fn test_webhook_retries() {
+    let (url, log) = start_endpoint(&[500, 503, 200]);
+    let addr = start_with(webhook(&url));
+    common::run(addr, &format!("{}\nC: Subject: retried\nC:\nC: Hi\nC: .\nS: 250 OK queued as *", SEND));
+    let requests = log.lock().unwrap();
+    assert_eq!(requests.len(), 3);
+    // Every attempt carries the same message.
+    assert_eq!(requests[0].header("X-Webhook-Id"), requests[2].header("X-Webhook-Id"));
+}
+
+#[test]
+fn test_webhook_failures() {
+    // Gives up after the configured attempts.
+    let (url, log) = start_endpoint(&[502]);
+    let addr = start_with(webhook(&url));
+    common::run(addr, &format!("{}\nC: Subject: down\nC:\nC: Hi\nC: .\nS: 451 4.3.0 Could not deliver message, try again later", SEND));
+    assert_eq!(log.lock().unwrap().len(), 3);
+
+    // A client error is not retried.
+    let (url, log) = start_endpoint(&[422]);
+    let addr = start_with(webhook(&url));
+    common::run(addr, &format!("{}\nC: Subject: refused\nC:\nC: Hi\nC: .\nS: 451 4.3.0 *\nC: QUIT\nS: 221*", SEND));
+    assert_eq!(log.lock().unwrap().len(), 1);
+
+    // Nothing listening.
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let addr = start_with(Webhook { attempts: 2, ..webhook(&format!("http://127.0.0.1:{}/", port)) });
+    common::run(addr, &format!("{}\nC: Subject: nobody\nC:\nC: Hi\nC: .\nS: 451 4.3.0 *", SEND));
+}
+
+#[test]
+fn test_webhook_failure_files_nothing() {
+    let (url, log) = start_endpoint(&[422, 200]);
+    let mut scripts = MemoryScripts::new();
+    scripts.add("qa@sink.test", Script::parse("redirect \"pager@sink.test\"; keep;").unwrap());
+    let store = Arc::new(MemoryStore::new());
+    let addr = common::start(Config {
+        webhook: Some(Arc::new(webhook(&url))),
+        sieve: Some(Arc::new(Sieve::new(Arc::new(scripts)))),
+        store: Some(store.clone()),
+        ..common::offline_config()
+    });
+    common::run(addr, &format!("{}\nC: Subject: twice\nC:\nC: Hi\nC: .\nS: 451 4.3.0 *", SEND));
+    assert!(store.list().unwrap().is_empty());
+
+    // The client's retry is filed once.
+    common::run(addr, &format!("{}\nC: Subject: twice\nC:\nC: Hi\nC: .\nS: 250 OK queued as *", SEND));
+    assert_eq!(log.lock().unwrap().len(), 2);
+    let mut recipients: Vec<_> = store.list().unwrap().into_iter().map(|msg| msg.recipients).collect();
+    recipients.sort();
+    assert_eq!(recipients, [["pager@sink.test"], ["qa@sink.test"]]);
+}
+
+#[test]
+fn test_webhook_url() {
+    let webhook = Webhook::new("http://hooks.example.com").unwrap();
+    assert_eq!((webhook.authority.as_str(), webhook.path.as_str()), ("hooks.example.com:80", "/"));
+    let webhook = Webhook::new("HTTP://[::1]:8080/in?token=x").unwrap();
+    assert_eq!((webhook.authority.as_str(), webhook.path.as_str()), ("[::1]:8080", "/in?token=x"));
+    let webhook = Webhook::new("http://[::1]/").unwrap();
+    assert_eq!(webhook.authority, "[::1]:80");
+    assert!(Webhook::new("https://hooks.example.com/").is_err());
+    assert!(Webhook::new("http:///path").is_err());
+}